@@ -0,0 +1,307 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BlocklistError {
+    #[error("Line {line} of the blocklist is invalid: {reason}")]
+    InvalidLine { line: usize, reason: String },
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+/// IPv4 addresses are kept as IPv4-mapped IPv6 addresses so both fit in one list, which also
+/// blocks IPv4 peers reaching an IPv6 socket
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// Sorts the ranges and merges those that overlap or touch
+fn merge_ranges(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Address ranges to refuse peers from, merged and sorted so that a lookup is a binary search
+///
+/// Parses the P2P plaintext format (`name:start-end`) and the DAT format
+/// (`start - end , access , name`, where an access level of 128 or more lets the range through)
+/// line by line, so a file may mix them. Empty lines and lines starting with `#` or `//` are
+/// skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Blocklist {
+    /// Inclusive, non-overlapping and sorted
+    ranges: Vec<(u128, u128)>,
+}
+
+impl Blocklist {
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (IpAddr, IpAddr)>) -> Self {
+        let ranges = ranges
+            .into_iter()
+            .map(|(start, end)| {
+                let (start, end) = (key(start), key(end));
+                (start.min(end), start.max(end))
+            })
+            .collect();
+        Self {
+            ranges: merge_ranges(ranges),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BlocklistError> {
+        let text = std::fs::read(path).map_err(|err| BlocklistError::Io(err.to_string()))?;
+        // Lists in the wild aren't always UTF-8, only the addresses need to be
+        String::from_utf8_lossy(&text).parse()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = key(ip);
+        let after = self.ranges.partition_point(|(start, _)| *start <= ip);
+        after > 0 && self.ranges[after - 1].1 >= ip
+    }
+
+    /// Number of ranges once the overlapping ones are merged
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The ranges of both lists
+    pub fn merge(&self, other: &Blocklist) -> Blocklist {
+        Blocklist {
+            ranges: merge_ranges([self.ranges.as_slice(), other.ranges.as_slice()].concat()),
+        }
+    }
+}
+
+impl FromStr for Blocklist {
+    type Err = BlocklistError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for (at, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let invalid = |reason: &str| BlocklistError::InvalidLine {
+                line: at + 1,
+                reason: reason.to_owned(),
+            };
+            let range = if is_dat_line(line) {
+                parse_dat_line(line).map_err(|reason| invalid(&reason))?
+            } else {
+                parse_p2p_line(line).map_err(|reason| invalid(&reason))?
+            };
+            ranges.extend(range);
+        }
+        Ok(Self::from_ranges(ranges))
+    }
+}
+
+/// `name:start-end`. Both the name and IPv6 addresses may hold colons, so the range is the
+/// first one that parses after a colon
+fn parse_p2p_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, String> {
+    let mut last_err = "Expected <name>:<start>-<end>".to_owned();
+    for (at, _) in line.match_indices(':') {
+        match parse_range(&line[at + 1..]) {
+            Ok(range) => return Ok(Some(range)),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Whether the line starts with the `start - end ,` of a DAT line. P2P names may hold commas too,
+/// so a comma alone doesn't tell the formats apart
+fn is_dat_line(line: &str) -> bool {
+    line.split_once(',')
+        .is_some_and(|(range, _)| parse_range(range).is_ok())
+}
+
+/// `start - end , access , name`. Ranges with an access level of 128 or more are allowed
+fn parse_dat_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, String> {
+    let mut fields = line.split(',');
+    let range = fields.next().unwrap_or_default();
+    let access = fields
+        .next()
+        .ok_or("Expected <start> - <end> , <access> , <name>".to_owned())?
+        .trim()
+        .parse::<u32>()
+        .map_err(|err| format!("Invalid access level: {err}"))?;
+    if access >= 128 {
+        return Ok(None);
+    }
+    parse_range(range).map(Some)
+}
+
+fn parse_range(range: &str) -> Result<(IpAddr, IpAddr), String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or("Expected <start>-<end>".to_owned())?;
+    Ok((parse_ip(start.trim())?, parse_ip(end.trim())?))
+}
+
+/// Like parsing an [`IpAddr`], except that IPv4 octets may be zero padded as DAT files do
+fn parse_ip(s: &str) -> Result<IpAddr, String> {
+    if s.contains(':') {
+        return s
+            .parse::<Ipv6Addr>()
+            .map(IpAddr::V6)
+            .map_err(|err| format!("Invalid address `{s}`: {err}"));
+    }
+    let octets = s
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Invalid address `{s}`: {err}"))?;
+    let octets =
+        <[u8; 4]>::try_from(octets).map_err(|_| format!("Invalid address `{s}`: not 4 octets"))?;
+    Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+#[derive(Debug, Default)]
+struct FilterState {
+    blocklist: Blocklist,
+    /// The files the blocklist was loaded from, read again on reload
+    paths: Vec<PathBuf>,
+}
+
+/// The blocklist in effect, shared by everything that takes in peers so that a reload applies
+/// everywhere at once. The default filter blocks nothing
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    state: Arc<RwLock<FilterState>>,
+}
+
+impl IpFilter {
+    /// Loads and merges the blocklist files
+    pub fn load(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Result<Self, BlocklistError> {
+        let filter = Self::default();
+        {
+            let mut state = filter.state.write().expect("Poisoned lock");
+            state.paths = paths
+                .into_iter()
+                .map(|path| path.as_ref().to_path_buf())
+                .collect();
+        }
+        filter.reload()?;
+        Ok(filter)
+    }
+
+    /// Reads the blocklist files again, keeping the current list if any of them fails. Returns
+    /// the number of ranges
+    pub fn reload(&self) -> Result<usize, BlocklistError> {
+        let paths = self.state.read().expect("Poisoned lock").paths.clone();
+        let mut blocklist = Blocklist::default();
+        for path in &paths {
+            blocklist = blocklist.merge(&Blocklist::load(path)?);
+        }
+        let len = blocklist.len();
+        self.set(blocklist);
+        Ok(len)
+    }
+
+    /// Replaces the blocklist in effect
+    pub fn set(&self, blocklist: Blocklist) {
+        self.state.write().expect("Poisoned lock").blocklist = blocklist;
+    }
+
+    #[inline]
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.state
+            .read()
+            .expect("Poisoned lock")
+            .blocklist
+            .contains(ip)
+    }
+
+    /// Number of ranges blocked
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.state.read().expect("Poisoned lock").blocklist.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Blocklist, BlocklistError, IpFilter};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_both_formats() {
+        let blocklist = "# comment\n\
+                         Some org: a subsidiary:10.0.0.0-10.0.0.255\n\
+                         Foo Corp, Inc:172.16.0.0-172.16.0.255\n\
+                         010.000.001.000 - 010.000.001.255 , 000 , Adjacent range\n\
+                         192.168.000.000 - 192.168.255.255 , 200 , Allowed\n\
+                         v6 range:2001:db8::-2001:db8::ffff\n"
+            .parse::<Blocklist>()
+            .unwrap();
+        // The two adjacent ranges are merged
+        assert_eq!(3, blocklist.len());
+        assert!(blocklist.contains(ip("10.0.0.7")));
+        assert!(blocklist.contains(ip("172.16.0.9")));
+        assert!(blocklist.contains(ip("10.0.1.255")));
+        assert!(!blocklist.contains(ip("10.0.2.0")));
+        assert!(!blocklist.contains(ip("192.168.1.1")));
+        assert!(blocklist.contains(ip("2001:db8::1")));
+        assert!(!blocklist.contains(ip("2001:db8::1:0")));
+        assert!(blocklist.contains(ip("::ffff:10.0.0.1")));
+        assert_eq!(
+            Err(BlocklistError::InvalidLine {
+                line: 2,
+                reason: "Invalid address `10.0.0`: not 4 octets".to_owned()
+            }),
+            "\nbad:10.0.0-10.0.0.1".parse::<Blocklist>()
+        );
+    }
+
+    #[test]
+    fn test_filter_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.p2p");
+        std::fs::write(&path, "a:10.0.0.1-10.0.0.1\n").unwrap();
+        let filter = IpFilter::load([&path]).unwrap();
+        assert!(filter.is_blocked(ip("10.0.0.1")));
+
+        std::fs::write(&path, "b:10.0.0.2-10.0.0.2\n").unwrap();
+        assert_eq!(Ok(1), filter.reload());
+        assert!(!filter.is_blocked(ip("10.0.0.1")));
+        assert!(filter.clone().is_blocked(ip("10.0.0.2")));
+        // A broken file leaves the list in effect alone
+        std::fs::write(&path, "garbage\n").unwrap();
+        assert!(filter.reload().is_err());
+        assert!(filter.is_blocked(ip("10.0.0.2")));
+    }
+}
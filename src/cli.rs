@@ -1,10 +1,23 @@
 use std::{net::SocketAddrV4, path::PathBuf};
 
-use clap::{Parser, Subcommand};
+use bittorrent_starter_rust::{
+    config::ClientConfig,
+    daemon::{ApiEndpoint, DEFAULT_API_ENDPOINT},
+    mse::EncryptionPolicy,
+    peer::picker::DEFAULT_DEADLINE_WINDOW,
+    proxy::Proxy,
+    ratelimit::parse_size,
+};
+use clap::{Args, Parser, Subcommand};
+use reqwest::Url;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    /// A TOML file of client settings, which flags override. Read from $BITTORRENT_CONFIG when
+    /// omitted
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
     #[command(subcommand)]
     pub commands: Commands,
 }
@@ -27,6 +40,10 @@ pub enum Commands {
         /// The torrent file that is parsed and extracted from to get the information required for
         /// searching for peers to connect to.
         torrent_file: PathBuf,
+        #[command(flatten)]
+        dht: DhtArgs,
+        #[command(flatten)]
+        proxy: ProxyArgs,
     },
     /// Sets up a TCP connection with a peer
     Handshake {
@@ -35,6 +52,8 @@ pub enum Commands {
         torrent_file: PathBuf,
         /// The IP address and port of the peer: <peer_ip>:<peer_port>
         peer_addr: SocketAddrV4,
+        #[command(flatten)]
+        proxy: ProxyArgs,
     },
     /// Downloads a piece
     DownloadPiece {
@@ -45,12 +64,196 @@ pub enum Commands {
         /// The output path location to the piece downloaded
         #[arg(long, short)]
         out_file: PathBuf,
+        #[command(flatten)]
+        dht: DhtArgs,
+        /// Also finds peers on the local network through multicast announcements
+        #[arg(long)]
+        lsd: bool,
+        /// Whether peer connections are encrypted: require, prefer or plaintext
+        #[arg(long)]
+        encryption: Option<EncryptionPolicy>,
+        /// Connects to peers over uTP, falling back to TCP for peers that don't answer
+        #[arg(long)]
+        utp: bool,
+        #[command(flatten)]
+        proxy: ProxyArgs,
     },
     /// Downloads an entire file from the torrent
     Download {
         torrent_file: PathBuf,
-        /// The output path location to the piece downloaded
+        /// The output file of a single file torrent or directory of a multi file torrent
+        #[arg(long, short)]
+        out_file: PathBuf,
+        /// Only downloads the files matching, given as an index or a glob like 'bin/*'
+        #[arg(long)]
+        only: Vec<String>,
+        /// Sets the priority of the files matching: <FILE>=skip|low|normal|high
+        #[arg(long)]
+        priority: Vec<String>,
+        /// Downloads pieces in order so that files can be used before they finish
+        #[arg(long)]
+        sequential: bool,
+        /// Pieces after the reading position that are fetched before anything else
+        #[arg(long, default_value_t = DEFAULT_DEADLINE_WINDOW)]
+        deadline_window: usize,
+        /// Streams progress events to stdout as JSON, one per line, instead of a progress bar
+        #[arg(long)]
+        json_events: bool,
+        #[command(flatten)]
+        rates: RateArgs,
+        /// A blocklist of addresses never to talk to, in the P2P or DAT format; may be repeated
+        #[arg(long)]
+        blocklist: Vec<PathBuf>,
+        #[command(flatten)]
+        dht: DhtArgs,
+        /// Also finds peers on the local network through multicast announcements
+        #[arg(long)]
+        lsd: bool,
+        /// Whether peer connections are encrypted: require, prefer or plaintext
+        #[arg(long)]
+        encryption: Option<EncryptionPolicy>,
+        /// Connects to peers over uTP, falling back to TCP for peers that don't answer
+        #[arg(long)]
+        utp: bool,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// Creates a torrent file out of a file or a directory
+    Create {
+        /// The file or directory the torrent describes
+        path: PathBuf,
+        /// The output path location to the torrent file created
         #[arg(long, short)]
         out_file: PathBuf,
+        /// The tracker URLs to announce to; the first one is the primary tracker
+        #[arg(long, short, required = true)]
+        announce: Vec<Url>,
+        /// The number of bytes in each piece; picked from the total size when omitted
+        #[arg(long)]
+        piece_length: Option<u64>,
+        /// A free-form comment stored in the torrent file
+        #[arg(long)]
+        comment: Option<String>,
+        /// Marks the torrent as private so that peers are only taken from its trackers
+        #[arg(long)]
+        private: bool,
+    },
+    /// Runs torrents in the background, managed through a JSON API
+    Daemon {
+        /// Where the API is served: <ip>:<port> or unix:<path>
+        #[arg(long, default_value = DEFAULT_API_ENDPOINT)]
+        api: ApiEndpoint,
+        /// The directory torrents are downloaded into unless told otherwise
+        #[arg(long, short)]
+        download_dir: Option<PathBuf>,
+        /// The port peers connect to
+        #[arg(long)]
+        port: Option<u16>,
+        #[command(flatten)]
+        rates: RateArgs,
+        /// A blocklist of addresses never to talk to, in the P2P or DAT format; may be repeated
+        #[arg(long)]
+        blocklist: Vec<PathBuf>,
+        #[command(flatten)]
+        dht: DhtArgs,
+        /// Also finds peers on the local network through multicast announcements
+        #[arg(long)]
+        lsd: bool,
+        /// Whether peer connections are encrypted: require, prefer or plaintext
+        #[arg(long)]
+        encryption: Option<EncryptionPolicy>,
+        #[command(flatten)]
+        proxy: ProxyArgs,
+    },
+    /// Talks to a running daemon
+    Ctl {
+        /// Where the daemon serves its API: <ip>:<port> or unix:<path>
+        #[arg(long, default_value = DEFAULT_API_ENDPOINT)]
+        api: ApiEndpoint,
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+#[command(rename_all = "snake_case")]
+pub enum CtlCommand {
+    /// Adds a torrent file or a magnet link
+    Add {
+        /// The path to a torrent file or a magnet link
+        source: String,
+        /// The output file or directory; the daemon's download directory when omitted
+        #[arg(long, short)]
+        out: Option<PathBuf>,
+    },
+    /// Lists the torrents with their progress
+    List,
+    /// Pauses a torrent
+    Pause { info_hash: String },
+    /// Resumes a paused or failed torrent
+    Resume { info_hash: String },
+    /// Removes a torrent, leaving what was downloaded on disk
+    Remove { info_hash: String },
+    /// Lists the peers known for a torrent
+    Peers { info_hash: String },
+    /// Shows the stats of a torrent
+    Stats { info_hash: String },
+    /// Makes the daemon read its blocklist files again
+    ReloadBlocklist,
+    /// Changes the rate limits of the daemon or, given an info hash, of one torrent
+    Limit {
+        info_hash: Option<String>,
+        #[command(flatten)]
+        rates: RateArgs,
     },
 }
+
+/// Bandwidth caps in bytes per second, with an optional K, M or G suffix; 0 means unlimited
+#[derive(Args, Debug, Clone)]
+pub struct RateArgs {
+    /// The most bytes per second downloaded
+    #[arg(long, value_parser = parse_size)]
+    pub max_download_rate: Option<u64>,
+    /// The most bytes per second uploaded
+    #[arg(long, value_parser = parse_size)]
+    pub max_upload_rate: Option<u64>,
+}
+
+impl RateArgs {
+    /// Overrides the rates of the config with those given
+    pub fn apply(&self, config: &mut ClientConfig) {
+        if let Some(rate) = self.max_download_rate {
+            config.max_download_rate = rate;
+        }
+        if let Some(rate) = self.max_upload_rate {
+            config.max_upload_rate = rate;
+        }
+    }
+}
+
+/// A proxy that tracker announces and peer connections go through
+#[derive(Args, Debug, Clone)]
+pub struct ProxyArgs {
+    /// The proxy: socks5://, socks5h:// (the proxy resolves host names) or http://, followed by
+    /// [<user>:<pass>@]<host>:<port>
+    #[arg(long)]
+    pub proxy: Option<Proxy>,
+    /// Leaves off anything that would bypass the proxy: inbound connections, the DHT and LSD.
+    /// uTP is refused
+    #[arg(long, requires = "proxy")]
+    pub proxy_only: bool,
+}
+
+/// Options of the mainline DHT used to find peers without a tracker
+#[derive(Args, Debug, Clone)]
+pub struct DhtArgs {
+    /// Also finds peers through the DHT. Trackerless torrents always use the DHT
+    #[arg(long)]
+    pub dht: bool,
+    /// The <host>:<port> of a DHT node to join through instead of the well known routers
+    #[arg(long)]
+    pub dht_bootstrap: Vec<String>,
+    /// The file the DHT routing table is kept in between runs
+    #[arg(long)]
+    pub dht_state: Option<PathBuf>,
+}
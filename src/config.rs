@@ -0,0 +1,351 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    mse::EncryptionPolicy,
+    peer::{cache::DEFAULT_CACHE_SIZE, manager::DEFAULT_CONNECTIONS_PER_TORRENT},
+    peerid,
+    ratelimit::parse_size,
+    session::DEFAULT_MAX_CONNECTIONS,
+    tracker::Compact,
+    PEER_ID_SIZE,
+};
+
+/// Names the config file when `--config` is not given
+pub const CONFIG_ENV: &str = "BITTORRENT_CONFIG";
+/// Environment variables starting with this override the setting named by the rest, e.g.
+/// `BITTORRENT_LISTEN_PORT` or `BITTORRENT_EXTENSIONS_DHT`
+pub const ENV_PREFIX: &str = "BITTORRENT_";
+/// The size of the blocks pieces are requested in, which every client accepts
+pub const DEFAULT_BLOCK_SIZE: u32 = 1 << 14;
+/// Larger blocks get rejected by most clients
+const MAX_BLOCK_SIZE: u32 = 1 << 17;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("Line {line} of the config is invalid: {reason}")]
+    InvalidLine { line: usize, reason: String },
+    #[error("Invalid value for `{key}`: {reason}")]
+    InvalidValue { key: String, reason: String },
+    #[error("Invalid setting in the environment: {0}")]
+    InvalidEnv(String),
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+/// How downloaded pieces get to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Plain reads and writes of the files
+    #[default]
+    File,
+    /// The files mapped into memory, for large torrents. Downloads skipping any file use plain
+    /// files instead
+    Mmap,
+}
+
+/// The protocol extensions and peer sources in use
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Extensions {
+    /// Finds peers through the DHT. Trackerless torrents use it either way
+    pub dht: bool,
+    /// Finds peers on the local network
+    pub lsd: bool,
+    /// Exchanges peers over `ut_pex`. Private torrents never do
+    pub pex: bool,
+    /// Connects to peers over uTP, falling back to TCP
+    pub utp: bool,
+    pub encryption: EncryptionPolicy,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Self {
+            dht: false,
+            lsd: false,
+            pex: true,
+            utp: false,
+            encryption: EncryptionPolicy::Plaintext,
+        }
+    }
+}
+
+/// The settings of the client, read from a TOML file and the environment
+///
+/// The file holds the settings at its top level, and those of [`Extensions`] under an
+/// `[extensions]` table. Rates and sizes take a `K`, `M` or `G` suffix when given as strings,
+/// and timeouts are in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The port peers connect to, announced to trackers and the DHT
+    pub listen_port: u16,
+    /// The start of our peer id, the rest of which is random. [`peerid::client_prefix`] by
+    /// default
+    pub peer_id_prefix: String,
+    /// Inbound connections served at once across every torrent
+    pub max_connections: usize,
+    /// Peers each torrent downloads from at once
+    pub connections_per_torrent: usize,
+    #[serde(with = "secs")]
+    pub connect_timeout: Duration,
+    /// How long a peer may go without sending anything while we wait on it
+    #[serde(with = "secs")]
+    pub request_timeout: Duration,
+    /// Requests sent to a peer before waiting for any of their blocks
+    pub pipeline_depth: usize,
+    pub block_size: u32,
+    /// Whether trackers are asked for the compact peer list
+    pub compact: bool,
+    /// Bytes per second, 0 meaning unlimited
+    #[serde(deserialize_with = "size")]
+    pub max_download_rate: u64,
+    #[serde(deserialize_with = "size")]
+    pub max_upload_rate: u64,
+    pub download_dir: PathBuf,
+    pub storage: StorageKind,
+    /// Bytes of pieces each torrent keeps in memory before writing them, 0 writing every block
+    /// straight away
+    #[serde(deserialize_with = "size")]
+    pub cache_size: u64,
+    pub extensions: Extensions,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            peer_id_prefix: peerid::client_prefix(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connections_per_torrent: DEFAULT_CONNECTIONS_PER_TORRENT,
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(60),
+            pipeline_depth: 5,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compact: true,
+            max_download_rate: 0,
+            max_upload_rate: 0,
+            download_dir: PathBuf::from("."),
+            storage: StorageKind::default(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            extensions: Extensions::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io(err.to_string()))?
+            .parse()
+    }
+
+    /// Overrides the settings named by the variables starting with [`ENV_PREFIX`]. Variables
+    /// naming no setting are left alone
+    pub fn with_env(
+        self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let invalid = |err: toml::ser::Error| ConfigError::InvalidEnv(err.to_string());
+        let mut settings = toml::Table::try_from(&self).map_err(invalid)?;
+        for (name, value) in vars {
+            let Some(name) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if let Some(setting) = setting_mut(&mut settings, name) {
+                *setting = env_value(setting, value);
+            }
+        }
+        let s = Self::deserialize(settings)
+            .map_err(|err| ConfigError::InvalidEnv(err.message().to_owned()))?;
+        s.validate()?;
+        Ok(s)
+    }
+
+    /// A peer id starting with the prefix, with random bytes after it. Every call gives another
+    /// one
+    pub fn peer_id(&self) -> [u8; PEER_ID_SIZE] {
+        peerid::generate(self.peer_id_prefix.as_bytes())
+    }
+
+    #[inline]
+    pub fn compact(&self) -> Compact {
+        if self.compact {
+            Compact::Compact
+        } else {
+            Compact::NotCompact
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, reason: &str| {
+            Err(ConfigError::InvalidValue {
+                key: key.to_owned(),
+                reason: reason.to_owned(),
+            })
+        };
+        if self.peer_id_prefix.len() > PEER_ID_SIZE {
+            return invalid("peer_id_prefix", "longer than a peer id");
+        }
+        if !(1..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return invalid("block_size", "must be between 1 and 128 KiB");
+        }
+        if self.pipeline_depth == 0 {
+            return invalid("pipeline_depth", "must be at least 1");
+        }
+        if self.connections_per_torrent == 0 {
+            return invalid("connections_per_torrent", "must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ClientConfig {
+    type Err = ConfigError;
+
+    /// Reads the settings of a TOML file, leaving those it doesn't name at their defaults
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config = toml::from_str::<Self>(s).map_err(|err| ConfigError::InvalidLine {
+            // Spans are byte offsets, which the lines before them are counted up to
+            line: err
+                .span()
+                .map_or(1, |span| s[..span.start].matches('\n').count() + 1),
+            reason: err.message().to_owned(),
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// The setting an environment variable names, like `EXTENSIONS_DHT` for `dht` of the
+/// `[extensions]` table
+fn setting_mut<'a>(settings: &'a mut toml::Table, name: &str) -> Option<&'a mut toml::Value> {
+    for (key, value) in settings.iter_mut() {
+        match value {
+            toml::Value::Table(table) => {
+                let found = table
+                    .iter_mut()
+                    .find(|(inner, _)| format!("{key}_{inner}").eq_ignore_ascii_case(name));
+                if let Some((_, value)) = found {
+                    return Some(value);
+                }
+            }
+            value if key.eq_ignore_ascii_case(name) => return Some(value),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Environment variables are always strings, which are read as whatever the setting held
+/// before. Those that don't read as such are left strings, for rates like `2M` or for the
+/// setting to reject
+fn env_value(setting: &toml::Value, value: String) -> toml::Value {
+    let parsed = match setting {
+        toml::Value::Integer(_) => value.trim().parse().ok().map(toml::Value::Integer),
+        toml::Value::Boolean(_) => value.trim().parse().ok().map(toml::Value::Boolean),
+        _ => None,
+    };
+    parsed.unwrap_or(toml::Value::String(value))
+}
+
+/// Rates and sizes in bytes, as integers or as strings parsed by [`parse_size`]
+fn size<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Suffixed(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Suffixed(s) => parse_size(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Timeouts in whole seconds
+mod secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ClientConfig, ConfigError};
+    use crate::mse::EncryptionPolicy;
+
+    #[test]
+    fn test_file_then_env() {
+        let config = "# Settings\n\
+                      listen_port = 51413\n\
+                      peer_id_prefix = \"-RS0100-\" # ours\n\
+                      max_download_rate = '2M'\n\
+                      request_timeout = 1_000\n\
+                      \n\
+                      [extensions]\n\
+                      dht = true\n\
+                      encryption = \"prefer\"\n"
+            .parse::<ClientConfig>()
+            .unwrap();
+        assert_eq!(51413, config.listen_port);
+        assert_eq!(2 << 20, config.max_download_rate);
+        assert_eq!(Duration::from_secs(1000), config.request_timeout);
+        assert!(config.extensions.dht);
+        assert_eq!(EncryptionPolicy::Prefer, config.extensions.encryption);
+        assert!(config.peer_id().starts_with(b"-RS0100-"));
+        assert_eq!(
+            Ok(64 << 20),
+            "cache_size = \"64MiB\""
+                .parse::<ClientConfig>()
+                .map(|config| config.cache_size)
+        );
+
+        let config = config
+            .with_env([
+                ("BITTORRENT_LISTEN_PORT".to_owned(), "6882".to_owned()),
+                ("BITTORRENT_EXTENSIONS_DHT".to_owned(), "false".to_owned()),
+                ("BITTORRENT_MAX_UPLOAD_RATE".to_owned(), "1K".to_owned()),
+                ("BITTORRENT_CONFIG".to_owned(), "ignored.toml".to_owned()),
+                ("HOME".to_owned(), "/root".to_owned()),
+            ])
+            .unwrap();
+        assert_eq!(6882, config.listen_port);
+        assert!(!config.extensions.dht);
+        assert_eq!(1 << 10, config.max_upload_rate);
+
+        assert!(matches!(
+            "listen_port = 1\n[extensions]\nwebtorrent = true".parse::<ClientConfig>(),
+            Err(ConfigError::InvalidLine { line: 3, .. })
+        ));
+        assert!(matches!(
+            config
+                .clone()
+                .with_env([("BITTORRENT_LISTEN_PORT".to_owned(), "high".to_owned())]),
+            Err(ConfigError::InvalidEnv(_))
+        ));
+        assert!("listen_port = 70000".parse::<ClientConfig>().is_err());
+        assert!(matches!(
+            "pipeline_depth = \"deep".parse::<ClientConfig>(),
+            Err(ConfigError::InvalidLine { line: 1, .. })
+        ));
+    }
+}
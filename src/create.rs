@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::Url;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    hashpool::HashPool,
+    torrent::{FileInfo, FileType, MetaInfo, Piece},
+    PIECE_SIZE,
+};
+
+/// Smallest piece length picked automatically (16 KiB), which is also the size of a block request
+pub const MIN_PIECE_LENGTH: u64 = 1 << 14;
+/// Largest piece length picked automatically (16 MiB)
+pub const MAX_PIECE_LENGTH: u64 = 1 << 24;
+/// Number of pieces the automatic piece length tries to stay under
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// Picks a power of two piece length for a torrent of `total_length` bytes so that the torrent
+/// has at most roughly [`TARGET_PIECE_COUNT`] pieces
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total_length / piece_length > TARGET_PIECE_COUNT {
+        piece_length <<= 1;
+    }
+    piece_length
+}
+
+/// Builds a bencoded .torrent out of a file or a directory on disk
+///
+/// Files of a directory are ordered by their path relative to the directory so that the same
+/// directory always produces the same info hash regardless of the machine it is created on.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    root: PathBuf,
+    piece_length: Option<u64>,
+    announce: Vec<Url>,
+    comment: Option<String>,
+    private: bool,
+    hasher: Option<HashPool>,
+}
+
+impl TorrentBuilder {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            piece_length: None,
+            announce: Vec::new(),
+            comment: None,
+            private: false,
+            hasher: None,
+        }
+    }
+
+    pub fn with_piece_length(self, piece_length: u64) -> Self {
+        let mut s = self;
+        s.piece_length = Some(piece_length);
+        s
+    }
+
+    /// Adds a tracker URL. The first one added becomes `announce` while every one of them is
+    /// listed in `announce-list` when more than one is given
+    pub fn with_announce(self, url: Url) -> Self {
+        let mut s = self;
+        s.announce.push(url);
+        s
+    }
+
+    pub fn with_comment(self, comment: impl Into<String>) -> Self {
+        let mut s = self;
+        s.comment = Some(comment.into());
+        s
+    }
+
+    pub fn with_private(self, private: bool) -> Self {
+        let mut s = self;
+        s.private = private;
+        s
+    }
+
+    /// Hashes the pieces on `hasher` instead of a pool of its own with a thread per core
+    pub fn with_hasher(self, hasher: HashPool) -> Self {
+        let mut s = self;
+        s.hasher = Some(hasher);
+        s
+    }
+
+    /// Hashes the pieces of the files and returns the bencoded torrent along with the MetaInfo
+    /// that was written into its `info` dictionary
+    pub fn build(self) -> Result<(Vec<u8>, MetaInfo), CreateError> {
+        let announce = self.announce.first().ok_or(CreateError::MissingAnnounce)?;
+        let name = self
+            .root
+            .file_name()
+            .ok_or(CreateError::InvalidPath(self.root.clone()))?
+            .to_string_lossy()
+            .into_owned();
+        let files = collect_files(&self.root)?;
+        let total_length = files.iter().map(|(_, length)| length).sum::<u64>();
+        if total_length == 0 {
+            return Err(CreateError::Empty(self.root));
+        }
+        let piece_length = match self.piece_length {
+            Some(piece_length) if piece_length == 0 || !piece_length.is_power_of_two() => {
+                return Err(CreateError::InvalidPieceLength(piece_length))
+            }
+            Some(piece_length) => piece_length,
+            None => auto_piece_length(total_length),
+        };
+        let hasher = self.hasher.clone().unwrap_or_default();
+        let pieces = hash_pieces(&hasher, &files, piece_length)?;
+        let file_type = if self.root.is_file() {
+            FileType::SingleFile(total_length)
+        } else {
+            FileType::MultiFile(
+                files
+                    .iter()
+                    .map(|(path, length)| {
+                        let components = path
+                            .strip_prefix(&self.root)
+                            .expect("Collected files must live under the root")
+                            .iter()
+                            .map(|component| component.to_string_lossy().into_owned())
+                            .collect();
+                        FileInfo::new(*length, components)
+                    })
+                    .collect(),
+            )
+        };
+        let metainfo =
+            MetaInfo::new(name, piece_length, pieces, file_type).with_private(self.private);
+
+        let info = serde_bencode::to_bytes(&metainfo)
+            .and_then(|bytes| serde_bencode::from_bytes::<Value>(&bytes))
+            .map_err(|err| CreateError::Serialization(err.to_string()))?;
+        let mut torrent = HashMap::new();
+        torrent.insert(
+            b"announce".to_vec(),
+            Value::Bytes(announce.as_str().as_bytes().to_vec()),
+        );
+        if self.announce.len() > 1 {
+            let tiers = self
+                .announce
+                .iter()
+                .map(|url| Value::List(vec![Value::Bytes(url.as_str().as_bytes().to_vec())]))
+                .collect();
+            torrent.insert(b"announce-list".to_vec(), Value::List(tiers));
+        }
+        if let Some(comment) = self.comment {
+            torrent.insert(b"comment".to_vec(), Value::Bytes(comment.into_bytes()));
+        }
+        torrent.insert(
+            b"created by".to_vec(),
+            Value::Bytes(
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        );
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default();
+        torrent.insert(b"creation date".to_vec(), Value::Int(creation_date));
+        torrent.insert(b"info".to_vec(), info);
+        let bytes = serde_bencode::to_bytes(&Value::Dict(torrent))
+            .map_err(|err| CreateError::Serialization(err.to_string()))?;
+        Ok((bytes, metainfo))
+    }
+}
+
+/// Collects every regular file under `root` (or `root` itself if it is a file) together with its
+/// length, sorted by path so that the order does not depend on the order the filesystem lists
+/// directory entries in. Symbolic links to directories are skipped
+fn collect_files(root: &Path) -> Result<Vec<(PathBuf, u64)>, CreateError> {
+    let metadata = fs::metadata(root).map_err(|err| CreateError::Io(err.to_string()))?;
+    if metadata.is_file() {
+        return Ok(vec![(root.to_path_buf(), metadata.len())]);
+    }
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).map_err(|err| CreateError::Io(err.to_string()))? {
+            let path = entry
+                .map_err(|err| CreateError::Io(err.to_string()))?
+                .path();
+            let mut metadata =
+                fs::symlink_metadata(&path).map_err(|err| CreateError::Io(err.to_string()))?;
+            if metadata.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            // Linked files are read through the link, but linked directories are left out since
+            // they may lead back into the root or out of it
+            if metadata.is_symlink() {
+                metadata = fs::metadata(&path).map_err(|err| CreateError::Io(err.to_string()))?;
+            }
+            if metadata.is_file() {
+                files.push((path, metadata.len()));
+            }
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.iter().cmp(b.iter()));
+    Ok(files)
+}
+
+/// Hashes every piece of the concatenation of `files` with SHA-1 on the threads of `hasher`
+fn hash_pieces(
+    hasher: &HashPool,
+    files: &[(PathBuf, u64)],
+    piece_length: u64,
+) -> Result<Vec<Piece>, CreateError> {
+    let total_length = files.iter().map(|(_, length)| length).sum::<u64>();
+    let piece_count = (total_length + piece_length - 1) / piece_length;
+    let files = Arc::new(files.to_vec());
+    let jobs = (0..piece_count).map(|piece_idx| {
+        let files = files.clone();
+        move || {
+            let offset = piece_idx * piece_length;
+            let mut buf = vec![0; piece_length.min(total_length - offset) as usize];
+            read_span(&files, offset, &mut buf)?;
+            Ok(<[u8; PIECE_SIZE]>::from(Sha1::digest(&buf)))
+        }
+    });
+    hasher
+        .run_all(jobs)
+        .map_err(|err| CreateError::Io(err.to_string()))?
+        .into_iter()
+        .collect()
+}
+
+fn read_span(files: &[(PathBuf, u64)], offset: u64, buf: &mut [u8]) -> Result<(), CreateError> {
+    let mut file_start = 0;
+    let mut filled = 0;
+    for (path, length) in files {
+        let file_end = file_start + length;
+        let position = offset + filled as u64;
+        if filled < buf.len() && position < file_end {
+            let to_read = (buf.len() - filled).min((file_end - position) as usize);
+            let mut file = File::open(path).map_err(|err| CreateError::Io(err.to_string()))?;
+            file.seek(SeekFrom::Start(position - file_start))
+                .and_then(|_| file.read_exact(&mut buf[filled..filled + to_read]))
+                .map_err(|err| CreateError::Io(format!("{}: {}", path.display(), err)))?;
+            filled += to_read;
+        }
+        file_start = file_end;
+    }
+    Ok(())
+}
+
+/// Error type for torrent creation
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CreateError {
+    /// At least one tracker URL is required since it fills the `announce` key
+    #[error("At least one announce URL is required")]
+    MissingAnnounce,
+    #[error("Path has no usable file name: {0:?}")]
+    InvalidPath(PathBuf),
+    #[error("No data to create a torrent from in {0:?}")]
+    Empty(PathBuf),
+    #[error("Piece length must be a non zero power of two; got {0}")]
+    InvalidPieceLength(u64),
+    #[error("I/O error while reading the files: {0}")]
+    Io(String),
+    #[error("Failed to bencode the torrent: {0}")]
+    Serialization(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use reqwest::Url;
+
+    use super::{auto_piece_length, TorrentBuilder, MIN_PIECE_LENGTH};
+    use crate::torrent::{from_bytes, FileType};
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(MIN_PIECE_LENGTH, auto_piece_length(0));
+        assert_eq!(1 << 20, auto_piece_length(1500 * (1 << 20)));
+        assert_eq!(1 << 24, auto_piece_length(u64::MAX));
+    }
+
+    #[test]
+    fn test_create_multi_file_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("artifacts");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin").join("tool"), vec![7u8; 40_000]).unwrap();
+        fs::write(root.join("README"), b"hello world").unwrap();
+        // A link back to the root would otherwise be followed forever
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&root, root.join("bin").join("loop")).unwrap();
+        let url = Url::parse("http://tracker.example/announce").unwrap();
+        let (bytes, created) = TorrentBuilder::new(&root)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(url.clone())
+            .with_comment("nightly")
+            .with_private(true)
+            .build()
+            .unwrap();
+        let (announce, parsed) = from_bytes(bytes).unwrap();
+        assert_eq!(Some(url.clone()), announce);
+        assert!(parsed.is_private());
+        assert_eq!(3, parsed.pieces().len());
+        assert_eq!(created.info_hash(), parsed.info_hash());
+        match parsed.file_type() {
+            FileType::MultiFile(files) => {
+                let paths = files
+                    .iter()
+                    .map(|file| file.path().to_str().unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(vec!["README", "bin/tool"], paths);
+            }
+            FileType::SingleFile(_) => panic!("Expected a multi file torrent"),
+        }
+
+        // The same content laid out on another machine must give the same info hash
+        let other = tempfile::tempdir().unwrap();
+        let other_root = other.path().join("artifacts");
+        fs::create_dir_all(other_root.join("bin")).unwrap();
+        fs::write(other_root.join("README"), b"hello world").unwrap();
+        fs::write(other_root.join("bin").join("tool"), vec![7u8; 40_000]).unwrap();
+        let (_, other_created) = TorrentBuilder::new(&other_root)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(url)
+            .with_private(true)
+            .build()
+            .unwrap();
+        assert_eq!(created.info_hash(), other_created.info_hash());
+    }
+}
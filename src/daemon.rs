@@ -0,0 +1,769 @@
+use std::{
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    magnet::Magnet,
+    session::{Session, SessionError, TorrentState, TorrentStatus},
+    ParseError, INFO_HASH_SIZE,
+};
+
+/// Where the API listens unless told otherwise
+pub const DEFAULT_API_ENDPOINT: &str = "127.0.0.1:7070";
+const UNIX_PREFIX: &str = "unix:";
+/// Largest request body the API reads
+const MAX_BODY_SIZE: usize = 1 << 20;
+/// Largest request line and headers together the API reads
+const MAX_HEAD_SIZE: u64 = 16 << 10;
+/// How long a client has to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the JSON API of a daemon is served: `<ip>:<port>` or `unix:<path>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiEndpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ApiEndpoint {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            return Ok(ApiEndpoint::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(ApiEndpoint::Tcp)
+            .map_err(|_| ParseError::Deserialization(format!("`{s}` is not an API endpoint")))
+    }
+}
+
+impl Display for ApiEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiEndpoint::Tcp(addr) => write!(f, "{addr}"),
+            ApiEndpoint::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Serves the JSON API of a session over HTTP
+///
+/// The API has no authentication, so it is only served on loopback addresses and Unix sockets.
+/// Requests must name a local `Host` so that a web page cannot reach it by rebinding a domain to
+/// 127.0.0.1, and requests other than `GET` must be sent as `application/json`, which a web page
+/// cannot do across origins without a preflight the API never answers.
+///
+/// Routes:
+/// - `GET /torrents` lists the torrents with their progress
+/// - `POST /torrents` adds `{"torrent": <path>}` or `{"magnet": <link>}`, with an optional
+///   `"out"` location
+/// - `GET /torrents/<info hash>` gives the stats of a torrent
+/// - `GET /torrents/<info hash>/peers` lists its peers with the client they run, their connection
+///   attempts, failures and whether they are banned
+/// - `POST /torrents/<info hash>/pause` and `POST /torrents/<info hash>/resume`
+/// - `DELETE /torrents/<info hash>`
+/// - `POST /limits` and `POST /torrents/<info hash>/limits` set the rate limits of the session
+///   or of a torrent from `{"download": <bytes/s>, "upload": <bytes/s>}`, 0 meaning unlimited
+/// - `POST /blocklist/reload` reads the blocklist files again
+#[derive(Debug)]
+pub struct ApiServer {
+    listener: Listener,
+    endpoint: ApiEndpoint,
+}
+
+impl ApiServer {
+    /// Listens on `endpoint`, which must be a loopback address or a Unix socket. A Unix socket
+    /// left behind by an earlier daemon is replaced, but nothing else at its path is. The socket
+    /// is only accessible to its owner
+    pub async fn bind(endpoint: &ApiEndpoint) -> io::Result<Self> {
+        match endpoint {
+            ApiEndpoint::Tcp(addr) if !addr.ip().is_loopback() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The API has no authentication and cannot be served on {addr}; use a \
+                     loopback address or a Unix socket"
+                ),
+            )),
+            ApiEndpoint::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let endpoint = ApiEndpoint::Tcp(listener.local_addr()?);
+                Ok(Self {
+                    listener: Listener::Tcp(listener),
+                    endpoint,
+                })
+            }
+            #[cfg(unix)]
+            ApiEndpoint::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+                match tokio::fs::symlink_metadata(path).await {
+                    Ok(metadata) if metadata.file_type().is_socket() => {
+                        tokio::fs::remove_file(path).await?
+                    }
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", path.display()),
+                        ))
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
+                }
+                let listener = tokio::net::UnixListener::bind(path)?;
+                tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+                Ok(Self {
+                    listener: Listener::Unix(listener),
+                    endpoint: endpoint.clone(),
+                })
+            }
+            #[cfg(not(unix))]
+            ApiEndpoint::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// The endpoint being served, with the port picked when binding to port 0
+    #[inline]
+    pub fn endpoint(&self) -> &ApiEndpoint {
+        &self.endpoint
+    }
+
+    /// Answers requests until the listener fails. Torrents added without an `"out"` location
+    /// are downloaded into `download_dir`
+    pub async fn serve(self, session: Session, download_dir: PathBuf) -> io::Result<()> {
+        loop {
+            let session = session.clone();
+            let download_dir = download_dir.clone();
+            match &self.listener {
+                Listener::Tcp(listener) => {
+                    let (stream, _) = listener.accept().await?;
+                    tokio::spawn(async move {
+                        let _ = handle_connection(stream, &session, &download_dir).await;
+                    });
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    let (stream, _) = listener.accept().await?;
+                    tokio::spawn(async move {
+                        let _ = handle_connection(stream, &session, &download_dir).await;
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Reads one request and answers it; every connection carries a single request
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    session: &Session,
+    download_dir: &Path,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    // A client that never finishes its request would hold the connection open for good
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Request took too long"))?;
+    let (status, body) = match request {
+        Ok(request) => match check_origin(&request) {
+            Ok(()) => {
+                route(
+                    session,
+                    download_dir,
+                    &request.method,
+                    &request.path,
+                    &request.body,
+                )
+                .await
+            }
+            Err(err) => err,
+        },
+        Err(err) => (400, json!({ "error": err.to_string() })),
+    };
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut BufReader<S>) -> io::Result<Request> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let mut head = (&mut *stream).take(MAX_HEAD_SIZE);
+    let mut line = String::new();
+    head.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(invalid("Malformed request line"));
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+    let mut content_length = 0;
+    let (mut host, mut content_type) = (None, None);
+    loop {
+        line.clear();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(if head.limit() == 0 {
+                invalid("Request headers are too large")
+            } else {
+                invalid("Request ended in its headers")
+            });
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("Malformed Content-Length"))?;
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_owned());
+            } else if name.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.trim().to_owned());
+            }
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(invalid("Request body is too large"));
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        host,
+        content_type,
+        body,
+    })
+}
+
+/// Turns away requests a web page could have sent: those addressed to another host, which
+/// reach the API through DNS rebinding, and those that change state without being JSON
+fn check_origin(request: &Request) -> Result<(), (u16, Value)> {
+    let host = request.host.as_deref().unwrap_or_default();
+    if !is_local_host(host) {
+        return Err(error(403, format!("`{host}` is not a local host")));
+    }
+    let is_json = request.content_type.as_deref().is_some_and(|value| {
+        value
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("application/json")
+    });
+    if request.method != "GET" && !is_json {
+        return Err(error(415, "Requests must be sent as application/json"));
+    }
+    Ok(())
+}
+
+/// Whether a `Host` header names this machine: `localhost` or a loopback address, with an
+/// optional port
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    }
+}
+
+fn error(status: u16, message: impl Display) -> (u16, Value) {
+    (status, json!({ "error": message.to_string() }))
+}
+
+fn session_error(err: SessionError) -> (u16, Value) {
+    let status = match err {
+        SessionError::UnknownTorrent(_) => 404,
+        SessionError::DuplicateTorrent(_) => 409,
+        SessionError::InvalidTorrent(_) | SessionError::Metadata(_) => 400,
+        SessionError::Io(_) | SessionError::Blocklist(_) => 500,
+    };
+    error(status, err)
+}
+
+async fn route(
+    session: &Session,
+    download_dir: &Path,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> (u16, Value) {
+    let segments = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let rest = match segments.as_slice() {
+        ["torrents", rest @ ..] => rest,
+        ["limits"] if method == "POST" => {
+            return match rates(body) {
+                Ok((download, upload)) => {
+                    session.set_rate_limits(download, upload);
+                    (200, json!({}))
+                }
+                Err(err) => err,
+            };
+        }
+        ["blocklist", "reload"] if method == "POST" => {
+            return match session.reload_blocklist() {
+                Ok(ranges) => (200, json!({ "ranges": ranges })),
+                Err(err) => session_error(err),
+            };
+        }
+        _ => return error(404, format!("No route for {method} {path}")),
+    };
+    let Some((hash, action)) = rest.split_first() else {
+        return match method {
+            "GET" => (
+                200,
+                Value::Array(session.torrents().iter().map(torrent_json).collect()),
+            ),
+            "POST" => add(session, download_dir, body).await,
+            _ => error(405, format!("{method} is not allowed on {path}")),
+        };
+    };
+    let Some(info_hash) = hex::decode(hash)
+        .ok()
+        .and_then(|bytes| <[u8; INFO_HASH_SIZE]>::try_from(bytes).ok())
+    else {
+        return error(400, format!("`{hash}` is not an info hash"));
+    };
+    let result = match (method, action) {
+        ("GET", []) => session
+            .status(&info_hash)
+            .map(|status| torrent_json(&status))
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash))),
+        ("DELETE", []) => session.remove(&info_hash).await.map(|_| json!({})),
+        ("POST", ["pause"]) => session.pause(&info_hash).map(|_| json!({})),
+        ("POST", ["resume"]) => session.resume(&info_hash).map(|_| json!({})),
+        ("POST", ["limits"]) => match rates(body) {
+            Ok((download, upload)) => session
+                .set_torrent_rate_limits(&info_hash, download, upload)
+                .map(|_| json!({})),
+            Err(err) => return err,
+        },
+        ("GET", ["peers"]) => session.peers(&info_hash).map(|peers| {
+            peers
+                .into_iter()
+                .map(|peer| {
+                    json!({
+                        "addr": peer.addr.to_string(),
+                        "source": format!("{:?}", peer.source).to_lowercase(),
+                        "client": peer.client,
+                        "attempts": peer.stats.attempts,
+                        "failures": peer.stats.failures,
+                        "hash_failures": peer.stats.hash_failures,
+                        "last_error": peer.stats.last_error,
+                        "banned": peer.banned,
+                    })
+                })
+                .collect()
+        }),
+        _ => return error(404, format!("No route for {method} {path}")),
+    };
+    match result {
+        Ok(body) => (200, body),
+        Err(err) => session_error(err),
+    }
+}
+
+async fn add(session: &Session, download_dir: &Path, body: &[u8]) -> (u16, Value) {
+    let Ok(request) = serde_json::from_slice::<Value>(body) else {
+        return error(400, "The body is not JSON");
+    };
+    let out = request["out"].as_str().map(PathBuf::from);
+    let result = match (request["torrent"].as_str(), request["magnet"].as_str()) {
+        (Some(torrent), _) => {
+            let name = match crate::torrent::from_file(torrent) {
+                Ok((_, metainfo)) => metainfo.name().clone(),
+                Err(err) => return error(400, err),
+            };
+            let out = out.unwrap_or_else(|| download_dir.join(name));
+            session.add_torrent(torrent, out).await
+        }
+        (None, Some(magnet)) => match magnet.parse::<Magnet>() {
+            Ok(magnet) => {
+                let out_dir = out.unwrap_or_else(|| download_dir.to_path_buf());
+                session.add_magnet(&magnet, out_dir).await
+            }
+            Err(err) => return error(400, err),
+        },
+        (None, None) => return error(400, "Expected a `torrent` or a `magnet`"),
+    };
+    match result {
+        Ok(info_hash) => (200, json!({ "info_hash": hex::encode(info_hash) })),
+        Err(err) => session_error(err),
+    }
+}
+
+/// The `download` and `upload` rates of a request body, each 0 when left out
+fn rates(body: &[u8]) -> Result<(u64, u64), (u16, Value)> {
+    let request =
+        serde_json::from_slice::<Value>(body).map_err(|_| error(400, "The body is not JSON"))?;
+    let rate = |key: &str| match &request[key] {
+        Value::Null => Ok(0),
+        value => value.as_u64().ok_or(error(
+            400,
+            format!("`{key}` is not a number of bytes per second"),
+        )),
+    };
+    Ok((rate("download")?, rate("upload")?))
+}
+
+fn torrent_json(status: &TorrentStatus) -> Value {
+    let (state, error) = match &status.state {
+        TorrentState::Starting => ("starting", None),
+        TorrentState::Downloading => ("downloading", None),
+        TorrentState::Paused => ("paused", None),
+        TorrentState::Seeding => ("seeding", None),
+        TorrentState::Failed(err) => ("failed", Some(err)),
+    };
+    let progress = if status.num_pieces == 0 {
+        1.0
+    } else {
+        status.pieces_done as f64 / status.num_pieces as f64
+    };
+    json!({
+        "info_hash": hex::encode(status.info_hash),
+        "name": status.name,
+        "state": state,
+        "error": error,
+        "pieces_done": status.pieces_done,
+        "num_pieces": status.num_pieces,
+        "progress": progress,
+        "uploaded": status.uploaded,
+        "peers": status.num_peers,
+    })
+}
+
+/// Sends one request to the API at `endpoint`, returning the status code and the JSON body of
+/// the answer
+pub async fn request(
+    endpoint: &ApiEndpoint,
+    method: &str,
+    path: &str,
+    body: Option<&Value>,
+) -> io::Result<(u16, Value)> {
+    match endpoint {
+        ApiEndpoint::Tcp(addr) => {
+            let stream = TcpStream::connect(addr).await?;
+            send_request(stream, method, path, body).await
+        }
+        #[cfg(unix)]
+        ApiEndpoint::Unix(path_) => {
+            let stream = tokio::net::UnixStream::connect(path_).await?;
+            send_request(stream, method, path, body).await
+        }
+        #[cfg(not(unix))]
+        ApiEndpoint::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+    }
+}
+
+async fn send_request<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    method: &str,
+    path: &str,
+    body: Option<&Value>,
+) -> io::Result<(u16, Value)> {
+    let body = body.map(Value::to_string).unwrap_or_default();
+    let head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(invalid("Response had no end of headers"))?;
+    let status = std::str::from_utf8(&response[..split])
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1)?.parse().ok())
+        .ok_or(invalid("Malformed status line"))?;
+    let body = serde_json::from_slice(&response[split + 4..])
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok((status, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use reqwest::{Client, Url};
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{check_origin, read_request, request, ApiEndpoint, ApiServer};
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::client::PeerClient,
+        session::{Session, SessionConfig},
+    };
+
+    /// Answers every announce without any peer
+    async fn tracker() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let _ = socket.read(&mut buf).await.unwrap();
+                let body = b"d8:intervali1800e5:peers0:e";
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(body).await.unwrap();
+            }
+        });
+        Url::parse(&format!("http://{addr}/announce")).unwrap()
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            ApiEndpoint::Tcp("127.0.0.1:7070".parse().unwrap()),
+            "127.0.0.1:7070".parse().unwrap()
+        );
+        let unix = "unix:/tmp/bittorrent.sock".parse::<ApiEndpoint>().unwrap();
+        assert_eq!(ApiEndpoint::Unix("/tmp/bittorrent.sock".into()), unix);
+        assert_eq!("unix:/tmp/bittorrent.sock", unix.to_string());
+        assert!("localhost".parse::<ApiEndpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_oversized_headers() {
+        let mut request = b"GET /torrents HTTP/1.1\r\nX-Filler: ".to_vec();
+        request.extend(vec![b'a'; 1 << 20]);
+        let mut stream = tokio::io::BufReader::new(&request[..]);
+        let err = read_request(&mut stream).await.unwrap_err();
+        assert_eq!("Request headers are too large", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_refuse_remote_endpoints() {
+        let endpoint = ApiEndpoint::Tcp("0.0.0.0:0".parse().unwrap());
+        let err = ApiServer::bind(&endpoint).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert!(
+            ApiServer::bind(&ApiEndpoint::Tcp("127.0.0.1:0".parse().unwrap()))
+                .await
+                .is_ok()
+        );
+
+        // Only a stale socket is replaced, never a file the path was mistyped as
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("notes.txt");
+            fs::write(&path, b"keep me").unwrap();
+            let err = ApiServer::bind(&ApiEndpoint::Unix(path.clone()))
+                .await
+                .unwrap_err();
+            assert_eq!(std::io::ErrorKind::AlreadyExists, err.kind());
+            assert_eq!(b"keep me".to_vec(), fs::read(&path).unwrap());
+
+            let path = dir.path().join("api.sock");
+            drop(
+                ApiServer::bind(&ApiEndpoint::Unix(path.clone()))
+                    .await
+                    .unwrap(),
+            );
+            ApiServer::bind(&ApiEndpoint::Unix(path.clone()))
+                .await
+                .unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refuse_cross_origin_requests() {
+        let check = |raw: &'static str| async move {
+            let mut stream = tokio::io::BufReader::new(raw.as_bytes());
+            check_origin(&read_request(&mut stream).await.unwrap()).map_err(|(status, _)| status)
+        };
+        assert_eq!(
+            Err(415),
+            check(
+                "POST /torrents HTTP/1.1\r\nHost: localhost:7070\r\n\
+                 Content-Type: text/plain\r\nContent-Length: 2\r\n\r\n{}"
+            )
+            .await
+        );
+        assert_eq!(
+            Err(403),
+            check(
+                "POST /torrents HTTP/1.1\r\nHost: evil.example:7070\r\n\
+                 Content-Type: application/json\r\nContent-Length: 2\r\n\r\n{}"
+            )
+            .await
+        );
+        assert_eq!(Err(403), check("GET /torrents HTTP/1.1\r\n\r\n").await);
+        assert_eq!(
+            Ok(()),
+            check(
+                "POST /torrents HTTP/1.1\r\nHost: [::1]:7070\r\n\
+                 Content-Type: application/json; charset=utf-8\r\nContent-Length: 2\r\n\r\n{}"
+            )
+            .await
+        );
+        assert_eq!(
+            Ok(()),
+            check("GET /torrents HTTP/1.1\r\nHost: 127.0.0.1:7070\r\n\r\n").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_manages_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("image");
+        fs::write(&source, vec![7u8; 20_000]).unwrap();
+        let (bytes, _) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(tracker().await)
+            .build()
+            .unwrap();
+        let torrent_file = dir.path().join("image.torrent");
+        fs::write(&torrent_file, bytes).unwrap();
+
+        let session = Session::bind(
+            PeerClient::new(Client::new(), [1; 20]),
+            SessionConfig {
+                listen_addr: ([127, 0, 0, 1], 0).into(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let server = ApiServer::bind(&ApiEndpoint::Unix(dir.path().join("api.sock")))
+            .await
+            .unwrap();
+        let endpoint = server.endpoint().clone();
+        tokio::spawn(server.serve(session.clone(), dir.path().to_path_buf()));
+
+        let add = json!({ "torrent": torrent_file, "out": source });
+        let (status, body) = request(&endpoint, "POST", "/torrents", Some(&add))
+            .await
+            .unwrap();
+        assert_eq!(200, status, "{body}");
+        let info_hash = body["info_hash"].as_str().unwrap().to_owned();
+        let (status, _) = request(&endpoint, "POST", "/torrents", Some(&add))
+            .await
+            .unwrap();
+        assert_eq!(409, status);
+
+        let (status, list) = request(&endpoint, "GET", "/torrents", None).await.unwrap();
+        assert_eq!(200, status);
+        assert_eq!(info_hash, list[0]["info_hash"]);
+        assert_eq!("image", list[0]["name"]);
+        assert_eq!(2, list[0]["num_pieces"]);
+
+        let path = format!("/torrents/{info_hash}");
+        let (status, _) = request(&endpoint, "POST", &format!("{path}/pause"), None)
+            .await
+            .unwrap();
+        assert_eq!(200, status);
+        let (_, stats) = request(&endpoint, "GET", &path, None).await.unwrap();
+        assert_eq!("paused", stats["state"]);
+        let (status, peers) = request(&endpoint, "GET", &format!("{path}/peers"), None)
+            .await
+            .unwrap();
+        assert_eq!(200, status);
+        assert!(peers.is_array());
+
+        let limits = json!({ "download": 1 << 20 });
+        let (status, _) = request(&endpoint, "POST", &format!("{path}/limits"), Some(&limits))
+            .await
+            .unwrap();
+        assert_eq!(200, status);
+        let (status, _) = request(
+            &endpoint,
+            "POST",
+            "/limits",
+            Some(&json!({ "upload": "x" })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(400, status);
+
+        let (status, _) = request(&endpoint, "DELETE", &path, None).await.unwrap();
+        assert_eq!(200, status);
+        let (status, _) = request(&endpoint, "GET", &path, None).await.unwrap();
+        assert_eq!(404, status);
+        let (status, _) = request(&endpoint, "GET", "/torrents/nothex", None)
+            .await
+            .unwrap();
+        assert_eq!(400, status);
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
+};
+
+use serde_bencode::value::Value;
+
+use crate::{tracker::TRACKER_RESPONSE_PEER_SIZE, ParseError, INFO_HASH_SIZE};
+
+pub const NODE_ID_SIZE: usize = 20;
+/// Size of a node in "compact node info" format: the 20 byte node id followed by the compact
+/// IPv4 address and port
+pub const COMPACT_NODE_SIZE: usize = NODE_ID_SIZE + TRACKER_RESPONSE_PEER_SIZE;
+
+pub type NodeId = [u8; NODE_ID_SIZE];
+
+/// Error codes of KRPC error messages
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: [u8; INFO_HASH_SIZE],
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: [u8; INFO_HASH_SIZE],
+        port: u16,
+        /// Tells the receiver to use the source port of the UDP packet instead of `port`
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    pub fn id(&self) -> &NodeId {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. } => id,
+        }
+    }
+
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The `r` dictionary of a response. Which fields are filled depends on the query answered
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// A KRPC message: a bencoded dictionary sent in a single UDP packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        dict.insert(b"t".to_vec(), Value::Bytes(self.transaction_id.clone()));
+        match &self.body {
+            Body::Query(query) => {
+                let mut args = HashMap::new();
+                args.insert(b"id".to_vec(), Value::Bytes(query.id().to_vec()));
+                match query {
+                    Query::Ping { .. } => {}
+                    Query::FindNode { target, .. } => {
+                        args.insert(b"target".to_vec(), Value::Bytes(target.to_vec()));
+                    }
+                    Query::GetPeers { info_hash, .. } => {
+                        args.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                        ..
+                    } => {
+                        args.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                        args.insert(b"port".to_vec(), Value::Int(i64::from(*port)));
+                        args.insert(
+                            b"implied_port".to_vec(),
+                            Value::Int(i64::from(*implied_port)),
+                        );
+                        args.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                    }
+                }
+                dict.insert(b"y".to_vec(), Value::Bytes(b"q".to_vec()));
+                dict.insert(
+                    b"q".to_vec(),
+                    Value::Bytes(query.method().as_bytes().to_vec()),
+                );
+                dict.insert(b"a".to_vec(), Value::Dict(args));
+            }
+            Body::Response(response) => {
+                let mut values = HashMap::new();
+                values.insert(b"id".to_vec(), Value::Bytes(response.id.to_vec()));
+                if !response.nodes.is_empty() {
+                    values.insert(
+                        b"nodes".to_vec(),
+                        Value::Bytes(encode_compact_nodes(&response.nodes)),
+                    );
+                }
+                if !response.values.is_empty() {
+                    values.insert(
+                        b"values".to_vec(),
+                        Value::List(
+                            response
+                                .values
+                                .iter()
+                                .map(|peer| Value::Bytes(encode_compact_peer(peer).to_vec()))
+                                .collect(),
+                        ),
+                    );
+                }
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                }
+                dict.insert(b"y".to_vec(), Value::Bytes(b"r".to_vec()));
+                dict.insert(b"r".to_vec(), Value::Dict(values));
+            }
+            Body::Error { code, message } => {
+                dict.insert(b"y".to_vec(), Value::Bytes(b"e".to_vec()));
+                dict.insert(
+                    b"e".to_vec(),
+                    Value::List(vec![
+                        Value::Int(*code),
+                        Value::Bytes(message.as_bytes().to_vec()),
+                    ]),
+                );
+            }
+        }
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("A Value always serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let Value::Dict(dict) = serde_bencode::from_bytes::<Value>(bytes)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?
+        else {
+            return Err(ParseError::Deserialization(
+                "KRPC message did not deserialize into a dictionary".to_owned(),
+            ));
+        };
+        let transaction_id = get_bytes(&dict, "t")?.to_vec();
+        let body = match get_bytes(&dict, "y")? {
+            b"q" => {
+                let Some(Value::Dict(args)) = dict.get("a".as_bytes()) else {
+                    return Err(ParseError::MissingField("a".to_string()));
+                };
+                let id = get_id(args, "id")?;
+                let query = match get_bytes(&dict, "q")? {
+                    b"ping" => Query::Ping { id },
+                    b"find_node" => Query::FindNode {
+                        id,
+                        target: get_id(args, "target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        id,
+                        info_hash: get_id(args, "info_hash")?,
+                    },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        id,
+                        info_hash: get_id(args, "info_hash")?,
+                        port: match args.get("port".as_bytes()) {
+                            Some(Value::Int(port)) => u16::try_from(*port)
+                                .map_err(|err| ParseError::Deserialization(err.to_string()))?,
+                            _ => return Err(ParseError::MissingField("port".to_string())),
+                        },
+                        implied_port: matches!(
+                            args.get("implied_port".as_bytes()),
+                            Some(Value::Int(1))
+                        ),
+                        token: get_bytes(args, "token")?.to_vec(),
+                    },
+                    method => {
+                        return Err(ParseError::Deserialization(format!(
+                            "Unknown KRPC method {}",
+                            String::from_utf8_lossy(method)
+                        )))
+                    }
+                };
+                Body::Query(query)
+            }
+            b"r" => {
+                let Some(Value::Dict(values)) = dict.get("r".as_bytes()) else {
+                    return Err(ParseError::MissingField("r".to_string()));
+                };
+                let nodes = match values.get("nodes".as_bytes()) {
+                    Some(Value::Bytes(nodes)) => decode_compact_nodes(nodes)?,
+                    _ => Vec::new(),
+                };
+                let values_list = match values.get("values".as_bytes()) {
+                    Some(Value::List(peers)) => peers
+                        .iter()
+                        .filter_map(|peer| match peer {
+                            Value::Bytes(peer) => decode_compact_peer(peer),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let token = match values.get("token".as_bytes()) {
+                    Some(Value::Bytes(token)) => Some(token.clone()),
+                    _ => None,
+                };
+                Body::Response(Response {
+                    id: get_id(values, "id")?,
+                    nodes,
+                    values: values_list,
+                    token,
+                })
+            }
+            b"e" => match dict.get("e".as_bytes()) {
+                Some(Value::List(error)) => match error.as_slice() {
+                    [Value::Int(code), Value::Bytes(message)] => Body::Error {
+                        code: *code,
+                        message: String::from_utf8_lossy(message).into_owned(),
+                    },
+                    _ => {
+                        return Err(ParseError::Deserialization(
+                            "`e` was not a [code, message] list".to_owned(),
+                        ))
+                    }
+                },
+                _ => return Err(ParseError::MissingField("e".to_string())),
+            },
+            kind => {
+                return Err(ParseError::Deserialization(format!(
+                    "Unknown KRPC message type {}",
+                    String::from_utf8_lossy(kind)
+                )))
+            }
+        };
+        Ok(Self {
+            transaction_id,
+            body,
+        })
+    }
+}
+
+fn get_bytes<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> Result<&'a [u8], ParseError> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Bytes(bytes)) => Ok(bytes),
+        Some(_) => Err(ParseError::Deserialization(format!(
+            "`{key}` did not deserialize into bytes"
+        ))),
+        None => Err(ParseError::MissingField(key.to_string())),
+    }
+}
+
+fn get_id(dict: &HashMap<Vec<u8>, Value>, key: &str) -> Result<NodeId, ParseError> {
+    <NodeId>::try_from(get_bytes(dict, key)?)
+        .map_err(|_| ParseError::Deserialization(format!("`{key}` was not 20 bytes")))
+}
+
+pub fn encode_compact_peer(peer: &SocketAddrV4) -> [u8; TRACKER_RESPONSE_PEER_SIZE] {
+    let mut compact = [0u8; TRACKER_RESPONSE_PEER_SIZE];
+    compact[..4].copy_from_slice(&peer.ip().octets());
+    compact[4..].copy_from_slice(&peer.port().to_be_bytes());
+    compact
+}
+
+pub fn decode_compact_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    let bytes = <[u8; TRACKER_RESPONSE_PEER_SIZE]>::try_from(bytes).ok()?;
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+    Some(SocketAddrV4::new(ip, port))
+}
+
+pub fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_SIZE);
+    for node in nodes {
+        bytes.extend_from_slice(&node.id);
+        bytes.extend_from_slice(&encode_compact_peer(&node.addr));
+    }
+    bytes
+}
+
+pub fn decode_compact_nodes(bytes: &[u8]) -> Result<Vec<NodeInfo>, ParseError> {
+    if bytes.len() % COMPACT_NODE_SIZE != 0 {
+        return Err(ParseError::Deserialization(
+            "`nodes` length was not a multiple of 26".to_owned(),
+        ));
+    }
+    Ok(bytes
+        .chunks_exact(COMPACT_NODE_SIZE)
+        .map(|chunk| NodeInfo {
+            id: <NodeId>::try_from(&chunk[..NODE_ID_SIZE]).expect("Must necessarily be 20 bytes"),
+            addr: decode_compact_peer(&chunk[NODE_ID_SIZE..]).expect("Must necessarily be 6 bytes"),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::{Body, Message, NodeInfo, Query, Response};
+
+    #[test]
+    fn test_ping_wire_format() {
+        // Example taken from BEP 5
+        let ping = Message {
+            transaction_id: b"aa".to_vec(),
+            body: Body::Query(Query::Ping {
+                id: *b"abcdefghij0123456789",
+            }),
+        };
+        let bytes = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(bytes.to_vec(), ping.to_bytes());
+        assert_eq!(ping, Message::from_bytes(bytes).unwrap());
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let response = Message {
+            transaction_id: b"xy".to_vec(),
+            body: Body::Response(Response {
+                id: [1; 20],
+                nodes: vec![NodeInfo {
+                    id: [2; 20],
+                    addr: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881),
+                }],
+                values: vec![SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 51413)],
+                token: Some(b"token".to_vec()),
+            }),
+        };
+        assert_eq!(response, Message::from_bytes(&response.to_bytes()).unwrap());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod krpc;
+pub mod node;
+pub mod routing;
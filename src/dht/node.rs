@@ -0,0 +1,723 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::{
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+};
+
+use super::{
+    krpc::{Body, Message, NodeId, NodeInfo, Query, Response, ERROR_PROTOCOL},
+    routing::{distance, RoutingTable, K},
+};
+use crate::INFO_HASH_SIZE;
+
+/// Number of queries a lookup keeps in flight at once
+const ALPHA: usize = 3;
+/// How often the secret that tokens are derived from changes
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// How long an announced peer is handed out in `get_peers` responses
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Most peers kept for one info hash; the one announced longest ago makes room for a new one
+const MAX_PEERS_PER_INFO_HASH: usize = 200;
+/// Most peers kept across every info hash; announces past that are not stored
+const MAX_STORED_PEERS: usize = 10_000;
+/// Largest KRPC packet we expect to receive
+const MAX_PACKET_SIZE: usize = 1500;
+/// How long receiving waits after a socket error, which would otherwise repeat in a busy loop
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Well known nodes to join the mainline DHT through
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// `host:port` of the nodes to join the DHT through
+    pub bootstrap_nodes: Vec<String>,
+    /// File the node id and routing table are loaded from on startup and saved to
+    pub state_file: Option<PathBuf>,
+    /// How long to wait for a node to answer a query
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            state_file: None,
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Secrets that `get_peers` tokens are derived from. The previous secret is still honored so
+/// that a token stays valid for up to twice the rotation period
+#[derive(Debug)]
+struct TokenSecrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl TokenSecrets {
+    fn new() -> Self {
+        Self {
+            current: rand::thread_rng().gen(),
+            previous: rand::thread_rng().gen(),
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn rotate_if_stale(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::thread_rng().gen();
+            self.rotated_at = Instant::now();
+        }
+    }
+
+    fn token(secret: &[u8; 20], addr: &SocketAddrV4) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().octets());
+        hasher.finalize().to_vec()
+    }
+
+    fn issue(&mut self, addr: &SocketAddrV4) -> Vec<u8> {
+        self.rotate_if_stale();
+        Self::token(&self.current, addr)
+    }
+
+    fn is_valid(&mut self, addr: &SocketAddrV4, token: &[u8]) -> bool {
+        self.rotate_if_stale();
+        Self::token(&self.current, addr) == token || Self::token(&self.previous, addr) == token
+    }
+}
+
+/// The node a query was sent to and where its answer goes
+type PendingQuery = (SocketAddrV4, oneshot::Sender<Body>);
+
+/// Peers announced to us, each handed out for [`PEER_TTL`]. Bounded per info hash and in
+/// total, so that announcing can't make it grow without limit
+#[derive(Debug, Default)]
+struct PeerStore {
+    torrents: HashMap<[u8; INFO_HASH_SIZE], HashMap<SocketAddrV4, Instant>>,
+}
+
+impl PeerStore {
+    fn len(&self) -> usize {
+        self.torrents.values().map(HashMap::len).sum()
+    }
+
+    /// The peers of the info hash announced within the TTL
+    fn get(&mut self, info_hash: &[u8; INFO_HASH_SIZE]) -> Vec<SocketAddrV4> {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        let values = peers.keys().copied().collect::<Vec<SocketAddrV4>>();
+        if values.is_empty() {
+            self.torrents.remove(info_hash);
+        }
+        values
+    }
+
+    fn expire(&mut self) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        }
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+
+    fn insert(&mut self, info_hash: [u8; INFO_HASH_SIZE], peer: SocketAddrV4) {
+        let now = Instant::now();
+        if let Some(announced) = self
+            .torrents
+            .get_mut(&info_hash)
+            .and_then(|peers| peers.get_mut(&peer))
+        {
+            *announced = now;
+            return;
+        }
+        if self.len() >= MAX_STORED_PEERS {
+            self.expire();
+            if self.len() >= MAX_STORED_PEERS {
+                return;
+            }
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if peers.len() >= MAX_PEERS_PER_INFO_HASH {
+            peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest.filter(|_| peers.len() >= MAX_PEERS_PER_INFO_HASH) {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, now);
+    }
+}
+
+#[derive(Debug)]
+struct DhtInner {
+    socket: Arc<UdpSocket>,
+    config: DhtConfig,
+    routing_table: Mutex<RoutingTable>,
+    /// Queries waiting for an answer by transaction id, with the node each was sent to
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    tokens: Mutex<TokenSecrets>,
+    peers: Mutex<PeerStore>,
+    receiver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for DhtInner {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.lock().expect("Poisoned lock").take() {
+            receiver.abort();
+        }
+    }
+}
+
+/// A node of the mainline DHT (BEP 5) speaking KRPC over UDP
+///
+/// The handle is cheap to clone; the socket keeps answering queries of other nodes until the
+/// last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Dht {
+    inner: Arc<DhtInner>,
+}
+
+impl Dht {
+    /// Binds the UDP socket and starts answering queries. The routing table is restored from the
+    /// state file when there is one, which keeps the node id stable across runs
+    pub async fn bind(addr: impl ToSocketAddrs, config: DhtConfig) -> Result<Dht, DhtError> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .map_err(|err| DhtError::Io(err.to_string()))?;
+        let routing_table = config
+            .state_file
+            .as_ref()
+            .filter(|path| path.exists())
+            .and_then(|path| RoutingTable::load(path).ok())
+            .unwrap_or_else(|| RoutingTable::new(rand::thread_rng().gen()));
+        let inner = Arc::new(DhtInner {
+            socket: Arc::new(socket),
+            config,
+            routing_table: Mutex::new(routing_table),
+            pending: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(TokenSecrets::new()),
+            peers: Mutex::new(PeerStore::default()),
+            receiver: Mutex::new(None),
+        });
+        let receiver = tokio::spawn(receive_loop(inner.socket.clone(), Arc::downgrade(&inner)));
+        *inner.receiver.lock().expect("Poisoned lock") = Some(receiver);
+        Ok(Dht { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        *self.routing_table().id()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        self.inner
+            .socket
+            .local_addr()
+            .map_err(|err| DhtError::Io(err.to_string()))
+    }
+
+    /// Number of nodes in the routing table
+    pub fn num_nodes(&self) -> usize {
+        self.routing_table().len()
+    }
+
+    fn routing_table(&self) -> std::sync::MutexGuard<'_, RoutingTable> {
+        self.inner.routing_table.lock().expect("Poisoned lock")
+    }
+
+    /// Writes the routing table to the configured state file
+    pub fn save(&self) -> Result<(), DhtError> {
+        match &self.inner.config.state_file {
+            Some(path) => self
+                .routing_table()
+                .save(path)
+                .map_err(|err| DhtError::Io(err.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Joins the DHT by pinging the bootstrap nodes and looking up our own id, which fills the
+    /// routing table with the nodes closest to us. Returns the number of nodes known afterwards
+    pub async fn bootstrap(&self) -> Result<usize, DhtError> {
+        let mut addrs = Vec::new();
+        for node in &self.inner.config.bootstrap_nodes {
+            if let Ok(resolved) = lookup_host(node).await {
+                addrs.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                }));
+            }
+        }
+        // Nodes restored from the state file are just as good to start from
+        addrs.extend(self.routing_table().nodes().iter().map(|node| node.addr));
+        let mut pings = JoinSet::new();
+        for addr in addrs {
+            let dht = self.clone();
+            pings.spawn(async move { dht.ping(addr).await });
+        }
+        while pings.join_next().await.is_some() {}
+        if self.routing_table().is_empty() {
+            return Err(DhtError::NoNodes);
+        }
+        self.lookup(self.id(), false).await;
+        Ok(self.num_nodes())
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId, DhtError> {
+        let response = self.query(addr, Query::Ping { id: self.id() }).await?;
+        Ok(response.id)
+    }
+
+    pub async fn find_node(
+        &self,
+        addr: SocketAddrV4,
+        target: NodeId,
+    ) -> Result<Vec<NodeInfo>, DhtError> {
+        let response = self
+            .query(
+                addr,
+                Query::FindNode {
+                    id: self.id(),
+                    target,
+                },
+            )
+            .await?;
+        Ok(response.nodes)
+    }
+
+    /// Asks a node for peers of a torrent. The response carries either peers (`values`) or the
+    /// nodes closest to the info hash, and a token to announce with
+    pub async fn get_peers(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; INFO_HASH_SIZE],
+    ) -> Result<Response, DhtError> {
+        self.query(
+            addr,
+            Query::GetPeers {
+                id: self.id(),
+                info_hash,
+            },
+        )
+        .await
+    }
+
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; INFO_HASH_SIZE],
+        port: u16,
+        token: Vec<u8>,
+    ) -> Result<(), DhtError> {
+        self.query(
+            addr,
+            Query::AnnouncePeer {
+                id: self.id(),
+                info_hash,
+                port,
+                implied_port: false,
+                token,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Finds peers of a torrent by walking the DHT towards the info hash
+    pub async fn lookup_peers(&self, info_hash: [u8; INFO_HASH_SIZE]) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, true).await.1
+    }
+
+    /// Finds peers of a torrent and announces that we are downloading it on `port` to the nodes
+    /// closest to the info hash. Returns the peers found along the way
+    pub async fn announce(&self, info_hash: [u8; INFO_HASH_SIZE], port: u16) -> Vec<SocketAddrV4> {
+        let (closest, peers) = self.lookup(info_hash, true).await;
+        let mut announces = JoinSet::new();
+        for (node, token) in closest {
+            if let Some(token) = token {
+                let dht = self.clone();
+                announces.spawn(async move {
+                    dht.announce_peer(node.addr, info_hash, port, token).await
+                });
+            }
+        }
+        while announces.join_next().await.is_some() {}
+        peers
+    }
+
+    /// Iterative Kademlia lookup of `target`, querying up to [`ALPHA`] of the closest nodes not
+    /// queried yet at a time until the [`K`] closest nodes have all answered or failed
+    async fn lookup(
+        &self,
+        target: NodeId,
+        get_peers: bool,
+    ) -> (Vec<(NodeInfo, Option<Vec<u8>>)>, Vec<SocketAddrV4>) {
+        let mut shortlist = self.routing_table().closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut responded = Vec::new();
+        let mut peers = HashSet::new();
+        loop {
+            shortlist.sort_by_key(|node| distance(&node.id, &target));
+            shortlist.dedup_by_key(|node| node.id);
+            let batch = shortlist
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect::<Vec<NodeInfo>>();
+            if batch.is_empty() {
+                break;
+            }
+            let mut queries = JoinSet::new();
+            for node in batch {
+                queried.insert(node.id);
+                let dht = self.clone();
+                queries.spawn(async move {
+                    let res = if get_peers {
+                        dht.get_peers(node.addr, target).await
+                    } else {
+                        dht.find_node(node.addr, target)
+                            .await
+                            .map(|nodes| Response {
+                                nodes,
+                                ..Default::default()
+                            })
+                    };
+                    (node, res)
+                });
+            }
+            while let Some(Ok((node, res))) = queries.join_next().await {
+                match res {
+                    Ok(response) => {
+                        peers.extend(response.values);
+                        let own_id = self.id();
+                        shortlist.extend(
+                            response
+                                .nodes
+                                .into_iter()
+                                .filter(|found| found.id != own_id),
+                        );
+                        responded.push((node, response.token));
+                    }
+                    Err(_) => shortlist.retain(|other| other.id != node.id),
+                }
+            }
+        }
+        responded.sort_by_key(|(node, _)| distance(&node.id, &target));
+        responded.truncate(K);
+        (responded, peers.into_iter().collect())
+    }
+
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> Result<Response, DhtError> {
+        let (sender, receiver) = oneshot::channel();
+        // Random ids keep off-path nodes from guessing which answer we wait for
+        let transaction_id = {
+            let mut pending = self.inner.pending.lock().expect("Poisoned lock");
+            let transaction_id = loop {
+                let transaction_id = rand::thread_rng().gen::<[u8; 4]>().to_vec();
+                if !pending.contains_key(&transaction_id) {
+                    break transaction_id;
+                }
+            };
+            pending.insert(transaction_id.clone(), (addr, sender));
+            transaction_id
+        };
+        let message = Message {
+            transaction_id: transaction_id.clone(),
+            body: Body::Query(query),
+        };
+        let res = async {
+            self.inner
+                .socket
+                .send_to(&message.to_bytes(), addr)
+                .await
+                .map_err(|err| DhtError::Io(err.to_string()))?;
+            tokio::time::timeout(self.inner.config.query_timeout, receiver)
+                .await
+                .map_err(|_| DhtError::Timeout(addr))?
+                .map_err(|_| DhtError::Timeout(addr))
+        }
+        .await;
+        self.inner
+            .pending
+            .lock()
+            .expect("Poisoned lock")
+            .remove(&transaction_id);
+        match res? {
+            Body::Response(response) => {
+                add_node(
+                    &self.inner,
+                    NodeInfo {
+                        id: response.id,
+                        addr,
+                    },
+                );
+                Ok(response)
+            }
+            Body::Error { code, message } => Err(DhtError::Remote { code, message }),
+            Body::Query(_) => Err(DhtError::Remote {
+                code: ERROR_PROTOCOL,
+                message: "Got a query as a response".to_owned(),
+            }),
+        }
+    }
+}
+
+/// Adds a node heard from to the routing table. When its bucket is full, a questionable node of
+/// it is pinged and the new node only takes its place if the ping times out
+fn add_node(inner: &Arc<DhtInner>, node: NodeInfo) {
+    let questionable = {
+        let mut routing_table = inner.routing_table.lock().expect("Poisoned lock");
+        if routing_table.insert(node) {
+            return;
+        }
+        routing_table.questionable(&node.id)
+    };
+    let Some(questionable) = questionable else {
+        return;
+    };
+    let dht = Dht {
+        inner: inner.clone(),
+    };
+    tokio::spawn(async move {
+        // An answer refreshes the node where it is
+        if let Err(DhtError::Timeout(_)) = dht.ping(questionable.addr).await {
+            let mut routing_table = dht.routing_table();
+            routing_table.remove(&questionable.id);
+            routing_table.insert(node);
+        }
+    });
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, inner: Weak<DhtInner>) {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            tokio::time::sleep(RECV_ERROR_BACKOFF).await;
+            continue;
+        };
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Ok(message) = Message::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        match message.body {
+            Body::Query(query) => {
+                let body = handle_query(&inner, from, query);
+                let reply = Message {
+                    transaction_id: message.transaction_id,
+                    body,
+                };
+                let _ = socket.send_to(&reply.to_bytes(), from).await;
+            }
+            body => {
+                // Only the node a query went to may answer it
+                let sender = {
+                    let mut pending = inner.pending.lock().expect("Poisoned lock");
+                    match pending.get(&message.transaction_id) {
+                        Some((addr, _)) if *addr == from => pending
+                            .remove(&message.transaction_id)
+                            .map(|(_, sender)| sender),
+                        _ => None,
+                    }
+                };
+                if let Some(sender) = sender {
+                    let _ = sender.send(body);
+                }
+            }
+        }
+    }
+}
+
+fn handle_query(inner: &Arc<DhtInner>, from: SocketAddrV4, query: Query) -> Body {
+    add_node(
+        inner,
+        NodeInfo {
+            id: *query.id(),
+            addr: from,
+        },
+    );
+    let routing_table = inner.routing_table.lock().expect("Poisoned lock");
+    let id = *routing_table.id();
+    match query {
+        Query::Ping { .. } => Body::Response(Response {
+            id,
+            ..Default::default()
+        }),
+        Query::FindNode { target, .. } => Body::Response(Response {
+            id,
+            nodes: routing_table.closest(&target, K),
+            ..Default::default()
+        }),
+        Query::GetPeers { info_hash, .. } => {
+            let token = inner.tokens.lock().expect("Poisoned lock").issue(&from);
+            let values = inner.peers.lock().expect("Poisoned lock").get(&info_hash);
+            let nodes = if values.is_empty() {
+                routing_table.closest(&info_hash, K)
+            } else {
+                Vec::new()
+            };
+            Body::Response(Response {
+                id,
+                nodes,
+                values,
+                token: Some(token),
+            })
+        }
+        Query::AnnouncePeer {
+            info_hash,
+            port,
+            implied_port,
+            token,
+            ..
+        } => {
+            if !inner
+                .tokens
+                .lock()
+                .expect("Poisoned lock")
+                .is_valid(&from, &token)
+            {
+                return Body::Error {
+                    code: ERROR_PROTOCOL,
+                    message: "Bad token".to_owned(),
+                };
+            }
+            let port = if implied_port { from.port() } else { port };
+            inner
+                .peers
+                .lock()
+                .expect("Poisoned lock")
+                .insert(info_hash, SocketAddrV4::new(*from.ip(), port));
+            Body::Response(Response {
+                id,
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Error type for DHT operations
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DhtError {
+    #[error("DHT socket error: {0}")]
+    Io(String),
+    #[error("DHT node {0} did not answer in time")]
+    Timeout(SocketAddrV4),
+    #[error("DHT node answered with error {code}: {message}")]
+    Remote { code: i64, message: String },
+    #[error("None of the bootstrap nodes answered")]
+    NoNodes,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use super::{Dht, DhtConfig, PeerStore, MAX_PEERS_PER_INFO_HASH, MAX_STORED_PEERS};
+
+    async fn spawn_node(
+        bootstrap: Option<SocketAddr>,
+        state_file: Option<std::path::PathBuf>,
+    ) -> Dht {
+        let config = DhtConfig {
+            bootstrap_nodes: bootstrap.iter().map(ToString::to_string).collect(),
+            state_file,
+            query_timeout: Duration::from_millis(500),
+        };
+        Dht::bind("127.0.0.1:0", config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_local_swarm_announce_and_lookup() {
+        let root = spawn_node(None, None).await;
+        let root_addr = root.local_addr().unwrap();
+        let mut nodes = Vec::new();
+        for _ in 0..6 {
+            let node = spawn_node(Some(root_addr), None).await;
+            node.bootstrap().await.unwrap();
+            nodes.push(node);
+        }
+        for node in &nodes {
+            node.bootstrap().await.unwrap();
+        }
+
+        let info_hash = [0xab; 20];
+        let announcer = &nodes[0];
+        announcer.announce(info_hash, 51413).await;
+
+        let seeker = &nodes[5];
+        let peers = seeker.lookup_peers(info_hash).await;
+        assert!(peers.iter().any(|peer| peer.port() == 51413));
+    }
+
+    #[tokio::test]
+    async fn test_routing_table_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("dht.dat");
+        let root = spawn_node(None, None).await;
+        let node = spawn_node(Some(root.local_addr().unwrap()), Some(state_file.clone())).await;
+        node.bootstrap().await.unwrap();
+        node.save().unwrap();
+        let id = node.id();
+        drop(node);
+
+        let restored = spawn_node(None, Some(state_file)).await;
+        assert_eq!(id, restored.id());
+        assert_eq!(1, restored.num_nodes());
+    }
+
+    #[test]
+    fn test_peer_store_is_bounded() {
+        let peer = |n: usize| SocketAddrV4::new(Ipv4Addr::from(n as u32), 6881);
+        let mut store = PeerStore::default();
+        for n in 0..=MAX_PEERS_PER_INFO_HASH {
+            store.insert([0; 20], peer(n));
+        }
+        let peers = store.get(&[0; 20]);
+        assert_eq!(MAX_PEERS_PER_INFO_HASH, peers.len());
+        assert!(peers.contains(&peer(MAX_PEERS_PER_INFO_HASH)));
+
+        let mut info_hash = [0u8; 20];
+        for n in 0..MAX_STORED_PEERS {
+            info_hash[..8].copy_from_slice(&(n as u64 + 1).to_be_bytes());
+            store.insert(info_hash, peer(n));
+        }
+        assert_eq!(MAX_STORED_PEERS, store.len());
+        assert!(store.get(&info_hash).is_empty());
+    }
+}
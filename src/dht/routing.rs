@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde_bencode::value::Value;
+
+use super::krpc::{self, NodeId, NodeInfo, NODE_ID_SIZE};
+use crate::ParseError;
+
+/// Maximum number of nodes kept in a bucket
+pub const K: usize = 8;
+/// A node that hasn't been heard from for this long is pinged when a new node wants its place
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// How long a questionable node is left alone after being pinged, whatever came of it
+pub const QUESTIONABLE_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// XOR distance between two node ids, compared as big endian numbers
+#[inline]
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; NODE_ID_SIZE];
+    for (idx, byte) in distance.iter_mut().enumerate() {
+        *byte = a[idx] ^ b[idx];
+    }
+    distance
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    /// When the node was last pinged to find out whether it is still there
+    pinged: Option<Instant>,
+}
+
+impl Entry {
+    fn new(node: NodeInfo) -> Self {
+        Self {
+            node,
+            last_seen: Instant::now(),
+            pinged: None,
+        }
+    }
+}
+
+/// Kademlia routing table with one k-bucket per length of the prefix shared with our own id
+///
+/// Bucket `i` holds the nodes whose distance to us has its highest set bit at position `i`, so
+/// that the table knows many nodes close to us and only a few far away.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); NODE_ID_SIZE * 8],
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let leading_zeros = distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|idx| idx * 8 + distance[idx].leading_zeros() as usize)?;
+        Some(NODE_ID_SIZE * 8 - 1 - leading_zeros)
+    }
+
+    /// Records that a node was heard from. A full bucket doesn't take the node in; see
+    /// [`Self::questionable`] for making room
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(idx) = self.bucket_index(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[idx];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            *entry = Entry::new(node);
+            return true;
+        }
+        if bucket.len() < K {
+            bucket.push(Entry::new(node));
+            return true;
+        }
+        false
+    }
+
+    /// The stalest node gone questionable of the full bucket `id` belongs in, which is to be
+    /// pinged and replaced only if it doesn't answer (BEP 5). A node is handed out once per
+    /// [`QUESTIONABLE_PING_INTERVAL`]
+    pub fn questionable(&mut self, id: &NodeId) -> Option<NodeInfo> {
+        let idx = self.bucket_index(id)?;
+        let bucket = &mut self.buckets[idx];
+        if bucket.len() < K || bucket.iter().any(|entry| entry.node.id == *id) {
+            return None;
+        }
+        let stalest = bucket
+            .iter_mut()
+            .filter(|entry| entry.last_seen.elapsed() >= QUESTIONABLE_AFTER)
+            .filter(|entry| {
+                entry.pinged.map_or(true, |pinged| {
+                    pinged.elapsed() >= QUESTIONABLE_PING_INTERVAL
+                })
+            })
+            .min_by_key(|entry| entry.last_seen)?;
+        stalest.pinged = Some(Instant::now());
+        Some(stalest.node)
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(idx) = self.bucket_index(id) {
+            self.buckets[idx].retain(|entry| entry.node.id != *id);
+        }
+    }
+
+    /// The `count` known nodes closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bencodes our id and every known node so that the table survives restarts
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        dict.insert(b"id".to_vec(), Value::Bytes(self.id.to_vec()));
+        dict.insert(
+            b"nodes".to_vec(),
+            Value::Bytes(krpc::encode_compact_nodes(&self.nodes())),
+        );
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("A Value always serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let Value::Dict(dict) = serde_bencode::from_bytes::<Value>(bytes)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?
+        else {
+            return Err(ParseError::Deserialization(
+                "Routing table did not deserialize into a dictionary".to_owned(),
+            ));
+        };
+        let id = match dict.get("id".as_bytes()) {
+            Some(Value::Bytes(id)) => <NodeId>::try_from(&id[..])
+                .map_err(|_| ParseError::Deserialization("`id` was not 20 bytes".to_owned())),
+            _ => Err(ParseError::MissingField("id".to_string())),
+        }?;
+        let nodes = match dict.get("nodes".as_bytes()) {
+            Some(Value::Bytes(nodes)) => krpc::decode_compact_nodes(nodes),
+            _ => Err(ParseError::MissingField("nodes".to_string())),
+        }?;
+        let mut table = Self::new(id);
+        for node in nodes {
+            table.insert(node);
+        }
+        Ok(table)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+        let bytes =
+            std::fs::read(path).map_err(|err| ParseError::Deserialization(err.to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Instant,
+    };
+
+    use super::{RoutingTable, K, QUESTIONABLE_AFTER};
+    use crate::dht::krpc::NodeInfo;
+
+    fn node(first_byte: u8, last_byte: u8) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+        NodeInfo {
+            id,
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881 + u16::from(last_byte)),
+        }
+    }
+
+    #[test]
+    fn test_bucket_limit_and_closest() {
+        let mut table = RoutingTable::new([0u8; 20]);
+        // Every one of these shares no prefix bit with us so they all land in the same bucket
+        for idx in 0..(K as u8 + 2) {
+            table.insert(node(0x80, idx));
+        }
+        assert_eq!(K, table.len());
+        // Nodes heard from lately keep their place
+        assert_eq!(None, table.questionable(&node(0x80, K as u8 + 2).id));
+        assert!(table.insert(node(0x01, 0)));
+        assert!(!table.insert(node(0, 0)));
+        let closest = table.closest(&[0u8; 20], 2);
+        assert_eq!(node(0x01, 0), closest[0]);
+        assert_eq!(node(0x80, 0), closest[1]);
+
+        let restored = RoutingTable::from_bytes(&table.to_bytes()).unwrap();
+        assert_eq!(table.id(), restored.id());
+        assert_eq!(table.len(), restored.len());
+    }
+
+    #[test]
+    fn test_questionable_node_is_offered_once() {
+        let mut table = RoutingTable::new([0u8; 20]);
+        for idx in 0..K as u8 {
+            table.insert(node(0x80, idx));
+        }
+        // The monotonic clock may have started too recently to go back that far
+        let Some(long_ago) = Instant::now().checked_sub(QUESTIONABLE_AFTER) else {
+            return;
+        };
+        let newcomer = node(0x80, K as u8);
+        let idx = table.bucket_index(&newcomer.id).unwrap();
+        table.buckets[idx][3].last_seen = long_ago;
+        assert_eq!(Some(node(0x80, 3)), table.questionable(&newcomer.id));
+        // It is being pinged, and keeps its place until that times out
+        assert_eq!(None, table.questionable(&newcomer.id));
+        assert!(!table.insert(newcomer));
+        assert_eq!(K, table.len());
+    }
+}
@@ -1,13 +1,17 @@
 use thiserror::Error;
-use tokio::{
-    io,
-    net::{TcpStream, ToSocketAddrs},
-};
+use tokio::io::{self, AsyncRead, AsyncWrite};
 
 use super::INFO_HASH_SIZE;
-use std::io::{Cursor, Read};
+use std::{
+    io::{Cursor, Read},
+    net::SocketAddr,
+};
 
-use crate::{ParseError, PEER_ID_SIZE};
+use crate::{
+    mse::{self, EncryptionPolicy, MseStream},
+    transport::{PeerConnection, Transport},
+    ParseError, PEER_ID_SIZE,
+};
 
 pub const LENGTH_BYTE_SIZE: usize = 1;
 pub const HANDSHAKE_LENGTH_SIZE: usize = 19;
@@ -16,33 +20,106 @@ pub const PROTOCOL_STRING: [u8; HANDSHAKE_LENGTH_SIZE] = *b"BitTorrent protocol"
 pub const HANDSHAKE_SIZE: usize =
     LENGTH_BYTE_SIZE + HANDSHAKE_LENGTH_SIZE + RESERVED_SIZE + INFO_HASH_SIZE + PEER_ID_SIZE;
 
-pub async fn connect<A: ToSocketAddrs>(
-    peer: A,
+/// Byte of the reserved bytes and the bit within it that advertises the extension protocol
+/// (BEP 10)
+pub const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+/// Byte of the reserved bytes and the bit within it that advertises the Fast Extension (BEP 6)
+pub const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+
+pub async fn connect(
+    peer: SocketAddr,
     info_hash: &[u8; INFO_HASH_SIZE],
     peer_id: &[u8; PEER_ID_SIZE],
-) -> Result<(TcpStream, [u8; PEER_ID_SIZE]), HandshakeError> {
-    let self_hand = Handshake::new(info_hash, peer_id);
-    let mut stream = TcpStream::connect(peer)
-        .await
-        .map_err(|err| HandshakeError::Connection(err.to_string()))?;
-    let body = self_hand.as_bytes();
-    io::AsyncWriteExt::write_all(&mut stream, &body)
-        .await
-        .map_err(|err| HandshakeError::Connection(err.to_string()))?;
+    transport: &Transport,
+) -> Result<(PeerConnection, [u8; PEER_ID_SIZE]), HandshakeError> {
+    let (stream, peer_hand) =
+        connect_with(peer, Handshake::new(info_hash, peer_id), transport).await?;
+    Ok((stream, peer_hand.peer_id))
+}
 
-    let mut buf = [0; HANDSHAKE_SIZE];
-    io::AsyncReadExt::read_exact(&mut stream, &mut buf)
+/// Connects to the peer sending our own handshake as is, which lets the reserved bytes advertise
+/// extensions. Returns the handshake of the peer so that its reserved bytes can be checked too
+pub async fn connect_with(
+    peer: SocketAddr,
+    self_hand: Handshake,
+    transport: &Transport,
+) -> Result<(PeerConnection, Handshake), HandshakeError> {
+    let mut stream = transport
+        .connect(peer)
         .await
         .map_err(|err| HandshakeError::Connection(err.to_string()))?;
-    let peer_hand =
-        Handshake::from_bytes(&buf).map_err(|err| HandshakeError::Connection(err.to_string()))?;
-    if self_hand != peer_hand {
+    let peer_hand = exchange(&mut stream, &self_hand).await?;
+    Ok((stream, peer_hand))
+}
+
+/// Like [`connect_with`] over either transport, encrypting the connection with MSE as `policy`
+/// asks. With [`EncryptionPolicy::Prefer`] a peer that fails the encryption handshake is
+/// connected to again in plaintext
+pub async fn connect_encrypted(
+    peer: SocketAddr,
+    self_hand: Handshake,
+    policy: EncryptionPolicy,
+    transport: &Transport,
+) -> Result<(MseStream<PeerConnection>, Handshake), HandshakeError> {
+    let connect = || async {
+        transport
+            .connect(peer)
+            .await
+            .map_err(|err| HandshakeError::Connection(err.to_string()))
+    };
+    if policy != EncryptionPolicy::Plaintext {
+        let encrypted = match mse::initiate(connect().await?, &self_hand.infohash, policy).await {
+            Ok(mut stream) => exchange(&mut stream, &self_hand)
+                .await
+                .map(|peer_hand| (stream, peer_hand)),
+            Err(err) => Err(HandshakeError::Encryption(err.to_string())),
+        };
+        if encrypted.is_ok() || policy == EncryptionPolicy::Require {
+            return encrypted;
+        }
+    }
+    let mut stream = MseStream::plaintext(connect().await?);
+    let peer_hand = exchange(&mut stream, &self_hand).await?;
+    Ok((stream, peer_hand))
+}
+
+/// Sends our handshake over an established connection and validates the one the peer answers
+/// with
+pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    self_hand: &Handshake,
+) -> Result<Handshake, HandshakeError> {
+    write_handshake(stream, self_hand).await?;
+    let peer_hand = read_handshake(stream).await?;
+    if *self_hand != peer_hand {
         return Err(HandshakeError::Connection(
             "Handshake could not be validated since hands did not compromise!".to_owned(),
         ));
     }
+    Ok(peer_hand)
+}
 
-    Ok((stream, peer_hand.peer_id))
+pub async fn write_handshake<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    hand: &Handshake,
+) -> Result<(), HandshakeError> {
+    io::AsyncWriteExt::write_all(stream, &hand.as_bytes())
+        .await
+        .map_err(|err| HandshakeError::Connection(err.to_string()))?;
+    io::AsyncWriteExt::flush(stream)
+        .await
+        .map_err(|err| HandshakeError::Connection(err.to_string()))
+}
+
+/// Reads the handshake of a peer, which is the first thing a peer connecting to us sends
+pub async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Handshake, HandshakeError> {
+    let mut buf = [0; HANDSHAKE_SIZE];
+    io::AsyncReadExt::read_exact(stream, &mut buf)
+        .await
+        .map_err(|err| HandshakeError::Connection(err.to_string()))?;
+    Handshake::from_bytes(&buf).map_err(|err| HandshakeError::Connection(err.to_string()))
 }
 
 // TODO: If the receiving side's peer id doesn't match the one the initiating side expects, it severs the connection.
@@ -86,6 +163,33 @@ impl Handshake {
         &self.peer_id
     }
 
+    /// Advertises support for the extension protocol (BEP 10)
+    pub fn with_extensions(self) -> Self {
+        let mut s = self;
+        let (byte, bit) = EXTENSION_PROTOCOL_BIT;
+        s.reserved[byte] |= bit;
+        s
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        let (byte, bit) = EXTENSION_PROTOCOL_BIT;
+        self.reserved[byte] & bit != 0
+    }
+
+    /// Advertises support for the Fast Extension (BEP 6)
+    pub fn with_fast(self) -> Self {
+        let mut s = self;
+        let (byte, bit) = FAST_EXTENSION_BIT;
+        s.reserved[byte] |= bit;
+        s
+    }
+
+    /// Fast Extension messages may only be sent when both sides advertised it
+    pub fn supports_fast(&self) -> bool {
+        let (byte, bit) = FAST_EXTENSION_BIT;
+        self.reserved[byte] & bit != 0
+    }
+
     pub fn new(infohash: &[u8; 20], peer_id: &[u8; 20]) -> Self {
         Self {
             length: HANDSHAKE_LENGTH_SIZE as u8,
@@ -161,4 +265,6 @@ impl TryFrom<&[u8]> for Handshake {
 pub enum HandshakeError {
     #[error("Error connecting to the peer: {0}")]
     Connection(String),
+    #[error("Error encrypting the connection to the peer: {0}")]
+    Encryption(String),
 }
@@ -0,0 +1,217 @@
+use std::{
+    future::Future,
+    io,
+    panic::{self, AssertUnwindSafe},
+    pin::pin,
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    task::{Context, Poll, Wake, Waker},
+    thread,
+};
+
+use tokio::sync::{oneshot, Semaphore};
+
+/// Jobs that may wait in the queue for each thread, on top of the one it is running
+const QUEUED_PER_THREAD: usize = 2;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug)]
+struct Inner {
+    threads: usize,
+    /// Started along with the threads on the first job
+    jobs: OnceLock<mpsc::Sender<Job>>,
+    /// A slot per job queued or running, which async callers wait on once the queue is full
+    slots: Arc<Semaphore>,
+}
+
+/// A fixed number of threads hashing pieces, shared by downloads, rechecks and torrent creation
+/// so that together they use every core without piling up more work than the threads keep up
+/// with
+///
+/// Keeps the runtime's own threads free: a several MiB piece takes long enough to hash to stall
+/// every connection on the same thread. The threads exit once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct HashPool {
+    inner: Arc<Inner>,
+}
+
+impl Default for HashPool {
+    /// A thread per core
+    fn default() -> Self {
+        Self::new(
+            thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1),
+        )
+    }
+}
+
+impl HashPool {
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                threads,
+                jobs: OnceLock::new(),
+                slots: Arc::new(Semaphore::new(threads * (1 + QUEUED_PER_THREAD))),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn threads(&self) -> usize {
+        self.inner.threads
+    }
+
+    /// How many jobs can be queued or running before callers have to wait
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.inner.threads * (1 + QUEUED_PER_THREAD)
+    }
+
+    fn submit(&self, job: Job) {
+        let jobs = self.inner.jobs.get_or_init(|| {
+            let (jobs_tx, jobs_rx) = mpsc::channel::<Job>();
+            let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+            for index in 0..self.inner.threads {
+                let jobs_rx = jobs_rx.clone();
+                thread::Builder::new()
+                    .name(format!("hasher-{index}"))
+                    .spawn(move || loop {
+                        let job = jobs_rx.lock().expect("Poisoned lock").recv();
+                        match job {
+                            // Whoever waits on a job that panicked sees its result go missing
+                            Ok(job) => drop(panic::catch_unwind(AssertUnwindSafe(job))),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("Failed to spawn a hashing thread");
+            }
+            jobs_tx
+        });
+        jobs.send(job)
+            .expect("Hashing threads only exit once the pool is dropped");
+    }
+
+    /// Runs `job` on one of the threads, first waiting for a free slot when the queue is full
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> io::Result<T> {
+        let slot = self
+            .inner
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The slots are never closed");
+        let (result_tx, result_rx) = oneshot::channel();
+        self.submit(Box::new(move || {
+            let result = job();
+            drop(slot);
+            let _ = result_tx.send(result);
+        }));
+        result_rx
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Hashing job panicked"))
+    }
+
+    /// Runs every job on the threads and returns their results in order, blocking the calling
+    /// thread. Jobs are only taken from `jobs` as slots free up, sharing the queue with
+    /// [`Self::run`] so that together they keep at most [`Self::capacity`] jobs queued or
+    /// running
+    pub fn run_all<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(
+        &self,
+        jobs: impl IntoIterator<Item = F>,
+    ) -> io::Result<Vec<T>> {
+        let (results_tx, results_rx) = mpsc::channel();
+        let mut submitted = 0;
+        for (index, job) in jobs.into_iter().enumerate() {
+            let slot = block_on(self.inner.slots.clone().acquire_owned())
+                .expect("The slots are never closed");
+            let results_tx = results_tx.clone();
+            self.submit(Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(job)).ok();
+                drop(slot);
+                let _ = results_tx.send((index, result));
+            }));
+            submitted += 1;
+        }
+        drop(results_tx);
+        let mut results = (0..submitted).map(|_| None).collect::<Vec<_>>();
+        for (index, result) in results_rx {
+            results[index] =
+                Some(result.ok_or(io::Error::new(io::ErrorKind::Other, "Hashing job panicked"))?);
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+/// Wakes the thread blocked in [`block_on`]
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` to completion on the calling thread, parking it in between. Only for futures
+/// that don't need a runtime to make progress, such as waiting on a semaphore
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::HashPool;
+
+    #[tokio::test]
+    async fn test_run_and_run_all() {
+        let pool = HashPool::new(2);
+        let hash = pool
+            .run(|| <[u8; 20]>::from(Sha1::digest(b"piece")))
+            .await
+            .unwrap();
+        assert_eq!(<[u8; 20]>::from(Sha1::digest(b"piece")), hash);
+
+        // More jobs than fit the queue at once, which still come back in order
+        let jobs = (0..pool.capacity() * 4).map(|i| move || i * 2);
+        let doubled = pool.run_all(jobs).unwrap();
+        assert_eq!(
+            (0..pool.capacity() * 4).map(|i| i * 2).collect::<Vec<_>>(),
+            doubled
+        );
+
+        // Jobs run through `run` and `run_all` share the same slots
+        let taken = pool
+            .inner
+            .slots
+            .clone()
+            .acquire_many_owned(pool.capacity() as u32)
+            .await
+            .unwrap();
+        let run_all = {
+            let pool = pool.clone();
+            std::thread::spawn(move || pool.run_all([|| 1]).unwrap())
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!run_all.is_finished());
+        drop(taken);
+        assert_eq!(vec![1], run_all.join().unwrap());
+
+        assert!(pool.run(|| -> u8 { panic!("Bad job") }).await.is_err());
+        // The thread that ran it is still there
+        assert_eq!(4, pool.run(|| 2 + 2).await.unwrap());
+    }
+}
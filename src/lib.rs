@@ -1,10 +1,29 @@
 use thiserror::Error;
 
+pub mod blocklist;
+pub mod config;
+pub mod create;
+pub mod daemon;
+pub mod dht;
 pub mod handshake;
+pub mod hashpool;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+pub mod mse;
 pub mod peer;
+pub mod peerid;
+pub mod progress;
+pub mod proxy;
+pub mod ratelimit;
+pub mod resume;
+pub mod session;
 pub mod torrent;
 pub mod tracker;
+pub mod transport;
 pub mod util;
+pub mod utp;
+pub mod webseed;
 
 pub use handshake::HANDSHAKE_LENGTH_SIZE;
 pub use handshake::HANDSHAKE_SIZE;
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{
+    peer::pool::{PeerPool, PeerSource},
+    ParseError, INFO_HASH_SIZE,
+};
+
+/// Multicast group and port of Local Service Discovery over IPv4 (BEP 14)
+pub const LSD_MULTICAST_V4: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+/// Announcements are repeated this often for every active torrent
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Largest LSD datagram we expect to receive
+const MAX_PACKET_SIZE: usize = 1400;
+
+/// A `BT-SEARCH` announcement: an HTTP-like request sent to the multicast group telling the LAN
+/// that we have the torrents listed and accept peers on `port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    pub host: SocketAddrV4,
+    pub port: u16,
+    pub info_hashes: Vec<[u8; INFO_HASH_SIZE]>,
+    /// Opaque value that lets us recognize (and ignore) our own announcements
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            self.host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let message = std::str::from_utf8(bytes)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?;
+        let mut lines = message.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(ParseError::Deserialization(
+                "LSD message did not start with `BT-SEARCH * HTTP/1.1`".to_owned(),
+            ));
+        }
+        let mut host = None;
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            // Header names are case insensitive like in HTTP
+            match name.trim().to_ascii_lowercase().as_str() {
+                "host" => host = value.parse().ok(),
+                "port" => {
+                    port =
+                        Some(value.parse().map_err(|_| {
+                            ParseError::Deserialization(format!("Bad port {value}"))
+                        })?)
+                }
+                "infohash" => {
+                    let mut info_hash = [0u8; INFO_HASH_SIZE];
+                    hex::decode_to_slice(value, &mut info_hash)
+                        .map_err(|err| ParseError::Deserialization(err.to_string()))?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            host: host.unwrap_or(LSD_MULTICAST_V4),
+            port: port.ok_or(ParseError::MissingField("Port".to_string()))?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    pub multicast_addr: SocketAddrV4,
+    /// Interface to join the group and send announcements on
+    pub interface: Ipv4Addr,
+    /// The TCP port peers should connect to us on
+    pub listen_port: u16,
+    pub announce_interval: Duration,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            multicast_addr: LSD_MULTICAST_V4,
+            interface: Ipv4Addr::UNSPECIFIED,
+            listen_port: 6881,
+            announce_interval: LSD_ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LsdInner {
+    socket: Arc<UdpSocket>,
+    config: LsdConfig,
+    cookie: String,
+    torrents: Mutex<HashMap<[u8; INFO_HASH_SIZE], PeerPool>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Drop for LsdInner {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().expect("Poisoned lock").drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Local Service Discovery (BEP 14): announces the active torrents to the LAN over multicast and
+/// feeds the peers announcing the same torrents into their pools
+#[derive(Debug, Clone)]
+pub struct Lsd {
+    inner: Arc<LsdInner>,
+}
+
+impl Lsd {
+    /// Joins the multicast group, then listens for announcements and repeats our own every
+    /// announce interval until the last handle is dropped
+    pub async fn bind(config: LsdConfig) -> Result<Lsd, LsdError> {
+        let socket = multicast_socket(&config).map_err(|err| LsdError::Io(err.to_string()))?;
+        let inner = Arc::new(LsdInner {
+            socket: Arc::new(socket),
+            cookie: hex::encode(rand::thread_rng().gen::<[u8; 8]>()),
+            config,
+            torrents: Mutex::new(HashMap::new()),
+            tasks: Mutex::new(Vec::new()),
+        });
+        let receiver = tokio::spawn(receive_loop(inner.socket.clone(), Arc::downgrade(&inner)));
+        let announcer = tokio::spawn(announce_loop(Arc::downgrade(&inner)));
+        inner
+            .tasks
+            .lock()
+            .expect("Poisoned lock")
+            .extend([receiver, announcer]);
+        Ok(Lsd { inner })
+    }
+
+    /// Starts announcing a torrent. Peers found for it are added to `pool`
+    pub fn add_torrent(&self, info_hash: [u8; INFO_HASH_SIZE], pool: PeerPool) {
+        self.inner
+            .torrents
+            .lock()
+            .expect("Poisoned lock")
+            .insert(info_hash, pool);
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; INFO_HASH_SIZE]) {
+        self.inner
+            .torrents
+            .lock()
+            .expect("Poisoned lock")
+            .remove(info_hash);
+    }
+
+    /// Announces every active torrent right away instead of waiting for the next interval
+    pub async fn announce(&self) -> Result<(), LsdError> {
+        announce(&self.inner).await
+    }
+}
+
+fn multicast_socket(config: &LsdConfig) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Every LSD client on the host binds the same port
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.multicast_addr.port())).into())?;
+    socket.join_multicast_v4(config.multicast_addr.ip(), &config.interface)?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn announce(inner: &LsdInner) -> Result<(), LsdError> {
+    let info_hashes = inner
+        .torrents
+        .lock()
+        .expect("Poisoned lock")
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    if info_hashes.is_empty() {
+        return Ok(());
+    }
+    // Keep every datagram comfortably below the MTU
+    for info_hashes in info_hashes.chunks(8) {
+        let message = LsdAnnounce {
+            host: inner.config.multicast_addr,
+            port: inner.config.listen_port,
+            info_hashes: info_hashes.to_vec(),
+            cookie: Some(inner.cookie.clone()),
+        };
+        inner
+            .socket
+            .send_to(&message.to_bytes(), inner.config.multicast_addr)
+            .await
+            .map_err(|err| LsdError::Io(err.to_string()))?;
+    }
+    Ok(())
+}
+
+async fn announce_loop(inner: Weak<LsdInner>) {
+    loop {
+        let interval = match inner.upgrade() {
+            Some(inner) => {
+                let _ = announce(&inner).await;
+                inner.config.announce_interval
+            }
+            None => return,
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, inner: Weak<LsdInner>) {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let Ok(message) = LsdAnnounce::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        if message.cookie.as_ref() == Some(&inner.cookie) {
+            continue;
+        }
+        let peer = SocketAddr::new(from.ip(), message.port);
+        let torrents = inner.torrents.lock().expect("Poisoned lock");
+        for info_hash in &message.info_hashes {
+            if let Some(pool) = torrents.get(info_hash) {
+                pool.add(peer, PeerSource::Lsd);
+            }
+        }
+    }
+}
+
+/// Error type for Local Service Discovery
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LsdError {
+    #[error("LSD socket error: {0}")]
+    Io(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use super::{Lsd, LsdAnnounce, LsdConfig, LSD_MULTICAST_V4};
+    use crate::peer::pool::{PeerPool, PeerSource};
+
+    #[test]
+    fn test_announce_roundtrip() {
+        let announce = LsdAnnounce {
+            host: LSD_MULTICAST_V4,
+            port: 51413,
+            info_hashes: vec![[0x11; 20], [0x22; 20]],
+            cookie: Some("abc".to_owned()),
+        };
+        assert_eq!(
+            announce,
+            LsdAnnounce::from_bytes(&announce.to_bytes()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_loopback_discovery() {
+        let config = |listen_port| LsdConfig {
+            // A port of our own keeps the test away from real LSD traffic on the host
+            multicast_addr: SocketAddrV4::new(*LSD_MULTICAST_V4.ip(), 16771),
+            interface: Ipv4Addr::LOCALHOST,
+            listen_port,
+            announce_interval: Duration::from_secs(3600),
+        };
+        let info_hash = [0x42; 20];
+        let first = Lsd::bind(config(7001)).await.unwrap();
+        let second = Lsd::bind(config(7002)).await.unwrap();
+        let first_pool = PeerPool::default();
+        let second_pool = PeerPool::default();
+        first.add_torrent(info_hash, first_pool.clone());
+        second.add_torrent(info_hash, second_pool.clone());
+        first.announce().await.unwrap();
+        second.announce().await.unwrap();
+        assert!(first_pool.wait_for_peers(Duration::from_secs(2)).await);
+        assert!(second_pool.wait_for_peers(Duration::from_secs(2)).await);
+        let peer = first_pool.first().unwrap();
+        assert_eq!(7002, peer.port());
+        assert_eq!(Some(PeerSource::Lsd), first_pool.source(&peer));
+        // Our own announcements are never fed back into the pool
+        assert_eq!(1, first_pool.len());
+    }
+}
@@ -0,0 +1,151 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use reqwest::Url;
+
+use crate::{ParseError, INFO_HASH_SIZE};
+
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// A magnet link (BEP 9): the info hash of a torrent along with hints on where to find peers
+/// that can send its metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; INFO_HASH_SIZE],
+    /// `dn`, the name to show until the metadata arrives
+    pub name: Option<String>,
+    /// `tr`
+    pub trackers: Vec<Url>,
+    /// `x.pe`
+    pub peers: Vec<SocketAddr>,
+}
+
+impl FromStr for Magnet {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|err| ParseError::Deserialization(err.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(ParseError::Deserialization(format!(
+                "`{s}` is not a magnet link"
+            )));
+        }
+        let mut info_hash = None;
+        let mut magnet = Magnet {
+            info_hash: [0; INFO_HASH_SIZE],
+            name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(decode_btih(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value.into_owned()),
+                // Trackers we can't parse are of no use anyway
+                "tr" => magnet.trackers.extend(Url::parse(&value).ok()),
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.ok_or(ParseError::MissingField("xt".to_owned()))?;
+        Ok(magnet)
+    }
+}
+
+impl Magnet {
+    /// A torrent file made of the info dictionary fetched from peers, announcing to the first
+    /// tracker of the link if it has any. The dictionary is kept as is so that its info hash
+    /// stays the same
+    pub fn to_torrent(&self, info: &[u8]) -> Vec<u8> {
+        let mut bytes = b"d".to_vec();
+        if let Some(tracker) = self.trackers.first() {
+            let tracker = tracker.as_str();
+            bytes.extend(format!("8:announce{}:{tracker}", tracker.len()).as_bytes());
+        }
+        bytes.extend(b"4:info");
+        bytes.extend_from_slice(info);
+        bytes.push(b'e');
+        bytes
+    }
+}
+
+/// Info hashes come hex encoded or, in older links, base32 encoded
+fn decode_btih(hash: &str) -> Result<[u8; INFO_HASH_SIZE], ParseError> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| <[u8; INFO_HASH_SIZE]>::try_from(bytes).ok())
+        .ok_or(ParseError::Deserialization(format!(
+            "`{hash}` is not a hex or base32 info hash"
+        )))
+}
+
+fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = buffer << 5 | u64::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use sha1::{Digest, Sha1};
+
+    use super::Magnet;
+    use crate::torrent::from_bytes;
+
+    #[test]
+    fn test_parse_magnet() {
+        let magnet = "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt\
+            &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce&x.pe=10.0.0.1:6881"
+            .parse::<Magnet>()
+            .unwrap();
+        assert_eq!(
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            hex::encode(magnet.info_hash)
+        );
+        assert_eq!(Some("sample.txt".to_owned()), magnet.name);
+        assert_eq!(
+            "http://bittorrent-test-tracker.codecrafters.io/announce",
+            magnet.trackers[0].as_str()
+        );
+        assert_eq!(
+            vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()],
+            magnet.peers
+        );
+
+        let base32 = "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7"
+            .parse::<Magnet>()
+            .unwrap();
+        assert_eq!(magnet.info_hash, base32.info_hash);
+        assert!("magnet:?dn=nothing".parse::<Magnet>().is_err());
+
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let (announce, metainfo) = from_bytes(magnet.to_torrent(info)).unwrap();
+        assert_eq!(Some(&magnet.trackers[0]), announce.as_ref());
+        assert_eq!(
+            <[u8; 20]>::from(Sha1::digest(info)),
+            metainfo.info_hash().unwrap()
+        );
+    }
+}
@@ -1,19 +1,40 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bittorrent_starter_rust::{
+    blocklist::IpFilter,
+    config::{ClientConfig, CONFIG_ENV},
+    create::TorrentBuilder,
+    daemon::{self, ApiEndpoint, ApiServer},
+    dht::node::{Dht, DhtConfig},
     handshake::{self},
-    peer::client::{Downloader, PeerClient},
+    hashpool::HashPool,
+    lsd::{Lsd, LsdConfig},
+    peer::{
+        client::{Downloader, PeerClient, LSD_PEER_WAIT},
+        picker::{FileSelection, PickStrategy},
+    },
+    progress::{ProgressBar, ProgressReceiver},
+    session::{Session, SessionConfig},
     torrent::{from_file, FileType},
-    tracker::{self, Compact},
+    tracker,
+    transport::Transport,
     util,
+    utp::socket::UtpSocket,
 };
 use clap::Parser;
 use reqwest::Client;
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use serde_json::{json, Value};
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
 mod cli;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = cli::Cli::parse();
+    // Every piece hashed by the command, downloaded or created, goes through the same threads
+    let hasher = HashPool::default();
     match cli.commands {
         cli::Commands::Decode { bencoded_value } => {
             let decoded = util::decode_bencoded_value(&bencoded_value)?;
@@ -29,40 +50,59 @@ async fn main() -> Result<()> {
                     panic!("Expected single type files only!")
                 }
             };
-            let info_hash = info.info_hash().context("Failed to calculate info hash!")?;
+            let info_hash = info
+                .handshake_info_hash()
+                .context("Failed to calculate info hash!")?;
             let piece_length = info.piece_length();
             let pieces = info.pieces();
-            println!("Tracker URL: {}", url);
+            if let Some(url) = url {
+                println!("Tracker URL: {}", url);
+            }
             println!("Length: {}", length);
             println!("Info Hash: {}", hex::encode(info_hash));
+            if let Some(info_hash_v2) = info.info_hash_v2()? {
+                println!("Info Hash v2: {}", hex::encode(info_hash_v2));
+            }
             println!("Piece Length: {}", piece_length);
             println!("Piece Hashes:");
             for piece in pieces {
                 println!("{}", hex::encode(piece));
             }
         }
-        cli::Commands::Peers { torrent_file } => {
-            let client = Client::new();
+        cli::Commands::Peers {
+            torrent_file,
+            dht,
+            proxy,
+        } => {
+            let config = load_config(cli.config.as_deref())?;
+            let client = http_client(&proxy)?;
             let (url, info) =
                 from_file(torrent_file).context("Failed to parse metainfo from file")?;
-            let left_length = {
-                match info.file_type() {
-                    FileType::SingleFile(length) => *length,
-                    FileType::MultiFile(_) => {
-                        todo!("Multifile torrent support has not been implemented yet!")
+            let left_length = info.total_length();
+            let info_hash = info.handshake_info_hash()?;
+            let mut peers = vec![];
+            if let Some(url) = &url {
+                peers = tracker::discover_peers(
+                    &client,
+                    &info_hash,
+                    url.clone(),
+                    config.listen_port,
+                    config.compact(),
+                    &config.peer_id(),
+                    (0, 0, left_length),
+                )
+                .await?;
+            }
+            if let Some(dht) =
+                start_dht(&dht, &config, &proxy, url.is_none(), info.dht_nodes()).await?
+            {
+                for peer in dht.lookup_peers(info_hash).await {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
                     }
                 }
-            };
-            let peers = tracker::discover_peers(
-                &client,
-                &info.info_hash()?,
-                url,
-                6881,
-                Compact::Compact,
-                b"00112233445566778899",
-                (0, 0, left_length),
-            )
-            .await?;
+                dht.save()?;
+            }
             for peer in peers {
                 println!("{}", peer);
             }
@@ -70,22 +110,55 @@ async fn main() -> Result<()> {
         cli::Commands::Handshake {
             torrent_file,
             peer_addr,
+            proxy,
         } => {
+            let config = load_config(cli.config.as_deref())?;
             let (_, info) = from_file(torrent_file)?;
-            let (_, peer_id) =
-                handshake::connect(peer_addr, &info.info_hash()?, b"00112233445566778899").await?;
+            let (_, peer_id) = handshake::connect(
+                peer_addr.into(),
+                &info.handshake_info_hash()?,
+                &config.peer_id(),
+                &transport(false, &proxy).await?,
+            )
+            .await?;
             println!("Peer ID: {}", hex::encode(peer_id));
         }
         cli::Commands::DownloadPiece {
             torrent_file,
             piece_num,
             out_file,
+            dht,
+            lsd,
+            encryption,
+            utp,
+            proxy,
         } => {
-            let client = Client::new();
-            let peer_id = b"00112233445566778899";
-            let mut downloader =
-                Downloader::new(&client, 6881, torrent_file, peer_id, Compact::Compact).await?;
-            let bytes = downloader.download_piece(piece_num).await?;
+            let mut config = load_config(cli.config.as_deref())?;
+            config.extensions.lsd |= lsd;
+            config.extensions.utp |= utp;
+            if let Some(encryption) = encryption {
+                config.extensions.encryption = encryption;
+            }
+            let client = http_client(&proxy)?;
+            let mut downloader = Downloader::new(&client, torrent_file, &config.peer_id(), &config)
+                .await?
+                .with_transport(transport(config.extensions.utp, &proxy).await?)
+                .with_hasher(hasher);
+            let trackerless = downloader.peers().is_empty();
+            let nodes = downloader.metainfo().dht_nodes().clone();
+            if let Some(dht) = start_dht(&dht, &config, &proxy, trackerless, &nodes).await? {
+                downloader.add_dht_peers(&dht).await;
+                dht.save()?;
+            }
+            let lsd = start_lsd(config.extensions.lsd, &proxy).await?;
+            if let Some(lsd) = &lsd {
+                downloader.add_lsd(lsd, LSD_PEER_WAIT).await;
+            }
+            downloader.download_piece(piece_num).await?;
+            let bytes = downloader
+                .storage()
+                .read_piece(piece_num)?
+                .context("The piece downloaded was not stored")?;
             let mut file = OpenOptions::new()
                 .write(true)
                 .read(true)
@@ -97,16 +170,328 @@ async fn main() -> Result<()> {
         cli::Commands::Download {
             torrent_file,
             out_file,
+            only,
+            priority,
+            sequential,
+            deadline_window,
+            json_events,
+            rates,
+            blocklist,
+            dht,
+            lsd,
+            encryption,
+            utp,
+            proxy,
         } => {
-            let peer_id = b"00112233445566778899";
-            let client = PeerClient::new(Client::new(), *peer_id);
-            client.download(&torrent_file, &out_file).await?;
+            let mut config = load_config(cli.config.as_deref())?;
+            rates.apply(&mut config);
+            config.extensions.lsd |= lsd;
+            config.extensions.utp |= utp;
+            if let Some(encryption) = encryption {
+                config.extensions.encryption = encryption;
+            }
+            let (url, info) = from_file(&torrent_file)?;
+            let mut selection = FileSelection::only(&info, &only)?;
+            for assignment in &priority {
+                selection.assign(&info, assignment)?;
+            }
+            let mut client = PeerClient::new(http_client(&proxy)?, config.peer_id())
+                .with_config(config.clone())
+                .with_selection(selection)
+                .with_strategy(if sequential {
+                    PickStrategy::Sequential
+                } else {
+                    PickStrategy::Priority
+                })
+                .with_deadline_window(deadline_window)
+                .with_ip_filter(IpFilter::load(&blocklist)?)
+                .with_transport(transport(config.extensions.utp, &proxy).await?)
+                .with_hasher(hasher);
+            let dht = start_dht(&dht, &config, &proxy, url.is_none(), info.dht_nodes()).await?;
+            if let Some(dht) = &dht {
+                client = client.with_dht(dht.clone());
+            }
+            if let Some(lsd) = start_lsd(config.extensions.lsd, &proxy).await? {
+                client = client.with_lsd(lsd);
+            }
+            let (events, receiver) = mpsc::unbounded_channel();
+            let reporter = tokio::spawn(report_progress(receiver, json_events));
+            let result = client
+                .with_progress(events)
+                .download(&torrent_file, &out_file)
+                .await;
+            // Every sender is gone with the client, which ends the reporter
+            reporter.await??;
+            result?;
+            if let Some(dht) = dht {
+                dht.save()?;
+            }
+            if json_events {
+                return Ok(());
+            }
             println!(
                 "Downloaded {} to {}",
                 torrent_file.file_name().unwrap().to_str().unwrap(),
                 out_file.file_name().unwrap().to_str().unwrap()
             );
         }
+        cli::Commands::Create {
+            path,
+            out_file,
+            announce,
+            piece_length,
+            comment,
+            private,
+        } => {
+            let mut builder = TorrentBuilder::new(path)
+                .with_private(private)
+                .with_hasher(hasher);
+            for url in announce {
+                builder = builder.with_announce(url);
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.with_piece_length(piece_length);
+            }
+            if let Some(comment) = comment {
+                builder = builder.with_comment(comment);
+            }
+            let (bytes, info) = tokio::task::spawn_blocking(move || builder.build()).await??;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&out_file)
+                .await?;
+            file.write_all(&bytes).await?;
+            println!("Created {}", out_file.display());
+            println!("Info Hash: {}", hex::encode(info.info_hash()?));
+        }
+        cli::Commands::Daemon {
+            api,
+            download_dir,
+            port,
+            rates,
+            blocklist,
+            dht,
+            lsd,
+            encryption,
+            proxy,
+        } => {
+            let mut config = load_config(cli.config.as_deref())?;
+            rates.apply(&mut config);
+            config.extensions.lsd |= lsd;
+            if let Some(encryption) = encryption {
+                config.extensions.encryption = encryption;
+            }
+            if let Some(port) = port {
+                config.listen_port = port;
+            }
+            if let Some(download_dir) = download_dir {
+                config.download_dir = download_dir;
+            }
+            let mut client = PeerClient::new(http_client(&proxy)?, config.peer_id())
+                .with_config(config.clone())
+                .with_ip_filter(IpFilter::load(&blocklist)?)
+                .with_transport(transport(false, &proxy).await?)
+                .with_hasher(hasher);
+            let dht = start_dht(&dht, &config, &proxy, false, &[]).await?;
+            if let Some(dht) = &dht {
+                client = client.with_dht(dht.clone());
+            }
+            if let Some(lsd) = start_lsd(config.extensions.lsd, &proxy).await? {
+                client = client.with_lsd(lsd);
+            }
+            let session_config = SessionConfig {
+                listen_addr: ([0, 0, 0, 0], config.listen_port).into(),
+                max_connections: config.max_connections,
+                incoming: !proxy.proxy_only,
+                ..Default::default()
+            };
+            let session = Session::bind(client, session_config).await?;
+            let server = ApiServer::bind(&api).await?;
+            println!("Serving the API on {}", server.endpoint());
+            server.serve(session, config.download_dir).await?;
+        }
+        cli::Commands::Ctl { api, command } => ctl(&api, command).await?,
     };
     Ok(())
 }
+
+/// Sends the command to the daemon and prints its answer
+async fn ctl(api: &ApiEndpoint, command: cli::CtlCommand) -> Result<()> {
+    let (method, path, body) = match &command {
+        cli::CtlCommand::Add { source, out } => {
+            let mut body = serde_json::Map::new();
+            if source.starts_with("magnet:") {
+                body.insert("magnet".to_owned(), json!(source));
+            } else {
+                // The daemon may run from another directory
+                let torrent = tokio::fs::canonicalize(source).await?;
+                body.insert("torrent".to_owned(), json!(torrent));
+            }
+            if let Some(out) = out {
+                let out = std::env::current_dir()?.join(out);
+                body.insert("out".to_owned(), json!(out));
+            }
+            ("POST", "/torrents".to_owned(), Some(Value::Object(body)))
+        }
+        cli::CtlCommand::List => ("GET", "/torrents".to_owned(), None),
+        cli::CtlCommand::Pause { info_hash } => {
+            ("POST", format!("/torrents/{info_hash}/pause"), None)
+        }
+        cli::CtlCommand::Resume { info_hash } => {
+            ("POST", format!("/torrents/{info_hash}/resume"), None)
+        }
+        cli::CtlCommand::Remove { info_hash } => ("DELETE", format!("/torrents/{info_hash}"), None),
+        cli::CtlCommand::Peers { info_hash } => {
+            ("GET", format!("/torrents/{info_hash}/peers"), None)
+        }
+        cli::CtlCommand::Stats { info_hash } => ("GET", format!("/torrents/{info_hash}"), None),
+        cli::CtlCommand::ReloadBlocklist => ("POST", "/blocklist/reload".to_owned(), None),
+        cli::CtlCommand::Limit { info_hash, rates } => {
+            let path = match info_hash {
+                Some(info_hash) => format!("/torrents/{info_hash}/limits"),
+                None => "/limits".to_owned(),
+            };
+            let body = json!({
+                "download": rates.max_download_rate.unwrap_or(0),
+                "upload": rates.max_upload_rate.unwrap_or(0),
+            });
+            ("POST", path, Some(body))
+        }
+    };
+    let (status, response) = daemon::request(api, method, &path, body.as_ref())
+        .await
+        .with_context(|| format!("Failed to reach the daemon at {api}"))?;
+    if status != 200 {
+        bail!("{}", response["error"].as_str().unwrap_or("Request failed"));
+    }
+    match command {
+        cli::CtlCommand::Add { .. } => println!("Info Hash: {}", response["info_hash"]),
+        cli::CtlCommand::List => {
+            for torrent in response.as_array().into_iter().flatten() {
+                println!(
+                    "{} {} {}/{} {}",
+                    torrent["info_hash"].as_str().unwrap_or_default(),
+                    torrent["state"].as_str().unwrap_or_default(),
+                    torrent["pieces_done"],
+                    torrent["num_pieces"],
+                    torrent["name"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        cli::CtlCommand::Peers { .. } => {
+            for peer in response.as_array().into_iter().flatten() {
+                println!(
+                    "{} {} {} {}/{} failed{}",
+                    peer["addr"].as_str().unwrap_or_default(),
+                    peer["source"].as_str().unwrap_or_default(),
+                    peer["client"].as_str().unwrap_or("unknown"),
+                    peer["failures"],
+                    peer["attempts"],
+                    if peer["banned"].as_bool().unwrap_or_default() {
+                        " banned"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        cli::CtlCommand::Stats { .. } => println!("{}", serde_json::to_string_pretty(&response)?),
+        cli::CtlCommand::ReloadBlocklist => println!("Blocking {} ranges", response["ranges"]),
+        cli::CtlCommand::Pause { .. }
+        | cli::CtlCommand::Resume { .. }
+        | cli::CtlCommand::Remove { .. }
+        | cli::CtlCommand::Limit { .. } => {}
+    }
+    Ok(())
+}
+
+/// Prints the events of a download as they come, either as JSON lines or as a progress bar
+/// redrawn in place. The bar is left out when stderr is not a terminal
+async fn report_progress(mut events: ProgressReceiver, json: bool) -> Result<()> {
+    let draw = !json && std::io::stderr().is_terminal();
+    let mut bar = ProgressBar::default();
+    while let Some(event) = events.recv().await {
+        if json {
+            println!("{}", serde_json::to_string(&event)?);
+        } else if draw {
+            bar.update(&event);
+            eprint!("\r{}", bar.render());
+        }
+    }
+    if draw {
+        eprintln!();
+    }
+    Ok(())
+}
+
+/// Reads the config file named by the flag or the environment, then lets environment
+/// variables override its settings
+fn load_config(path: Option<&Path>) -> Result<ClientConfig> {
+    let path = path
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+    let config = match path {
+        Some(path) => ClientConfig::load(&path)
+            .with_context(|| format!("Failed to read the config {}", path.display()))?,
+        None => ClientConfig::default(),
+    };
+    Ok(config.with_env(std::env::vars())?)
+}
+
+/// Joins the DHT when it was asked for or when there is no tracker to get peers from. In
+/// proxy-only mode the DHT is left off since its UDP traffic would bypass the proxy
+async fn start_dht(
+    args: &cli::DhtArgs,
+    config: &ClientConfig,
+    proxy: &cli::ProxyArgs,
+    trackerless: bool,
+    torrent_nodes: &[String],
+) -> Result<Option<Dht>> {
+    let enabled = args.dht || config.extensions.dht;
+    if proxy.proxy_only || (!enabled && !trackerless) {
+        return Ok(None);
+    }
+    let listen_port = config.listen_port;
+    let mut dht_config = DhtConfig {
+        state_file: args.dht_state.clone(),
+        ..Default::default()
+    };
+    if !args.dht_bootstrap.is_empty() {
+        dht_config.bootstrap_nodes = args.dht_bootstrap.clone();
+    }
+    dht_config
+        .bootstrap_nodes
+        .extend(torrent_nodes.iter().cloned());
+    let dht = Dht::bind(("0.0.0.0", listen_port), dht_config).await?;
+    dht.bootstrap().await?;
+    Ok(Some(dht))
+}
+
+/// Joins the LSD multicast group when it was asked for, except in proxy-only mode where its
+/// multicast traffic would bypass the proxy
+async fn start_lsd(enabled: bool, proxy: &cli::ProxyArgs) -> Result<Option<Lsd>> {
+    if !enabled || proxy.proxy_only {
+        return Ok(None);
+    }
+    Ok(Some(Lsd::bind(LsdConfig::default()).await?))
+}
+
+/// uTP connections go out of a socket of their own since the DHT already uses the listen port. With a
+/// proxy every connection is TCP through it
+async fn transport(utp: bool, proxy: &cli::ProxyArgs) -> Result<Transport> {
+    match (&proxy.proxy, utp) {
+        (Some(_), true) => bail!("uTP can't go through a proxy"),
+        (Some(proxy), false) => Ok(Transport::Proxy(proxy.clone())),
+        (None, true) => Ok(Transport::Utp(UtpSocket::bind("0.0.0.0:0").await?)),
+        (None, false) => Ok(Transport::Tcp),
+    }
+}
+
+/// The client tracker announces are made with, through the proxy when there is one
+fn http_client(proxy: &cli::ProxyArgs) -> Result<Client> {
+    match &proxy.proxy {
+        Some(proxy) => proxy.http_client(),
+        None => Ok(Client::new()),
+    }
+}
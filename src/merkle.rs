@@ -0,0 +1,163 @@
+use sha2::{Digest, Sha256};
+
+/// Size of the leaves of a BitTorrent v2 merkle tree. Each leaf is the SHA-256 hash of a 16 KiB
+/// block of a file
+pub const MERKLE_BLOCK_SIZE: usize = 1 << 14;
+pub const HASH_256_SIZE: usize = 32;
+
+pub type Hash256 = [u8; HASH_256_SIZE];
+
+/// Hash used for the leaves past the end of a file when padding a tree to a power of two width
+pub const ZERO_HASH: Hash256 = [0u8; HASH_256_SIZE];
+
+#[inline]
+pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    <Hash256>::from(hasher.finalize())
+}
+
+/// Hashes every 16 KiB block of `data`. The last block is hashed as is when it is shorter than a
+/// full block
+pub fn block_hashes(data: &[u8]) -> Vec<Hash256> {
+    data.chunks(MERKLE_BLOCK_SIZE)
+        .map(|block| <Hash256>::from(Sha256::digest(block)))
+        .collect()
+}
+
+/// Root of a tree of `width` leaves which are all [`ZERO_HASH`]. This is the hash that pads the
+/// piece layer of a file past its last piece
+pub fn pad_hash(width: usize) -> Hash256 {
+    let mut hash = ZERO_HASH;
+    let mut width = width.max(1);
+    while width > 1 {
+        hash = hash_pair(&hash, &hash);
+        width >>= 1;
+    }
+    hash
+}
+
+/// Computes the root of a tree whose leaves are `leaves` followed by `pad` up to `width` leaves
+///
+/// `width` is rounded up to a power of two that fits every leaf
+pub fn root(leaves: &[Hash256], width: usize, pad: Hash256) -> Hash256 {
+    MerkleTree::new(leaves, width, pad).root()
+}
+
+/// A complete binary hash tree kept layer by layer, starting from the leaves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    layers: Vec<Vec<Hash256>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: &[Hash256], width: usize, pad: Hash256) -> Self {
+        let width = width.max(leaves.len()).max(1).next_power_of_two();
+        let mut layer = leaves.to_vec();
+        layer.resize(width, pad);
+        let mut layers = vec![layer];
+        while layers.last().map(Vec::len).unwrap_or_default() > 1 {
+            let next = layers
+                .last()
+                .expect("There is always at least one layer")
+                .chunks_exact(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+            layers.push(next);
+        }
+        Self { layers }
+    }
+
+    pub fn root(&self) -> Hash256 {
+        self.layers
+            .last()
+            .and_then(|layer| layer.first())
+            .copied()
+            .expect("There is always a root")
+    }
+
+    pub fn leaves(&self) -> &[Hash256] {
+        &self.layers[0]
+    }
+
+    /// The sibling hashes from the leaf at `index` up to (but not including) the root
+    pub fn proof(&self, index: usize) -> Option<Vec<Hash256>> {
+        if index >= self.leaves().len() {
+            return None;
+        }
+        let mut index = index;
+        let mut proof = Vec::new();
+        for layer in &self.layers[..self.layers.len() - 1] {
+            proof.push(layer[index ^ 1]);
+            index >>= 1;
+        }
+        Some(proof)
+    }
+}
+
+/// Checks that `leaf` sits at `index` of the tree with the given `root` using the sibling hashes
+/// of [`MerkleTree::proof`]
+pub fn verify_proof(leaf: &Hash256, index: usize, proof: &[Hash256], root: &Hash256) -> bool {
+    let mut index = index;
+    let mut hash = *leaf;
+    for sibling in proof {
+        hash = if index & 1 == 0 {
+            hash_pair(&hash, sibling)
+        } else {
+            hash_pair(sibling, &hash)
+        };
+        index >>= 1;
+    }
+    index == 0 && hash == *root
+}
+
+/// Checks a single 16 KiB `block` at `block_index` of a piece against the piece's layer hash
+pub fn verify_block(
+    block: &[u8],
+    block_index: usize,
+    proof: &[Hash256],
+    piece_hash: &Hash256,
+) -> bool {
+    let leaf = <Hash256>::from(Sha256::digest(block));
+    verify_proof(&leaf, block_index, proof, piece_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::{
+        block_hashes, hash_pair, pad_hash, root, verify_block, MerkleTree, MERKLE_BLOCK_SIZE,
+        ZERO_HASH,
+    };
+
+    #[test]
+    fn test_root_padding() {
+        let a = <[u8; 32]>::from(Sha256::digest(b"a"));
+        let b = <[u8; 32]>::from(Sha256::digest(b"b"));
+        assert_eq!(a, root(&[a], 1, ZERO_HASH));
+        assert_eq!(hash_pair(&a, &b), root(&[a, b], 2, ZERO_HASH));
+        assert_eq!(
+            hash_pair(&hash_pair(&a, &b), &hash_pair(&ZERO_HASH, &ZERO_HASH)),
+            root(&[a, b], 4, ZERO_HASH)
+        );
+        assert_eq!(hash_pair(&ZERO_HASH, &ZERO_HASH), pad_hash(2));
+        assert_eq!(root(&[], 8, ZERO_HASH), pad_hash(8));
+    }
+
+    #[test]
+    fn test_block_proofs() {
+        let data = (0..MERKLE_BLOCK_SIZE * 5)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let leaves = block_hashes(&data);
+        let tree = MerkleTree::new(&leaves, 8, ZERO_HASH);
+        for (idx, block) in data.chunks(MERKLE_BLOCK_SIZE).enumerate() {
+            let proof = tree.proof(idx).unwrap();
+            assert!(verify_block(block, idx, &proof, &tree.root()));
+            assert!(!verify_block(block, idx ^ 1, &proof, &tree.root()));
+        }
+        assert!(tree.proof(8).is_none());
+    }
+}
@@ -0,0 +1,557 @@
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use num_bigint::BigUint;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{handshake::PROTOCOL_STRING, INFO_HASH_SIZE};
+
+/// The 768 bit safe prime the Diffie-Hellman exchange of MSE is done in
+const DH_PRIME: [u8; DH_KEY_SIZE] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const DH_GENERATOR: u32 = 2;
+/// Size of the public keys and of the shared secret
+pub const DH_KEY_SIZE: usize = 96;
+/// Size of the private keys, which only need to be 160 bits
+const DH_PRIVATE_KEY_SIZE: usize = 20;
+/// Largest number of random padding bytes in any step of the handshake
+pub const MAX_PAD_SIZE: usize = 512;
+/// Bytes of the RC4 keystream thrown away before encrypting anything
+const RC4_DISCARD: usize = 1024;
+/// Verification constant, 8 zero bytes sent encrypted to find the start of the encrypted stream
+const VC: [u8; 8] = [0; 8];
+
+/// `crypto_provide` and `crypto_select` bit asking for no encryption after the handshake
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+/// `crypto_provide` and `crypto_select` bit asking for RC4 encryption of the whole connection
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections use Message Stream Encryption
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    /// Only encrypted connections: plaintext peers are refused
+    Require,
+    /// Encrypted connections, falling back to plaintext for peers without MSE
+    Prefer,
+    /// Never encrypt, which is what the plain BitTorrent handshake does
+    #[default]
+    Plaintext,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "require" => Ok(EncryptionPolicy::Require),
+            "prefer" => Ok(EncryptionPolicy::Prefer),
+            "plaintext" => Ok(EncryptionPolicy::Plaintext),
+            _ => Err(format!(
+                "Unknown encryption policy {s}, expected require, prefer or plaintext"
+            )),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    /// The `crypto_provide` bits an outgoing connection offers
+    fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            EncryptionPolicy::Prefer | EncryptionPolicy::Plaintext => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /// The method an incoming connection gets out of the methods the peer offered
+    fn crypto_select(&self, provide: u32) -> Option<u32> {
+        if provide & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if provide & CRYPTO_PLAINTEXT != 0 && *self != EncryptionPolicy::Require {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+/// The RC4 stream cipher, which MSE uses with the first 1024 bytes of keystream discarded
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rc4").finish_non_exhaustive()
+    }
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (idx, byte) in state.iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, usize::from(j));
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// The cipher MSE uses: RC4 keyed with SHA-1 of `label`, the shared secret and the info hash
+    fn mse(label: &[u8], secret: &[u8], info_hash: &[u8; INFO_HASH_SIZE]) -> Self {
+        let key = sha1_of(&[label, secret, info_hash]);
+        let mut cipher = Self::new(&key);
+        cipher.apply(&mut [0u8; RC4_DISCARD]);
+        cipher
+    }
+
+    /// Encrypts or decrypts `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[usize::from(self.i)]);
+            self.state.swap(usize::from(self.i), usize::from(self.j));
+            let idx = self.state[usize::from(self.i)].wrapping_add(self.state[usize::from(self.j)]);
+            *byte ^= self.state[usize::from(idx)];
+        }
+    }
+}
+
+fn sha1_of(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// One side of the Diffie-Hellman exchange
+struct DhKey {
+    private: BigUint,
+    public: [u8; DH_KEY_SIZE],
+}
+
+impl DhKey {
+    fn generate() -> Self {
+        let private =
+            BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; DH_PRIVATE_KEY_SIZE]>());
+        let public =
+            BigUint::from(DH_GENERATOR).modpow(&private, &BigUint::from_bytes_be(&DH_PRIME));
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8; DH_KEY_SIZE]) -> [u8; DH_KEY_SIZE] {
+        let remote = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote.modpow(&self.private, &BigUint::from_bytes_be(&DH_PRIME)))
+    }
+}
+
+/// Big endian bytes left padded with zeroes to the size of a key
+fn to_key_bytes(value: &BigUint) -> [u8; DH_KEY_SIZE] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; DH_KEY_SIZE];
+    key[DH_KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_SIZE);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// A connection after the MSE handshake, encrypted with RC4 or left as is when plaintext was
+/// selected
+///
+/// Bytes the handshake read past its end (the initial payload of an incoming connection, or the
+/// beginning of a plaintext BitTorrent handshake) are handed out before anything else is read.
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Plaintext bytes already read off `inner`
+    pending_read: Vec<u8>,
+    /// Encrypted bytes not yet written to `inner`
+    pending_write: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    /// A stream passing everything through untouched
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            read_cipher: None,
+            write_cipher: None,
+            pending_read: Vec::new(),
+            pending_write: Vec::new(),
+        }
+    }
+
+    /// Whether the stream is encrypted with RC4
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.pending_write.is_empty() {
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.pending_write) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(written)) => written,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending_write.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending_read.is_empty() {
+            let len = this.pending_read.len().min(buf.remaining());
+            buf.put_slice(&this.pending_read[..len]);
+            this.pending_read.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(cipher)) = (&poll, &mut this.read_cipher) {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        // The keystream has moved past whatever was encrypted, so it is buffered until written
+        if this.poll_write_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let mut encrypted = data.to_vec();
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut encrypted);
+        }
+        this.pending_write = encrypted;
+        let _ = this.poll_write_pending(cx)?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.poll_write_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.poll_write_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads byte by byte until the last bytes read equal `marker`, giving up once more than
+/// `MAX_PAD_SIZE` bytes came before it
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> Result<(), MseError> {
+    let mut window = Vec::with_capacity(MAX_PAD_SIZE + marker.len());
+    while window.len() < MAX_PAD_SIZE + marker.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    Err(MseError::Protocol(
+        "Could not find the start of the encrypted stream".to_owned(),
+    ))
+}
+
+async fn read_decrypted<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    len: usize,
+) -> Result<Vec<u8>, MseError> {
+    let mut bytes = vec![0u8; len];
+    stream.read_exact(&mut bytes).await?;
+    cipher.apply(&mut bytes);
+    Ok(bytes)
+}
+
+/// Runs the MSE handshake as the side opening the connection to a peer of the torrent with
+/// `info_hash`. A peer selecting plaintext gets a stream that isn't encrypted
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8; INFO_HASH_SIZE],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, MseError> {
+    let key = DhKey::generate();
+    let mut message = key.public.to_vec();
+    message.extend(random_pad());
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let mut remote_public = [0u8; DH_KEY_SIZE];
+    stream.read_exact(&mut remote_public).await?;
+    let secret = key.shared_secret(&remote_public);
+    let mut encrypt = Rc4::mse(b"keyA", &secret, info_hash);
+    let mut decrypt = Rc4::mse(b"keyB", &secret, info_hash);
+
+    let provide = policy.crypto_provide();
+    let mut message = sha1_of(&[b"req1", &secret]).to_vec();
+    let req2 = sha1_of(&[b"req2", info_hash]);
+    let req3 = sha1_of(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&provide.to_be_bytes());
+    // No padding and no initial payload: the BitTorrent handshake follows over the stream
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    // The peer's padding is skipped by looking for the verification constant it encrypted
+    let mut marker = VC;
+    decrypt.clone().apply(&mut marker);
+    synchronize(&mut stream, &marker).await?;
+    decrypt.apply(&mut VC.clone());
+    let select = read_decrypted(&mut stream, &mut decrypt, 4).await?;
+    let select = u32::from_be_bytes(select.try_into().expect("Read 4 bytes"));
+    let pad_len = read_decrypted(&mut stream, &mut decrypt, 2).await?;
+    let pad_len = usize::from(u16::from_be_bytes([pad_len[0], pad_len[1]]));
+    if pad_len > MAX_PAD_SIZE {
+        return Err(MseError::Protocol(format!("Padding of {pad_len} bytes")));
+    }
+    read_decrypted(&mut stream, &mut decrypt, pad_len).await?;
+
+    let mut mse = MseStream::plaintext(stream);
+    match select {
+        CRYPTO_RC4 => {
+            mse.read_cipher = Some(decrypt);
+            mse.write_cipher = Some(encrypt);
+        }
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => {}
+        _ => return Err(MseError::NoCommonMethod),
+    }
+    Ok(mse)
+}
+
+/// Runs the MSE handshake as the side accepting a connection, for a peer wanting any of the
+/// torrents in `info_hashes`. Returns the stream along with the info hash the peer asked for
+///
+/// A peer starting with a plaintext BitTorrent handshake is let through as is unless `policy`
+/// requires encryption, in which case no info hash is known yet.
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[[u8; INFO_HASH_SIZE]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, Option<[u8; INFO_HASH_SIZE]>), MseError> {
+    let mut remote_public = [0u8; DH_KEY_SIZE];
+    let prefix_len = PROTOCOL_STRING.len() + 1;
+    stream.read_exact(&mut remote_public[..prefix_len]).await?;
+    if remote_public[0] == PROTOCOL_STRING.len() as u8
+        && remote_public[1..prefix_len] == PROTOCOL_STRING
+    {
+        if policy == EncryptionPolicy::Require {
+            return Err(MseError::NoCommonMethod);
+        }
+        let mut mse = MseStream::plaintext(stream);
+        mse.pending_read = remote_public[..prefix_len].to_vec();
+        return Ok((mse, None));
+    }
+    if policy == EncryptionPolicy::Plaintext {
+        return Err(MseError::NoCommonMethod);
+    }
+    stream.read_exact(&mut remote_public[prefix_len..]).await?;
+
+    let key = DhKey::generate();
+    let mut message = key.public.to_vec();
+    message.extend(random_pad());
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+    let secret = key.shared_secret(&remote_public);
+
+    synchronize(&mut stream, &sha1_of(&[b"req1", &secret])).await?;
+    let mut hashes = [0u8; 20];
+    stream.read_exact(&mut hashes).await?;
+    let req3 = sha1_of(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| {
+            let req2 = sha1_of(&[b"req2", info_hash.as_slice()]);
+            req2.iter().zip(req3).map(|(a, b)| a ^ b).eq(hashes)
+        })
+        .ok_or(MseError::UnknownTorrent)?;
+    let mut decrypt = Rc4::mse(b"keyA", &secret, &info_hash);
+    let mut encrypt = Rc4::mse(b"keyB", &secret, &info_hash);
+
+    let header = read_decrypted(&mut stream, &mut decrypt, VC.len() + 4 + 2).await?;
+    if header[..VC.len()] != VC {
+        return Err(MseError::Protocol(
+            "Verification constant did not match".to_owned(),
+        ));
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().expect("Sliced 4 bytes"));
+    let pad_len = usize::from(u16::from_be_bytes([header[12], header[13]]));
+    if pad_len > MAX_PAD_SIZE {
+        return Err(MseError::Protocol(format!("Padding of {pad_len} bytes")));
+    }
+    read_decrypted(&mut stream, &mut decrypt, pad_len).await?;
+    let initial_len = read_decrypted(&mut stream, &mut decrypt, 2).await?;
+    let initial_len = usize::from(u16::from_be_bytes([initial_len[0], initial_len[1]]));
+    let initial_payload = read_decrypted(&mut stream, &mut decrypt, initial_len).await?;
+
+    let select = policy
+        .crypto_select(provide)
+        .ok_or(MseError::NoCommonMethod)?;
+    let mut message = VC.to_vec();
+    message.extend_from_slice(&select.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut message);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let mut mse = MseStream::plaintext(stream);
+    mse.pending_read = initial_payload;
+    if select == CRYPTO_RC4 {
+        mse.read_cipher = Some(decrypt);
+        mse.write_cipher = Some(encrypt);
+    }
+    Ok((mse, Some(info_hash)))
+}
+
+/// Error type for Message Stream Encryption
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MseError {
+    #[error("Error during the encryption handshake: {0}")]
+    Io(String),
+    #[error("Invalid encryption handshake: {0}")]
+    Protocol(String),
+    #[error("Peer asked for a torrent we don't have")]
+    UnknownTorrent,
+    #[error("No encryption method acceptable to both sides")]
+    NoCommonMethod,
+}
+
+impl From<std::io::Error> for MseError {
+    fn from(err: std::io::Error) -> Self {
+        MseError::Io(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{initiate, respond, EncryptionPolicy, MseError, Rc4};
+
+    #[test]
+    fn test_rc4_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!("bbf316e8d940af0ad3", hex::encode(data));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_policies() {
+        let info_hash = [0x5a; 20];
+        for (initiator, responder, encrypted) in [
+            (EncryptionPolicy::Require, EncryptionPolicy::Prefer, true),
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Require, true),
+        ] {
+            let (local, remote) = tokio::io::duplex(4096);
+            let accept = tokio::spawn(async move {
+                let (mut stream, found) = respond(remote, &[[1; 20], info_hash], responder)
+                    .await
+                    .unwrap();
+                assert_eq!(Some(info_hash), found);
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.flush().await.unwrap();
+                stream.is_encrypted()
+            });
+            let mut stream = initiate(local, &info_hash, initiator).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"hello", &buf);
+            assert_eq!(encrypted, stream.is_encrypted());
+            assert_eq!(encrypted, accept.await.unwrap());
+        }
+
+        // Plaintext handshakes get through untouched
+        let (mut local, remote) = tokio::io::duplex(4096);
+        local
+            .write_all(b"\x13BitTorrent protocol rest")
+            .await
+            .unwrap();
+        let (mut stream, found) = respond(remote, &[info_hash], EncryptionPolicy::Prefer)
+            .await
+            .unwrap();
+        assert_eq!(None, found);
+        let mut buf = [0u8; 25];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"\x13BitTorrent protocol rest", &buf);
+
+        // A responder that doesn't know the torrent can't decrypt anything
+        let (local, remote) = tokio::io::duplex(4096);
+        let accept = tokio::spawn(async move {
+            respond(remote, &[[1; 20]], EncryptionPolicy::Prefer)
+                .await
+                .map(|_| ())
+        });
+        let _ = initiate(local, &info_hash, EncryptionPolicy::Require).await;
+        assert_eq!(Err(MseError::UnknownTorrent), accept.await.unwrap());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use sha1::{Digest, Sha1};
+
+use super::storage::{block_spans, PieceBuffer, Storage};
+use crate::{torrent::MetaInfo, INFO_HASH_SIZE};
+
+/// Bytes a torrent's cache holds unless configured otherwise
+pub const DEFAULT_CACHE_SIZE: u64 = 32 << 20;
+/// Largest write made out of consecutive pieces when writing back
+const MAX_WRITE_RUN: usize = 4 << 20;
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Blocks not written back yet, by piece so that writing back goes through the files in order
+    dirty: BTreeMap<usize, PieceBuffer>,
+    dirty_bytes: u64,
+    /// Blocks being written back at the moment, older than those of the same piece in `dirty`
+    writing: Option<Arc<BTreeMap<usize, PieceBuffer>>>,
+    /// Whole pieces read for uploads, the least recently used first
+    reads: VecDeque<(usize, Arc<[u8]>)>,
+    read_bytes: u64,
+    /// Counts the writes, so that a piece read while it was written isn't kept
+    writes: u64,
+}
+
+impl CacheState {
+    fn forget_read(&mut self, piece: usize) {
+        if let Some(at) = self.reads.iter().position(|(read, _)| *read == piece) {
+            let (_, bytes) = self.reads.remove(at).expect("Position was just found");
+            self.read_bytes -= bytes.len() as u64;
+        }
+    }
+
+    fn is_buffered(&self, piece: usize) -> bool {
+        self.dirty.contains_key(&piece)
+            || self
+                .writing
+                .as_ref()
+                .is_some_and(|writing| writing.contains_key(&piece))
+    }
+
+    /// Drops read pieces until back under `limit`, telling whether that was enough
+    fn drop_reads(&mut self, limit: u64) -> bool {
+        while self.dirty_bytes + self.read_bytes > limit {
+            let Some((_, bytes)) = self.reads.pop_front() else {
+                return false;
+            };
+            self.read_bytes -= bytes.len() as u64;
+        }
+        true
+    }
+}
+
+/// Sits in front of another storage, putting pieces together in memory so that they are hashed
+/// there and reach the disk in few, sequential writes instead of a write per block
+///
+/// Pieces stay in memory until [`Storage::flush`], or until the cache holds more than its limit,
+/// at which point pieces read for uploads are dropped first and then everything not written yet
+/// is written back. Dropping the cache loses what it still holds, so its owner flushes it once
+/// done with it.
+#[derive(Debug)]
+pub struct CachedStorage {
+    inner: Arc<dyn Storage>,
+    /// Bytes held across written and read pieces
+    limit: u64,
+    state: Mutex<CacheState>,
+    /// Held while writing back, so that write-backs reach the disk in order and reads of what
+    /// they write can wait for them
+    writing: Mutex<()>,
+}
+
+impl CachedStorage {
+    pub fn new(inner: Arc<dyn Storage>, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            state: Mutex::new(CacheState::default()),
+            writing: Mutex::new(()),
+        }
+    }
+
+    #[inline]
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().expect("Poisoned lock")
+    }
+
+    /// Writes every piece in memory to the storage behind, joining runs of complete consecutive
+    /// pieces into one write. The pieces are taken out of the cache first, so that blocks keep
+    /// coming in while the disk is busy, and are put back if writing fails
+    fn write_back(&self) -> io::Result<()> {
+        let _writing = self.writing.lock().expect("Poisoned lock");
+        let dirty = {
+            let mut state = self.state();
+            if state.dirty.is_empty() {
+                return Ok(());
+            }
+            state.dirty_bytes = 0;
+            let dirty = Arc::new(std::mem::take(&mut state.dirty));
+            state.writing = Some(dirty.clone());
+            dirty
+        };
+        let result = self.write_pieces_back(&dirty);
+        let mut state = self.state();
+        state.writing = None;
+        if result.is_err() {
+            let dirty = Arc::try_unwrap(dirty).expect("Only write-backs hold the pieces");
+            for (piece, mut buffer) in dirty {
+                // Blocks written since are newer
+                if let Some(newer) = state.dirty.remove(&piece) {
+                    for (begin, data) in newer.written() {
+                        buffer.write(begin, data);
+                    }
+                } else {
+                    state.dirty_bytes += buffer.bytes().len() as u64;
+                }
+                state.dirty.insert(piece, buffer);
+            }
+        }
+        result
+    }
+
+    fn write_pieces_back(&self, dirty: &BTreeMap<usize, PieceBuffer>) -> io::Result<()> {
+        let mut run: Option<(usize, Vec<u8>)> = None;
+        let mut next = 0;
+        for (piece, buffer) in dirty {
+            let complete = buffer.is_complete();
+            let extends = complete
+                && *piece == next
+                && run
+                    .as_ref()
+                    .is_some_and(|(_, bytes)| bytes.len() + buffer.bytes().len() <= MAX_WRITE_RUN);
+            if !extends {
+                if let Some((first, bytes)) = run.take() {
+                    self.inner.write_pieces(first, &bytes)?;
+                }
+            }
+            if !complete {
+                for (begin, data) in buffer.written() {
+                    self.inner.write_block(*piece, begin as u32, data)?;
+                }
+                continue;
+            }
+            run.get_or_insert_with(|| (*piece, Vec::new()))
+                .1
+                .extend_from_slice(buffer.bytes());
+            next = piece + 1;
+        }
+        if let Some((first, bytes)) = run {
+            self.inner.write_pieces(first, &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Gets back under the limit, dropping read pieces before writing anything back
+    fn relieve(&self, mut state: MutexGuard<'_, CacheState>) -> io::Result<()> {
+        if state.drop_reads(self.limit) {
+            return Ok(());
+        }
+        drop(state);
+        self.write_back()
+    }
+
+    /// Reads a block of a piece only partly in memory from the storage behind, with what is in
+    /// memory laid over it
+    fn read_buffered(&self, piece: usize, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>> {
+        // With no write-back going on, the storage behind has every block not in `dirty`
+        let _writing = self.writing.lock().expect("Poisoned lock");
+        let Some(mut block) = self.inner.read_block(piece, begin, length)? else {
+            return Ok(None);
+        };
+        let (begin, end) = (begin as usize, (begin + length) as usize);
+        if let Some(buffer) = self.state().dirty.get(&piece) {
+            for (start, data) in buffer.written() {
+                let (from, to) = (start.max(begin), (start + data.len()).min(end));
+                if from < to {
+                    block[from - begin..to - begin]
+                        .copy_from_slice(&data[from - start..to - start]);
+                }
+            }
+        }
+        Ok(Some(block))
+    }
+}
+
+impl Storage for CachedStorage {
+    fn metainfo(&self) -> &MetaInfo {
+        self.inner.metainfo()
+    }
+
+    /// Popular pieces are read whole once and served from memory after that
+    fn read_block(&self, piece: usize, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>> {
+        block_spans(self.metainfo(), piece, begin, length as u64)?;
+        let range = begin as usize..(begin + length) as usize;
+        let writes = {
+            let mut state = self.state();
+            if let Some(bytes) = state
+                .dirty
+                .get(&piece)
+                .and_then(|buffer| buffer.read(range.clone()))
+            {
+                return Ok(Some(bytes.to_vec()));
+            }
+            if state.is_buffered(piece) {
+                drop(state);
+                return self.read_buffered(piece, begin, length);
+            }
+            if let Some(at) = state.reads.iter().position(|(read, _)| *read == piece) {
+                let entry = state.reads.remove(at).expect("Position was just found");
+                let bytes = entry.1[range].to_vec();
+                state.reads.push_back(entry);
+                return Ok(Some(bytes));
+            }
+            state.writes
+        };
+        let size = self.metainfo().piece_size(piece).unwrap_or_default();
+        // A piece taking a good part of the cache would push out everything else
+        if size > self.limit / 4 {
+            return self.inner.read_block(piece, begin, length);
+        }
+        let Some(bytes) = self.inner.read_piece(piece)? else {
+            return self.inner.read_block(piece, begin, length);
+        };
+        let block = bytes[range].to_vec();
+        let mut state = self.state();
+        if state.writes == writes {
+            state.read_bytes += bytes.len() as u64;
+            state.reads.push_back((piece, bytes.into()));
+            return self.relieve(state).map(|()| Some(block));
+        }
+        Ok(Some(block))
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> io::Result<()> {
+        block_spans(self.metainfo(), piece, begin, data.len() as u64)?;
+        let size = self.metainfo().piece_size(piece).unwrap_or_default();
+        let mut state = self.state();
+        state.writes += 1;
+        state.forget_read(piece);
+        if !state.dirty.contains_key(&piece) {
+            state.dirty_bytes += size;
+        }
+        state
+            .dirty
+            .entry(piece)
+            .or_insert_with(|| PieceBuffer::new(size as usize))
+            .write(begin as usize, data);
+        self.relieve(state)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.write_back()?;
+        self.inner.flush()
+    }
+
+    /// Complete pieces in memory are hashed there, without touching the disk
+    fn hash_piece(&self, piece: usize) -> io::Result<Option<[u8; INFO_HASH_SIZE]>> {
+        let state = self.state();
+        if let Some(buffer) = state.dirty.get(&piece) {
+            if buffer.is_complete() {
+                return Ok(Some(Sha1::digest(buffer.bytes()).into()));
+            }
+        }
+        if let Some((_, bytes)) = state.reads.iter().find(|(read, _)| *read == piece) {
+            return Ok(Some(Sha1::digest(bytes).into()));
+        }
+        let buffered = state.is_buffered(piece);
+        drop(state);
+        if buffered {
+            return Ok(self
+                .read_piece(piece)?
+                .map(|bytes| Sha1::digest(bytes).into()));
+        }
+        self.inner.hash_piece(piece)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use reqwest::Url;
+    use sha1::{Digest, Sha1};
+
+    use super::CachedStorage;
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::{picker::FileSelection, storage::Storage, writer::FileWriter},
+    };
+
+    #[tokio::test]
+    async fn test_write_back_and_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("image");
+        let data = (0..40_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let out = dir.path().join("out");
+        let writer = Arc::new(
+            FileWriter::create(&metainfo, &FileSelection::all(&metainfo), &out)
+                .await
+                .unwrap(),
+        );
+        let cache = CachedStorage::new(writer.clone(), 1 << 20);
+        let size = MIN_PIECE_LENGTH as usize;
+        for piece in 0..2 {
+            let bytes = &data[piece * size..(piece + 1) * size];
+            cache.write_block(piece, 1000, &bytes[1000..]).unwrap();
+            cache.write_block(piece, 0, &bytes[..1000]).unwrap();
+        }
+        // Nothing reached the file yet, but the pieces are whole in memory
+        assert_eq!(vec![0; 2 * size], fs::read(&out).unwrap()[..2 * size]);
+        assert_eq!(
+            Some(<[u8; 20]>::from(Sha1::digest(&data[size..2 * size]))),
+            cache.hash_piece(1).unwrap()
+        );
+        assert_eq!(
+            Some(data[size + 10..size + 20].to_vec()),
+            cache.read_block(1, 10, 10).unwrap()
+        );
+
+        cache.flush().unwrap();
+        assert_eq!(data[..2 * size], fs::read(&out).unwrap()[..2 * size]);
+
+        // Reads are kept, until the piece is written again
+        assert_eq!(Some(data[..5].to_vec()), cache.read_block(0, 0, 5).unwrap());
+        writer.write_block(0, 0, &[9; 5]).unwrap();
+        assert_eq!(Some(data[..5].to_vec()), cache.read_block(0, 0, 5).unwrap());
+        cache.write_block(0, 0, &[7; 5]).unwrap();
+        assert_eq!(Some(vec![7; 5]), cache.read_block(0, 0, 5).unwrap());
+
+        // A block partly in memory is read from the file for the rest, without writing back
+        assert_eq!(
+            Some([vec![7; 5], data[5..10].to_vec()].concat()),
+            cache.read_block(0, 0, 10).unwrap()
+        );
+        assert_eq!(vec![9; 5], fs::read(&out).unwrap()[..5]);
+        assert_eq!(
+            Some(<[u8; 20]>::from(Sha1::digest(
+                [&[7; 5], &data[5..size]].concat()
+            ))),
+            cache.hash_piece(0).unwrap()
+        );
+
+        cache.flush().unwrap();
+        drop(cache);
+        assert_eq!(vec![7; 5], fs::read(&out).unwrap()[..5]);
+
+        // Past the limit, everything in memory is written back
+        let small = CachedStorage::new(writer, size as u64);
+        small.write_block(2, 0, &[1; 100]).unwrap();
+        assert_eq!(
+            vec![0; 100],
+            fs::read(&out).unwrap()[2 * size..2 * size + 100]
+        );
+        small.write_block(1, 0, &[2; 100]).unwrap();
+        assert_eq!(
+            vec![1; 100],
+            fs::read(&out).unwrap()[2 * size..2 * size + 100]
+        );
+        assert_eq!(vec![2; 100], fs::read(&out).unwrap()[size..size + 100]);
+    }
+}
@@ -1,29 +1,79 @@
 use anyhow::{anyhow, Result};
 use sha1::{Digest, Sha1};
-use std::{mem, net::SocketAddrV4, path::Path};
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use reqwest::Client;
 use thiserror::Error;
+use tokio::{
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+};
 
 use crate::{
-    handshake,
-    peer::message::{PeerBufferStream, PeerMessageId},
-    torrent::{from_file, FileType, MetaInfo},
-    tracker::{discover_peers, Compact},
+    blocklist::IpFilter,
+    config::{ClientConfig, StorageKind},
+    dht::node::Dht,
+    handshake::{self, Handshake},
+    hashpool::HashPool,
+    lsd::Lsd,
+    magnet::Magnet,
+    mse::EncryptionPolicy,
+    peer::{
+        cache::CachedStorage,
+        connection::{self, BlockQueue, PeerContext, ReceivedBlock},
+        manager::ConnectionManager,
+        message::PeerBufferStream,
+        metadata,
+        mmap::MmapStorage,
+        pex::PexState,
+        picker::{FileSelection, PickStrategy, PiecePicker, DEFAULT_DEADLINE_WINDOW},
+        pool::{PeerPool, PeerSource},
+        smartban::{block_hash, SmartBan},
+        storage::{self, MemoryStorage, Storage},
+        stream::{FileStream, PieceTracker},
+        writer::FileWriter,
+    },
+    progress::{eta, ProgressEvent, ProgressSender, RateMeter},
+    ratelimit::{RateLimits, TorrentLimits},
+    resume::{resume_path, ResumeData},
+    torrent::{from_file, MetaInfo},
+    tracker::discover_peers,
+    transport::Transport,
+    webseed::{WebSeed, WebSeedError},
     INFO_HASH_SIZE, PEER_ID_SIZE,
 };
 
-use super::message::PeerMessage;
-
-// NOTE: Currently tightly coupled with the torrent_file
-// TODO: Make it decoupled with torrent_file and users of
-// the client can provide torrent_files to a method?
-#[derive(Debug)]
+/// The settings torrents are downloaded with. [`crate::session::Session`] runs many torrents
+/// with one of these
+#[derive(Debug, Clone)]
 pub struct PeerClient {
     peer_id: [u8; PEER_ID_SIZE],
     client: Client,
-    listener_port: u16,
+    config: ClientConfig,
+    dht: Option<Dht>,
+    lsd: Option<Lsd>,
+    transport: Transport,
+    selection: Option<FileSelection>,
+    strategy: PickStrategy,
+    deadline_window: usize,
+    ip_filter: IpFilter,
+    progress: Option<ProgressSender>,
+    /// Shared by every torrent downloaded with this client and its clones
+    limits: RateLimits,
+    peer_limits: RateLimits,
+    /// Hashes the pieces of every torrent downloaded with this client and its clones
+    hasher: HashPool,
+    /// Connections allowed at once across every torrent downloaded with this client
+    connection_slots: Option<Arc<Semaphore>>,
 }
 
 impl PeerClient {
@@ -31,240 +81,1262 @@ impl PeerClient {
         Self {
             client,
             peer_id,
-            listener_port: 6881,
+            config: ClientConfig::default(),
+            dht: None,
+            lsd: None,
+            transport: Transport::default(),
+            selection: None,
+            strategy: PickStrategy::default(),
+            deadline_window: DEFAULT_DEADLINE_WINDOW,
+            ip_filter: IpFilter::default(),
+            progress: None,
+            limits: RateLimits::default(),
+            peer_limits: RateLimits::default(),
+            hasher: HashPool::default(),
+            connection_slots: None,
         }
     }
+
+    /// Takes the settings of the config, including its rate limits
+    pub fn with_config(self, config: ClientConfig) -> Self {
+        let mut s = self;
+        s.limits = RateLimits::new(config.max_download_rate, config.max_upload_rate);
+        s.config = config;
+        s
+    }
+
+    #[inline]
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Sets the port announced to trackers and the DHT
+    pub fn with_listener_port(self, listener_port: u16) -> Self {
+        let mut s = self;
+        s.config.listen_port = listener_port;
+        s
+    }
+
+    #[inline]
+    pub fn peer_id(&self) -> &[u8; PEER_ID_SIZE] {
+        &self.peer_id
+    }
+
+    #[inline]
+    pub fn encryption(&self) -> EncryptionPolicy {
+        self.config.extensions.encryption
+    }
+
+    /// Uses the DHT node as an additional source of peers
+    pub fn with_dht(self, dht: Dht) -> Self {
+        let mut s = self;
+        s.dht = Some(dht);
+        s
+    }
+
+    /// Hashes pieces on `hasher`, which may be shared with other work such as creating torrents
+    pub fn with_hasher(self, hasher: HashPool) -> Self {
+        let mut s = self;
+        s.hasher = hasher;
+        s
+    }
+
+    #[inline]
+    pub fn hasher(&self) -> &HashPool {
+        &self.hasher
+    }
+
+    /// Counts the peer connections of every torrent against `slots`, which whatever accepts
+    /// inbound connections may share
+    pub fn with_connection_slots(self, slots: Arc<Semaphore>) -> Self {
+        let mut s = self;
+        s.connection_slots = Some(slots);
+        s
+    }
+
+    /// Looks for peers on the local network through Local Service Discovery
+    pub fn with_lsd(self, lsd: Lsd) -> Self {
+        let mut s = self;
+        s.lsd = Some(lsd);
+        s
+    }
+
+    /// Sets whether peer connections use Message Stream Encryption
+    pub fn with_encryption(self, encryption: EncryptionPolicy) -> Self {
+        let mut s = self;
+        s.config.extensions.encryption = encryption;
+        s
+    }
+
+    /// Sets how peers are connected to
+    pub fn with_transport(self, transport: Transport) -> Self {
+        let mut s = self;
+        s.transport = transport;
+        s
+    }
+
+    /// Only downloads the files selected, in the order of their priorities
+    pub fn with_selection(self, selection: FileSelection) -> Self {
+        let mut s = self;
+        s.selection = Some(selection);
+        s
+    }
+
+    /// Sets the order pieces are downloaded in
+    pub fn with_strategy(self, strategy: PickStrategy) -> Self {
+        let mut s = self;
+        s.strategy = strategy;
+        s
+    }
+
+    /// Sets how many pieces after a reader's position are fetched before anything else
+    pub fn with_deadline_window(self, deadline_window: usize) -> Self {
+        let mut s = self;
+        s.deadline_window = deadline_window;
+        s
+    }
+
+    /// Refuses the peers the filter blocks, wherever they were learned from
+    pub fn with_ip_filter(self, ip_filter: IpFilter) -> Self {
+        let mut s = self;
+        s.ip_filter = ip_filter;
+        s
+    }
+
+    #[inline]
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+
+    /// Sets how many peers each torrent downloads from at once
+    pub fn with_connections_per_torrent(self, connections_per_torrent: usize) -> Self {
+        let mut s = self;
+        s.config.connections_per_torrent = connections_per_torrent;
+        s
+    }
+
+    /// Caps the bytes per second of every torrent together, 0 meaning unlimited
+    pub fn with_rate_limits(self, download_rate: u64, upload_rate: u64) -> Self {
+        let mut s = self;
+        s.limits = RateLimits::new(download_rate, upload_rate);
+        s
+    }
+
+    /// Caps the bytes per second of each peer connection, 0 meaning unlimited
+    pub fn with_peer_rate_limits(self, download_rate: u64, upload_rate: u64) -> Self {
+        let mut s = self;
+        s.peer_limits = RateLimits::new(download_rate, upload_rate);
+        s
+    }
+
+    /// The limits shared by every torrent, which can be changed while they run
+    #[inline]
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// The limits each peer connection gets, which apply to connections made after a change
+    #[inline]
+    pub fn peer_rate_limits(&self) -> &RateLimits {
+        &self.peer_limits
+    }
+
+    /// Reports the progress of downloads over `progress`
+    pub fn with_progress(self, progress: ProgressSender) -> Self {
+        let mut s = self;
+        s.progress = Some(progress);
+        s
+    }
+
+    /// Downloads the torrent into `out_file`, which is a directory for multi file torrents. A
+    /// resume file saved next to it lets a later run skip the pieces already there
     pub async fn download(
         &self,
         torrent_file: impl AsRef<Path>,
         out_file: impl AsRef<Path>,
     ) -> Result<()> {
-        let mut downloader = Downloader::new(
-            &self.client,
-            self.listener_port,
-            torrent_file,
-            &self.peer_id,
-            Compact::Compact,
-        )
-        .await?;
-        let pieces_len = downloader.pieces_downloaded.len();
-        // TODO: optimize by writing to hard disk since this can store nearly like 5gb in ram lol
-        let mut final_output = vec![];
-        for piece_idx in 0..pieces_len {
-            let bytes = downloader.download_piece(piece_idx).await?;
-            final_output.extend(bytes);
-        }
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(out_file)
-            .await?;
-        file.write_all(&final_output).await?;
+        let mut downloader = self.downloader(torrent_file).await?;
+        let (writer, tracker) = self.prepare(&mut downloader, &out_file, true).await?;
+        let tracker = Arc::new(tracker);
+        fetch_pieces(&mut downloader, &writer, &tracker, &resume_path(out_file)).await
+    }
+
+    /// Starts downloading the torrent in the background and hands back a way of reading its files
+    /// before it finishes
+    pub async fn stream(
+        &self,
+        torrent_file: impl AsRef<Path>,
+        out_file: impl AsRef<Path>,
+    ) -> Result<StreamingDownload> {
+        let mut downloader = self.downloader(torrent_file).await?;
+        // Readers go to the files as soon as a piece is done, so nothing may wait in a cache
+        let (writer, tracker) = self.prepare(&mut downloader, &out_file, false).await?;
+        let metainfo = downloader.metainfo().clone();
+        let tracker = Arc::new(tracker);
+        let resume = resume_path(out_file);
+        let task = tokio::spawn({
+            let (writer, tracker) = (writer.clone(), tracker.clone());
+            async move { fetch_pieces(&mut downloader, &writer, &tracker, &resume).await }
+        });
+        Ok(StreamingDownload {
+            metainfo,
+            writer,
+            tracker,
+            task,
+        })
+    }
+
+    /// Gets the info dictionary of the magnet link's torrent from the peers named in the link or
+    /// found through its trackers and the DHT, and turns it into the bytes of a torrent file.
+    /// Gives up after [`METADATA_TIMEOUT`]
+    pub async fn fetch_metadata(&self, magnet: &Magnet) -> Result<Vec<u8>> {
+        let deadline = tokio::time::Instant::now() + METADATA_TIMEOUT;
+        let peers = PeerPool::default();
+        peers.set_filter(self.ip_filter.clone());
+        peers.extend(magnet.peers.iter().copied(), PeerSource::Tracker);
+        for tracker in &magnet.trackers {
+            // The size of the torrent is what we are after, so nothing is left as far as we know
+            let found = discover_peers(
+                &self.client,
+                &magnet.info_hash,
+                tracker.clone(),
+                self.config.listen_port,
+                self.config.compact(),
+                &self.peer_id,
+                (0, 0, 0),
+            );
+            if let Ok(Ok(found)) = tokio::time::timeout_at(deadline, found).await {
+                peers.extend(found.into_iter().map(SocketAddr::V4), PeerSource::Tracker);
+            }
+        }
+        if let Some(dht) = &self.dht {
+            let found = dht.lookup_peers(magnet.info_hash);
+            if let Ok(found) = tokio::time::timeout_at(deadline, found).await {
+                peers.extend(found.into_iter().map(SocketAddr::V4), PeerSource::Dht);
+            }
+        }
+        let mut last_err = anyhow!("No peer found for {}", hex::encode(magnet.info_hash));
+        for peer in peers.peers() {
+            let self_hand = Handshake::new(&magnet.info_hash, &self.peer_id).with_extensions();
+            let result = tokio::time::timeout_at(deadline, async {
+                let (stream, peer_hand) = tokio::time::timeout(
+                    self.config.connect_timeout,
+                    handshake::connect_encrypted(
+                        peer,
+                        self_hand,
+                        self.encryption(),
+                        &self.transport,
+                    ),
+                )
+                .await??;
+                if !peer_hand.supports_extensions() {
+                    return Err(anyhow!("Peer {peer} does not support extensions"));
+                }
+                let (download, upload) = self.torrent_limits(RateLimits::default()).connection();
+                let mut stream =
+                    PeerBufferStream::from_stream(stream).with_throttles(download, upload);
+                metadata::fetch_metadata(&mut stream, &magnet.info_hash, self.config.listen_port)
+                    .await
+            })
+            .await;
+            match result {
+                Ok(Ok(info)) => return Ok(magnet.to_torrent(&info)),
+                Ok(Err(err)) => last_err = err,
+                Err(_) => {
+                    return Err(anyhow!(
+                        "Timed out getting the metadata of {}: {last_err}",
+                        hex::encode(magnet.info_hash)
+                    ))
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// The limits of a torrent downloaded with this client, whose own limits are `torrent`
+    pub fn torrent_limits(&self, torrent: RateLimits) -> TorrentLimits {
+        TorrentLimits {
+            session: self.limits.clone(),
+            torrent,
+            peer: self.peer_limits.clone(),
+        }
+    }
+
+    pub(crate) async fn downloader(&self, torrent_file: impl AsRef<Path>) -> Result<Downloader> {
+        let mut downloader =
+            Downloader::new(&self.client, torrent_file, &self.peer_id, &self.config)
+                .await?
+                .with_transport(self.transport.clone())
+                .with_limits(self.torrent_limits(RateLimits::default()))
+                .with_ip_filter(self.ip_filter.clone())
+                .with_hasher(self.hasher.clone());
+        if let Some(progress) = &self.progress {
+            downloader = downloader.with_progress(progress.clone());
+        }
+        if let Some(slots) = &self.connection_slots {
+            downloader = downloader.with_connection_slots(slots.clone());
+        }
+        if let Some(dht) = &self.dht {
+            downloader.add_dht_peers(dht).await;
+        }
+        if let Some(lsd) = &self.lsd {
+            downloader.add_lsd(lsd, LSD_PEER_WAIT).await;
+        }
+        Ok(downloader)
+    }
+
+    /// Creates the selected files and has the downloader store pieces in them, picks up what an
+    /// earlier run left in the resume file and decides the order the remaining pieces come in.
+    /// With `write_back`, pieces go through a cache of the configured size on their way there
+    pub(crate) async fn prepare(
+        &self,
+        downloader: &mut Downloader,
+        out_file: impl AsRef<Path>,
+        write_back: bool,
+    ) -> Result<(Arc<FileWriter>, PieceTracker)> {
+        let metainfo = downloader.metainfo();
+        let selection = self
+            .selection
+            .clone()
+            .unwrap_or_else(|| FileSelection::all(metainfo));
+        let writer = Arc::new(FileWriter::create(metainfo, &selection, &out_file).await?);
+        // Mapping needs every file, so partial downloads go through the files instead
+        downloader.storage = match self.config.storage {
+            StorageKind::Mmap
+                if (0..metainfo.files().len()).all(|file| selection.is_wanted(file)) =>
+            {
+                Arc::new(MmapStorage::open(&writer)?)
+            }
+            _ => writer.clone(),
+        };
+        if write_back && self.config.cache_size > 0 {
+            downloader.storage = Arc::new(CachedStorage::new(
+                downloader.storage.clone(),
+                self.config.cache_size,
+            ));
+        }
+        let metainfo = downloader.metainfo();
+        let picker = PiecePicker::new(metainfo, &selection)
+            .with_strategy(self.strategy)
+            .with_deadline_window(self.deadline_window);
+        let tracker = PieceTracker::new(picker, metainfo.num_pieces());
+        downloader.resume(&writer, resume_path(&out_file)).await;
+        for piece in 0..downloader.metainfo().num_pieces() {
+            if downloader.has_piece(piece) {
+                tracker.piece_done(piece);
+            }
+        }
+        Ok((writer, tracker))
+    }
+}
+
+/// How often the resume file is saved while downloading
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Downloads the pieces the tracker asks for until none are left or it is paused, saving the resume file to
+/// `resume` along the way and once done
+pub(crate) async fn fetch_pieces(
+    downloader: &mut Downloader,
+    writer: &FileWriter,
+    tracker: &Arc<PieceTracker>,
+    resume: &Path,
+) -> Result<()> {
+    let mut last_save = Instant::now();
+    let (mut download_rate, mut upload_rate) = (RateMeter::default(), RateMeter::default());
+    let num_pieces = downloader.metainfo().num_pieces();
+    let result = async {
+        let mut swarm = downloader.swarm(tracker.clone());
+        while let Some(piece_idx) = downloader.next_piece(&mut swarm).await? {
+            downloader.emit(ProgressEvent::PieceVerified {
+                piece: piece_idx,
+                pieces_done: tracker.have().iter().filter(|have| **have).count(),
+                num_pieces,
+            });
+            let now = Instant::now();
+            download_rate.record(now, downloader.downloaded());
+            upload_rate.record(now, downloader.uploaded());
+            let left = tracker
+                .missing()
+                .iter()
+                .filter_map(|piece| downloader.metainfo().piece_size(*piece))
+                .sum();
+            downloader.emit(ProgressEvent::Rates {
+                downloaded: downloader.downloaded(),
+                uploaded: downloader.uploaded(),
+                left,
+                download_rate: download_rate.rate(),
+                upload_rate: upload_rate.rate(),
+                eta_secs: eta(left, download_rate.rate()).map(|eta| eta.as_secs()),
+                peers: downloader.num_connected(),
+            });
+            if last_save.elapsed() >= RESUME_SAVE_INTERVAL {
+                downloader.flush().await?;
+                downloader.resume_data(writer).save(resume)?;
+                last_save = Instant::now();
+            }
+        }
         Ok(())
     }
+    .await;
+    // Readers wait out a pause but not the end of the download
+    if result.is_err() || !tracker.is_paused() {
+        tracker.stop();
+    }
+    if result.is_ok() && !tracker.is_paused() {
+        downloader.emit(ProgressEvent::Finished {
+            downloaded: downloader.downloaded(),
+            uploaded: downloader.uploaded(),
+        });
+    }
+    downloader.flush().await?;
+    downloader.resume_data(writer).save(resume)?;
+    result
 }
 
+/// A download running in the background whose files can be read while it goes. Dropping it
+/// stops the download
+#[derive(Debug)]
+pub struct StreamingDownload {
+    metainfo: MetaInfo,
+    writer: Arc<FileWriter>,
+    tracker: Arc<PieceTracker>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl StreamingDownload {
+    #[inline]
+    pub fn metainfo(&self) -> &MetaInfo {
+        &self.metainfo
+    }
+
+    /// Opens the file at `file_index` for reading, waiting on pieces as the reads reach them
+    pub fn open_file(&self, file_index: usize) -> std::io::Result<FileStream> {
+        FileStream::open(
+            &self.metainfo,
+            &self.writer,
+            self.tracker.clone(),
+            file_index,
+        )
+    }
+
+    /// Waits for every selected piece to be downloaded
+    pub async fn wait(mut self) -> Result<()> {
+        (&mut self.task).await?
+    }
+}
+
+impl Drop for StreamingDownload {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The connections of a download and what they brought in so far. Dropping it closes them
+#[derive(Debug)]
+pub(crate) struct Swarm {
+    queue: Arc<BlockQueue>,
+    ctx: Arc<PeerContext>,
+    blocks_tx: mpsc::UnboundedSender<ReceivedBlock>,
+    blocks_rx: mpsc::UnboundedReceiver<ReceivedBlock>,
+    /// Hashes of the blocks stored so far of the pieces being downloaded, each with the peer
+    /// that sent it
+    pieces: HashMap<usize, BTreeMap<u32, ([u8; 20], SocketAddr)>>,
+    /// Times each piece failed the hash check
+    attempts: HashMap<usize, usize>,
+    /// Waits for a peer backing off since the last block came in
+    retry_waits: usize,
+    last_block: Instant,
+    manager: ConnectionManager,
+}
+
+impl Drop for Swarm {
+    fn drop(&mut self) {
+        self.queue.close();
+        self.manager.disconnect_all();
+    }
+}
+
+/// Times a piece may fail the hash check before it is downloaded from one peer at a time, none
+/// of which sent a failed version while another peer can take it
+const MAX_PIECE_ATTEMPTS: usize = 3;
+/// Times a download waits for a peer backing off when no other peer is left
+const MAX_RETRY_WAITS: usize = 3;
+/// How often a download with free connection slots looks for peers to fill them with
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a download with no other peers waits for LSD to find one
+pub const LSD_PEER_WAIT: Duration = Duration::from_secs(3);
+/// How long getting the info dictionary of a magnet link may take in all
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(120);
+
 #[derive(Debug)]
 pub struct Downloader {
     pieces_downloaded: Vec<(bool, u64)>,
     metainfo: MetaInfo,
     info_hash: [u8; INFO_HASH_SIZE],
     peer_id: [u8; PEER_ID_SIZE],
-    peers: Vec<SocketAddrV4>,
+    peers: PeerPool,
+    /// What each peer has been told over `ut_pex` so far
+    pex_states: Arc<Mutex<HashMap<SocketAddr, PexState>>>,
+    web_seeds: Vec<WebSeed>,
+    client: Client,
+    config: ClientConfig,
+    transport: Transport,
+    /// Shared with whatever serves the torrent's pieces to other peers
+    uploaded: Arc<AtomicU64>,
+    /// Shared the same way, for whatever saves the resume file once the download is gone
+    downloaded: Arc<AtomicU64>,
+    events: Option<ProgressSender>,
+    /// Peers a piece is being downloaded from right now
+    connected: Arc<AtomicUsize>,
+    limits: TorrentLimits,
+    manager: ConnectionManager,
+    /// Blocks of the pieces that failed the hash check, to find out who sent the bad ones
+    smart_ban: Mutex<SmartBan>,
+    /// Where downloaded blocks are written and pieces are checked
+    storage: Arc<dyn Storage>,
+    hasher: HashPool,
+    /// Connections allowed at once across the torrents sharing it, when there is such a limit
+    slots: Option<Arc<Semaphore>>,
 }
 
 impl Downloader {
-    /// BLK_SIZE = 2^14
-    const BLK_SIZE: u64 = 1 << 14;
+    /// Reads the torrent file and asks its tracker for peers, announcing the port of `config`
     pub async fn new(
         client: &Client,
-        port: u16,
         torrent_file: impl AsRef<Path>,
         peer_id: &[u8; PEER_ID_SIZE],
-        compact: Compact,
+        config: &ClientConfig,
     ) -> Result<Downloader> {
         let (url, info) = from_file(torrent_file)?;
-        let left = match info.file_type() {
-            FileType::SingleFile(left) => *left,
-            FileType::MultiFile(_) => todo!("MultiFile support is not implemented yet!"),
+        let left = info.total_length();
+        let info_hash = info.handshake_info_hash()?;
+        let web_seeds = info
+            .web_seeds()
+            .iter()
+            .cloned()
+            .map(WebSeed::new)
+            .collect::<Vec<WebSeed>>();
+        let peers = match url {
+            Some(url) => match discover_peers(
+                client,
+                &info_hash,
+                url,
+                config.listen_port,
+                config.compact(),
+                peer_id,
+                (0, 0, left),
+            )
+            .await
+            {
+                Ok(peers) => peers,
+                // Web seeds keep the download going when the tracker can't be reached
+                Err(_) if !web_seeds.is_empty() => Vec::new(),
+                Err(err) => return Err(err),
+            },
+            // Trackerless torrents get their peers from the DHT through `add_dht_peers`
+            None => Vec::new(),
         };
-        let info_hash = info.info_hash()?;
-        let peers = discover_peers(
-            client,
-            &info_hash,
-            url,
-            port,
-            compact,
-            peer_id,
-            (0, 0, left),
-        )
-        .await?;
-        let piece_len = info.pieces().len();
-        let piece_size = info.piece_length();
-        let last_piece_length = left % piece_size;
-        let mut pieces_downloaded = vec![(false, piece_size); piece_len];
-        let piece_downloaded_len = pieces_downloaded.len();
-        if last_piece_length != 0 {
-            pieces_downloaded[piece_downloaded_len - 1] = (false, last_piece_length);
-        }
+        let pool = PeerPool::default();
+        pool.extend(peers.into_iter().map(SocketAddr::V4), PeerSource::Tracker);
+        let pieces_downloaded = (0..info.num_pieces())
+            .map(|piece_num| (false, info.piece_size(piece_num).unwrap_or_default()))
+            .collect();
+        let storage = Arc::new(MemoryStorage::new(&info));
         Ok(Self {
-            peers,
+            peers: pool,
+            pex_states: Arc::new(Mutex::new(HashMap::new())),
+            web_seeds,
+            client: client.clone(),
+            config: config.clone(),
+            transport: Transport::default(),
             info_hash,
             peer_id: *peer_id,
             metainfo: info,
             pieces_downloaded,
+            uploaded: Arc::new(AtomicU64::new(0)),
+            downloaded: Arc::new(AtomicU64::new(0)),
+            events: None,
+            connected: Arc::new(AtomicUsize::new(0)),
+            limits: TorrentLimits::default(),
+            manager: ConnectionManager::new(config.connections_per_torrent),
+            smart_ban: Mutex::new(SmartBan::default()),
+            storage,
+            hasher: HashPool::default(),
+            slots: None,
         })
     }
-    pub async fn download_piece(&mut self, piece_num: usize) -> Result<Vec<u8>, DownloadError> {
-        let piece =
-            self.pieces_downloaded
-                .get_mut(piece_num)
-                .ok_or(DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: "piece num is out of bounds".to_owned(),
-                })?;
+
+    /// Sets where pieces are stored, which is memory until then
+    pub fn with_storage(self, storage: Arc<dyn Storage>) -> Self {
+        let mut s = self;
+        s.storage = storage;
+        s
+    }
+
+    #[inline]
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// Hashes pieces on `hasher` instead of a pool of its own with a thread per core
+    pub fn with_hasher(self, hasher: HashPool) -> Self {
+        let mut s = self;
+        s.hasher = hasher;
+        s
+    }
+
+    /// Holds a permit of `slots` for every peer connection, on top of the limit per torrent
+    pub fn with_connection_slots(self, slots: Arc<Semaphore>) -> Self {
+        let mut s = self;
+        s.slots = Some(slots);
+        s
+    }
+
+    /// Makes the pieces stored so far durable
+    pub async fn flush(&self) -> std::io::Result<()> {
+        storage::blocking(&self.storage, |storage| storage.flush()).await
+    }
+
+    /// Sets whether peer connections use Message Stream Encryption
+    pub fn with_encryption(self, encryption: EncryptionPolicy) -> Self {
+        let mut s = self;
+        s.config.extensions.encryption = encryption;
+        s
+    }
+
+    /// Sets how peers are connected to
+    pub fn with_transport(self, transport: Transport) -> Self {
+        let mut s = self;
+        s.transport = transport;
+        s
+    }
+
+    /// Refuses the peers the filter blocks. Those the tracker already returned are no longer
+    /// tried
+    pub fn with_ip_filter(self, filter: IpFilter) -> Self {
+        self.peers.set_filter(filter);
+        self
+    }
+
+    /// Sets the rate limits peer connections go through
+    pub fn with_limits(self, limits: TorrentLimits) -> Self {
+        let mut s = self;
+        s.limits = limits;
+        s
+    }
+
+    #[inline]
+    pub fn limits(&self) -> &TorrentLimits {
+        &self.limits
+    }
+
+    /// Sets which peers get connected to and how many at once. Sharing the manager with whatever
+    /// accepts inbound connections lets it turn away banned peers too
+    pub fn with_connections(self, manager: ConnectionManager) -> Self {
+        let mut s = self;
+        s.manager = manager;
+        s
+    }
+
+    #[inline]
+    pub fn connections(&self) -> &ConnectionManager {
+        &self.manager
+    }
+
+    /// Reports peers connecting and disconnecting over `events`
+    pub fn with_progress(self, events: ProgressSender) -> Self {
+        let mut s = self;
+        s.events = Some(events);
+        s
+    }
+
+    /// Sends the event to whoever follows the download, if anyone
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(events) = &self.events {
+            // Nobody listening any more is fine
+            let _ = events.send(event);
+        }
+    }
+
+    #[inline]
+    pub fn num_connected(&self) -> usize {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Looks the torrent up in the DHT, announcing our listener port to the nodes closest to it,
+    /// and adds the peers found. Private torrents never use the DHT. Returns the number of new
+    /// peers
+    pub async fn add_dht_peers(&mut self, dht: &Dht) -> usize {
+        if self.metainfo.is_private() {
+            return 0;
+        }
+        let found = dht.announce(self.info_hash, self.config.listen_port).await;
+        self.peers
+            .extend(found.into_iter().map(SocketAddr::V4), PeerSource::Dht)
+    }
+
+    /// Announces the torrent on the local network; peers answering with the same torrent are
+    /// added to the pool as they show up. Private torrents never use LSD. When the pool is still
+    /// empty this waits up to `wait` for a first peer. Returns whether the pool has any peer
+    pub async fn add_lsd(&self, lsd: &Lsd, wait: Duration) -> bool {
+        if self.metainfo.is_private() {
+            return !self.peers.is_empty();
+        }
+        lsd.add_torrent(self.info_hash, self.peers.clone());
+        // Best effort, the periodic announce retries anyway
+        let _ = lsd.announce().await;
+        self.peers.wait_for_peers(wait).await
+    }
+
+    #[inline]
+    pub fn info_hash(&self) -> &[u8; INFO_HASH_SIZE] {
+        &self.info_hash
+    }
+
+    #[inline]
+    pub fn metainfo(&self) -> &MetaInfo {
+        &self.metainfo
+    }
+
+    #[inline]
+    pub fn peers(&self) -> &PeerPool {
+        &self.peers
+    }
+
+    /// Downloads a piece into the storage from the peers of the pool, falling back to the web
+    /// seeds of the torrent when no peer could deliver it or there is no peer at all
+    pub async fn download_piece(&mut self, piece_num: usize) -> Result<(), DownloadError> {
+        let piece = *self
+            .pieces_downloaded
+            .get(piece_num)
+            .ok_or(DownloadError::InvalidPiece {
+                piece_num,
+                reason: "piece num is out of bounds".to_owned(),
+            })?;
         if piece.0 {
             return Err(DownloadError::InvalidPiece {
                 piece_num,
                 reason: "piece has already been downloaded!".to_owned(),
             });
         }
-        let peer = self.peers.first().ok_or(DownloadError::InvalidPiece {
-            piece_num,
-            reason: "No peer found!".to_owned(),
-        })?;
-        let (stream, _peer) = handshake::connect(peer, &self.info_hash, &self.peer_id)
-            .await
-            .map_err(|err| DownloadError::InvalidPiece {
-                piece_num,
-                reason: err.to_string(),
-            })?;
-        let (reader, writer) = stream.into_split();
-        let mut stream = PeerBufferStream::new(reader, writer);
-        verify_incoming_message(&mut stream, PeerMessageId::Bitfield)
-            .await
-            .map_err(|err| DownloadError::InvalidPiece {
-                piece_num,
-                reason: err.to_string(),
-            })?;
-        stream
-            .write_message(PeerMessageId::Interested, &[])
-            .await
-            .map_err(|err| DownloadError::InvalidPiece {
-                piece_num,
-                reason: err.to_string(),
-            })?;
-        verify_incoming_message(&mut stream, PeerMessageId::Unchoke)
+        let picker = PiecePicker::new(&self.metainfo, &FileSelection::all(&self.metainfo));
+        let tracker = Arc::new(PieceTracker::new(picker, self.metainfo.num_pieces()));
+        for piece in (0..self.metainfo.num_pieces()).filter(|piece| *piece != piece_num) {
+            tracker.piece_done(piece);
+        }
+        let mut swarm = self.swarm(tracker);
+        while self.next_piece(&mut swarm).await?.is_some() {}
+        Ok(())
+    }
+
+    #[inline]
+    pub fn has_piece(&self, piece_num: usize) -> bool {
+        self.pieces_downloaded
+            .get(piece_num)
+            .is_some_and(|(downloaded, _)| *downloaded)
+    }
+
+    /// Bytes of verified pieces downloaded, including earlier runs that were resumed
+    #[inline]
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// The counter of bytes uploaded, for the side serving pieces to add to
+    #[inline]
+    pub fn uploaded_counter(&self) -> Arc<AtomicU64> {
+        self.uploaded.clone()
+    }
+
+    /// The counter behind [`Self::downloaded`]
+    #[inline]
+    pub fn downloaded_counter(&self) -> Arc<AtomicU64> {
+        self.downloaded.clone()
+    }
+
+    /// Picks up where an earlier run saved to the resume file at `path` left off. Its pieces are
+    /// trusted while every file still has the size and modification time it was saved with;
+    /// otherwise the pieces on disk are hashed again. Without a resume file, only files that
+    /// were already there get rechecked. Returns the number of pieces we have
+    pub async fn resume(&mut self, writer: &FileWriter, path: impl AsRef<Path>) -> usize {
+        let saved = ResumeData::load(path)
+            .ok()
+            .filter(|data| data.info_hash == self.info_hash);
+        if let Some(data) = &saved {
+            self.uploaded.store(data.uploaded, Ordering::Relaxed);
+            self.downloaded.store(data.downloaded, Ordering::Relaxed);
+            self.peers
+                .extend(data.peers.iter().copied(), PeerSource::Resume);
+        }
+        let num_pieces = self.pieces_downloaded.len();
+        match saved {
+            Some(data) if data.files == writer.file_stamps() && data.have.len() >= num_pieces => {
+                for (piece, have) in self.pieces_downloaded.iter_mut().zip(data.have) {
+                    piece.0 = have;
+                }
+            }
+            Some(_) => self.recheck().await,
+            None if writer.preexisting() => self.recheck().await,
+            None => {}
+        }
+        self.pieces_downloaded
+            .iter()
+            .filter(|(downloaded, _)| *downloaded)
+            .count()
+    }
+
+    /// Hashes every stored piece again, which is what `pieces_downloaded` ends up holding. Pieces
+    /// are hashed in parallel, as many at a time as the hashing pool takes
+    pub async fn recheck(&mut self) {
+        let mut checks = JoinSet::new();
+        let mut pieces = 0..self.pieces_downloaded.len();
+        loop {
+            while checks.len() < self.hasher.capacity() {
+                let Some(piece_num) = pieces.next() else {
+                    break;
+                };
+                let (hasher, storage) = (self.hasher.clone(), self.storage.clone());
+                checks.spawn(async move {
+                    let verified = hasher
+                        .run(move || verify_stored(storage.as_ref(), piece_num))
+                        .await;
+                    (piece_num, matches!(verified, Ok(Ok(()))))
+                });
+            }
+            match checks.join_next().await {
+                Some(Ok((piece_num, verified))) => self.pieces_downloaded[piece_num].0 = verified,
+                Some(Err(_)) => {}
+                None => break,
+            }
+        }
+    }
+
+    /// What a restart needs to skip the pieces we have
+    pub fn resume_data(&self, writer: &FileWriter) -> ResumeData {
+        ResumeData {
+            info_hash: self.info_hash,
+            have: self
+                .pieces_downloaded
+                .iter()
+                .map(|(downloaded, _)| *downloaded)
+                .collect(),
+            files: writer.file_stamps(),
+            uploaded: self.uploaded(),
+            downloaded: self.downloaded(),
+            peers: self.peers.peers(),
+        }
+    }
+
+    /// Checks and stores a piece downloaded from a web seed, returning its bytes
+    async fn store_web_seed_piece(
+        &self,
+        piece_num: usize,
+        piece_bytes: Vec<u8>,
+    ) -> Result<Arc<Vec<u8>>, DownloadError> {
+        let piece_bytes = Arc::new(piece_bytes);
+        let storage = self.storage.clone();
+        self.hasher
+            .run({
+                let piece_bytes = piece_bytes.clone();
+                move || verify_piece(storage.metainfo(), piece_num, &piece_bytes)
+            })
             .await
             .map_err(|err| DownloadError::InvalidPiece {
                 piece_num,
                 reason: err.to_string(),
-            })?;
-        let length = piece.1;
-        let rounds = length / Downloader::BLK_SIZE;
-        let bytes_left = length % Downloader::BLK_SIZE;
-        let mut piece_bytes = vec![];
-        // TODO: make this concurrent later?
-        for i in 0..=rounds {
-            if i == rounds && bytes_left == 0 {
+            })??;
+        storage::blocking(&self.storage, {
+            let piece_bytes = piece_bytes.clone();
+            move |storage| storage.write_piece(piece_num, &piece_bytes)
+        })
+        .await
+        .map_err(|err| DownloadError::InvalidPiece {
+            piece_num,
+            reason: err.to_string(),
+        })?;
+        Ok(piece_bytes)
+    }
+
+    /// What the connections of this download share
+    fn context(&self) -> PeerContext {
+        PeerContext {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.config.listen_port,
+            // Private torrents only get their peers from the tracker (BEP 27)
+            pex: self.config.extensions.pex && !self.metainfo.is_private(),
+            encryption: self.config.extensions.encryption,
+            transport: self.transport.clone(),
+            pipeline_depth: self.config.pipeline_depth,
+            connect_timeout: self.config.connect_timeout,
+            request_timeout: self.config.request_timeout,
+            peers: self.peers.clone(),
+            pex_states: self.pex_states.clone(),
+            limits: self.limits.clone(),
+            events: self.events.clone(),
+            connected: self.connected.clone(),
+            manager: self.manager.clone(),
+        }
+    }
+
+    /// Sets up the connections of a download of the pieces `tracker` picks, which
+    /// [`Self::next_piece`] then keeps going
+    pub(crate) fn swarm(&self, tracker: Arc<PieceTracker>) -> Swarm {
+        let (blocks_tx, blocks_rx) = mpsc::unbounded_channel();
+        Swarm {
+            queue: Arc::new(BlockQueue::new(
+                tracker,
+                &self.metainfo,
+                self.config.block_size,
+            )),
+            ctx: Arc::new(self.context()),
+            blocks_tx,
+            blocks_rx,
+            pieces: HashMap::new(),
+            attempts: HashMap::new(),
+            retry_waits: 0,
+            last_block: Instant::now(),
+            manager: self.manager.clone(),
+        }
+    }
+
+    /// Downloads until the next piece is verified and stored, returning which one it was, or
+    /// `None` once the tracker has no piece left to download or is paused. Up to the allowed
+    /// number of connections take blocks off the queue of the swarm for as long as it lasts,
+    /// each peer asked only for pieces it has. With no peer to connect to, pieces come from
+    /// the web seeds of the torrent instead
+    pub(crate) async fn next_piece(
+        &mut self,
+        swarm: &mut Swarm,
+    ) -> Result<Option<usize>, DownloadError> {
+        loop {
+            while let Ok(block) = swarm.blocks_rx.try_recv() {
+                if let Some(piece) = self.add_block(swarm, block).await? {
+                    return Ok(Some(piece));
+                }
+            }
+            let tracker = swarm.queue.tracker();
+            if tracker.is_paused() || (swarm.queue.is_idle() && tracker.next_piece().is_none()) {
+                return Ok(None);
+            }
+            self.connect(swarm);
+            if self.manager.num_connected() == 0 {
+                // Connections of other torrents or inbound ones may hold every slot for now
+                if let Some(slots) = self
+                    .slots
+                    .as_ref()
+                    .filter(|slots| slots.available_permits() == 0)
+                {
+                    let _ = slots.acquire().await;
+                    continue;
+                }
+                let mut failed = None;
+                if !self.web_seeds.is_empty() {
+                    match self.download_from_web_seeds(swarm).await {
+                        Ok(Some(piece)) => return Ok(Some(piece)),
+                        Ok(None) => {}
+                        Err(err) => failed = Some(err),
+                    }
+                }
+                // Every peer is banned or backing off, the next one to come back may do
+                match self.manager.next_retry(&self.peers) {
+                    Some(retry_at) if swarm.retry_waits < MAX_RETRY_WAITS => {
+                        swarm.retry_waits += 1;
+                        tokio::time::sleep_until(retry_at.into()).await;
+                        continue;
+                    }
+                    _ => {
+                        return Err(failed.unwrap_or_else(|| {
+                            DownloadError::NoSource(
+                                self.manager
+                                    .last_error()
+                                    .unwrap_or("No peer or web seed found!".to_owned()),
+                            )
+                        }))
+                    }
+                }
+            }
+            tokio::select! {
+                Some(block) = swarm.blocks_rx.recv() => {
+                    if let Some(piece) = self.add_block(swarm, block).await? {
+                        return Ok(Some(piece));
+                    }
+                }
+                // A freed slot may go to another peer
+                _ = self.manager.wait_ended() => {}
+                // And so may one no peer was left for, once the pool grows
+                _ = tokio::time::sleep(RECONNECT_INTERVAL) => {
+                    // Nothing coming in for that long may be down to pieces on parole that
+                    // only suspects have
+                    if swarm.last_block.elapsed() >= self.config.request_timeout {
+                        swarm.queue.pardon();
+                        swarm.last_block = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fills the free connection slots of the swarm with peers of the pool
+    fn connect(&self, swarm: &Swarm) {
+        while self.manager.num_connected() < self.manager.max_connections() {
+            let Some(permit) = self.connection_slot() else {
                 break;
+            };
+            let Some(peer) = self.manager.pick(&self.peers, 1).pop() else {
+                break;
+            };
+            let (ctx, queue, blocks) = (
+                swarm.ctx.clone(),
+                swarm.queue.clone(),
+                swarm.blocks_tx.clone(),
+            );
+            self.manager.spawn(peer, async move {
+                let _permit = permit;
+                connection::download_from(ctx, peer, queue, blocks).await
+            });
+        }
+    }
+
+    /// Adds a block to the piece it belongs to. Once the piece has every block it is stored and
+    /// checked, returning the piece when it verified. A piece failing the hash check is
+    /// downloaded again, its blocks kept aside so that the peer which sent the bad one can be
+    /// told apart from the others once a download verifies
+    async fn add_block(
+        &mut self,
+        swarm: &mut Swarm,
+        block: ReceivedBlock,
+    ) -> Result<Option<usize>, DownloadError> {
+        let piece_num = block.request.index as usize;
+        // Blocks of a piece taken over by a web seed or another peer may still trickle in
+        if !swarm.queue.accepts(piece_num, block.peer) {
+            return Ok(None);
+        }
+        swarm.retry_waits = 0;
+        swarm.last_block = Instant::now();
+        let ReceivedBlock {
+            request,
+            data,
+            peer,
+        } = block;
+        // Blocks go to storage as they come, only their hashes are kept for smart banning
+        let hash = storage::blocking(&self.storage, move |storage| {
+            storage.write_block(piece_num, request.begin, &data)?;
+            Ok(block_hash(&data))
+        })
+        .await
+        .map_err(|err| DownloadError::InvalidPiece {
+            piece_num,
+            reason: err.to_string(),
+        })?;
+        let blocks = swarm.pieces.entry(piece_num).or_default();
+        // A piece on parole is put together from its current peer alone
+        if swarm.queue.is_on_parole(piece_num) {
+            blocks.retain(|_, (_, sender)| *sender == peer);
+        }
+        blocks.insert(request.begin, (hash, peer));
+        if blocks.len() < swarm.queue.num_blocks(piece_num) {
+            return Ok(None);
+        }
+        let blocks = swarm.pieces.remove(&piece_num).unwrap_or_default();
+        match self.verify_stored(piece_num).await {
+            Ok(()) => {
+                let culprits = self
+                    .smart_ban
+                    .lock()
+                    .expect("Poisoned lock")
+                    .piece_verified(
+                        piece_num,
+                        blocks.iter().map(|(begin, (hash, _))| (*begin, *hash)),
+                    );
+                self.punish(culprits);
+                self.mark_downloaded(swarm, piece_num);
+                Ok(Some(piece_num))
             }
-            let index = u32::try_from(piece_num).map_err(|err| DownloadError::InvalidPiece {
-                piece_num,
-                reason: err.to_string(),
-            })?;
-            let begin = u32::try_from(i * Downloader::BLK_SIZE).map_err(|err| {
-                DownloadError::InvalidPiece {
+            Err(_) => {
+                let sender = self.smart_ban.lock().expect("Poisoned lock").piece_failed(
                     piece_num,
-                    reason: err.to_string(),
+                    blocks
+                        .iter()
+                        .map(|(begin, (hash, peer))| (*begin, *hash, *peer)),
+                );
+                // Only when one peer sent the whole piece is it known to be at fault already
+                if let Some(peer) = sender {
+                    self.manager.record_hash_failure(peer);
                 }
-            })?;
-            let length = u32::try_from({
-                if i != rounds {
-                    Downloader::BLK_SIZE
-                } else {
-                    bytes_left
+                let attempts = swarm.attempts.entry(piece_num).or_default();
+                *attempts += 1;
+                let suspects = (*attempts >= MAX_PIECE_ATTEMPTS).then(|| {
+                    self.smart_ban
+                        .lock()
+                        .expect("Poisoned lock")
+                        .suspects(piece_num)
+                });
+                swarm.queue.reopen(piece_num, suspects);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Bans the peers caught sending corrupt blocks. Those blamed for the piece already had it
+    /// counted against them
+    fn punish(&self, culprits: Vec<(SocketAddr, bool)>) {
+        for (peer, blamed) in culprits {
+            if blamed {
+                self.manager.ban(peer.ip());
+            } else {
+                self.manager.record_corrupt(peer);
+            }
+        }
+    }
+
+    /// Downloads a piece of the swarm from the web seeds, taking it out of the queue. Returns
+    /// `None` when there is no piece left to take
+    async fn download_from_web_seeds(
+        &mut self,
+        swarm: &mut Swarm,
+    ) -> Result<Option<usize>, DownloadError> {
+        let Some(piece_num) = swarm.queue.take_piece() else {
+            return Ok(None);
+        };
+        swarm.pieces.remove(&piece_num);
+        let mut res = Err(DownloadError::NoSource("No web seed found!".to_owned()));
+        let mut at = 0;
+        while let Some(web_seed) = self.web_seeds.get(at) {
+            res = match web_seed
+                .download_piece(&self.client, &self.metainfo, piece_num)
+                .await
+            {
+                Ok(piece_bytes) => self.store_web_seed_piece(piece_num, piece_bytes).await,
+                // Each piece would cost a download of the whole file, so the seed is dropped
+                Err(err @ WebSeedError::RangesUnsupported(_)) => {
+                    self.web_seeds.remove(at);
+                    Err(DownloadError::InvalidPiece {
+                        piece_num,
+                        reason: err.to_string(),
+                    })
                 }
-            })
+                Err(err) => {
+                    at += 1;
+                    Err(DownloadError::InvalidPiece {
+                        piece_num,
+                        reason: err.to_string(),
+                    })
+                }
+            };
+            if res.is_ok() {
+                break;
+            }
+        }
+        let piece_bytes = match res {
+            Ok(piece_bytes) => piece_bytes,
+            Err(err) => {
+                swarm.queue.reopen(piece_num, None);
+                return Err(err);
+            }
+        };
+        // A good copy tells who sent the bad blocks of the versions that failed
+        let block_size = self.config.block_size as usize;
+        let culprits = self
+            .smart_ban
+            .lock()
+            .expect("Poisoned lock")
+            .piece_verified(
+                piece_num,
+                piece_bytes
+                    .chunks(block_size)
+                    .enumerate()
+                    .map(|(block, data)| ((block * block_size) as u32, block_hash(data))),
+            );
+        self.punish(culprits);
+        self.mark_downloaded(swarm, piece_num);
+        Ok(Some(piece_num))
+    }
+
+    /// Marks a piece as downloaded. The tracker learns first so that the queue doesn't open the
+    /// piece again
+    fn mark_downloaded(&mut self, swarm: &mut Swarm, piece_num: usize) {
+        swarm.queue.tracker().piece_done(piece_num);
+        swarm.queue.piece_done(piece_num);
+        swarm.attempts.remove(&piece_num);
+        // Whatever failed versions it had can't be compared any more
+        self.smart_ban
+            .lock()
+            .expect("Poisoned lock")
+            .forget(piece_num);
+        let piece = &mut self.pieces_downloaded[piece_num];
+        piece.0 = true;
+        self.downloaded.fetch_add(piece.1, Ordering::Relaxed);
+    }
+
+    /// Takes a slot for one more connection out of those shared with other torrents. `None`
+    /// when they are all taken; without a shared limit there is always one
+    fn connection_slot(&self) -> Option<Option<OwnedSemaphorePermit>> {
+        match &self.slots {
+            Some(slots) => slots.clone().try_acquire_owned().ok().map(Some),
+            None => Some(None),
+        }
+    }
+
+    /// Checks the piece as stored on the hashing pool
+    async fn verify_stored(&self, piece_num: usize) -> Result<(), DownloadError> {
+        let storage = self.storage.clone();
+        self.hasher
+            .run(move || verify_stored(storage.as_ref(), piece_num))
+            .await
             .map_err(|err| DownloadError::InvalidPiece {
                 piece_num,
                 reason: err.to_string(),
             })?
-            .to_be_bytes();
-            let mut bytes = vec![];
-            bytes.extend_from_slice(&index.to_be_bytes());
-            bytes.extend_from_slice(&begin.to_be_bytes());
-            bytes.extend_from_slice(&length);
-            stream
-                .write_message(PeerMessageId::Request, &bytes)
-                .await
-                .map_err(|err| DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: err.to_string(),
-                })?;
-            let msg = verify_incoming_message(&mut stream, PeerMessageId::Piece)
-                .await
-                .map_err(|err| DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: err.to_string(),
-                })?;
-            let actual_index = u32::from_be_bytes(
-                <[u8; mem::size_of::<u32>()]>::try_from(
-                    msg.payload.get(0..mem::size_of::<u32>()).ok_or(
-                        DownloadError::InvalidPiece {
-                            piece_num,
-                            reason: "Couldn't get resp index!".to_owned(),
-                        },
-                    )?,
-                )
-                .map_err(|err| DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: err.to_string(),
-                })?,
-            );
-            if actual_index != index {
-                return Err(DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: format!(
-                        "Did not download the correct index! Got {} but expected {}",
-                        actual_index, index
-                    ),
-                });
-            }
-            let actual_begin = u32::from_be_bytes(
-                <[u8; mem::size_of::<u32>()]>::try_from(
-                    msg.payload
-                        .get(mem::size_of::<u32>()..(mem::size_of::<u32>() * 2))
-                        .ok_or(DownloadError::InvalidPiece {
-                            piece_num,
-                            reason: "Couldn't get resp index!".to_owned(),
-                        })?,
-                )
-                .map_err(|err| DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: err.to_string(),
-                })?,
-            );
-            if actual_begin != begin {
-                return Err(DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: format!(
-                        "Did not download the correct begin bytes! Got {} but expected {}",
-                        actual_begin, begin
-                    ),
-                });
-            }
-            piece_bytes.extend(msg.payload.get((mem::size_of::<u32>() * 2)..).ok_or(
-                DownloadError::InvalidPiece {
-                    piece_num,
-                    reason: "Couldn't get the actual block!".to_owned(),
-                },
-            )?);
-        }
-        let bytes = Sha1::digest(&piece_bytes);
-        let actual_bytes = <[u8; INFO_HASH_SIZE]>::from(bytes);
-        let expected_bytes = self.metainfo.pieces()[piece_num];
-        if actual_bytes != expected_bytes {
+    }
+}
+
+/// Checks the bytes of a piece against the SHA-1 piece hash and, for v2 and hybrid torrents,
+/// against the merkle tree of the file it belongs to. Hashing takes a while, so this runs on the
+/// hashing pool
+fn verify_piece(
+    metainfo: &MetaInfo,
+    piece_num: usize,
+    piece_bytes: &[u8],
+) -> Result<(), DownloadError> {
+    check_sha1(metainfo, piece_num, Sha1::digest(piece_bytes).into())?;
+    check_merkle(metainfo, piece_num, piece_bytes)
+}
+
+/// Like [`verify_piece`] for the piece as stored. Only v2 pieces get read whole, the storage
+/// hashes v1 pieces itself
+fn verify_stored(storage: &dyn Storage, piece_num: usize) -> Result<(), DownloadError> {
+    let invalid = |reason: String| DownloadError::InvalidPiece { piece_num, reason };
+    let metainfo = storage.metainfo();
+    if !metainfo.pieces().is_empty() {
+        let hash = storage
+            .hash_piece(piece_num)
+            .map_err(|err| invalid(err.to_string()))?
+            .ok_or(invalid("Piece is not stored".to_owned()))?;
+        check_sha1(metainfo, piece_num, hash)?;
+    }
+    if metainfo.v2().is_some() {
+        let bytes = storage
+            .read_piece(piece_num)
+            .map_err(|err| invalid(err.to_string()))?
+            .ok_or(invalid("Piece is not stored".to_owned()))?;
+        check_merkle(metainfo, piece_num, &bytes)?;
+    }
+    Ok(())
+}
+
+fn check_sha1(
+    metainfo: &MetaInfo,
+    piece_num: usize,
+    actual_bytes: [u8; INFO_HASH_SIZE],
+) -> Result<(), DownloadError> {
+    if let Some(expected_bytes) = metainfo.pieces().get(piece_num) {
+        if actual_bytes != *expected_bytes {
             return Err(DownloadError::InvalidPiece {
                 piece_num,
                 reason: format!(
@@ -274,25 +1346,24 @@ impl Downloader {
                 ),
             });
         }
-        piece.0 = true;
-        Ok(piece_bytes)
     }
+    Ok(())
 }
 
-async fn verify_incoming_message(
-    stream: &mut PeerBufferStream,
-    expected: PeerMessageId,
-) -> Result<PeerMessage> {
-    let message = stream.read_message().await?;
-    if message.id == expected {
-        Ok(message)
-    } else {
-        Err(anyhow!(
-            "Did not get a {} message; got {}",
-            stringify!(expected),
-            stringify!(message.id)
-        ))
+fn check_merkle(
+    metainfo: &MetaInfo,
+    piece_num: usize,
+    piece_bytes: &[u8],
+) -> Result<(), DownloadError> {
+    if let Some(v2) = metainfo.v2() {
+        if !v2.verify_piece(piece_num, piece_bytes) {
+            return Err(DownloadError::InvalidPiece {
+                piece_num,
+                reason: "Merkle tree hashes did not match".to_owned(),
+            });
+        }
     }
+    Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -305,4 +1376,6 @@ pub enum PeerClientError {
 pub enum DownloadError {
     #[error("Error downloading piece {piece_num:?}: {reason:?}")]
     InvalidPiece { piece_num: usize, reason: String },
+    #[error("Nothing left to download the remaining pieces from: {0}")]
+    NoSource(String),
 }
@@ -0,0 +1,804 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    sync::{futures::Notified, mpsc, Notify},
+    time::MissedTickBehavior,
+};
+
+use super::{
+    extension::{
+        join_extended, split_extended, ExtendedHandshake, EXTENDED_HANDSHAKE_ID, LOCAL_UT_PEX_ID,
+        UT_PEX,
+    },
+    manager::ConnectionManager,
+    message::{BlockRequest, MessageReceiver, PeerBufferStream, PeerMessageId, PeerWriter},
+    pex::{PexFlags, PexMessage, PexState, PEX_INTERVAL},
+    pool::PeerPool,
+    stream::PieceTracker,
+};
+use crate::{
+    handshake::{self, Handshake},
+    mse::EncryptionPolicy,
+    progress::{ProgressEvent, ProgressSender},
+    ratelimit::TorrentLimits,
+    torrent::MetaInfo,
+    transport::Transport,
+    INFO_HASH_SIZE, PEER_ID_SIZE,
+};
+
+/// Requests rejected in a row while unchoked after which a peer is given up on
+const MAX_REJECTIONS: usize = 3;
+
+/// A piece being downloaded
+#[derive(Debug)]
+struct OpenPiece {
+    piece: usize,
+    /// Blocks nobody asked for yet
+    pending: VecDeque<BlockRequest>,
+    /// Set once the piece failed the hash check too often to keep sharing it between peers
+    parole: Option<Parole>,
+}
+
+/// A piece that is downloaded whole from one peer at a time, so that the next failure has a
+/// single sender to blame
+#[derive(Debug, Default)]
+struct Parole {
+    /// The peer downloading the piece right now
+    owner: Option<SocketAddr>,
+    /// Peers that sent blocks of a version that failed, left to the others
+    suspects: HashSet<SocketAddr>,
+}
+
+impl OpenPiece {
+    /// Whether `peer` may ask for blocks of the piece
+    fn admits(&self, peer: SocketAddr) -> bool {
+        match &self.parole {
+            None => true,
+            Some(parole) => match parole.owner {
+                Some(owner) => owner == peer,
+                None => !parole.suspects.contains(&peer),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    /// Pieces being downloaded in the order they were opened. A piece stays open until it
+    /// verifies
+    open: Vec<OpenPiece>,
+    /// Set once the download is over, which ends its connections
+    closed: bool,
+}
+
+impl QueueState {
+    fn get_mut(&mut self, piece: usize) -> Option<&mut OpenPiece> {
+        self.open.iter_mut().find(|open| open.piece == piece)
+    }
+
+    fn is_open(&self, piece: usize) -> bool {
+        self.open.iter().any(|open| open.piece == piece)
+    }
+}
+
+/// The blocks of a download that its connections ask their peers for
+///
+/// Pieces are opened as connections run out of blocks to ask for, each connection only taking
+/// blocks of the pieces its peer has. Which piece gets opened next is up to the tracker, so that
+/// file priorities and the position of readers keep deciding the order.
+#[derive(Debug)]
+pub struct BlockQueue {
+    tracker: Arc<PieceTracker>,
+    piece_sizes: Vec<u32>,
+    block_size: u32,
+    state: Mutex<QueueState>,
+    /// Woken when blocks come back to the queue or the download ends
+    changed: Notify,
+}
+
+impl BlockQueue {
+    pub fn new(tracker: Arc<PieceTracker>, metainfo: &MetaInfo, block_size: u32) -> Self {
+        let piece_sizes = (0..metainfo.num_pieces())
+            .map(|piece| metainfo.piece_size(piece).unwrap_or_default() as u32)
+            .collect();
+        Self {
+            tracker,
+            piece_sizes,
+            block_size,
+            state: Mutex::new(QueueState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().expect("Poisoned lock")
+    }
+
+    #[inline]
+    pub fn tracker(&self) -> &Arc<PieceTracker> {
+        &self.tracker
+    }
+
+    #[inline]
+    pub fn num_pieces(&self) -> usize {
+        self.piece_sizes.len()
+    }
+
+    /// Blocks making up `piece`
+    pub fn num_blocks(&self, piece: usize) -> usize {
+        let size = self.piece_sizes.get(piece).copied().unwrap_or_default();
+        ((size + self.block_size - 1) / self.block_size) as usize
+    }
+
+    fn blocks(&self, piece: usize) -> VecDeque<BlockRequest> {
+        let size = self.piece_sizes[piece];
+        (0..self.num_blocks(piece) as u32)
+            .map(|block| BlockRequest {
+                index: piece as u32,
+                begin: block * self.block_size,
+                length: self.block_size.min(size - block * self.block_size),
+            })
+            .collect()
+    }
+
+    /// The next block to ask `peer` for, which has the pieces `have` says, out of the pieces
+    /// `accept` takes. Blocks of open pieces come first, then the next piece the tracker picks
+    /// gets opened
+    pub fn next_request(
+        &self,
+        peer: SocketAddr,
+        have: &[bool],
+        accept: impl Fn(usize) -> bool,
+    ) -> Option<BlockRequest> {
+        let has = |piece: usize| have.get(piece).copied().unwrap_or(false) && accept(piece);
+        let mut state = self.state();
+        if state.closed {
+            return None;
+        }
+        if let Some(open) = state
+            .open
+            .iter_mut()
+            .find(|open| !open.pending.is_empty() && has(open.piece) && open.admits(peer))
+        {
+            if let Some(parole) = &mut open.parole {
+                parole.owner = Some(peer);
+            }
+            return open.pending.pop_front();
+        }
+        let piece = {
+            let state = &*state;
+            self.tracker
+                .next_piece_where(|piece| has(piece) && !state.is_open(piece))?
+        };
+        let mut pending = self.blocks(piece);
+        let request = pending.pop_front();
+        state.open.push(OpenPiece {
+            piece,
+            pending,
+            parole: None,
+        });
+        request
+    }
+
+    /// Opens the next piece the tracker picks, or one left with blocks nobody asked for, as a
+    /// whole, for something other than a peer connection to download
+    pub fn take_piece(&self) -> Option<usize> {
+        let mut state = self.state();
+        if let Some(open) = state.open.iter_mut().find(|open| !open.pending.is_empty()) {
+            open.pending.clear();
+            open.parole = None;
+            return Some(open.piece);
+        }
+        let piece = {
+            let state = &*state;
+            self.tracker
+                .next_piece_where(|piece| !state.is_open(piece))?
+        };
+        state.open.push(OpenPiece {
+            piece,
+            pending: VecDeque::new(),
+            parole: None,
+        });
+        Some(piece)
+    }
+
+    /// Puts blocks asked for but never received back in the queue
+    pub fn requeue(&self, requests: impl IntoIterator<Item = BlockRequest>) {
+        let mut state = self.state();
+        let mut requeued = false;
+        for request in requests {
+            if let Some(open) = state.get_mut(request.index as usize) {
+                open.pending.push_front(request);
+                requeued = true;
+            }
+        }
+        drop(state);
+        if requeued {
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Hands the pieces on parole that `peer` was downloading to someone else, from the start
+    pub fn release(&self, peer: SocketAddr) {
+        let mut released = false;
+        for open in &mut self.state().open {
+            if let Some(parole) = open
+                .parole
+                .as_mut()
+                .filter(|parole| parole.owner == Some(peer))
+            {
+                parole.owner = None;
+                open.pending = self.blocks(open.piece);
+                released = true;
+            }
+        }
+        if released {
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Queues every block of an open piece again after it failed the hash check. With
+    /// `suspects`, the piece goes on parole: it is downloaded whole by one peer at a time, and
+    /// none of the suspects while another peer may take it
+    pub fn reopen(&self, piece: usize, suspects: Option<HashSet<SocketAddr>>) {
+        let blocks = self.blocks(piece);
+        if let Some(open) = self.state().get_mut(piece) {
+            open.pending = blocks;
+            if let Some(suspects) = suspects {
+                open.parole = Some(Parole {
+                    owner: None,
+                    suspects,
+                });
+            }
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Lets the suspects of the pieces on parole that nobody took have a go at them, one at a
+    /// time, for when no other peer is left to take them
+    pub fn pardon(&self) {
+        for open in &mut self.state().open {
+            if let Some(parole) = open.parole.as_mut().filter(|parole| parole.owner.is_none()) {
+                parole.suspects.clear();
+            }
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Closes a piece that verified. The tracker is told first, so that it isn't opened again
+    pub fn piece_done(&self, piece: usize) {
+        self.state().open.retain(|open| open.piece != piece);
+    }
+
+    /// Whether blocks of `piece` from `peer` are wanted: the piece must be open, and when it is
+    /// on parole `peer` must be the one downloading it
+    pub fn accepts(&self, piece: usize, peer: SocketAddr) -> bool {
+        self.state().open.iter().any(|open| {
+            open.piece == piece
+                && open
+                    .parole
+                    .as_ref()
+                    .map_or(true, |parole| parole.owner == Some(peer))
+        })
+    }
+
+    /// Whether `piece` is on parole, downloaded from one peer at a time
+    pub fn is_on_parole(&self, piece: usize) -> bool {
+        self.state()
+            .open
+            .iter()
+            .any(|open| open.piece == piece && open.parole.is_some())
+    }
+
+    /// Whether no piece is being downloaded
+    #[inline]
+    pub fn is_idle(&self) -> bool {
+        self.state().open.is_empty()
+    }
+
+    /// Whether a peer having the pieces `have` says has any we still want
+    pub fn wants_any(&self, have: &[bool]) -> bool {
+        self.tracker
+            .missing()
+            .into_iter()
+            .any(|piece| have.get(piece).copied().unwrap_or(false))
+    }
+
+    /// Ends the connections taking blocks off the queue
+    pub fn close(&self) {
+        self.state().closed = true;
+        self.changed.notify_waiters();
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    /// Completes once blocks come back to the queue or it closes, counting from the call
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+}
+
+/// A block as it came from a peer
+#[derive(Debug, Clone)]
+pub struct ReceivedBlock {
+    pub request: BlockRequest,
+    pub data: Vec<u8>,
+    pub peer: SocketAddr,
+}
+
+/// Everything the connections of a download need, shared between the tasks running them
+#[derive(Debug)]
+pub struct PeerContext {
+    pub info_hash: [u8; INFO_HASH_SIZE],
+    pub peer_id: [u8; PEER_ID_SIZE],
+    pub port: u16,
+    /// Whether peers are exchanged over `ut_pex`
+    pub pex: bool,
+    pub encryption: EncryptionPolicy,
+    pub transport: Transport,
+    /// Requests kept outstanding on each connection
+    pub pipeline_depth: usize,
+    pub connect_timeout: Duration,
+    /// How long a peer may stay silent while requests are outstanding
+    pub request_timeout: Duration,
+    pub peers: PeerPool,
+    /// What each peer has been told over `ut_pex` so far
+    pub pex_states: Arc<Mutex<HashMap<SocketAddr, PexState>>>,
+    pub limits: TorrentLimits,
+    pub events: Option<ProgressSender>,
+    /// Peers we are connected to right now
+    pub connected: Arc<AtomicUsize>,
+    pub manager: ConnectionManager,
+}
+
+impl PeerContext {
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(events) = &self.events {
+            // Nobody listening any more is fine
+            let _ = events.send(event);
+        }
+    }
+
+    /// Handles an extension message, remembering in `pex_id` the id the peer gave `ut_pex` in
+    /// its extended handshake
+    async fn handle_extended(
+        &self,
+        stream: &mut PeerWriter,
+        peer: SocketAddr,
+        payload: &[u8],
+        pex_id: &mut Option<u8>,
+    ) -> Result<()> {
+        if !self.pex {
+            return Ok(());
+        }
+        let (id, payload) = split_extended(payload)?;
+        match id {
+            EXTENDED_HANDSHAKE_ID => {
+                let ext_hand = ExtendedHandshake::from_bytes(payload)?;
+                *pex_id = ext_hand.extension_id(UT_PEX);
+                if let Some(pex_id) = *pex_id {
+                    self.send_pex(stream, peer, pex_id).await?;
+                }
+            }
+            LOCAL_UT_PEX_ID => {
+                let message = PexMessage::from_bytes(payload)?;
+                self.peers.add_pex(peer, &message);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tells the peer over `ut_pex` which peers came and went since it was last told, unless
+    /// that was less than [`PEX_INTERVAL`] ago
+    async fn send_pex(&self, stream: &mut PeerWriter, peer: SocketAddr, pex_id: u8) -> Result<()> {
+        let message = self
+            .pex_states
+            .lock()
+            .expect("Poisoned lock")
+            .entry(peer)
+            .or_default()
+            .next_message(&self.peers.pex_view(), peer);
+        if let Some(message) = message {
+            stream
+                .write_message(
+                    PeerMessageId::Extended,
+                    &join_extended(pex_id, &message.to_bytes()),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports the connection as gone when dropped, including when its task is aborted
+struct Connected<'a> {
+    ctx: &'a PeerContext,
+    peer: SocketAddr,
+    error: Option<String>,
+}
+
+impl<'a> Connected<'a> {
+    fn new(ctx: &'a PeerContext, peer: SocketAddr) -> Self {
+        ctx.connected.fetch_add(1, Ordering::Relaxed);
+        ctx.emit(ProgressEvent::PeerConnected { peer });
+        Self {
+            ctx,
+            peer,
+            error: None,
+        }
+    }
+}
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        self.ctx.connected.fetch_sub(1, Ordering::Relaxed);
+        self.ctx.emit(ProgressEvent::PeerDisconnected {
+            peer: self.peer,
+            error: self.error.take(),
+        });
+    }
+}
+
+/// Requests sent and not answered yet, which go back in the queue however the connection ends,
+/// including when it is aborted
+struct Outstanding<'a> {
+    queue: &'a BlockQueue,
+    peer: SocketAddr,
+    requests: VecDeque<BlockRequest>,
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.queue.requeue(self.requests.drain(..));
+        self.queue.release(self.peer);
+    }
+}
+
+/// Connects to `peer` and downloads from it for as long as the download lasts, asking for
+/// blocks of the pieces it has off the shared queue and sending each block received to
+/// `blocks`. Blocks asked for but not received go back in the queue for another connection to
+/// take. Returns once the queue closes
+pub async fn download_from(
+    ctx: Arc<PeerContext>,
+    peer: SocketAddr,
+    queue: Arc<BlockQueue>,
+    blocks: mpsc::UnboundedSender<ReceivedBlock>,
+) -> Result<()> {
+    let self_hand = Handshake::new(&ctx.info_hash, &ctx.peer_id)
+        .with_extensions()
+        .with_fast();
+    let (stream, peer_hand) = tokio::time::timeout(
+        ctx.connect_timeout,
+        handshake::connect_encrypted(peer, self_hand, ctx.encryption, &ctx.transport),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out connecting"))??;
+    let mut flags = PexFlags::REACHABLE;
+    if stream.is_encrypted() {
+        flags = flags.with(PexFlags::ENCRYPTION);
+    }
+    ctx.peers.set_flags(peer, flags);
+    ctx.peers.set_peer_id(peer, *peer_hand.peer_id());
+    let (download, upload) = ctx.limits.connection();
+    let (reader, mut writer) = PeerBufferStream::from_stream(stream)
+        .with_throttles(download, upload)
+        .into_split();
+    // Messages are read in the background so that waiting on them can be given up on
+    let mut messages = reader.into_receiver();
+    let mut connected = Connected::new(&ctx, peer);
+    let mut outstanding = Outstanding {
+        queue: &queue,
+        peer,
+        requests: VecDeque::new(),
+    };
+    let result = request_blocks(
+        &ctx,
+        &mut writer,
+        &mut messages,
+        peer,
+        &peer_hand,
+        &queue,
+        &blocks,
+        &mut outstanding.requests,
+    )
+    .await;
+    connected.error = result.as_ref().err().map(ToString::to_string);
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn request_blocks(
+    ctx: &PeerContext,
+    writer: &mut PeerWriter,
+    messages: &mut MessageReceiver,
+    peer: SocketAddr,
+    peer_hand: &Handshake,
+    queue: &BlockQueue,
+    blocks: &mpsc::UnboundedSender<ReceivedBlock>,
+    outstanding: &mut VecDeque<BlockRequest>,
+) -> Result<()> {
+    if peer_hand.supports_extensions() {
+        let mut ext_hand = ExtendedHandshake::local(ctx.port);
+        if !ctx.pex {
+            ext_hand.extensions.remove(UT_PEX);
+        }
+        writer
+            .write_message(
+                PeerMessageId::Extended,
+                &join_extended(EXTENDED_HANDSHAKE_ID, &ext_hand.to_bytes()),
+            )
+            .await?;
+    }
+    if peer_hand.supports_fast() {
+        // With the Fast Extension something must stand in for the bitfield we don't send
+        writer.write_message(PeerMessageId::HaveNone, &[]).await?;
+    }
+    let mut have = vec![false; queue.num_pieces()];
+    let (mut choked, mut interested) = (true, false);
+    let mut allowed_fast = HashSet::new();
+    let mut rejections = 0;
+    let mut delivered = false;
+    // Known once the peer's extended handshake says it takes `ut_pex`
+    let mut pex_id = None;
+    let mut pex_tick = tokio::time::interval(PEX_INTERVAL);
+    pex_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let changed = queue.changed();
+        // Allowed fast pieces can be requested before the peer unchokes us
+        while outstanding.len() < ctx.pipeline_depth {
+            let Some(request) = queue.next_request(peer, &have, |piece| {
+                !choked || allowed_fast.contains(&piece)
+            }) else {
+                break;
+            };
+            writer
+                .write_message(PeerMessageId::Request, &request.to_bytes())
+                .await?;
+            outstanding.push_back(request);
+        }
+        if queue.is_closed() {
+            return Ok(());
+        }
+        // Interest follows whether the peer has anything left that we want
+        let wanted = !outstanding.is_empty() || queue.wants_any(&have);
+        if wanted != interested {
+            interested = wanted;
+            let id = if wanted {
+                PeerMessageId::Interested
+            } else {
+                PeerMessageId::NotInterested
+            };
+            writer.write_message(id, &[]).await?;
+        }
+        // A peer that was asked for something must answer in time. One whose pieces are all
+        // being fetched from others may stay quiet until blocks come back to the queue
+        let idle = interested && !choked && outstanding.is_empty();
+        let timeout = tokio::time::sleep(ctx.request_timeout);
+        tokio::pin!(changed, timeout);
+        // Peers are exchanged while waiting, without restarting the wait
+        let message = loop {
+            tokio::select! {
+                message = messages.recv() => break Some(message),
+                _ = &mut changed, if idle => break None,
+                _ = &mut timeout, if !idle => {
+                    return Err(if interested {
+                        anyhow!("Peer sent nothing for {:?}", ctx.request_timeout)
+                    } else {
+                        anyhow!("Peer has none of the pieces we want")
+                    });
+                }
+                _ = pex_tick.tick(), if pex_id.is_some() => {
+                    ctx.send_pex(writer, peer, pex_id.expect("Checked by the guard")).await?;
+                }
+            }
+        };
+        let Some(message) = message else {
+            continue;
+        };
+        let message = message?;
+        match message.id {
+            PeerMessageId::Unchoke => choked = false,
+            PeerMessageId::Choke => {
+                choked = true;
+                // Without the Fast Extension a choke silently drops every pending request,
+                // with it each one gets rejected explicitly
+                if !peer_hand.supports_fast() {
+                    queue.requeue(outstanding.drain(..));
+                }
+            }
+            PeerMessageId::Have => {
+                if let Some(has) = have.get_mut(piece_index(&message.payload)? as usize) {
+                    *has = true;
+                }
+            }
+            PeerMessageId::Bitfield => have = parse_bitfield(&message.payload, have.len())?,
+            PeerMessageId::HaveAll => have.fill(true),
+            PeerMessageId::HaveNone => have.fill(false),
+            PeerMessageId::AllowedFast => {
+                allowed_fast.insert(piece_index(&message.payload)? as usize);
+            }
+            PeerMessageId::RejectRequest => {
+                let rejected = BlockRequest::from_bytes(&message.payload)?;
+                if let Some(at) = outstanding.iter().position(|request| *request == rejected) {
+                    // Requeued right away instead of waiting for the peer to time out
+                    outstanding.remove(at);
+                    queue.requeue([rejected]);
+                    // Requests pending at a choke are all rejected, which is no fault
+                    if !choked {
+                        rejections += 1;
+                        if rejections > MAX_REJECTIONS {
+                            return Err(anyhow!("Peer rejected {rejections} requests in a row"));
+                        }
+                    }
+                }
+            }
+            PeerMessageId::Piece => {
+                let header = message
+                    .payload
+                    .get(..8)
+                    .ok_or(anyhow!("Couldn't get resp index!"))?;
+                let index = u32::from_be_bytes(header[..4].try_into().expect("Sliced 4 bytes"));
+                let begin = u32::from_be_bytes(header[4..].try_into().expect("Sliced 4 bytes"));
+                let block = &message.payload[8..];
+                // A block requested before a choke may still come after it was requeued
+                let Some(at) = outstanding
+                    .iter()
+                    .position(|request| request.index == index && request.begin == begin)
+                else {
+                    continue;
+                };
+                let request = outstanding[at];
+                if block.len() != request.length as usize {
+                    return Err(anyhow!(
+                        "Did not download the correct block length! Got {} but expected {}",
+                        block.len(),
+                        request.length
+                    ));
+                }
+                outstanding.remove(at);
+                rejections = 0;
+                if !delivered {
+                    delivered = true;
+                    ctx.manager.record_success(peer);
+                }
+                // Nobody waiting for blocks any more means the download is over
+                if blocks
+                    .send(ReceivedBlock {
+                        request,
+                        data: block.to_vec(),
+                        peer,
+                    })
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            PeerMessageId::Extended => {
+                ctx.handle_extended(writer, peer, &message.payload, &mut pex_id)
+                    .await?
+            }
+            // Requests and interest don't matter to a connection that only downloads
+            _ => {}
+        }
+        if matches!(
+            message.id,
+            PeerMessageId::Bitfield | PeerMessageId::HaveAll | PeerMessageId::HaveNone
+        ) && !queue.wants_any(&have)
+        {
+            return Err(anyhow!("Peer has none of the pieces we want"));
+        }
+    }
+}
+
+/// The piece index `Have` and `AllowedFast` messages carry
+fn piece_index(payload: &[u8]) -> Result<u32> {
+    payload
+        .get(..4)
+        .and_then(|bytes| <[u8; 4]>::try_from(bytes).ok())
+        .map(u32::from_be_bytes)
+        .ok_or(anyhow!("Message was too short for a piece index"))
+}
+
+/// Which of `num_pieces` pieces a `Bitfield` message says the peer has, the first piece being
+/// the high bit of the first byte
+pub fn parse_bitfield(payload: &[u8], num_pieces: usize) -> Result<Vec<bool>> {
+    if payload.len() != (num_pieces + 7) / 8 {
+        return Err(anyhow!(
+            "Bitfield of {} bytes for {num_pieces} pieces",
+            payload.len()
+        ));
+    }
+    Ok((0..num_pieces)
+        .map(|piece| payload[piece / 8] & (0x80 >> (piece % 8)) != 0)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs, net::SocketAddr, sync::Arc};
+
+    use reqwest::Url;
+
+    use super::{parse_bitfield, BlockQueue};
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::{
+            picker::{FileSelection, PiecePicker},
+            stream::PieceTracker,
+        },
+    };
+
+    #[test]
+    fn test_queue_only_hands_out_pieces_the_peer_has() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("file");
+        let data = (0..3 * MIN_PIECE_LENGTH as u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let picker = PiecePicker::new(&metainfo, &FileSelection::all(&metainfo));
+        let tracker = Arc::new(PieceTracker::new(picker, metainfo.num_pieces()));
+        let block_size = MIN_PIECE_LENGTH as u32 / 2;
+        let queue = BlockQueue::new(tracker.clone(), &metainfo, block_size);
+
+        let peer = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        let have = parse_bitfield(&[0b0100_0000], 3).unwrap();
+        assert_eq!(have, [false, true, false]);
+        assert!(parse_bitfield(&[0, 0], 3).is_err());
+        let first = queue.next_request(peer, &have, |_| true).unwrap();
+        let second = queue.next_request(peer, &have, |_| true).unwrap();
+        assert_eq!((first.index, first.begin), (1, 0));
+        assert_eq!((second.index, second.begin), (1, block_size));
+        // Every block of the piece is asked for and the peer has nothing else
+        assert!(queue.next_request(peer, &have, |_| true).is_none());
+        assert!(queue
+            .next_request(peer, &[true; 3], |piece| piece == 1)
+            .is_none());
+
+        queue.requeue([second]);
+        assert_eq!(queue.next_request(peer, &[true; 3], |_| true), Some(second));
+        tracker.piece_done(1);
+        queue.piece_done(1);
+        assert!(!queue.wants_any(&have));
+        assert_eq!(
+            queue
+                .next_request(peer, &[true; 3], |_| true)
+                .unwrap()
+                .index,
+            0
+        );
+
+        // On parole the piece goes whole to one peer that didn't send a failed version
+        let other = "10.0.0.2:6881".parse::<SocketAddr>().unwrap();
+        queue.reopen(0, Some(HashSet::from([peer])));
+        let only = [true, false, false];
+        assert!(queue.next_request(peer, &only, |_| true).is_none());
+        assert_eq!(queue.next_request(other, &only, |_| true).unwrap().index, 0);
+        assert!(queue.accepts(0, other) && !queue.accepts(0, peer));
+        queue.release(other);
+        assert!(queue.next_request(peer, &only, |_| true).is_none());
+        queue.pardon();
+        assert_eq!(queue.next_request(peer, &only, |_| true).unwrap().begin, 0);
+
+        queue.close();
+        assert!(queue.next_request(peer, &[true; 3], |_| true).is_none());
+    }
+}
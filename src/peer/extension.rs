@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use serde_bencode::value::Value;
+
+use crate::ParseError;
+
+/// Extended message id of the extension handshake itself
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+pub const UT_PEX: &str = "ut_pex";
+/// Id we ask peers to use when sending us `ut_pex` messages
+pub const LOCAL_UT_PEX_ID: u8 = 1;
+
+/// The handshake of the extension protocol (BEP 10), sent as the first extended message
+///
+/// `m` maps the names of the extensions a peer supports to the extended message ids it wants to
+/// receive them with. An id of 0 means the extension is disabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    pub extensions: HashMap<String, u8>,
+    /// The TCP port the peer listens on
+    pub listen_port: Option<u16>,
+    /// Name and version of the peer's client
+    pub client: Option<String>,
+    /// Size of the info dictionary, sent by peers able to hand it out over `ut_metadata`
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    /// The handshake advertising every extension this crate implements
+    pub fn local(listen_port: u16) -> Self {
+        Self {
+            extensions: HashMap::from([(UT_PEX.to_owned(), LOCAL_UT_PEX_ID)]),
+            listen_port: Some(listen_port),
+            client: Some(
+                concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_owned(),
+            ),
+            metadata_size: None,
+        }
+    }
+
+    /// The id to send an extension's messages with, if the peer supports it
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Int(i64::from(*id))))
+            .collect();
+        let mut dict = HashMap::new();
+        dict.insert(b"m".to_vec(), Value::Dict(m));
+        if let Some(port) = self.listen_port {
+            dict.insert(b"p".to_vec(), Value::Int(i64::from(port)));
+        }
+        if let Some(client) = &self.client {
+            dict.insert(b"v".to_vec(), Value::Bytes(client.as_bytes().to_vec()));
+        }
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), Value::Int(size as i64));
+        }
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("A Value always serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let Value::Dict(dict) = serde_bencode::from_bytes::<Value>(bytes)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?
+        else {
+            return Err(ParseError::Deserialization(
+                "Extended handshake did not deserialize into a dictionary".to_owned(),
+            ));
+        };
+        let extensions = match dict.get("m".as_bytes()) {
+            Some(Value::Dict(m)) => m
+                .iter()
+                .filter_map(|(name, id)| match id {
+                    Value::Int(id) => Some((
+                        String::from_utf8_lossy(name).into_owned(),
+                        u8::try_from(*id).ok()?,
+                    )),
+                    _ => None,
+                })
+                .collect(),
+            _ => return Err(ParseError::MissingField("m".to_string())),
+        };
+        let listen_port = match dict.get("p".as_bytes()) {
+            Some(Value::Int(port)) => u16::try_from(*port).ok(),
+            _ => None,
+        };
+        let client = match dict.get("v".as_bytes()) {
+            Some(Value::Bytes(client)) => Some(String::from_utf8_lossy(client).into_owned()),
+            _ => None,
+        };
+        let metadata_size = match dict.get("metadata_size".as_bytes()) {
+            Some(Value::Int(size)) => u64::try_from(*size).ok(),
+            _ => None,
+        };
+        Ok(Self {
+            extensions,
+            listen_port,
+            client,
+            metadata_size,
+        })
+    }
+}
+
+/// Splits the payload of an `Extended` peer message into its extended message id and the
+/// payload of the extension
+pub fn split_extended(payload: &[u8]) -> Result<(u8, &[u8]), ParseError> {
+    payload
+        .split_first()
+        .map(|(id, payload)| (*id, payload))
+        .ok_or(ParseError::Deserialization(
+            "Extended message had no extended message id".to_owned(),
+        ))
+}
+
+/// Builds the payload of an `Extended` peer message
+pub fn join_extended(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 1);
+    bytes.push(id);
+    bytes.extend_from_slice(payload);
+    bytes
+}
@@ -0,0 +1,61 @@
+use std::net::Ipv4Addr;
+
+use sha1::{Digest, Sha1};
+
+use crate::INFO_HASH_SIZE;
+
+/// Number of allowed fast pieces given to each peer
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// The canonical allowed fast set of BEP 6: the `k` pieces a peer at `ip` may request while
+/// choked, derived from its /24 network and the info hash so that every client picks the same
+/// ones and a peer can't get more by reconnecting from nearby addresses
+pub fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &[u8; INFO_HASH_SIZE],
+    num_pieces: u32,
+    k: usize,
+) -> Vec<u32> {
+    let mut set = Vec::with_capacity(k);
+    if num_pieces == 0 {
+        return set;
+    }
+    let k = k.min(num_pieces as usize);
+    let mut x = (u32::from(ip) & 0xFFFF_FF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let index =
+                u32::from_be_bytes(chunk.try_into().expect("Chunks of 4 bytes")) % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::allowed_fast_set;
+
+    #[test]
+    fn test_reference_vectors() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            vec![1059, 431, 808, 1217, 287, 376, 1188],
+            allowed_fast_set(ip, &info_hash, 1313, 7)
+        );
+        assert_eq!(
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508],
+            allowed_fast_set(ip, &info_hash, 1313, 9)
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{sync::Notify, task::AbortHandle};
+
+use super::pool::PeerPool;
+
+/// Connections a torrent keeps open at once by default
+pub const DEFAULT_CONNECTIONS_PER_TORRENT: usize = 4;
+/// Wait before the first retry of a peer that failed
+pub const BASE_BACKOFF: Duration = Duration::from_secs(2);
+/// Longest wait before retrying a peer, however often it failed
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Pieces failing the hash check a peer may send before it is banned
+pub const DEFAULT_BAN_THRESHOLD: u32 = 2;
+
+/// What is known about the connections made to a peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub attempts: u32,
+    pub failures: u32,
+    /// Failures since the last connection that went well, which the backoff grows with
+    pub consecutive_failures: u32,
+    /// Pieces the peer sent that failed the hash check
+    pub hash_failures: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct PeerRecord {
+    stats: PeerStats,
+    /// When the peer may be connected to again after failing
+    retry_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct ManagerState {
+    records: HashMap<SocketAddr, PeerRecord>,
+    /// Peers connected or being connected to
+    connected: HashSet<SocketAddr>,
+    /// Every port of a banned peer is banned
+    banned: HashSet<IpAddr>,
+    /// Tasks of the connections started through [`ConnectionManager::spawn`], including some
+    /// that ended since
+    tasks: Vec<(SocketAddr, AbortHandle)>,
+    /// Why the last connection that failed did
+    last_error: Option<String>,
+    max_connections: usize,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    ban_threshold: u32,
+}
+
+/// Decides which peers of a torrent get connected to: at most a set number at once, none that
+/// is banned and none still backing off after failing. Failed peers are retried after a jittered
+/// exponential delay
+///
+/// The connections themselves run on tasks the manager keeps for as long as they last, which
+/// for a download is until it ends. Banning a peer closes its connections.
+#[derive(Debug, Clone)]
+pub struct ConnectionManager {
+    state: Arc<Mutex<ManagerState>>,
+    /// Woken when a connection ends and frees its slot
+    ended: Arc<Notify>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONNECTIONS_PER_TORRENT)
+    }
+}
+
+impl ConnectionManager {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ManagerState {
+                records: HashMap::new(),
+                connected: HashSet::new(),
+                banned: HashSet::new(),
+                tasks: Vec::new(),
+                last_error: None,
+                max_connections,
+                base_backoff: BASE_BACKOFF,
+                max_backoff: MAX_BACKOFF,
+                ban_threshold: DEFAULT_BAN_THRESHOLD,
+            })),
+            ended: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Sets the delay before the first retry of a failed peer and the longest delay it grows to
+    pub fn with_backoff(self, base_backoff: Duration, max_backoff: Duration) -> Self {
+        {
+            let mut state = self.state();
+            state.base_backoff = base_backoff;
+            state.max_backoff = max_backoff;
+        }
+        self
+    }
+
+    /// Sets how many pieces failing the hash check get a peer banned
+    pub fn with_ban_threshold(self, ban_threshold: u32) -> Self {
+        self.state().ban_threshold = ban_threshold;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, ManagerState> {
+        self.state.lock().expect("Poisoned lock")
+    }
+
+    #[inline]
+    pub fn max_connections(&self) -> usize {
+        self.state().max_connections
+    }
+
+    /// Peers connected or being connected to
+    #[inline]
+    pub fn num_connected(&self) -> usize {
+        self.state().connected.len()
+    }
+
+    /// Takes up to `wanted` peers of the pool to connect to, as many as the free slots allow, in
+    /// the order the pool tries them. Each one must be handed back with [`Self::release`]
+    pub fn pick(&self, pool: &PeerPool, wanted: usize) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let mut state = self.state();
+        let free = state.max_connections.saturating_sub(state.connected.len());
+        let picked = pool
+            .peers()
+            .into_iter()
+            .filter(|addr| state.is_available(addr, now))
+            .take(wanted.min(free))
+            .collect::<Vec<_>>();
+        for addr in &picked {
+            state.connected.insert(*addr);
+            state.records.entry(*addr).or_default().stats.attempts += 1;
+        }
+        picked
+    }
+
+    /// When the first peer of the pool that is backing off may be retried
+    pub fn next_retry(&self, pool: &PeerPool) -> Option<Instant> {
+        let state = self.state();
+        pool.peers()
+            .into_iter()
+            .filter(|addr| !state.connected.contains(addr) && !state.banned.contains(&addr.ip()))
+            .filter_map(|addr| state.records.get(&addr)?.retry_at)
+            .min()
+    }
+
+    /// Frees the slot of a peer picked earlier
+    pub fn release(&self, addr: &SocketAddr) {
+        self.state().connected.remove(addr);
+        self.ended.notify_one();
+    }
+
+    /// Runs the connection to `addr`, a peer taken with [`Self::pick`], on a task of its own. Once
+    /// the connection ends its outcome is recorded and its slot freed, whether it returned,
+    /// panicked or was aborted
+    pub fn spawn<F>(&self, addr: SocketAddr, connection: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let slot = Slot {
+            manager: self.clone(),
+            addr,
+            result: None,
+        };
+        let task = tokio::spawn(async move {
+            // Moves the whole slot in rather than the one field written. Declared first, it is
+            // dropped last, so whatever the connection cleans up is done before its slot frees
+            let mut slot = slot;
+            let connection = connection;
+            slot.result = Some(connection.await.map_err(|err| err.to_string()));
+        });
+        let mut state = self.state();
+        state.tasks.retain(|(_, task)| !task.is_finished());
+        state.tasks.push((addr, task.abort_handle()));
+    }
+
+    /// Waits until a connection ends
+    pub async fn wait_ended(&self) {
+        self.ended.notified().await;
+    }
+
+    /// Closes every connection started through [`Self::spawn`]; their slots are freed as their
+    /// tasks wind down
+    pub fn disconnect_all(&self) {
+        let tasks = std::mem::take(&mut self.state().tasks);
+        for (_, task) in tasks {
+            task.abort();
+        }
+    }
+
+    /// Closes the connections to every port of `ip`
+    fn disconnect(&self, ip: IpAddr) {
+        let tasks = {
+            let mut state = self.state();
+            let (matching, rest) = std::mem::take(&mut state.tasks)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(addr, _)| addr.ip() == ip);
+            state.tasks = rest;
+            matching
+        };
+        for (_, task) in tasks {
+            task.abort();
+        }
+    }
+
+    /// The connection to the peer went well, so it is no longer backing off
+    pub fn record_success(&self, addr: SocketAddr) {
+        let mut state = self.state();
+        let record = state.records.entry(addr).or_default();
+        record.stats.consecutive_failures = 0;
+        record.retry_at = None;
+    }
+
+    /// The connection to the peer failed, so it isn't retried before the backoff is over
+    pub fn record_failure(&self, addr: SocketAddr, error: impl ToString) {
+        let mut state = self.state();
+        let (base, max) = (state.base_backoff, state.max_backoff);
+        let record = state.records.entry(addr).or_default();
+        record.stats.failures += 1;
+        record.stats.consecutive_failures += 1;
+        let error = error.to_string();
+        record.stats.last_error = Some(error.clone());
+        record.retry_at =
+            Some(Instant::now() + backoff(base, max, record.stats.consecutive_failures));
+        state.last_error = Some(error);
+    }
+
+    /// Why the last connection that failed did, if any has
+    pub fn last_error(&self) -> Option<String> {
+        self.state().last_error.clone()
+    }
+
+    /// The peer sent a piece failing the hash check. Returns whether that got it banned
+    pub fn record_hash_failure(&self, addr: SocketAddr) -> bool {
+        let banned = {
+            let mut state = self.state();
+            let threshold = state.ban_threshold;
+            let record = state.records.entry(addr).or_default();
+            record.stats.hash_failures += 1;
+            record.stats.hash_failures >= threshold && state.banned.insert(addr.ip())
+        };
+        if banned {
+            self.disconnect(addr.ip());
+        }
+        banned
+    }
+
+    /// The peer was caught sending a corrupt block, which bans it right away
+    pub fn record_corrupt(&self, addr: SocketAddr) {
+        {
+            let mut state = self.state();
+            state.records.entry(addr).or_default().stats.hash_failures += 1;
+            state.banned.insert(addr.ip());
+        }
+        self.disconnect(addr.ip());
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        self.state().banned.insert(ip);
+        self.disconnect(ip);
+    }
+
+    #[inline]
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.state().banned.contains(&addr.ip())
+    }
+
+    pub fn stats(&self, addr: &SocketAddr) -> Option<PeerStats> {
+        self.state()
+            .records
+            .get(addr)
+            .map(|record| record.stats.clone())
+    }
+}
+
+/// The slot a connection task holds, handed back however the task ends
+struct Slot {
+    manager: ConnectionManager,
+    addr: SocketAddr,
+    /// Set once the connection returned
+    result: Option<Result<(), String>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        match self.result.take() {
+            Some(Ok(())) => self.manager.record_success(self.addr),
+            Some(Err(err)) => self.manager.record_failure(self.addr, err),
+            // Unwinding out of the connection means it broke, being aborted doesn't
+            None if std::thread::panicking() => self
+                .manager
+                .record_failure(self.addr, "The connection panicked"),
+            None => {}
+        }
+        self.manager.release(&self.addr);
+    }
+}
+
+impl ManagerState {
+    fn is_available(&self, addr: &SocketAddr, now: Instant) -> bool {
+        !self.connected.contains(addr)
+            && !self.banned.contains(&addr.ip())
+            && self
+                .records
+                .get(addr)
+                .and_then(|record| record.retry_at)
+                .map_or(true, |retry_at| retry_at <= now)
+    }
+}
+
+/// Doubles from `base` with every failure up to `max`, then picks a random delay between half
+/// of that and all of it so that peers failing together aren't retried together
+fn backoff(base: Duration, max: Duration, failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    let delay = base.saturating_mul(1 << exponent).min(max);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::{backoff, ConnectionManager};
+    use crate::peer::pool::{PeerPool, PeerSource};
+
+    #[test]
+    fn test_backoff_grows_and_stays_capped() {
+        let (base, max) = (Duration::from_secs(2), Duration::from_secs(60));
+        for failures in 1..10 {
+            let delay = backoff(base, max, failures);
+            let ceiling = (base * 2u32.pow(failures - 1)).min(max);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_picks_within_limits() {
+        let pool = PeerPool::default();
+        let peers = (1..=4)
+            .map(|i| format!("10.0.0.{i}:6881").parse::<SocketAddr>().unwrap())
+            .collect::<Vec<_>>();
+        pool.extend(peers.iter().copied(), PeerSource::Tracker);
+        let manager = ConnectionManager::new(2).with_ban_threshold(1);
+
+        assert_eq!(peers[..2], manager.pick(&pool, 3));
+        assert!(manager.pick(&pool, 3).is_empty());
+        manager.record_failure(peers[0], "refused");
+        manager.release(&peers[0]);
+        // The failed peer backs off, so the next one takes its slot
+        assert_eq!(vec![peers[2]], manager.pick(&pool, 3));
+        assert!(manager.next_retry(&pool).is_some());
+
+        assert!(manager.record_hash_failure(peers[3]));
+        manager.release(&peers[1]);
+        manager.release(&peers[2]);
+        let picked = manager.pick(&pool, 3);
+        assert!(!picked.contains(&peers[3]) && !picked.contains(&peers[0]));
+        let stats = manager.stats(&peers[0]).unwrap();
+        assert_eq!((1, 1), (stats.attempts, stats.failures));
+        assert_eq!(Some("refused".to_owned()), stats.last_error);
+    }
+}
@@ -1,26 +1,163 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
 };
 
-#[derive(Debug)]
+use crate::ratelimit::Throttle;
+
+/// Longest message a peer may send: a `Piece` carrying a block of the largest size requested,
+/// after its id, index and offset
+pub const MAX_MESSAGE_LENGTH: u32 = (1 << 17) + 9;
+
+/// Peer messages over any byte stream: a TCP connection or one wrapped in encryption
 pub struct PeerBufferStream {
-    reader: OwnedReadHalf,
-    writer: OwnedWriteHalf,
+    reader: PeerReader,
+    writer: PeerWriter,
+}
+
+/// The half of a [`PeerBufferStream`] messages are read from
+pub struct PeerReader {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    /// What the bytes read go through
+    download: Throttle,
+}
+
+/// The half of a [`PeerBufferStream`] messages are written to
+pub struct PeerWriter {
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// What the bytes written go through
+    upload: Throttle,
+}
+
+/// Messages a [`PeerReader`] reads on a task of its own, which stops once this is dropped
+#[derive(Debug)]
+pub struct MessageReceiver {
+    messages: mpsc::Receiver<Result<PeerMessage>>,
+    task: JoinHandle<()>,
+}
+
+impl MessageReceiver {
+    /// The next message. Unlike [`PeerReader::read_message`] this is cancel safe, so it can be
+    /// waited on alongside other work
+    pub async fn recv(&mut self) -> Result<PeerMessage> {
+        self.messages
+            .recv()
+            .await
+            .ok_or(anyhow!("Peer connection closed"))?
+    }
+}
+
+impl Drop for MessageReceiver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl std::fmt::Debug for PeerBufferStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerBufferStream").finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for PeerReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerReader").finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for PeerWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerWriter").finish_non_exhaustive()
+    }
 }
 
 impl PeerBufferStream {
-    pub fn new(reader: OwnedReadHalf, writer: OwnedWriteHalf) -> Self {
-        Self { reader, writer }
+    pub fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Self {
+            reader: PeerReader {
+                reader: Box::new(reader),
+                download: Throttle::default(),
+            },
+            writer: PeerWriter {
+                writer: Box::new(writer),
+                upload: Throttle::default(),
+            },
+        }
+    }
+
+    /// Limits the rate messages are read and written at
+    pub fn with_throttles(self, download: Throttle, upload: Throttle) -> Self {
+        let mut s = self;
+        s.reader.download = download;
+        s.writer.upload = upload;
+        s
+    }
+
+    /// Wraps a stream that can't be split into owned halves, like an encrypted one
+    pub fn from_stream(stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self::new(reader, writer)
+    }
+
+    /// Separates reading from writing, so that messages can be read on a task of their own.
+    /// Reading a message is not cancel safe, which rules out waiting on it alongside other work
+    pub fn into_split(self) -> (PeerReader, PeerWriter) {
+        (self.reader, self.writer)
     }
 
+    /// See [`PeerReader::read_message`]
     pub async fn read_message(&mut self) -> Result<PeerMessage> {
-        let length = self.reader.read_u32().await?;
+        self.reader.read_message().await
+    }
+
+    pub async fn write_message(&mut self, id: PeerMessageId, payload: &[u8]) -> Result<()> {
+        self.writer.write_message(id, payload).await
+    }
+}
+
+impl PeerReader {
+    /// Reads messages on a task of their own, handing them over one at a time
+    pub fn into_receiver(self) -> MessageReceiver {
+        let mut reader = self;
+        let (messages_tx, messages) = mpsc::channel(1);
+        let task = tokio::spawn(async move {
+            loop {
+                let message = reader.read_message().await;
+                let failed = message.is_err();
+                if messages_tx.send(message).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        MessageReceiver { messages, task }
+    }
+
+    /// Reads the next message, skipping keep-alives. Messages longer than [`MAX_MESSAGE_LENGTH`]
+    /// are rejected before anything is read or throttled for them
+    pub async fn read_message(&mut self) -> Result<PeerMessage> {
+        let length = loop {
+            let length = self.reader.read_u32().await?;
+            // Keep-alives are a bare length prefix of 0 without an id
+            if length != 0 {
+                break length;
+            }
+            self.download.acquire(4).await;
+        };
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(PeerParseError::Deserialization(format!(
+                "Message of {length} bytes is longer than {MAX_MESSAGE_LENGTH}"
+            ))
+            .into());
+        }
+        self.download.acquire(u64::from(length) + 4).await;
         let id = PeerMessageId::try_from(self.reader.read_u8().await?)?;
-        // TODO: think we need to subtract one byte here probably if message length
-        // prefix contains the id byte too in its length?
+        // The length prefix counts the id as well
         let mut payload = vec![0; usize::try_from(length - 1)?];
         self.reader.read_exact(&mut payload[..]).await?;
         Ok(PeerMessage {
@@ -29,6 +166,9 @@ impl PeerBufferStream {
             payload,
         })
     }
+}
+
+impl PeerWriter {
     pub async fn write_message(&mut self, id: PeerMessageId, payload: &[u8]) -> Result<()> {
         let byte_len = std::mem::size_of::<PeerMessageId>();
         let payload_len = payload.len();
@@ -36,7 +176,10 @@ impl PeerBufferStream {
         let mut buf = message_prefix_length.to_be_bytes().to_vec();
         buf.push(id as u8);
         buf.extend_from_slice(payload);
+        self.upload.acquire(buf.len() as u64).await;
         self.writer.write_all(&buf).await?;
+        // Encrypted streams may hold on to bytes until flushed
+        self.writer.flush().await?;
         Ok(())
     }
 }
@@ -53,6 +196,18 @@ pub enum PeerMessageId {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// Fast Extension (BEP 6): the peer recommends downloading this piece
+    SuggestPiece = 13,
+    /// Fast Extension: replaces the bitfield of a peer having every piece
+    HaveAll = 14,
+    /// Fast Extension: replaces the bitfield of a peer having no piece
+    HaveNone = 15,
+    /// Fast Extension: a request that will not be answered
+    RejectRequest = 16,
+    /// Fast Extension: a piece that may be requested while choked
+    AllowedFast = 17,
+    /// Carries the messages of the extension protocol (BEP 10)
+    Extended = 20,
 }
 
 impl TryFrom<u8> for PeerMessageId {
@@ -68,6 +223,12 @@ impl TryFrom<u8> for PeerMessageId {
             6 => PeerMessageId::Request,
             7 => PeerMessageId::Piece,
             8 => PeerMessageId::Cancel,
+            13 => PeerMessageId::SuggestPiece,
+            14 => PeerMessageId::HaveAll,
+            15 => PeerMessageId::HaveNone,
+            16 => PeerMessageId::RejectRequest,
+            17 => PeerMessageId::AllowedFast,
+            20 => PeerMessageId::Extended,
             _ => {
                 return Err(PeerParseError::Deserialization(format!(
                     "Message {value} is not defined in this implementation!"
@@ -78,6 +239,43 @@ impl TryFrom<u8> for PeerMessageId {
     }
 }
 
+/// The payload of `Request`, `Cancel` and `RejectRequest` messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub const SIZE: usize = 12;
+
+    pub fn to_bytes(&self) -> [u8; BlockRequest::SIZE] {
+        let mut bytes = [0u8; BlockRequest::SIZE];
+        bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PeerParseError> {
+        if bytes.len() != BlockRequest::SIZE {
+            return Err(PeerParseError::Deserialization(format!(
+                "Block request of {} bytes instead of {}",
+                bytes.len(),
+                BlockRequest::SIZE
+            )));
+        }
+        let u32_at =
+            |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().expect("Sliced 4 bytes"));
+        Ok(Self {
+            index: u32_at(0),
+            begin: u32_at(4),
+            length: u32_at(8),
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PeerMessage {
     pub length: u32,
@@ -114,3 +312,32 @@ pub enum PeerParseError {
     #[error("Error while trying to deserialize bytes into a peer message: {0}")]
     Deserialization(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::{PeerBufferStream, PeerMessageId, MAX_MESSAGE_LENGTH};
+
+    #[tokio::test]
+    async fn test_skip_keep_alives() {
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut stream = PeerBufferStream::from_stream(ours);
+        theirs.write_all(&[0, 0, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        theirs.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        let message = stream.read_message().await.unwrap();
+        assert_eq!(PeerMessageId::Interested, message.id);
+        assert!(message.payload.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reject_oversized_message() {
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut stream = PeerBufferStream::from_stream(ours);
+        theirs
+            .write_all(&(MAX_MESSAGE_LENGTH + 1).to_be_bytes())
+            .await
+            .unwrap();
+        assert!(stream.read_message().await.is_err());
+    }
+}
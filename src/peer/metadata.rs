@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use super::{
+    extension::{join_extended, split_extended, ExtendedHandshake, EXTENDED_HANDSHAKE_ID},
+    message::{PeerBufferStream, PeerMessageId},
+};
+use crate::{ParseError, INFO_HASH_SIZE};
+
+pub const UT_METADATA: &str = "ut_metadata";
+/// Id we ask peers to use when sending us `ut_metadata` messages
+pub const LOCAL_UT_METADATA_ID: u8 = 2;
+/// The info dictionary is sent in pieces of this size, except for the last one
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+/// Largest info dictionary we accept from a peer
+pub const MAX_METADATA_SIZE: u64 = 1 << 24;
+/// Deepest nesting of lists and dictionaries allowed in the dictionary of a `ut_metadata` message
+const MAX_MESSAGE_DEPTH: usize = 16;
+
+/// A message of the metadata extension (BEP 9), used to get the info dictionary of a torrent
+/// that is only known by its info hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        let mut dict = HashMap::from([
+            (b"msg_type".to_vec(), Value::Int(msg_type)),
+            (b"piece".to_vec(), Value::Int(*piece as i64)),
+        ]);
+        if let MetadataMessage::Data { total_size, .. } = self {
+            dict.insert(b"total_size".to_vec(), Value::Int(*total_size as i64));
+        }
+        let mut bytes =
+            serde_bencode::to_bytes(&Value::Dict(dict)).expect("A Value always serializes");
+        // The piece itself follows the dictionary rather than being part of it
+        if let MetadataMessage::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let dict_len = bencode_len(bytes, MAX_MESSAGE_DEPTH).ok_or(ParseError::Deserialization(
+            "`ut_metadata` message did not start with a dictionary".to_owned(),
+        ))?;
+        let Value::Dict(dict) = serde_bencode::from_bytes::<Value>(&bytes[..dict_len])
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?
+        else {
+            return Err(ParseError::Deserialization(
+                "`ut_metadata` message did not deserialize into a dictionary".to_owned(),
+            ));
+        };
+        let int_field = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Value::Int(int)) => Ok(*int),
+            _ => Err(ParseError::MissingField(key.to_owned())),
+        };
+        let piece = usize::try_from(int_field("piece")?)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?;
+        match int_field("msg_type")? {
+            0 => Ok(MetadataMessage::Request { piece }),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: u64::try_from(int_field("total_size")?)
+                    .map_err(|err| ParseError::Deserialization(err.to_string()))?,
+                data: bytes[dict_len..].to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject { piece }),
+            msg_type => Err(ParseError::Deserialization(format!(
+                "Unknown `ut_metadata` message type {msg_type}"
+            ))),
+        }
+    }
+}
+
+/// Length of the bencoded value at the start of `bytes`, if there is a whole one nested no more
+/// than `depth` lists and dictionaries deep
+fn bencode_len(bytes: &[u8], depth: usize) -> Option<usize> {
+    match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|byte| *byte == b'e')? + 1),
+        b'l' | b'd' => {
+            let depth = depth.checked_sub(1)?;
+            let mut len = 1;
+            while *bytes.get(len)? != b'e' {
+                len += bencode_len(&bytes[len..], depth)?;
+            }
+            Some(len + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|byte| *byte == b':')?;
+            let string_len = std::str::from_utf8(&bytes[..colon])
+                .ok()?
+                .parse::<usize>()
+                .ok()?;
+            let len = colon + 1 + string_len;
+            (len <= bytes.len()).then_some(len)
+        }
+        _ => None,
+    }
+}
+
+/// Gets the info dictionary of the torrent with `info_hash` from a peer over `ut_metadata`,
+/// once the handshakes with a peer supporting the extension protocol are done. The dictionary
+/// is checked against the info hash
+pub async fn fetch_metadata(
+    stream: &mut PeerBufferStream,
+    info_hash: &[u8; INFO_HASH_SIZE],
+    listen_port: u16,
+) -> Result<Vec<u8>> {
+    let mut ext_hand = ExtendedHandshake::local(listen_port);
+    ext_hand
+        .extensions
+        .insert(UT_METADATA.to_owned(), LOCAL_UT_METADATA_ID);
+    stream
+        .write_message(
+            PeerMessageId::Extended,
+            &join_extended(EXTENDED_HANDSHAKE_ID, &ext_hand.to_bytes()),
+        )
+        .await?;
+    let (metadata_id, size) = loop {
+        let message = stream.read_message().await?;
+        if message.id != PeerMessageId::Extended {
+            continue;
+        }
+        let (id, payload) = split_extended(&message.payload)?;
+        if id != EXTENDED_HANDSHAKE_ID {
+            continue;
+        }
+        let peer_hand = ExtendedHandshake::from_bytes(payload)?;
+        let metadata_id = peer_hand
+            .extension_id(UT_METADATA)
+            .ok_or(anyhow!("Peer does not support `ut_metadata`"))?;
+        let size = peer_hand
+            .metadata_size
+            .filter(|size| (1..=MAX_METADATA_SIZE).contains(size))
+            .ok_or(anyhow!("Peer sent no usable metadata size"))?;
+        break (metadata_id, size as usize);
+    };
+    let num_pieces = (size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+    for piece in 0..num_pieces {
+        let request = MetadataMessage::Request { piece };
+        stream
+            .write_message(
+                PeerMessageId::Extended,
+                &join_extended(metadata_id, &request.to_bytes()),
+            )
+            .await?;
+    }
+    let mut pieces = vec![None; num_pieces];
+    while pieces.iter().any(Option::is_none) {
+        let message = stream.read_message().await?;
+        if message.id != PeerMessageId::Extended {
+            continue;
+        }
+        let (id, payload) = split_extended(&message.payload)?;
+        if id != LOCAL_UT_METADATA_ID {
+            continue;
+        }
+        match MetadataMessage::from_bytes(payload)? {
+            MetadataMessage::Data { piece, data, .. } => {
+                let slot = pieces.get_mut(piece).ok_or(anyhow!(
+                    "Peer sent metadata piece {piece} we didn't ask for"
+                ))?;
+                let expected = METADATA_PIECE_SIZE.min(size - piece * METADATA_PIECE_SIZE);
+                if data.len() != expected {
+                    return Err(anyhow!(
+                        "Metadata piece {piece} was {} bytes instead of {expected}",
+                        data.len()
+                    ));
+                }
+                *slot = Some(data);
+            }
+            MetadataMessage::Reject { piece } => {
+                return Err(anyhow!(
+                    "Peer rejected the request for metadata piece {piece}"
+                ))
+            }
+            // We have no metadata to hand out
+            MetadataMessage::Request { piece } => {
+                let reject = MetadataMessage::Reject { piece };
+                stream
+                    .write_message(
+                        PeerMessageId::Extended,
+                        &join_extended(metadata_id, &reject.to_bytes()),
+                    )
+                    .await?;
+            }
+        }
+    }
+    let info = pieces.into_iter().flatten().flatten().collect::<Vec<_>>();
+    if <[u8; INFO_HASH_SIZE]>::from(Sha1::digest(&info)) != *info_hash {
+        return Err(anyhow!("Metadata did not match the info hash"));
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::{fetch_metadata, MetadataMessage, UT_METADATA};
+    use crate::peer::{
+        extension::{join_extended, split_extended, ExtendedHandshake, EXTENDED_HANDSHAKE_ID},
+        message::{PeerBufferStream, PeerMessageId},
+    };
+
+    #[test]
+    fn test_data_message_roundtrip() {
+        let message = MetadataMessage::Data {
+            piece: 1,
+            total_size: 16_400,
+            data: b"d4:name3:abce".to_vec(),
+        };
+        assert_eq!(
+            message,
+            MetadataMessage::from_bytes(&message.to_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reject_deeply_nested_message() {
+        let mut bytes = b"d1:x".repeat(100_000);
+        bytes.extend(b"e".repeat(100_000));
+        assert!(MetadataMessage::from_bytes(&bytes).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_in_pieces() {
+        let mut info = b"d6:lengthi20000e4:name5:image12:piece lengthi16384e6:pieces".to_vec();
+        let pieces = vec![b'x'; 20_000];
+        info.extend(format!("{}:", pieces.len()).as_bytes());
+        info.extend(pieces);
+        info.push(b'e');
+        let info_hash = <[u8; 20]>::from(Sha1::digest(&info));
+
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let peer = tokio::spawn({
+            let info = info.clone();
+            async move {
+                let mut stream = PeerBufferStream::from_stream(theirs);
+                let message = stream.read_message().await.unwrap();
+                let (_, payload) = split_extended(&message.payload).unwrap();
+                let our_id = ExtendedHandshake::from_bytes(payload)
+                    .unwrap()
+                    .extension_id(UT_METADATA)
+                    .unwrap();
+                let mut ext_hand = ExtendedHandshake::local(6881);
+                ext_hand.extensions.insert(UT_METADATA.to_owned(), 3);
+                ext_hand.metadata_size = Some(info.len() as u64);
+                stream
+                    .write_message(
+                        PeerMessageId::Extended,
+                        &join_extended(EXTENDED_HANDSHAKE_ID, &ext_hand.to_bytes()),
+                    )
+                    .await
+                    .unwrap();
+                for _ in 0..2 {
+                    let message = stream.read_message().await.unwrap();
+                    let (id, payload) = split_extended(&message.payload).unwrap();
+                    assert_eq!(3, id);
+                    let MetadataMessage::Request { piece } =
+                        MetadataMessage::from_bytes(payload).unwrap()
+                    else {
+                        panic!("Expected a request");
+                    };
+                    let start = piece * super::METADATA_PIECE_SIZE;
+                    let end = (start + super::METADATA_PIECE_SIZE).min(info.len());
+                    let data = MetadataMessage::Data {
+                        piece,
+                        total_size: info.len() as u64,
+                        data: info[start..end].to_vec(),
+                    };
+                    stream
+                        .write_message(
+                            PeerMessageId::Extended,
+                            &join_extended(our_id, &data.to_bytes()),
+                        )
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        let mut stream = PeerBufferStream::from_stream(ours);
+        let fetched = fetch_metadata(&mut stream, &info_hash, 6881).await.unwrap();
+        assert_eq!(info, fetched);
+        peer.await.unwrap();
+    }
+}
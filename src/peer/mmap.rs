@@ -0,0 +1,307 @@
+use std::{fs::File, io, sync::RwLock};
+
+use sha1::{Digest, Sha1};
+
+use super::{
+    storage::{block_spans, Storage},
+    writer::FileWriter,
+};
+use crate::{torrent::MetaInfo, INFO_HASH_SIZE};
+
+/// The few calls of the C library mapping files needs, which std links in anyway
+#[cfg(all(
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+    pub const MAP_SHARED: c_int = 1;
+    #[cfg(target_os = "linux")]
+    pub const MS_SYNC: c_int = 4;
+    #[cfg(target_os = "macos")]
+    pub const MS_SYNC: c_int = 0x10;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        pub fn msync(addr: *mut c_void, len: usize, flags: c_int) -> c_int;
+    }
+}
+
+/// A whole file mapped into memory, shared with the file so that writes end up in it
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    /// Kept open for as long as it is mapped
+    _file: File,
+}
+
+// The mapping is only touched through `MmapStorage`, which guards it with a lock
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+#[cfg(all(
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+))]
+impl Mapping {
+    fn new(file: File, len: usize) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: a fresh shared mapping of an open file, checked for failure below
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ | sys::PROT_WRITE,
+                sys::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+            _file: file,
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        // SAFETY: the range is the one mapped in `new`
+        if unsafe { sys::msync(self.ptr.cast(), self.len, sys::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+))]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the range is the one mapped in `new`, and nothing borrows it any more
+        unsafe { sys::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+#[cfg(not(all(
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+)))]
+impl Mapping {
+    fn new(_file: File, _len: usize) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Memory mapped storage is not supported on this platform",
+        ))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Mapping {
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is valid for `len` bytes until dropped
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Only called with the write lock of the storage held
+    #[allow(clippy::mut_from_ref)]
+    fn as_mut_slice(&self) -> &mut [u8] {
+        // SAFETY: as above, and the lock keeps anyone else from reading or writing meanwhile
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+/// Maps every file of a torrent into memory, so that hashing a piece reads straight from the
+/// page cache instead of copying the piece into a buffer first. Suits large torrents whose files
+/// are all downloaded
+///
+/// Files must keep their size while mapped; something else truncating them crashes the process.
+#[derive(Debug)]
+pub struct MmapStorage {
+    metainfo: MetaInfo,
+    /// Empty files can't be mapped and have nothing to read or write anyway. Pad files aren't on
+    /// disk and read as zeros
+    maps: Vec<Option<Mapping>>,
+    /// Writers exclude everyone else from the mappings
+    lock: RwLock<()>,
+}
+
+impl MmapStorage {
+    /// Maps the files `writer` created, which must be every file of the torrent
+    pub fn open(writer: &FileWriter) -> io::Result<Self> {
+        let metainfo = writer.metainfo().clone();
+        let mut maps = Vec::new();
+        for (index, (_, length)) in metainfo.files().into_iter().enumerate() {
+            let path = writer
+                .path(index)
+                .filter(|_| writer.is_wanted(index))
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("File {index} is skipped and can't be mapped"),
+                ))?;
+            if length == 0 || metainfo.is_pad_file(index) {
+                maps.push(None);
+                continue;
+            }
+            let len = usize::try_from(length)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?;
+            maps.push(Some(Mapping::new(file, len)?));
+        }
+        Ok(Self {
+            metainfo,
+            maps,
+            lock: RwLock::new(()),
+        })
+    }
+
+    fn map(&self, file_index: usize) -> io::Result<&Mapping> {
+        self.maps
+            .get(file_index)
+            .and_then(Option::as_ref)
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("File {file_index} is not mapped"),
+            ))
+    }
+}
+
+impl Storage for MmapStorage {
+    fn metainfo(&self) -> &MetaInfo {
+        &self.metainfo
+    }
+
+    fn read_block(&self, piece: usize, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>> {
+        let spans = block_spans(&self.metainfo, piece, begin, length as u64)?;
+        let _guard = self.lock.read().expect("Poisoned lock");
+        let mut bytes = Vec::with_capacity(length as usize);
+        for span in spans {
+            if self.metainfo.is_pad_file(span.file_index) {
+                bytes.resize(bytes.len() + span.length as usize, 0);
+                continue;
+            }
+            let start = span.file_offset as usize;
+            bytes.extend_from_slice(
+                &self.map(span.file_index)?.as_slice()[start..start + span.length as usize],
+            );
+        }
+        Ok(Some(bytes))
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = block_spans(&self.metainfo, piece, begin, data.len() as u64)?;
+        let _guard = self.lock.write().expect("Poisoned lock");
+        for span in spans {
+            if self.metainfo.is_pad_file(span.file_index) {
+                continue;
+            }
+            let start = span.file_offset as usize;
+            let from = (span.piece_offset - begin as u64) as usize;
+            self.map(span.file_index)?.as_mut_slice()[start..start + span.length as usize]
+                .copy_from_slice(&data[from..from + span.length as usize]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let _guard = self.lock.read().expect("Poisoned lock");
+        self.maps.iter().flatten().try_for_each(Mapping::sync)
+    }
+
+    fn hash_piece(&self, piece: usize) -> io::Result<Option<[u8; INFO_HASH_SIZE]>> {
+        let Some(size) = self.metainfo.piece_size(piece) else {
+            return Ok(None);
+        };
+        let spans = block_spans(&self.metainfo, piece, 0, size)?;
+        let _guard = self.lock.read().expect("Poisoned lock");
+        let mut hasher = Sha1::new();
+        for span in spans {
+            if self.metainfo.is_pad_file(span.file_index) {
+                hasher.update(vec![0; span.length as usize]);
+                continue;
+            }
+            let start = span.file_offset as usize;
+            hasher.update(
+                &self.map(span.file_index)?.as_slice()[start..start + span.length as usize],
+            );
+        }
+        Ok(Some(hasher.finalize().into()))
+    }
+}
+
+#[cfg(all(
+    test,
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+))]
+mod tests {
+    use std::fs;
+
+    use reqwest::Url;
+    use sha1::{Digest, Sha1};
+
+    use super::MmapStorage;
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::{picker::FileSelection, storage::Storage, writer::FileWriter},
+    };
+
+    #[tokio::test]
+    async fn test_blocks_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(&root).unwrap();
+        let data = (0..20_100u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(root.join("a"), &data[..100]).unwrap();
+        fs::write(root.join("b"), &data[100..]).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&root)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let out = dir.path().join("out");
+        let writer = FileWriter::create(&metainfo, &FileSelection::all(&metainfo), &out)
+            .await
+            .unwrap();
+        let storage = MmapStorage::open(&writer).unwrap();
+        let piece = &data[..MIN_PIECE_LENGTH as usize];
+        // The first block spans both files
+        storage.write_block(0, 0, &piece[..1000]).unwrap();
+        storage.write_block(0, 1000, &piece[1000..]).unwrap();
+        assert_eq!(
+            Some(<[u8; 20]>::from(Sha1::digest(piece))),
+            storage.hash_piece(0).unwrap()
+        );
+        assert_eq!(
+            Some(piece[50..150].to_vec()),
+            storage.read_block(0, 50, 100).unwrap()
+        );
+        storage.flush().unwrap();
+        drop(storage);
+        assert_eq!(data[..100], fs::read(out.join("a")).unwrap());
+        assert_eq!(Some(piece.to_vec()), writer.read_piece(0).unwrap());
+    }
+}
@@ -1,2 +1,17 @@
+pub mod cache;
 pub mod client;
+pub mod connection;
+pub mod extension;
+pub mod fast;
+pub mod manager;
 pub mod message;
+pub mod metadata;
+pub mod mmap;
+pub mod pex;
+pub mod picker;
+pub mod pool;
+pub mod smartban;
+pub mod storage;
+pub mod stream;
+pub mod upload;
+pub mod writer;
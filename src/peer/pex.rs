@@ -0,0 +1,244 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    time::{Duration, Instant},
+};
+
+use serde_bencode::value::Value;
+
+use crate::{
+    dht::krpc::{decode_compact_peer, encode_compact_peer},
+    tracker::TRACKER_RESPONSE_PEER_SIZE,
+    ParseError,
+};
+
+/// Peers must not send more than one `ut_pex` message a minute
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest number of `added` (and `dropped`) peers in a single message
+pub const MAX_PEX_PEERS: usize = 50;
+/// Size of a compact IPv6 address and port
+pub const COMPACT_PEER6_SIZE: usize = 18;
+
+/// Flags describing a peer of an `added` list
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    /// The peer prefers encrypted connections
+    pub const ENCRYPTION: PexFlags = PexFlags(0x01);
+    /// The peer is a seed
+    pub const SEED: PexFlags = PexFlags(0x02);
+    /// The peer supports uTP
+    pub const UTP: PexFlags = PexFlags(0x04);
+    /// The peer supports the holepunch extension
+    pub const HOLEPUNCH: PexFlags = PexFlags(0x08);
+    /// The peer was reached with an outgoing connection
+    pub const REACHABLE: PexFlags = PexFlags(0x10);
+
+    pub fn contains(&self, other: PexFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: PexFlags) -> Self {
+        PexFlags(self.0 | other.0)
+    }
+}
+
+/// A `ut_pex` message (BEP 11) listing the peers connected to and disconnected from since the
+/// previous message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut added = (Vec::new(), Vec::new());
+        let mut added6 = (Vec::new(), Vec::new());
+        for (peer, flags) in &self.added {
+            let (peers, peer_flags) = match peer {
+                SocketAddr::V4(_) => &mut added,
+                SocketAddr::V6(_) => &mut added6,
+            };
+            peers.extend(encode_compact(peer));
+            peer_flags.push(flags.0);
+        }
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        for peer in &self.dropped {
+            match peer {
+                SocketAddr::V4(_) => dropped.extend(encode_compact(peer)),
+                SocketAddr::V6(_) => dropped6.extend(encode_compact(peer)),
+            }
+        }
+        let dict = HashMap::from([
+            (b"added".to_vec(), Value::Bytes(added.0)),
+            (b"added.f".to_vec(), Value::Bytes(added.1)),
+            (b"added6".to_vec(), Value::Bytes(added6.0)),
+            (b"added6.f".to_vec(), Value::Bytes(added6.1)),
+            (b"dropped".to_vec(), Value::Bytes(dropped)),
+            (b"dropped6".to_vec(), Value::Bytes(dropped6)),
+        ]);
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("A Value always serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let Value::Dict(dict) = serde_bencode::from_bytes::<Value>(bytes)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?
+        else {
+            return Err(ParseError::Deserialization(
+                "ut_pex message did not deserialize into a dictionary".to_owned(),
+            ));
+        };
+        let field = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Value::Bytes(bytes)) => bytes.as_slice(),
+            _ => &[],
+        };
+        let mut added = Vec::new();
+        for (peers, flags, size) in [
+            (field("added"), field("added.f"), TRACKER_RESPONSE_PEER_SIZE),
+            (field("added6"), field("added6.f"), COMPACT_PEER6_SIZE),
+        ] {
+            let peers = decode_compact_list(peers, size)?;
+            for (idx, peer) in peers.into_iter().enumerate() {
+                let flags = flags.get(idx).copied().unwrap_or_default();
+                added.push((peer, PexFlags(flags)));
+            }
+        }
+        let mut dropped = decode_compact_list(field("dropped"), TRACKER_RESPONSE_PEER_SIZE)?;
+        dropped.extend(decode_compact_list(field("dropped6"), COMPACT_PEER6_SIZE)?);
+        Ok(Self { added, dropped })
+    }
+}
+
+pub(crate) fn encode_compact(peer: &SocketAddr) -> Vec<u8> {
+    match peer {
+        SocketAddr::V4(peer) => encode_compact_peer(peer).to_vec(),
+        SocketAddr::V6(peer) => {
+            let mut compact = peer.ip().octets().to_vec();
+            compact.extend_from_slice(&peer.port().to_be_bytes());
+            compact
+        }
+    }
+}
+
+pub(crate) fn decode_compact_list(
+    bytes: &[u8],
+    size: usize,
+) -> Result<Vec<SocketAddr>, ParseError> {
+    if bytes.len() % size != 0 {
+        return Err(ParseError::Deserialization(format!(
+            "Compact peer list length was not a multiple of {size}"
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(size)
+        .filter_map(|chunk| match size {
+            COMPACT_PEER6_SIZE => {
+                let ip = <[u8; 16]>::try_from(&chunk[..16]).ok()?;
+                let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(ip),
+                    port,
+                    0,
+                    0,
+                )))
+            }
+            _ => decode_compact_peer(chunk).map(SocketAddr::V4),
+        })
+        .collect())
+}
+
+/// What has been told to a single peer over `ut_pex`, used to send it only the changes and no
+/// more than once every [`PEX_INTERVAL`]
+#[derive(Debug, Clone, Default)]
+pub struct PexState {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    /// The message telling the peer about the changes between what it was last told and
+    /// `current`, or nothing when it is too early to send one or nothing changed
+    pub fn next_message(
+        &mut self,
+        current: &HashMap<SocketAddr, PexFlags>,
+        peer: SocketAddr,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added = current
+            .iter()
+            .filter(|(addr, _)| **addr != peer && !self.sent.contains(*addr))
+            .take(MAX_PEX_PEERS)
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect::<Vec<_>>();
+        let dropped = self
+            .sent
+            .iter()
+            .filter(|addr| !current.contains_key(*addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        let message = PexMessage { added, dropped };
+        if message.is_empty() {
+            return None;
+        }
+        for (addr, _) in &message.added {
+            self.sent.insert(*addr);
+        }
+        for addr in &message.dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(Instant::now());
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr};
+
+    use super::{PexFlags, PexMessage, PexState};
+
+    #[test]
+    fn test_message_roundtrip() {
+        let message = PexMessage {
+            added: vec![
+                (
+                    "10.0.0.1:6881".parse().unwrap(),
+                    PexFlags::SEED.with(PexFlags::UTP),
+                ),
+                ("[2001:db8::1]:51413".parse().unwrap(), PexFlags::ENCRYPTION),
+            ],
+            dropped: vec![
+                "10.0.0.2:6881".parse().unwrap(),
+                "[2001:db8::2]:6881".parse().unwrap(),
+            ],
+        };
+        let parsed = PexMessage::from_bytes(&message.to_bytes()).unwrap();
+        assert_eq!(message, parsed);
+        assert!(parsed.added[0].1.contains(PexFlags::SEED));
+    }
+
+    #[test]
+    fn test_state_sends_changes_once_a_minute() {
+        let peer: SocketAddr = "10.0.0.9:6881".parse().unwrap();
+        let known: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let current = HashMap::from([(known, PexFlags::default()), (peer, PexFlags::default())]);
+        let mut state = PexState::default();
+        let message = state.next_message(&current, peer).unwrap();
+        // The peer is never told about itself
+        assert_eq!(vec![(known, PexFlags::default())], message.added);
+        assert!(state.next_message(&HashMap::new(), peer).is_none());
+    }
+}
@@ -0,0 +1,356 @@
+use std::{path::PathBuf, str::FromStr};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::torrent::{FileType, MetaInfo};
+
+/// How much a file of the torrent is wanted. Pieces take the highest priority of the files they
+/// overlap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    /// Never downloaded nor created on disk
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for FilePriority {
+    type Err = SelectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(SelectionError::InvalidPriority(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SelectionError {
+    #[error("`{0}` is not one of skip, low, normal or high")]
+    InvalidPriority(String),
+    #[error("`{0}` is not of the form <FILE>=<PRIORITY>")]
+    InvalidAssignment(String),
+    #[error("File index {0} is out of range")]
+    InvalidIndex(usize),
+    #[error("No file of the torrent matches `{0}`")]
+    NoMatch(String),
+    #[error("`{0}` is not one of priority or sequential")]
+    InvalidStrategy(String),
+}
+
+/// The priority of every file of a torrent, in the order of [`MetaInfo::files`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSelection {
+    priorities: Vec<FilePriority>,
+}
+
+impl FileSelection {
+    /// Every file at normal priority
+    pub fn all(metainfo: &MetaInfo) -> Self {
+        Self {
+            priorities: vec![FilePriority::Normal; metainfo.files().len()],
+        }
+    }
+
+    /// Only the files matching one of `patterns`, at normal priority. Nothing given selects every
+    /// file
+    pub fn only(metainfo: &MetaInfo, patterns: &[String]) -> Result<Self, SelectionError> {
+        if patterns.is_empty() {
+            return Ok(Self::all(metainfo));
+        }
+        let mut selection = Self {
+            priorities: vec![FilePriority::Skip; metainfo.files().len()],
+        };
+        for pattern in patterns {
+            selection.set_priority(metainfo, pattern, FilePriority::Normal)?;
+        }
+        Ok(selection)
+    }
+
+    /// Gives the files matching `pattern` the priority given. See [`matching_files`] for what a
+    /// pattern can be
+    pub fn set_priority(
+        &mut self,
+        metainfo: &MetaInfo,
+        pattern: &str,
+        priority: FilePriority,
+    ) -> Result<(), SelectionError> {
+        for file_index in matching_files(metainfo, pattern)? {
+            self.priorities[file_index] = priority;
+        }
+        Ok(())
+    }
+
+    /// Applies an assignment of the form `<FILE>=<PRIORITY>`, e.g. `docs/*=low`
+    pub fn assign(&mut self, metainfo: &MetaInfo, assignment: &str) -> Result<(), SelectionError> {
+        let (pattern, priority) = assignment
+            .rsplit_once('=')
+            .ok_or(SelectionError::InvalidAssignment(assignment.to_owned()))?;
+        self.set_priority(metainfo, pattern, priority.parse()?)
+    }
+
+    #[inline]
+    pub fn priority(&self, file_index: usize) -> FilePriority {
+        self.priorities
+            .get(file_index)
+            .copied()
+            .unwrap_or(FilePriority::Skip)
+    }
+
+    #[inline]
+    pub fn is_wanted(&self, file_index: usize) -> bool {
+        self.priority(file_index) != FilePriority::Skip
+    }
+}
+
+/// Paths of the files relative to the root of the torrent, which is what patterns match against.
+/// A single file torrent's only file goes by the torrent's name
+pub fn relative_paths(metainfo: &MetaInfo) -> Vec<PathBuf> {
+    match metainfo.file_type() {
+        FileType::SingleFile(_) => vec![metainfo.name().clone()],
+        FileType::MultiFile(files) => files.iter().map(|file| file.path().clone()).collect(),
+    }
+}
+
+/// Indices of the files `pattern` picks: either a file index or a glob over the relative paths
+/// where `*` and `?` stay within a path component and `**` crosses them
+pub fn matching_files(metainfo: &MetaInfo, pattern: &str) -> Result<Vec<usize>, SelectionError> {
+    let paths = relative_paths(metainfo);
+    if let Ok(index) = pattern.parse::<usize>() {
+        return if index < paths.len() {
+            Ok(vec![index])
+        } else {
+            Err(SelectionError::InvalidIndex(index))
+        };
+    }
+    let glob = glob_regex(pattern);
+    let matches = paths
+        .iter()
+        .enumerate()
+        .filter(|(_, path)| {
+            let path = path.to_string_lossy().replace('\\', "/");
+            glob.is_match(&path)
+        })
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    if matches.is_empty() {
+        return Err(SelectionError::NoMatch(pattern.to_owned()));
+    }
+    Ok(matches)
+}
+
+fn glob_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("Every other character was escaped")
+}
+
+/// The order pieces are downloaded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PickStrategy {
+    /// Highest priority first, in order within the same priority
+    #[default]
+    Priority,
+    /// In order from the reading position onwards, so that files can be used while they download
+    Sequential,
+}
+
+impl FromStr for PickStrategy {
+    type Err = SelectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "priority" => Ok(PickStrategy::Priority),
+            "sequential" => Ok(PickStrategy::Sequential),
+            _ => Err(SelectionError::InvalidStrategy(s.to_owned())),
+        }
+    }
+}
+
+/// Pieces after the reading position that are fetched before anything else
+pub const DEFAULT_DEADLINE_WINDOW: usize = 8;
+
+/// Decides which pieces get downloaded and in what order out of the files selected
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    /// Highest priority of the files each piece overlaps
+    priorities: Vec<FilePriority>,
+    strategy: PickStrategy,
+    deadline_window: usize,
+    /// The piece a reader is waiting on, if any
+    position: Option<usize>,
+}
+
+impl PiecePicker {
+    pub fn new(metainfo: &MetaInfo, selection: &FileSelection) -> Self {
+        let priorities = (0..metainfo.num_pieces())
+            .map(|piece| {
+                metainfo
+                    .piece_spans(piece)
+                    .iter()
+                    .map(|span| selection.priority(span.file_index))
+                    .max()
+                    .unwrap_or(FilePriority::Skip)
+            })
+            .collect();
+        Self {
+            priorities,
+            strategy: PickStrategy::default(),
+            deadline_window: DEFAULT_DEADLINE_WINDOW,
+            position: None,
+        }
+    }
+
+    pub fn with_strategy(self, strategy: PickStrategy) -> Self {
+        let mut s = self;
+        s.strategy = strategy;
+        s
+    }
+
+    pub fn with_deadline_window(self, deadline_window: usize) -> Self {
+        let mut s = self;
+        s.deadline_window = deadline_window;
+        s
+    }
+
+    /// Moves the reading position, which puts the pieces of the deadline window from `piece`
+    /// onwards ahead of everything else
+    pub fn set_position(&mut self, piece: usize) {
+        self.position = Some(piece);
+    }
+
+    #[inline]
+    pub fn priority(&self, piece: usize) -> FilePriority {
+        self.priorities
+            .get(piece)
+            .copied()
+            .unwrap_or(FilePriority::Skip)
+    }
+
+    #[inline]
+    pub fn is_wanted(&self, piece: usize) -> bool {
+        self.priority(piece) != FilePriority::Skip
+    }
+
+    /// The pieces overlapping a wanted file, highest priority first and in order within the same
+    /// priority
+    pub fn pieces(&self) -> Vec<usize> {
+        let mut pieces = (0..self.priorities.len())
+            .filter(|piece| self.is_wanted(*piece))
+            .collect::<Vec<_>>();
+        pieces.sort_by_key(|piece| std::cmp::Reverse(self.priorities[*piece]));
+        pieces
+    }
+
+    /// The next wanted piece to download out of the ones that `have` says are still missing
+    pub fn next(&self, have: &[bool]) -> Option<usize> {
+        self.next_where(have, |_| true)
+    }
+
+    /// Like [`Self::next`], passing over the pieces `accept` turns down
+    pub fn next_where(&self, have: &[bool], accept: impl Fn(usize) -> bool) -> Option<usize> {
+        let missing = |piece: &usize| {
+            self.is_wanted(*piece) && !have.get(*piece).copied().unwrap_or(false) && accept(*piece)
+        };
+        let position = self.position.unwrap_or(0).min(self.priorities.len());
+        if self.position.is_some() {
+            let deadline_end = (position + self.deadline_window).min(self.priorities.len());
+            if let Some(piece) = (position..deadline_end).find(missing) {
+                return Some(piece);
+            }
+        }
+        match self.strategy {
+            PickStrategy::Sequential => (position..self.priorities.len())
+                .chain(0..position)
+                .find(missing),
+            PickStrategy::Priority => self.pieces().into_iter().find(missing),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use reqwest::Url;
+
+    use super::{FilePriority, FileSelection, PickStrategy, PiecePicker, SelectionError};
+    use crate::create::{TorrentBuilder, MIN_PIECE_LENGTH};
+
+    #[test]
+    fn test_select_and_pick() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("README"), vec![1u8; 100]).unwrap();
+        fs::write(root.join("bin").join("tool"), vec![2u8; 20_000]).unwrap();
+        fs::write(root.join("docs").join("manual"), vec![3u8; 30_000]).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&root)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+
+        let selection = FileSelection::only(&metainfo, &["bin/*".to_owned()]).unwrap();
+        assert!(!selection.is_wanted(0));
+        assert!(selection.is_wanted(1));
+        assert!(!selection.is_wanted(2));
+        // README, then bin/tool up to 20100 and docs/manual up to 50100
+        assert_eq!(vec![0, 1], PiecePicker::new(&metainfo, &selection).pieces());
+
+        let mut selection = FileSelection::all(&metainfo);
+        selection.assign(&metainfo, "docs/**=high").unwrap();
+        selection.assign(&metainfo, "0=skip").unwrap();
+        assert_eq!(FilePriority::High, selection.priority(2));
+        assert_eq!(
+            vec![1, 2, 3, 0],
+            PiecePicker::new(&metainfo, &selection).pieces()
+        );
+
+        assert_eq!(
+            Err(SelectionError::NoMatch("*.iso".to_owned())),
+            FileSelection::only(&metainfo, &["*.iso".to_owned()])
+        );
+        assert_eq!(
+            Err(SelectionError::InvalidIndex(3)),
+            FileSelection::only(&metainfo, &["3".to_owned()])
+        );
+
+        let mut sequential = PiecePicker::new(&metainfo, &FileSelection::all(&metainfo))
+            .with_strategy(PickStrategy::Sequential)
+            .with_deadline_window(1);
+        let mut have = vec![false; 4];
+        assert_eq!(Some(0), sequential.next(&have));
+        sequential.set_position(2);
+        assert_eq!(Some(2), sequential.next(&have));
+        have[2] = true;
+        have[3] = true;
+        assert_eq!(Some(0), sequential.next(&have));
+
+        // A reader waiting on a piece comes before higher priorities
+        let mut picker = PiecePicker::new(&metainfo, &selection).with_deadline_window(1);
+        assert_eq!(Some(1), picker.next(&[false; 4]));
+        picker.set_position(0);
+        assert_eq!(Some(0), picker.next(&[false; 4]));
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+use super::pex::{PexFlags, PexMessage, MAX_PEX_PEERS, PEX_INTERVAL};
+use crate::{blocklist::IpFilter, PEER_ID_SIZE};
+
+/// Largest number of peers a pool keeps by default
+pub const DEFAULT_MAX_PEERS: usize = 500;
+/// Slack given to peers sending `ut_pex` messages a bit earlier than once a minute
+const PEX_INTERVAL_SLACK: Duration = Duration::from_secs(10);
+
+/// Where a peer of the pool was learned from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Incoming,
+    /// Saved in the resume file of an earlier run
+    Resume,
+}
+
+#[derive(Debug, Clone)]
+struct PoolEntry {
+    source: PeerSource,
+    flags: PexFlags,
+    /// What the peer sent in its handshake, once connected to
+    peer_id: Option<[u8; PEER_ID_SIZE]>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    /// Peers in the order they were learned, which is the order they are tried in
+    order: Vec<SocketAddr>,
+    entries: HashMap<SocketAddr, PoolEntry>,
+    /// When the last `ut_pex` message of each peer was taken in
+    pex_received: HashMap<SocketAddr, Instant>,
+    max_peers: usize,
+    filter: IpFilter,
+}
+
+/// The peers known for a download, deduplicated across every source they come from
+///
+/// The pool is a shared handle so that trackers, the DHT, PEX and LSD can all feed the same
+/// download.
+#[derive(Debug, Clone)]
+pub struct PeerPool {
+    inner: Arc<Mutex<PoolInner>>,
+    /// Woken whenever a peer is added
+    added: Arc<Notify>,
+}
+
+impl Default for PeerPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PEERS)
+    }
+}
+
+impl PeerPool {
+    pub fn new(max_peers: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PoolInner {
+                order: Vec::new(),
+                entries: HashMap::new(),
+                pex_received: HashMap::new(),
+                max_peers,
+                filter: IpFilter::default(),
+            })),
+            added: Arc::new(Notify::new()),
+        }
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, PoolInner> {
+        self.inner.lock().expect("Poisoned lock")
+    }
+
+    /// Refuses the peers `filter` blocks from now on, whatever source they come from. Peers
+    /// already in the pool are no longer handed out once blocked, including after a reload
+    pub fn set_filter(&self, filter: IpFilter) {
+        self.inner().filter = filter;
+    }
+
+    /// Adds a peer unless it is already known, blocked or the pool is full. Returns whether it
+    /// was added
+    pub fn add(&self, addr: SocketAddr, source: PeerSource) -> bool {
+        self.add_with_flags(addr, source, PexFlags::default())
+    }
+
+    fn add_with_flags(&self, addr: SocketAddr, source: PeerSource, flags: PexFlags) -> bool {
+        let mut inner = self.inner();
+        if inner.entries.contains_key(&addr)
+            || inner.order.len() >= inner.max_peers
+            || inner.filter.is_blocked(addr.ip())
+        {
+            return false;
+        }
+        inner.order.push(addr);
+        inner.entries.insert(
+            addr,
+            PoolEntry {
+                source,
+                flags,
+                peer_id: None,
+            },
+        );
+        drop(inner);
+        self.added.notify_waiters();
+        true
+    }
+
+    /// Adds every peer and returns how many were new
+    pub fn extend(&self, addrs: impl IntoIterator<Item = SocketAddr>, source: PeerSource) -> usize {
+        addrs
+            .into_iter()
+            .filter(|addr| self.add(*addr, source))
+            .count()
+    }
+
+    /// Takes in the `added` peers of a `ut_pex` message sent by `from`
+    ///
+    /// Messages sent more often than [`PEX_INTERVAL`] are ignored and only the first
+    /// [`MAX_PEX_PEERS`] peers of a message are looked at, so that a single peer can't flood the
+    /// pool. Returns how many peers were new.
+    pub fn add_pex(&self, from: SocketAddr, message: &PexMessage) -> usize {
+        {
+            let mut inner = self.inner();
+            let now = Instant::now();
+            if let Some(received) = inner.pex_received.get(&from) {
+                if now.duration_since(*received) + PEX_INTERVAL_SLACK < PEX_INTERVAL {
+                    return 0;
+                }
+            }
+            inner.pex_received.insert(from, now);
+        }
+        message
+            .added
+            .iter()
+            .take(MAX_PEX_PEERS)
+            .filter(|(addr, flags)| {
+                *addr != from && self.add_with_flags(*addr, PeerSource::Pex, *flags)
+            })
+            .count()
+    }
+
+    /// Records what is known about a peer, e.g. that it was reachable
+    pub fn set_flags(&self, addr: SocketAddr, flags: PexFlags) {
+        if let Some(entry) = self.inner().entries.get_mut(&addr) {
+            entry.flags = flags;
+        }
+    }
+
+    /// Records the peer id the peer sent in its handshake
+    pub fn set_peer_id(&self, addr: SocketAddr, peer_id: [u8; PEER_ID_SIZE]) {
+        if let Some(entry) = self.inner().entries.get_mut(&addr) {
+            entry.peer_id = Some(peer_id);
+        }
+    }
+
+    pub fn peer_id(&self, addr: &SocketAddr) -> Option<[u8; PEER_ID_SIZE]> {
+        self.inner().entries.get(addr)?.peer_id
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        let mut inner = self.inner();
+        if inner.entries.remove(addr).is_some() {
+            inner.order.retain(|other| other != addr);
+        }
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.inner().entries.contains_key(addr)
+    }
+
+    pub fn source(&self, addr: &SocketAddr) -> Option<PeerSource> {
+        self.inner().entries.get(addr).map(|entry| entry.source)
+    }
+
+    pub fn first(&self) -> Option<SocketAddr> {
+        self.peers().first().copied()
+    }
+
+    /// The peers in the order they are tried in, leaving out those blocked since they were added
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let inner = self.inner();
+        inner
+            .order
+            .iter()
+            .filter(|addr| !inner.filter.is_blocked(addr.ip()))
+            .copied()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until the pool holds at least one peer or `timeout` runs out. Returns whether the
+    /// pool is non-empty
+    pub async fn wait_for_peers(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before checking so that an add in between is not missed
+            let added = self.added.notified();
+            if !self.is_empty() {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, added).await.is_err() {
+                return !self.is_empty();
+            }
+        }
+    }
+
+    /// Every peer along with its flags, which is what gets advertised over `ut_pex`
+    pub fn pex_view(&self) -> HashMap<SocketAddr, PexFlags> {
+        self.inner()
+            .entries
+            .iter()
+            .map(|(addr, entry)| (*addr, entry.flags))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{PeerPool, PeerSource};
+    use crate::{
+        blocklist::{Blocklist, IpFilter},
+        peer::pex::{PexFlags, PexMessage, MAX_PEX_PEERS},
+    };
+
+    #[test]
+    fn test_pex_dedup_and_rate_limit() {
+        let pool = PeerPool::new(1000);
+        let tracker_peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let from: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        pool.add(tracker_peer, PeerSource::Tracker);
+        let added = (0..100u16)
+            .map(|port| {
+                (
+                    SocketAddr::from(([10, 0, 1, 1], 7000 + port)),
+                    PexFlags::default(),
+                )
+            })
+            .chain([(tracker_peer, PexFlags::SEED)])
+            .collect();
+        let message = PexMessage {
+            added,
+            dropped: vec![],
+        };
+        assert_eq!(MAX_PEX_PEERS, pool.add_pex(from, &message));
+        assert_eq!(Some(PeerSource::Tracker), pool.source(&tracker_peer));
+        // A second message right away is ignored
+        assert_eq!(0, pool.add_pex(from, &message));
+        assert_eq!(MAX_PEX_PEERS + 1, pool.len());
+    }
+
+    #[test]
+    fn test_filter_blocks_peers() {
+        let pool = PeerPool::default();
+        let filter = IpFilter::default();
+        pool.set_filter(filter.clone());
+        let (kept, blocked): (SocketAddr, SocketAddr) = (
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.1.1:6881".parse().unwrap(),
+        );
+        filter.set("a:10.0.1.0-10.0.1.255".parse::<Blocklist>().unwrap());
+        assert!(!pool.add(blocked, PeerSource::Tracker));
+        assert!(pool.add(kept, PeerSource::Dht));
+        // A peer blocked after it was added is no longer handed out
+        filter.set("a:10.0.0.0-10.0.1.255".parse::<Blocklist>().unwrap());
+        assert!(pool.peers().is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use sha1::{Digest, Sha1};
+
+/// SHA-1 of a block and the peer that sent it
+type BlockHash = ([u8; 20], SocketAddr);
+
+/// What blocks are told apart by, so that the blocks themselves needn't be kept
+pub fn block_hash(data: &[u8]) -> [u8; 20] {
+    Sha1::digest(data).into()
+}
+
+/// Finds the peers that sent corrupt blocks of pieces failing the hash check
+///
+/// A piece put together from the blocks of several peers doesn't tell who sent the bad data when
+/// it fails. The hash of every block of a failed piece is kept along with its sender, and once
+/// the piece is downloaded again and verifies, the blocks are compared: whoever sent a block
+/// that differs from the good one is to blame, and everyone else is cleared.
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    /// Every version of each block of the failed pieces, by piece and block offset
+    failed: HashMap<usize, BTreeMap<u32, Vec<BlockHash>>>,
+    /// Peers of each failed piece that were blamed already, having sent a failed version alone
+    blamed: HashMap<usize, HashSet<SocketAddr>>,
+}
+
+impl SmartBan {
+    /// Remembers the blocks of a piece that failed the hash check. Returns the peer that sent
+    /// them when only one did, since the piece then proves it at fault right away
+    pub fn piece_failed(
+        &mut self,
+        piece: usize,
+        blocks: impl IntoIterator<Item = (u32, [u8; 20], SocketAddr)>,
+    ) -> Option<SocketAddr> {
+        let recorded = self.failed.entry(piece).or_default();
+        let mut senders = HashSet::new();
+        for (begin, hash, peer) in blocks {
+            senders.insert(peer);
+            let hash = (hash, peer);
+            let versions = recorded.entry(begin).or_default();
+            if !versions.contains(&hash) {
+                versions.push(hash);
+            }
+        }
+        let sender = match senders.len() {
+            1 => senders.into_iter().next(),
+            _ => None,
+        };
+        if let Some(sender) = sender {
+            self.blamed.entry(piece).or_default().insert(sender);
+        }
+        sender
+    }
+
+    /// Every peer that sent blocks of a failed version of the piece
+    pub fn suspects(&self, piece: usize) -> HashSet<SocketAddr> {
+        self.failed
+            .get(&piece)
+            .into_iter()
+            .flat_map(BTreeMap::values)
+            .flatten()
+            .map(|(_, peer)| *peer)
+            .collect()
+    }
+
+    /// Compares the blocks of a piece that verified with those of its failed downloads, if it
+    /// had any. Returns the peers that sent a block differing from the good one, each with
+    /// whether [`Self::piece_failed`] already blamed it for the piece
+    pub fn piece_verified(
+        &mut self,
+        piece: usize,
+        blocks: impl IntoIterator<Item = (u32, [u8; 20])>,
+    ) -> Vec<(SocketAddr, bool)> {
+        let blamed = self.blamed.remove(&piece).unwrap_or_default();
+        let Some(recorded) = self.failed.remove(&piece) else {
+            return Vec::new();
+        };
+        let mut culprits = HashSet::new();
+        for (begin, good) in blocks {
+            for (hash, peer) in recorded.get(&begin).into_iter().flatten() {
+                if *hash != good {
+                    culprits.insert(*peer);
+                }
+            }
+        }
+        culprits
+            .into_iter()
+            .map(|peer| (peer, blamed.contains(&peer)))
+            .collect()
+    }
+
+    /// Drops what was kept of a piece, once it no longer matters how it failed
+    pub fn forget(&mut self, piece: usize) {
+        self.failed.remove(&piece);
+        self.blamed.remove(&piece);
+    }
+
+    /// Failed pieces waiting on a good download
+    #[inline]
+    pub fn num_pending(&self) -> usize {
+        self.failed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, net::SocketAddr};
+
+    use super::{block_hash, SmartBan};
+
+    #[test]
+    fn test_blames_only_the_corrupt_sender() {
+        let honest = "10.0.0.1:6881".parse::<SocketAddr>().unwrap();
+        let corrupt = "10.0.0.2:6881".parse::<SocketAddr>().unwrap();
+        let other = "10.0.0.3:6881".parse::<SocketAddr>().unwrap();
+        let good = [[1u8; 16], [2; 16], [3; 16]].map(|block| block_hash(&block));
+        let bad = block_hash(&[9u8; 16]);
+        let mut smart_ban = SmartBan::default();
+
+        let failed = [
+            (0, good[0], honest),
+            (16, bad, corrupt),
+            (32, good[2], honest),
+        ];
+        assert_eq!(None, smart_ban.piece_failed(4, failed));
+        assert_eq!(1, smart_ban.num_pending());
+        // The good download came from other peers for the most part
+        let verified = [(0, good[0]), (16, good[1]), (32, good[2])];
+        assert_eq!(HashSet::from([honest, corrupt]), smart_ban.suspects(4));
+        assert_eq!(
+            vec![(corrupt, false)],
+            smart_ban.piece_verified(4, verified)
+        );
+        assert_eq!(0, smart_ban.num_pending());
+        assert!(smart_ban.piece_verified(4, verified).is_empty());
+
+        assert_eq!(
+            Some(other),
+            smart_ban.piece_failed(5, [(0, bad, other), (16, bad, other)])
+        );
+        // Blamed once already, which the verdict says so that it isn't counted twice
+        let verified = [(0, good[0]), (16, good[1])];
+        assert_eq!(vec![(other, true)], smart_ban.piece_verified(5, verified));
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    torrent::{FileSpan, MetaInfo},
+    INFO_HASH_SIZE,
+};
+
+/// Where the pieces of a torrent are kept. Blocks are addressed within their piece; how that maps
+/// onto files is up to the backend
+///
+/// Calls block, so async code makes them through [`blocking`].
+pub trait Storage: Debug + Send + Sync {
+    fn metainfo(&self) -> &MetaInfo;
+
+    /// Reads `length` bytes of `piece` from `begin`, or nothing when they were never stored
+    fn read_block(&self, piece: usize, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>>;
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> io::Result<()>;
+
+    /// Makes what was written so far durable
+    fn flush(&self) -> io::Result<()>;
+
+    /// The SHA-1 of the piece as stored, or nothing when it isn't
+    fn hash_piece(&self, piece: usize) -> io::Result<Option<[u8; INFO_HASH_SIZE]>> {
+        Ok(self
+            .read_piece(piece)?
+            .map(|bytes| Sha1::digest(bytes).into()))
+    }
+
+    fn read_piece(&self, piece: usize) -> io::Result<Option<Vec<u8>>> {
+        match self.metainfo().piece_size(piece) {
+            Some(size) => self.read_block(piece, 0, piece_u32(size)?),
+            None => Ok(None),
+        }
+    }
+
+    fn write_piece(&self, piece: usize, bytes: &[u8]) -> io::Result<()> {
+        self.write_block(piece, 0, bytes)
+    }
+
+    /// Writes whole pieces from `first` on, back to back in `bytes`. Backends that can turn
+    /// them into fewer, larger writes do
+    fn write_pieces(&self, first: usize, bytes: &[u8]) -> io::Result<()> {
+        piece_run(self.metainfo(), first, bytes.len())?
+            .into_iter()
+            .try_for_each(|(piece, range)| self.write_piece(piece, &bytes[range]))
+    }
+}
+
+/// Runs a call to the storage on the blocking pool
+pub async fn blocking<T: Send + 'static>(
+    storage: &Arc<dyn Storage>,
+    call: impl FnOnce(&dyn Storage) -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || call(storage.as_ref()))
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+}
+
+fn piece_u32(size: u64) -> io::Result<u32> {
+    u32::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Piece too large"))
+}
+
+/// The pieces `length` bytes from the start of `first` cover, each with its range of the bytes.
+/// The bytes must end where a piece does
+pub(crate) fn piece_run(
+    metainfo: &MetaInfo,
+    first: usize,
+    length: usize,
+) -> io::Result<Vec<(usize, Range<usize>)>> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < length {
+        let piece = first + pieces.len();
+        let size = metainfo.piece_size(piece).ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The torrent has no piece {piece}"),
+        ))? as usize;
+        pieces.push((piece, start..start + size));
+        start += size;
+    }
+    if start != length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The bytes don't end where a piece does",
+        ));
+    }
+    Ok(pieces)
+}
+
+/// The parts of the spans of `piece` that `length` bytes from `begin` cover. Offsets into the
+/// piece stay relative to its start
+pub(crate) fn block_spans(
+    metainfo: &MetaInfo,
+    piece: usize,
+    begin: u32,
+    length: u64,
+) -> io::Result<Vec<FileSpan>> {
+    let piece_size = metainfo.piece_size(piece).ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("The torrent has no piece {piece}"),
+    ))?;
+    let (begin, end) = (begin as u64, begin as u64 + length);
+    if end > piece_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Block {begin}..{end} is past the end of piece {piece}"),
+        ));
+    }
+    Ok(metainfo
+        .piece_spans(piece)
+        .into_iter()
+        .filter_map(|span| {
+            let start = span.piece_offset.max(begin);
+            let stop = (span.piece_offset + span.length).min(end);
+            (start < stop).then(|| FileSpan {
+                file_index: span.file_index,
+                file_offset: span.file_offset + (start - span.piece_offset),
+                piece_offset: start,
+                length: stop - start,
+            })
+        })
+        .collect())
+}
+
+/// A piece being put together in memory out of its blocks
+#[derive(Debug)]
+pub(crate) struct PieceBuffer {
+    bytes: Vec<u8>,
+    /// The ranges written so far, sorted and merged
+    written: Vec<Range<usize>>,
+}
+
+impl PieceBuffer {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            bytes: vec![0; size],
+            written: Vec::new(),
+        }
+    }
+
+    /// The caller checks the block fits in the piece
+    pub(crate) fn write(&mut self, begin: usize, data: &[u8]) {
+        let (mut start, mut end) = (begin, begin + data.len());
+        self.bytes[start..end].copy_from_slice(data);
+        // Ranges touching the new one are merged into it
+        self.written.retain(|range| {
+            let apart = range.end < start || range.start > end;
+            if !apart {
+                start = start.min(range.start);
+                end = end.max(range.end);
+            }
+            apart
+        });
+        let at = self.written.partition_point(|range| range.start < start);
+        self.written.insert(at, start..end);
+    }
+
+    /// The bytes of `range`, when all of them were written
+    pub(crate) fn read(&self, range: Range<usize>) -> Option<&[u8]> {
+        self.written
+            .iter()
+            .any(|written| written.start <= range.start && range.end <= written.end)
+            .then(|| &self.bytes[range])
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.read(0..self.bytes.len()).is_some()
+    }
+
+    /// Every written range along with its bytes
+    pub(crate) fn written(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.written
+            .iter()
+            .map(|range| (range.start, &self.bytes[range.clone()]))
+    }
+
+    #[inline]
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Keeps pieces in memory, allocating each one on its first write. Meant for tests and for
+/// downloads that end up somewhere other than the torrent's files
+#[derive(Debug)]
+pub struct MemoryStorage {
+    metainfo: MetaInfo,
+    pieces: Mutex<HashMap<usize, PieceBuffer>>,
+}
+
+impl MemoryStorage {
+    pub fn new(metainfo: &MetaInfo) -> Self {
+        Self {
+            metainfo: metainfo.clone(),
+            pieces: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn metainfo(&self) -> &MetaInfo {
+        &self.metainfo
+    }
+
+    fn read_block(&self, piece: usize, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>> {
+        block_spans(&self.metainfo, piece, begin, length as u64)?;
+        let pieces = self.pieces.lock().expect("Poisoned lock");
+        let range = begin as usize..(begin + length) as usize;
+        Ok(pieces
+            .get(&piece)
+            .and_then(|piece| piece.read(range))
+            .map(<[u8]>::to_vec))
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> io::Result<()> {
+        block_spans(&self.metainfo, piece, begin, data.len() as u64)?;
+        let size = self.metainfo.piece_size(piece).unwrap_or_default() as usize;
+        let mut pieces = self.pieces.lock().expect("Poisoned lock");
+        pieces
+            .entry(piece)
+            .or_insert_with(|| PieceBuffer::new(size))
+            .write(begin as usize, data);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use sha1::{Digest, Sha1};
+
+    use super::{MemoryStorage, Storage};
+    use crate::create::{TorrentBuilder, MIN_PIECE_LENGTH};
+
+    #[test]
+    fn test_memory_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("image");
+        let data = (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&source, &data).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let storage = MemoryStorage::new(&metainfo);
+        let tail = &data[MIN_PIECE_LENGTH as usize..];
+        storage.write_block(1, 1000, &tail[1000..]).unwrap();
+        // Half of the piece is still missing
+        assert_eq!(None, storage.read_piece(1).unwrap());
+        assert_eq!(None, storage.hash_piece(1).unwrap());
+        storage.write_block(1, 0, &tail[..1000]).unwrap();
+        assert_eq!(
+            Some(tail[500..1500].to_vec()),
+            storage.read_block(1, 500, 1000).unwrap()
+        );
+        assert_eq!(
+            Some(<[u8; 20]>::from(Sha1::digest(tail))),
+            storage.hash_piece(1).unwrap()
+        );
+        assert!(storage.write_block(1, 1000, &data).is_err());
+    }
+}
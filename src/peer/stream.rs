@@ -0,0 +1,384 @@
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf},
+    sync::{futures::Notified, Notify},
+};
+
+use super::{picker::PiecePicker, writer::FileWriter};
+use crate::torrent::MetaInfo;
+
+#[derive(Debug)]
+struct TrackerState {
+    picker: PiecePicker,
+    have: Vec<bool>,
+    /// Set once no more pieces are coming, whether the download finished or failed
+    stopped: bool,
+    /// No more pieces are handed out until unpaused
+    paused: bool,
+    waiters: Vec<Waker>,
+}
+
+/// The pieces of a download, shared between the task downloading them and the readers of its
+/// files. Readers move the picker's position to whatever they are waiting on
+#[derive(Debug)]
+pub struct PieceTracker {
+    state: Mutex<TrackerState>,
+    unpaused: Notify,
+    /// Woken whenever a piece is done
+    completed: Notify,
+}
+
+impl PieceTracker {
+    pub fn new(picker: PiecePicker, num_pieces: usize) -> Self {
+        Self {
+            state: Mutex::new(TrackerState {
+                picker,
+                have: vec![false; num_pieces],
+                stopped: false,
+                paused: false,
+                waiters: Vec::new(),
+            }),
+            unpaused: Notify::new(),
+            completed: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TrackerState> {
+        self.state.lock().expect("Poisoned lock")
+    }
+
+    /// The piece to download next, if any is left and the download isn't paused
+    pub fn next_piece(&self) -> Option<usize> {
+        let state = self.lock();
+        if state.paused {
+            return None;
+        }
+        state.picker.next(&state.have)
+    }
+
+    /// Like [`Self::next_piece`], passing over the pieces `accept` turns down
+    pub fn next_piece_where(&self, accept: impl Fn(usize) -> bool) -> Option<usize> {
+        let state = self.lock();
+        if state.paused {
+            return None;
+        }
+        state.picker.next_where(&state.have, accept)
+    }
+
+    /// Whether `piece` is wanted and not on disk yet
+    pub fn is_missing(&self, piece: usize) -> bool {
+        let state = self.lock();
+        state.picker.is_wanted(piece) && !state.have.get(piece).copied().unwrap_or(false)
+    }
+
+    /// Whether every wanted piece is on disk
+    pub fn is_complete(&self) -> bool {
+        let state = self.lock();
+        state.picker.next(&state.have).is_none()
+    }
+
+    /// Which pieces are on disk
+    pub fn have(&self) -> Vec<bool> {
+        self.lock().have.clone()
+    }
+
+    /// Wanted pieces that are not on disk yet
+    pub fn missing(&self) -> Vec<usize> {
+        let state = self.lock();
+        state
+            .picker
+            .pieces()
+            .into_iter()
+            .filter(|piece| !state.have.get(*piece).copied().unwrap_or(false))
+            .collect()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.lock().paused = paused;
+        if !paused {
+            self.unpaused.notify_waiters();
+        }
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Waits until the download is no longer paused
+    pub async fn wait_unpaused(&self) {
+        loop {
+            let unpaused = self.unpaused.notified();
+            if !self.is_paused() {
+                return;
+            }
+            unpaused.await;
+        }
+    }
+
+    /// Records a verified piece that is on disk and wakes the readers
+    pub fn piece_done(&self, piece: usize) {
+        let mut state = self.lock();
+        if let Some(have) = state.have.get_mut(piece) {
+            *have = true;
+        }
+        state.waiters.drain(..).for_each(Waker::wake);
+        drop(state);
+        self.completed.notify_waiters();
+    }
+
+    /// Completes once another piece is done, counting from the call
+    pub fn completed(&self) -> Notified<'_> {
+        self.completed.notified()
+    }
+
+    /// No more pieces are coming, which readers waiting on missing ones get an error for
+    pub fn stop(&self) {
+        let mut state = self.lock();
+        state.stopped = true;
+        state.waiters.drain(..).for_each(Waker::wake);
+    }
+
+    #[inline]
+    pub fn has_piece(&self, piece: usize) -> bool {
+        self.lock().have.get(piece).copied().unwrap_or(false)
+    }
+
+    /// Puts `piece` and the ones after it first in line
+    pub fn set_position(&self, piece: usize) {
+        self.lock().picker.set_position(piece);
+    }
+
+    /// Whether `piece` is on disk; otherwise the reader is woken once it or the end of the
+    /// download comes
+    fn poll_piece(&self, piece: usize, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.lock();
+        if state.have.get(piece).copied().unwrap_or(false) {
+            return Poll::Ready(Ok(()));
+        }
+        if state.stopped {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("The download stopped before piece {piece} came"),
+            )));
+        }
+        state.picker.set_position(piece);
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+type ReadFuture = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// A file inside a torrent that can be read while the torrent downloads. Reads wait until the
+/// pieces they need are verified and seeking moves the download to the new position
+pub struct FileStream {
+    tracker: Arc<PieceTracker>,
+    path: PathBuf,
+    piece_length: u64,
+    /// Offset of the file in the torrent's data
+    start: u64,
+    length: u64,
+    position: u64,
+    read: Option<ReadFuture>,
+}
+
+impl std::fmt::Debug for FileStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileStream")
+            .field("path", &self.path)
+            .field("start", &self.start)
+            .field("length", &self.length)
+            .field("position", &self.position)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileStream {
+    /// Opens the file at `file_index` of the torrent being written by `writer`
+    pub fn open(
+        metainfo: &MetaInfo,
+        writer: &FileWriter,
+        tracker: Arc<PieceTracker>,
+        file_index: usize,
+    ) -> io::Result<Self> {
+        let files = metainfo.files();
+        let (_, length) = files.get(file_index).ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The torrent has no file {file_index}"),
+        ))?;
+        let path = writer
+            .path(file_index)
+            .filter(|_| writer.is_wanted(file_index))
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("File {file_index} is not being downloaded"),
+            ))?
+            .clone();
+        let start = files[..file_index].iter().map(|(_, length)| length).sum();
+        let stream = Self {
+            tracker,
+            path,
+            piece_length: metainfo.piece_length(),
+            start,
+            length: *length,
+            position: 0,
+            read: None,
+        };
+        stream.tracker.set_position(stream.piece());
+        Ok(stream)
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// The piece holding the byte at the current position
+    fn piece(&self) -> usize {
+        ((self.start + self.position) / self.piece_length) as usize
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read.is_none() {
+            if this.position >= this.length || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let piece = this.piece();
+            if let Err(e) = std::task::ready!(this.tracker.poll_piece(piece, cx)) {
+                return Poll::Ready(Err(e));
+            }
+            // Reads stop at the end of the piece since the next one might still be missing
+            let piece_end = (piece as u64 + 1) * this.piece_length - this.start;
+            let len = (buf.remaining() as u64)
+                .min(piece_end - this.position)
+                .min(this.length - this.position);
+            let (path, offset) = (this.path.clone(), this.position);
+            this.read = Some(Box::pin(async move {
+                let mut file = File::open(path).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut bytes = vec![0; len as usize];
+                file.read_exact(&mut bytes).await?;
+                Ok(bytes)
+            }));
+        }
+        let read = this.read.as_mut().expect("A read was just started");
+        let result = std::task::ready!(read.as_mut().poll(cx));
+        this.read = None;
+        let bytes = result?;
+        // The buffer may have shrunk since the read started, in which case the rest is read again
+        let len = bytes.len().min(buf.remaining());
+        buf.put_slice(&bytes[..len]);
+        this.position += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        }
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Seeked before the start of the file",
+        ))?;
+        this.read = None;
+        this.position = position;
+        if position < this.length {
+            this.tracker.set_position(this.piece());
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::SeekFrom, sync::Arc};
+
+    use reqwest::Url;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::{FileStream, PieceTracker};
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::{
+            picker::{FileSelection, PickStrategy, PiecePicker},
+            storage::Storage,
+            writer::FileWriter,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_stream_waits_for_pieces_and_reprioritizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("movie");
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let selection = FileSelection::all(&metainfo);
+        let out = dir.path().join("out");
+        let writer = Arc::new(
+            FileWriter::create(&metainfo, &selection, &out)
+                .await
+                .unwrap(),
+        );
+        let picker = PiecePicker::new(&metainfo, &selection)
+            .with_strategy(PickStrategy::Sequential)
+            .with_deadline_window(1);
+        let tracker = Arc::new(PieceTracker::new(picker, metainfo.num_pieces()));
+
+        let mut stream = FileStream::open(&metainfo, &writer, tracker.clone(), 0).unwrap();
+        stream.seek(SeekFrom::Start(70_000)).await.unwrap();
+        // Piece 4 holds offset 70000, so the download jumps there first
+        assert_eq!(Some(4), tracker.next_piece());
+
+        let reader = tokio::spawn(async move {
+            let mut tail = Vec::new();
+            stream.read_to_end(&mut tail).await.unwrap();
+            tail
+        });
+        let piece_length = MIN_PIECE_LENGTH as usize;
+        while let Some(piece) = tracker.next_piece() {
+            let start = piece * piece_length;
+            let end = (start + piece_length).min(data.len());
+            writer.write_piece(piece, &data[start..end]).unwrap();
+            tracker.piece_done(piece);
+            tokio::task::yield_now().await;
+        }
+        tracker.stop();
+        assert_eq!(data[70_000..], reader.await.unwrap());
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use super::{
+    extension::{split_extended, ExtendedHandshake, EXTENDED_HANDSHAKE_ID},
+    message::{BlockRequest, PeerBufferStream, PeerMessageId},
+    storage::{self, Storage},
+    stream::PieceTracker,
+};
+
+/// Largest block a peer may ask for; bigger requests are ignored, or rejected with the Fast
+/// Extension
+pub const MAX_REQUEST_LENGTH: u32 = 1 << 17;
+
+/// Serves the pieces we have to a peer once the handshakes are done, until the peer goes away
+/// or sends nothing but keep-alives for `idle_timeout`. Peers are unchoked as soon as they are
+/// interested, and told about every piece done in the meantime
+///
+/// `allowed_fast` is set when both sides support the Fast Extension (BEP 6) and holds the pieces
+/// the peer may ask for while choked. What we have is then announced with `HaveAll` or
+/// `HaveNone` where that fits, and requests that can't be served get rejected instead of going
+/// unanswered.
+///
+/// `listen_port` is called with the port the peer listens on whenever its extended handshake
+/// (BEP 10) gives one, which is where it takes connections unlike the port it came from.
+pub async fn serve_peer(
+    stream: PeerBufferStream,
+    storage: &Arc<dyn Storage>,
+    tracker: &PieceTracker,
+    uploaded: &AtomicU64,
+    allowed_fast: Option<&[u32]>,
+    idle_timeout: Duration,
+    mut listen_port: impl FnMut(u16),
+) -> Result<()> {
+    let (reader, mut stream) = stream.into_split();
+    let fast = allowed_fast.is_some();
+    let mut announced = tracker.have();
+    if fast && announced.iter().all(|have| *have) {
+        stream.write_message(PeerMessageId::HaveAll, &[]).await?;
+    } else if fast && !announced.contains(&true) {
+        stream.write_message(PeerMessageId::HaveNone, &[]).await?;
+    } else {
+        let mut bitfield = vec![0u8; (announced.len() + 7) / 8];
+        for (piece, _) in announced.iter().enumerate().filter(|(_, have)| **have) {
+            bitfield[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        stream
+            .write_message(PeerMessageId::Bitfield, &bitfield)
+            .await?;
+    }
+    let allowed_fast = allowed_fast.unwrap_or_default();
+    for piece in allowed_fast {
+        stream
+            .write_message(PeerMessageId::AllowedFast, &piece.to_be_bytes())
+            .await?;
+    }
+    // Messages are read in the background so that pieces getting done can be announced meanwhile
+    let mut messages = reader.into_receiver();
+    let mut idle_deadline = tokio::time::Instant::now() + idle_timeout;
+    let mut choked = true;
+    loop {
+        let completed = tracker.completed();
+        let have = tracker.have();
+        for (piece, _) in have
+            .iter()
+            .zip(&announced)
+            .enumerate()
+            .filter(|(_, (have, announced))| **have && !**announced)
+        {
+            stream
+                .write_message(PeerMessageId::Have, &(piece as u32).to_be_bytes())
+                .await?;
+        }
+        announced = have;
+        let message = tokio::select! {
+            message = messages.recv() => message?,
+            _ = completed => continue,
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                return Err(anyhow!("Peer sent nothing for {idle_timeout:?}"));
+            }
+        };
+        idle_deadline = tokio::time::Instant::now() + idle_timeout;
+        match message.id {
+            PeerMessageId::Interested => {
+                choked = false;
+                stream.write_message(PeerMessageId::Unchoke, &[]).await?;
+            }
+            PeerMessageId::Request => {
+                let request = BlockRequest::from_bytes(&message.payload)?;
+                let block = if request.length > MAX_REQUEST_LENGTH
+                    || !tracker.has_piece(request.index as usize)
+                    || (choked && !allowed_fast.contains(&request.index))
+                {
+                    None
+                } else {
+                    // Blocks past the end of the piece are refused by the storage
+                    storage::blocking(storage, move |storage| {
+                        storage.read_block(request.index as usize, request.begin, request.length)
+                    })
+                    .await
+                    .ok()
+                    .flatten()
+                };
+                let Some(block) = block else {
+                    if fast {
+                        stream
+                            .write_message(PeerMessageId::RejectRequest, &request.to_bytes())
+                            .await?;
+                    }
+                    continue;
+                };
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend_from_slice(&request.index.to_be_bytes());
+                payload.extend_from_slice(&request.begin.to_be_bytes());
+                payload.extend_from_slice(&block);
+                stream.write_message(PeerMessageId::Piece, &payload).await?;
+                uploaded.fetch_add(block.len() as u64, Ordering::Relaxed);
+            }
+            PeerMessageId::Extended => {
+                let (id, payload) = split_extended(&message.payload)?;
+                if id == EXTENDED_HANDSHAKE_ID {
+                    let ext_hand = ExtendedHandshake::from_bytes(payload)?;
+                    if let Some(port) = ext_hand.listen_port.filter(|port| *port != 0) {
+                        listen_port(port);
+                    }
+                }
+            }
+            // Nothing else matters to a peer that only uploads
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{atomic::AtomicU64, Arc},
+        time::Duration,
+    };
+
+    use reqwest::Url;
+
+    use super::serve_peer;
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::{
+            message::{BlockRequest, PeerBufferStream, PeerMessageId},
+            picker::{FileSelection, PiecePicker},
+            storage::Storage,
+            stream::PieceTracker,
+            writer::FileWriter,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_serves_requested_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("image");
+        let data = (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let selection = FileSelection::all(&metainfo);
+        let storage: Arc<dyn Storage> = Arc::new(
+            FileWriter::create(&metainfo, &selection, &source)
+                .await
+                .unwrap(),
+        );
+        let tracker = Arc::new(PieceTracker::new(
+            PiecePicker::new(&metainfo, &selection),
+            2,
+        ));
+        tracker.piece_done(1);
+
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let server = tokio::spawn({
+            let tracker = tracker.clone();
+            async move {
+                let uploaded = AtomicU64::new(0);
+                let stream = PeerBufferStream::from_stream(ours);
+                let idle_timeout = Duration::from_secs(60);
+                let _ = serve_peer(
+                    stream,
+                    &storage,
+                    &tracker,
+                    &uploaded,
+                    None,
+                    idle_timeout,
+                    |_| {},
+                )
+                .await;
+                uploaded.into_inner()
+            }
+        });
+        let mut peer = PeerBufferStream::from_stream(theirs);
+        let bitfield = peer.read_message().await.unwrap();
+        assert_eq!(PeerMessageId::Bitfield, bitfield.id);
+        assert_eq!(vec![0b0100_0000], bitfield.payload);
+        peer.write_message(PeerMessageId::Interested, &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            PeerMessageId::Unchoke,
+            peer.read_message().await.unwrap().id
+        );
+        let request = BlockRequest {
+            index: 1,
+            begin: 100,
+            length: 1000,
+        };
+        peer.write_message(PeerMessageId::Request, &request.to_bytes())
+            .await
+            .unwrap();
+        let piece = peer.read_message().await.unwrap();
+        assert_eq!(PeerMessageId::Piece, piece.id);
+        let start = MIN_PIECE_LENGTH as usize + 100;
+        assert_eq!(data[start..start + 1000], piece.payload[8..]);
+        // Pieces done later are announced as they come
+        tracker.piece_done(0);
+        let have = peer.read_message().await.unwrap();
+        assert_eq!(PeerMessageId::Have, have.id);
+        assert_eq!(0u32.to_be_bytes().to_vec(), have.payload);
+        drop(peer);
+        assert_eq!(1000, server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fast_peers_get_allowed_pieces_and_rejections() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("image");
+        let data = (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let selection = FileSelection::all(&metainfo);
+        let storage: Arc<dyn Storage> = Arc::new(
+            FileWriter::create(&metainfo, &selection, &source)
+                .await
+                .unwrap(),
+        );
+        let tracker = PieceTracker::new(PiecePicker::new(&metainfo, &selection), 2);
+        tracker.piece_done(1);
+
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move {
+            let uploaded = AtomicU64::new(0);
+            let stream = PeerBufferStream::from_stream(ours);
+            let idle_timeout = Duration::from_secs(60);
+            let _ = serve_peer(
+                stream,
+                &storage,
+                &tracker,
+                &uploaded,
+                Some(&[1]),
+                idle_timeout,
+                |_| {},
+            )
+            .await;
+        });
+        let mut peer = PeerBufferStream::from_stream(theirs);
+        assert_eq!(
+            PeerMessageId::Bitfield,
+            peer.read_message().await.unwrap().id
+        );
+        let allowed = peer.read_message().await.unwrap();
+        assert_eq!(PeerMessageId::AllowedFast, allowed.id);
+        assert_eq!(1u32.to_be_bytes().to_vec(), allowed.payload);
+        // Still choked, but the piece is allowed fast
+        let allowed = BlockRequest {
+            index: 1,
+            begin: 0,
+            length: 1000,
+        };
+        peer.write_message(PeerMessageId::Request, &allowed.to_bytes())
+            .await
+            .unwrap();
+        assert_eq!(PeerMessageId::Piece, peer.read_message().await.unwrap().id);
+        // We don't have piece 0
+        let missing = BlockRequest {
+            index: 0,
+            begin: 0,
+            length: 1000,
+        };
+        peer.write_message(PeerMessageId::Request, &missing.to_bytes())
+            .await
+            .unwrap();
+        let rejected = peer.read_message().await.unwrap();
+        assert_eq!(PeerMessageId::RejectRequest, rejected.id);
+        assert_eq!(missing.to_bytes().to_vec(), rejected.payload);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+use tokio::fs::{self, OpenOptions};
+
+use super::{
+    picker::{relative_paths, FileSelection},
+    storage::{block_spans, piece_run, Storage},
+};
+use crate::{
+    resume::FileStamp,
+    torrent::{FileType, MetaInfo},
+    INFO_HASH_SIZE,
+};
+
+/// Directory under the download location that holds pieces spilling into skipped files
+pub const SIDECAR_DIR: &str = ".parts";
+
+/// Writes downloaded pieces into the files of a torrent, which makes it the file backend of
+/// [`Storage`]. Only the files selected are created;
+/// pieces that also cover skipped files are kept whole in the sidecar directory so that those
+/// files can be completed later without downloading the piece again
+#[derive(Debug)]
+pub struct FileWriter {
+    metainfo: MetaInfo,
+    selection: FileSelection,
+    /// Where each file of the torrent goes
+    paths: Vec<PathBuf>,
+    sidecar: PathBuf,
+    /// Whether any selected file was already on disk, which may hold pieces of an earlier run
+    preexisting: bool,
+}
+
+impl FileWriter {
+    /// `out` is the file of a single file torrent or the directory the files of a multi file
+    /// torrent go under. Files already there are kept as they are apart from their length
+    pub async fn create(
+        metainfo: &MetaInfo,
+        selection: &FileSelection,
+        out: impl AsRef<Path>,
+    ) -> std::io::Result<Self> {
+        let out = out.as_ref();
+        let paths = match metainfo.file_type() {
+            FileType::SingleFile(_) => vec![out.to_path_buf()],
+            FileType::MultiFile(_) => relative_paths(metainfo)
+                .into_iter()
+                .map(|path| out.join(path))
+                .collect(),
+        };
+        let mut preexisting = false;
+        for (index, (path, (_, length))) in paths.iter().zip(metainfo.files()).enumerate() {
+            if !selection.is_wanted(index) || metainfo.is_pad_file(index) {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            preexisting |= fs::try_exists(path).await?;
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .await?;
+            // Resizing touches the modification time, which resuming relies on
+            if file.metadata().await?.len() != length {
+                file.set_len(length).await?;
+            }
+        }
+        Ok(Self {
+            metainfo: metainfo.clone(),
+            selection: selection.clone(),
+            paths,
+            sidecar: out.join(SIDECAR_DIR),
+            preexisting,
+        })
+    }
+
+    /// Where the file at `file_index` is written to
+    #[inline]
+    pub fn path(&self, file_index: usize) -> Option<&PathBuf> {
+        self.paths.get(file_index)
+    }
+
+    #[inline]
+    pub fn is_wanted(&self, file_index: usize) -> bool {
+        self.selection.is_wanted(file_index)
+    }
+
+    #[inline]
+    pub fn preexisting(&self) -> bool {
+        self.preexisting
+    }
+
+    /// Size and modification time of every file of the torrent as it is on disk now
+    pub fn file_stamps(&self) -> Vec<FileStamp> {
+        self.paths.iter().map(FileStamp::of).collect()
+    }
+
+    /// Where a piece that spills into skipped files is kept
+    #[inline]
+    pub fn sidecar_path(&self, piece: usize) -> PathBuf {
+        self.sidecar.join(format!("{piece}.piece"))
+    }
+}
+
+impl FileWriter {
+    /// Whether a piece covers any file that is skipped, in which case it is kept whole in the
+    /// sidecar directory
+    fn spills(&self, piece: usize) -> bool {
+        self.metainfo
+            .piece_spans(piece)
+            .iter()
+            .any(|span| !self.selection.is_wanted(span.file_index) && !self.is_pad(span.file_index))
+    }
+
+    /// Pad files only hold zeros, so they are never created, read or written
+    #[inline]
+    fn is_pad(&self, file_index: usize) -> bool {
+        self.metainfo.is_pad_file(file_index)
+    }
+}
+
+impl Storage for FileWriter {
+    fn metainfo(&self) -> &MetaInfo {
+        &self.metainfo
+    }
+
+    /// Blocks of pieces spilling into skipped files come from the sidecar directory, and are
+    /// missing when they were never kept there
+    fn read_block(&self, piece: usize, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>> {
+        let spans = block_spans(&self.metainfo, piece, begin, length as u64)?;
+        let mut bytes = vec![0; length as usize];
+        if self.spills(piece) {
+            let result = std::fs::File::open(self.sidecar_path(piece)).and_then(|mut file| {
+                file.seek(SeekFrom::Start(begin as u64))?;
+                file.read_exact(&mut bytes)
+            });
+            return match result {
+                Ok(()) => Ok(Some(bytes)),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            };
+        }
+        for span in spans
+            .into_iter()
+            .filter(|span| !self.is_pad(span.file_index))
+        {
+            let mut file = std::fs::File::open(&self.paths[span.file_index])?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            let start = (span.piece_offset - begin as u64) as usize;
+            file.read_exact(&mut bytes[start..start + span.length as usize])?;
+        }
+        Ok(Some(bytes))
+    }
+
+    fn write_block(&self, piece: usize, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = block_spans(&self.metainfo, piece, begin, data.len() as u64)?;
+        for span in spans {
+            if !self.selection.is_wanted(span.file_index) || self.is_pad(span.file_index) {
+                continue;
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&self.paths[span.file_index])?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            let start = (span.piece_offset - begin as u64) as usize;
+            file.write_all(&data[start..start + span.length as usize])?;
+        }
+        if self.spills(piece) {
+            std::fs::create_dir_all(&self.sidecar)?;
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.sidecar_path(piece))?;
+            file.seek(SeekFrom::Start(begin as u64))?;
+            file.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// Consecutive pieces pick up in a file where the one before left off, so each file the run
+    /// covers gets one write. Runs with pieces spilling into skipped files go a piece at a time
+    fn write_pieces(&self, first: usize, bytes: &[u8]) -> io::Result<()> {
+        let pieces = piece_run(&self.metainfo, first, bytes.len())?;
+        if pieces.iter().any(|(piece, _)| self.spills(*piece)) {
+            return pieces
+                .into_iter()
+                .try_for_each(|(piece, range)| self.write_piece(piece, &bytes[range]));
+        }
+        let mut writes: Vec<(usize, u64, Range<usize>)> = Vec::new();
+        for (piece, range) in pieces {
+            for span in self.metainfo.piece_spans(piece) {
+                if self.is_pad(span.file_index) {
+                    continue;
+                }
+                let start = range.start + span.piece_offset as usize;
+                let end = start + span.length as usize;
+                match writes.last_mut() {
+                    Some((file_index, offset, data))
+                        if *file_index == span.file_index
+                            && *offset + data.len() as u64 == span.file_offset =>
+                    {
+                        data.end = end
+                    }
+                    _ => writes.push((span.file_index, span.file_offset, start..end)),
+                }
+            }
+        }
+        for (file_index, offset, range) in writes {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&self.paths[file_index])?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&bytes[range])?;
+        }
+        Ok(())
+    }
+
+    /// Writes go straight to the files, so this only asks the OS to put them on disk
+    fn flush(&self) -> io::Result<()> {
+        for (index, path) in self.paths.iter().enumerate() {
+            if self.selection.is_wanted(index) && !self.is_pad(index) {
+                std::fs::File::open(path)?.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hashes the piece a file range at a time instead of reading it whole first
+    fn hash_piece(&self, piece: usize) -> io::Result<Option<[u8; INFO_HASH_SIZE]>> {
+        if self.spills(piece) {
+            return Ok(self
+                .read_piece(piece)?
+                .map(|bytes| Sha1::digest(bytes).into()));
+        }
+        let mut hasher = Sha1::new();
+        for span in self.metainfo.piece_spans(piece) {
+            if self.is_pad(span.file_index) {
+                io::copy(&mut io::repeat(0).take(span.length), &mut hasher)?;
+                continue;
+            }
+            let mut file = std::fs::File::open(&self.paths[span.file_index])?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            let copied = io::copy(&mut file.take(span.length), &mut hasher)?;
+            if copied != span.length {
+                return Ok(None);
+            }
+        }
+        Ok(Some(hasher.finalize().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use reqwest::Url;
+
+    use super::FileWriter;
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        peer::{
+            picker::{FileSelection, PiecePicker},
+            storage::Storage,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_boundary_piece_goes_to_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        let data = (0..20_100u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(root.join("README"), &data[..100]).unwrap();
+        fs::write(root.join("bin").join("tool"), &data[100..]).unwrap();
+        let (_, metainfo) = TorrentBuilder::new(&root)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+
+        let out = dir.path().join("out");
+        let selection = FileSelection::only(&metainfo, &["bin/tool".to_owned()]).unwrap();
+        let writer = FileWriter::create(&metainfo, &selection, &out)
+            .await
+            .unwrap();
+        for piece in PiecePicker::new(&metainfo, &selection).pieces() {
+            let start = piece * MIN_PIECE_LENGTH as usize;
+            let end = (start + MIN_PIECE_LENGTH as usize).min(data.len());
+            writer.write_piece(piece, &data[start..end]).unwrap();
+        }
+
+        assert_eq!(data[100..], fs::read(out.join("bin").join("tool")).unwrap());
+        assert!(!out.join("README").exists());
+        assert_eq!(
+            data[..MIN_PIECE_LENGTH as usize],
+            fs::read(writer.sidecar_path(0)).unwrap()
+        );
+        assert!(!writer.sidecar_path(1).exists());
+        assert_eq!(
+            Some(data[MIN_PIECE_LENGTH as usize..].to_vec()),
+            writer.read_piece(1).unwrap()
+        );
+
+        // Reopening keeps what was written
+        let writer = FileWriter::create(&metainfo, &selection, &out)
+            .await
+            .unwrap();
+        assert!(writer.preexisting());
+        assert_eq!(
+            Some(data[..MIN_PIECE_LENGTH as usize].to_vec()),
+            writer.read_piece(0).unwrap()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::PEER_ID_SIZE;
+
+/// The two letters naming this client in Azureus-style peer ids
+pub const CLIENT_CODE: &str = "CR";
+
+/// Well-known clients by the two letters of their Azureus-style peer ids
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("CR", "bittorrent-starter-rust"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Well-known clients by the letter starting their Shadow-style peer ids
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// One character of an Azureus-style version: digits, then letters for 10 to 35
+fn version_char(number: u64) -> char {
+    char::from_digit(number.min(35) as u32, 36)
+        .unwrap_or('Z')
+        .to_ascii_uppercase()
+}
+
+fn version_number(c: u8) -> Option<u32> {
+    (c as char).to_digit(36)
+}
+
+/// The `-XXyyyy-` start of our peer ids, with the version of the crate in place of `yyyy`
+pub fn client_prefix() -> String {
+    let version = [
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH"),
+    ]
+    .iter()
+    .map(|part| version_char(part.parse().unwrap_or_default()))
+    .collect::<String>();
+    format!("-{CLIENT_CODE}{version}0-")
+}
+
+/// A peer id starting with `prefix`, or as much of it as fits, followed by random bytes
+pub fn generate(prefix: &[u8]) -> [u8; PEER_ID_SIZE] {
+    let mut peer_id = rand::random::<[u8; PEER_ID_SIZE]>();
+    let len = prefix.len().min(PEER_ID_SIZE);
+    peer_id[..len].copy_from_slice(&prefix[..len]);
+    peer_id
+}
+
+/// The client and version a peer id names, like `qBittorrent 4.2.5`, for the Azureus-style,
+/// Shadow-style and mainline formats. Ids of another format give nothing
+pub fn client_name(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    azureus_name(peer_id)
+        .or_else(|| mainline_name(peer_id))
+        .or_else(|| shadow_name(peer_id))
+}
+
+/// `-XXyyyy-`, where each `y` is a part of the version
+fn azureus_name(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = &peer_id[3..7];
+    if !code.chars().all(|c| c.is_ascii_alphanumeric())
+        || !version.iter().all(u8::is_ascii_alphanumeric)
+    {
+        return None;
+    }
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or(code, |(_, name)| *name);
+    let version = match code {
+        // One digit of major version and two of minor, with `Z` or `X` marking a nightly
+        "TR" => format!(
+            "{}.{}",
+            version[0] as char,
+            std::str::from_utf8(&version[1..3]).ok()?
+        ),
+        _ => {
+            let mut parts = version
+                .iter()
+                .map(|c| version_number(*c))
+                .collect::<Option<Vec<_>>>()?;
+            // The build number is only shown when there is one
+            if parts[3] == 0 {
+                parts.pop();
+            }
+            parts
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+    };
+    Some(format!("{name} {version}"))
+}
+
+/// `Mx-y-z--` of the original client, where each part of the version may take several digits
+fn mainline_name(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    let id = peer_id.strip_prefix(b"M")?;
+    let end = id.windows(2).position(|window| window == b"--")?;
+    let parts = id[..end].split(|c| *c == b'-').collect::<Vec<_>>();
+    if parts.len() != 3
+        || parts
+            .iter()
+            .any(|part| part.is_empty() || !part.iter().all(u8::is_ascii_digit))
+    {
+        return None;
+    }
+    let version = parts
+        .iter()
+        .map(|part| String::from_utf8_lossy(part))
+        .collect::<Vec<_>>()
+        .join(".");
+    Some(format!("BitTorrent {version}"))
+}
+
+/// A letter followed by up to five version characters, padded with `-` to at least `---`
+fn shadow_name(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    let name = SHADOW_CLIENTS
+        .iter()
+        .find(|(letter, _)| *letter == peer_id[0])
+        .map(|(_, name)| *name)?;
+    let version = &peer_id[1..6];
+    let len = version
+        .iter()
+        .position(|c| *c == b'-')
+        .unwrap_or(version.len());
+    if len == 0 || !peer_id[1 + len..].starts_with(b"---") {
+        return None;
+    }
+    let version = version[..len]
+        .iter()
+        .map(|c| version_number(*c).map(|number| number.to_string()))
+        .collect::<Option<Vec<_>>>()?
+        .join(".");
+    Some(format!("{name} {version}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_name, client_prefix, generate};
+    use crate::PEER_ID_SIZE;
+
+    fn id(s: &[u8]) -> [u8; PEER_ID_SIZE] {
+        generate(s)
+    }
+
+    #[test]
+    fn test_generate_and_decode() {
+        let prefix = client_prefix();
+        assert_eq!(8, prefix.len());
+        let ours = generate(prefix.as_bytes());
+        assert!(ours.starts_with(prefix.as_bytes()));
+        // The suffix is random, so two ids of the same client differ
+        assert_ne!(ours, generate(prefix.as_bytes()));
+        assert_eq!(
+            Some(format!(
+                "bittorrent-starter-rust {}",
+                env!("CARGO_PKG_VERSION")
+            )),
+            client_name(&ours)
+        );
+
+        assert_eq!(
+            Some("qBittorrent 4.2.5".to_owned()),
+            client_name(&id(b"-qB4250-"))
+        );
+        assert_eq!(
+            Some("Transmission 2.94".to_owned()),
+            client_name(&id(b"-TR2940-"))
+        );
+        assert_eq!(
+            Some("µTorrent 3.5.5".to_owned()),
+            client_name(&id(b"-UT3550-"))
+        );
+        assert_eq!(Some("ZZ 1.2.0.1".to_owned()), client_name(&id(b"-ZZ1201-")));
+        assert_eq!(
+            Some("BitTorrent 4.10.2".to_owned()),
+            client_name(&id(b"M4-10-2--"))
+        );
+        assert_eq!(
+            Some("BitTornado 0.3.17".to_owned()),
+            client_name(&id(b"T03H-----"))
+        );
+        assert_eq!(None, client_name(b"00112233445566778899"));
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// How far back transfer rates look
+pub const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// What a download reports as it goes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    PeerConnected {
+        peer: SocketAddr,
+    },
+    PeerDisconnected {
+        peer: SocketAddr,
+        /// Why the connection ended, if it failed
+        error: Option<String>,
+    },
+    PieceVerified {
+        piece: usize,
+        pieces_done: usize,
+        num_pieces: usize,
+    },
+    /// Sent along with every verified piece
+    Rates {
+        downloaded: u64,
+        uploaded: u64,
+        /// Bytes of the selected files still missing
+        left: u64,
+        /// Bytes per second over the last [`RATE_WINDOW`]
+        download_rate: u64,
+        upload_rate: u64,
+        eta_secs: Option<u64>,
+        /// Peers we are connected to
+        peers: usize,
+    },
+    Finished {
+        downloaded: u64,
+        uploaded: u64,
+    },
+}
+
+pub type ProgressSender = mpsc::UnboundedSender<ProgressEvent>;
+pub type ProgressReceiver = mpsc::UnboundedReceiver<ProgressEvent>;
+
+/// Bytes per second of a growing total, measured over a sliding window
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new(RATE_WINDOW)
+    }
+}
+
+impl RateMeter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records the total at `now`, forgetting the samples that fell out of the window
+    pub fn record(&mut self, now: Instant, total: u64) {
+        self.samples.push_back((now, total));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > self.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The rate between the oldest and the newest sample
+    pub fn rate(&self) -> u64 {
+        let (Some((first_at, first)), Some((last_at, last))) =
+            (self.samples.front(), self.samples.back())
+        else {
+            return 0;
+        };
+        let elapsed = last_at.duration_since(*first_at).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0;
+        }
+        ((last - first) as f64 / elapsed) as u64
+    }
+}
+
+/// How long `left` bytes take at `rate` bytes per second
+pub fn eta(left: u64, rate: u64) -> Option<Duration> {
+    (rate > 0).then(|| Duration::from_secs((left + rate - 1) / rate))
+}
+
+/// Formats a number of bytes with a binary unit, like `1.5 MiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Width of the bar itself, in characters
+const BAR_WIDTH: usize = 30;
+
+/// A one line summary of a download kept up to date from its events
+#[derive(Debug, Clone, Default)]
+pub struct ProgressBar {
+    pieces_done: usize,
+    num_pieces: usize,
+    peers: usize,
+    download_rate: u64,
+    upload_rate: u64,
+    eta_secs: Option<u64>,
+}
+
+impl ProgressBar {
+    pub fn update(&mut self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::PieceVerified {
+                pieces_done,
+                num_pieces,
+                ..
+            } => {
+                self.pieces_done = *pieces_done;
+                self.num_pieces = *num_pieces;
+            }
+            ProgressEvent::Rates {
+                download_rate,
+                upload_rate,
+                eta_secs,
+                peers,
+                ..
+            } => {
+                self.download_rate = *download_rate;
+                self.upload_rate = *upload_rate;
+                self.eta_secs = *eta_secs;
+                self.peers = *peers;
+            }
+            ProgressEvent::PeerConnected { .. } => self.peers += 1,
+            ProgressEvent::PeerDisconnected { .. } => self.peers = self.peers.saturating_sub(1),
+            ProgressEvent::Finished { .. } => {
+                self.pieces_done = self.num_pieces;
+                self.eta_secs = Some(0);
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let done = if self.num_pieces == 0 {
+            0.0
+        } else {
+            self.pieces_done as f64 / self.num_pieces as f64
+        };
+        let filled = (done * BAR_WIDTH as f64) as usize;
+        let eta = match self.eta_secs {
+            Some(secs) => format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+            None => "--:--:--".to_owned(),
+        };
+        format!(
+            "[{}{}] {:5.1}% {}/{} pieces, {} peers, down {}/s, up {}/s, ETA {eta}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            done * 100.0,
+            self.pieces_done,
+            self.num_pieces,
+            self.peers,
+            format_bytes(self.download_rate),
+            format_bytes(self.upload_rate),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{eta, format_bytes, ProgressBar, ProgressEvent, RateMeter};
+
+    #[test]
+    fn test_rate_over_window() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(Duration::from_secs(5));
+        assert_eq!(0, meter.rate());
+        meter.record(start, 0);
+        meter.record(start + Duration::from_secs(2), 2_000);
+        assert_eq!(1_000, meter.rate());
+        // The first sample drops out, leaving the faster part
+        meter.record(start + Duration::from_secs(6), 14_000);
+        assert_eq!(3_000, meter.rate());
+        assert_eq!(Some(Duration::from_secs(4)), eta(10_000, 3_000));
+        assert_eq!(None, eta(10_000, 0));
+        assert_eq!("512 B", format_bytes(512));
+        assert_eq!("1.5 MiB", format_bytes(3 << 19));
+    }
+
+    #[test]
+    fn test_events_serialize_to_tagged_json() {
+        let event = ProgressEvent::PeerConnected {
+            peer: "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+        };
+        assert_eq!(
+            r#"{"event":"peer_connected","peer":"10.0.0.1:6881"}"#,
+            serde_json::to_string(&event).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_render_bar() {
+        let mut bar = ProgressBar::default();
+        bar.update(&ProgressEvent::PieceVerified {
+            piece: 0,
+            pieces_done: 1,
+            num_pieces: 4,
+        });
+        bar.update(&ProgressEvent::Rates {
+            downloaded: 16_384,
+            uploaded: 0,
+            left: 49_152,
+            download_rate: 2048,
+            upload_rate: 0,
+            eta_secs: Some(24),
+            peers: 1,
+        });
+        assert_eq!(
+            "[#######-----------------------]  25.0% 1/4 pieces, 1 peers, down 2.0 KiB/s, \
+             up 0 B/s, ETA 00:00:24",
+            bar.render()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::ParseError;
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, 0 meaning unlimited
+    rate: u64,
+    /// Goes negative when bytes are reserved ahead of time, which is what later callers wait out
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        // A second worth of bytes is the largest burst allowed
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+/// A token bucket limiting the bytes per second of every connection sharing it
+///
+/// Bytes are reserved before they go through and callers wait for their reservation to be
+/// paid off, so connections are served in the order they asked and none can starve the others.
+/// A rate of 0 means unlimited. The rate can be changed at any time.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    fn bucket(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().expect("Poisoned lock")
+    }
+
+    #[inline]
+    pub fn rate(&self) -> u64 {
+        self.bucket().rate
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        bucket.tokens = if rate == 0 {
+            0.0
+        } else {
+            bucket.tokens.min(rate as f64)
+        };
+    }
+
+    /// Takes `amount` bytes out of the bucket, returning how long to wait until they are paid for
+    fn reserve_at(&self, now: Instant, amount: u64) -> Duration {
+        let mut bucket = self.bucket();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+        bucket.refill(now);
+        bucket.tokens -= amount as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+        }
+    }
+
+    /// Waits until `amount` bytes may go through
+    pub async fn acquire(&self, amount: u64) {
+        tokio::time::sleep(self.reserve_at(Instant::now(), amount)).await;
+    }
+}
+
+/// The download and upload limits of one level: the session, a torrent or a peer
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(download_rate: u64, upload_rate: u64) -> Self {
+        Self {
+            download: RateLimiter::new(download_rate),
+            upload: RateLimiter::new(upload_rate),
+        }
+    }
+
+    pub fn set_rates(&self, download_rate: u64, upload_rate: u64) {
+        self.download.set_rate(download_rate);
+        self.upload.set_rate(upload_rate);
+    }
+}
+
+/// Every limiter the bytes of one direction of a connection go through
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    limiters: Vec<RateLimiter>,
+}
+
+impl Throttle {
+    pub fn new(limiters: impl IntoIterator<Item = RateLimiter>) -> Self {
+        Self {
+            limiters: limiters.into_iter().collect(),
+        }
+    }
+
+    /// Waits until `amount` bytes may go through every limiter. The bytes are reserved from all
+    /// of them at once so that the waits overlap rather than add up
+    pub async fn acquire(&self, amount: u64) {
+        let now = Instant::now();
+        let wait = self
+            .limiters
+            .iter()
+            .map(|limiter| limiter.reserve_at(now, amount))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The limits a torrent's connections go through: those of the whole session, those of the
+/// torrent and those every one of its peers gets on its own
+#[derive(Debug, Clone, Default)]
+pub struct TorrentLimits {
+    pub session: RateLimits,
+    pub torrent: RateLimits,
+    /// Only the rates are used, each connection getting buckets of its own
+    pub peer: RateLimits,
+}
+
+impl TorrentLimits {
+    /// The download and upload throttles of a new connection
+    pub fn connection(&self) -> (Throttle, Throttle) {
+        let peer = RateLimits::new(self.peer.download.rate(), self.peer.upload.rate());
+        (
+            Throttle::new([
+                self.session.download.clone(),
+                self.torrent.download.clone(),
+                peer.download,
+            ]),
+            Throttle::new([
+                self.session.upload.clone(),
+                self.torrent.upload.clone(),
+                peer.upload,
+            ]),
+        )
+    }
+}
+
+/// Parses a number of bytes, or of bytes per second for rates, with an optional binary `K`, `M`
+/// or `G` suffix that may be followed by `B` or `iB`, like `500K` or `64MiB`
+pub fn parse_size(s: &str) -> Result<u64, ParseError> {
+    let s = s.trim();
+    let number = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix(['B', 'b']))
+        .unwrap_or(s);
+    let (number, shift) = match number.char_indices().last() {
+        Some((at, 'k' | 'K')) => (&number[..at], 10),
+        Some((at, 'm' | 'M')) => (&number[..at], 20),
+        Some((at, 'g' | 'G')) => (&number[..at], 30),
+        _ => (number, 0),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or(ParseError::Deserialization(format!("`{s}` is not a size")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{parse_size, RateLimiter, Throttle};
+    use crate::peer::message::{PeerBufferStream, PeerMessageId};
+
+    #[test]
+    fn test_bucket_reserves_in_order() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+        assert_eq!(Duration::ZERO, limiter.reserve_at(now, 1000));
+        // Each reservation waits for the ones before it
+        assert_eq!(Duration::from_millis(500), limiter.reserve_at(now, 500));
+        assert_eq!(Duration::from_millis(1000), limiter.reserve_at(now, 500));
+        assert_eq!(
+            Duration::ZERO,
+            limiter.reserve_at(now + Duration::from_secs(2), 0)
+        );
+
+        limiter.set_rate(0);
+        assert_eq!(Duration::ZERO, limiter.reserve_at(now, 1 << 30));
+        assert_eq!(Ok(500 << 10), parse_size("500K"));
+        assert_eq!(Ok(2 << 20), parse_size("2m"));
+        assert_eq!(Ok(512 << 10), parse_size("512KiB"));
+        assert_eq!(Ok(100), parse_size("100B"));
+        assert!(parse_size("fast").is_err());
+        assert!(parse_size("KiB").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_writes_are_throttled() {
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let limiter = RateLimiter::new(20_000);
+        let mut stream = PeerBufferStream::from_stream(ours)
+            .with_throttles(Throttle::default(), Throttle::new([limiter]));
+        let mut peer = PeerBufferStream::from_stream(theirs);
+        let start = Instant::now();
+        let reader = tokio::spawn(async move {
+            for _ in 0..3 {
+                peer.read_message().await.unwrap();
+            }
+        });
+        for _ in 0..3 {
+            stream
+                .write_message(PeerMessageId::Piece, &[0; 10_000])
+                .await
+                .unwrap();
+        }
+        reader.await.unwrap();
+        // The burst covers the first two messages, the third waits for half a second
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde_bencode::value::Value;
+
+use crate::{
+    peer::pex::{decode_compact_list, encode_compact, COMPACT_PEER6_SIZE},
+    tracker::TRACKER_RESPONSE_PEER_SIZE,
+    ParseError, INFO_HASH_SIZE,
+};
+
+/// Extension added to the download location to get the resume file saved next to it
+pub const RESUME_EXTENSION: &str = "resume";
+
+/// Where the resume file of a download into `out` goes
+pub fn resume_path(out: impl AsRef<Path>) -> PathBuf {
+    let mut path = OsString::from(out.as_ref().as_os_str());
+    path.push(".");
+    path.push(RESUME_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Size and modification time of a file on disk, both zero for a file that doesn't exist
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileStamp {
+    pub length: u64,
+    /// Seconds since the Unix epoch
+    pub mtime: u64,
+}
+
+impl FileStamp {
+    pub fn of(path: impl AsRef<Path>) -> Self {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return Self::default();
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        Self {
+            length: metadata.len(),
+            mtime,
+        }
+    }
+}
+
+/// What a download saves so that a restart doesn't have to hash everything again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; INFO_HASH_SIZE],
+    /// Pieces that were verified and written, padded to a whole number of bytes
+    pub have: Vec<bool>,
+    /// Stamps of the torrent's files when the pieces were saved
+    pub files: Vec<FileStamp>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl ResumeData {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut pieces = vec![0u8; (self.have.len() + 7) / 8];
+        for (piece, _) in self.have.iter().enumerate().filter(|(_, have)| **have) {
+            pieces[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        let files = self
+            .files
+            .iter()
+            .map(|stamp| {
+                Value::Dict(HashMap::from([
+                    (b"length".to_vec(), Value::Int(stamp.length as i64)),
+                    (b"mtime".to_vec(), Value::Int(stamp.mtime as i64)),
+                ]))
+            })
+            .collect();
+        let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+        for peer in &self.peers {
+            match peer {
+                SocketAddr::V4(_) => peers.extend(encode_compact(peer)),
+                SocketAddr::V6(_) => peers6.extend(encode_compact(peer)),
+            }
+        }
+        let dict = HashMap::from([
+            (b"info-hash".to_vec(), Value::Bytes(self.info_hash.to_vec())),
+            (b"pieces".to_vec(), Value::Bytes(pieces)),
+            (b"files".to_vec(), Value::List(files)),
+            (b"uploaded".to_vec(), Value::Int(self.uploaded as i64)),
+            (b"downloaded".to_vec(), Value::Int(self.downloaded as i64)),
+            (b"peers".to_vec(), Value::Bytes(peers)),
+            (b"peers6".to_vec(), Value::Bytes(peers6)),
+        ]);
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("A Value always serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let Value::Dict(dict) = serde_bencode::from_bytes::<Value>(bytes)
+            .map_err(|err| ParseError::Deserialization(err.to_string()))?
+        else {
+            return Err(ParseError::Deserialization(
+                "Resume file did not deserialize into a dictionary".to_owned(),
+            ));
+        };
+        let bytes_field = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Value::Bytes(bytes)) => Ok(bytes.as_slice()),
+            _ => Err(ParseError::MissingField(key.to_owned())),
+        };
+        let int_field = |value: Option<&Value>, key: &str| match value {
+            Some(Value::Int(int)) => Ok(*int as u64),
+            _ => Err(ParseError::MissingField(key.to_owned())),
+        };
+        let info_hash = <[u8; INFO_HASH_SIZE]>::try_from(bytes_field("info-hash")?)
+            .map_err(|_| ParseError::Deserialization("`info-hash` was not 20 bytes".to_owned()))?;
+        let have = bytes_field("pieces")?
+            .iter()
+            .flat_map(|byte| (0..8).map(move |bit| byte & (0x80 >> bit) != 0))
+            .collect();
+        let files = match dict.get("files".as_bytes()) {
+            Some(Value::List(files)) => files
+                .iter()
+                .map(|file| match file {
+                    Value::Dict(file) => Ok(FileStamp {
+                        length: int_field(file.get("length".as_bytes()), "length")?,
+                        mtime: int_field(file.get("mtime".as_bytes()), "mtime")?,
+                    }),
+                    _ => Err(ParseError::Deserialization(
+                        "File stamp was not a dictionary".to_owned(),
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(ParseError::MissingField("files".to_owned())),
+        };
+        let mut peers = decode_compact_list(bytes_field("peers")?, TRACKER_RESPONSE_PEER_SIZE)?;
+        peers.extend(decode_compact_list(
+            bytes_field("peers6")?,
+            COMPACT_PEER6_SIZE,
+        )?);
+        Ok(Self {
+            info_hash,
+            have,
+            files,
+            uploaded: int_field(dict.get("uploaded".as_bytes()), "uploaded")?,
+            downloaded: int_field(dict.get("downloaded".as_bytes()), "downloaded")?,
+            peers,
+        })
+    }
+
+    /// Writes to a temporary file first so that a crash never leaves half a resume file behind
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = OsString::from(path.as_os_str());
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_bytes())?;
+        std::fs::rename(tmp, path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+        let bytes =
+            std::fs::read(path).map_err(|err| ParseError::Deserialization(err.to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{resume_path, FileStamp, ResumeData};
+
+    #[test]
+    fn test_resume_roundtrip() {
+        let data = ResumeData {
+            info_hash: [3; 20],
+            have: vec![true, false, true, true, false, false, false, false, true],
+            files: vec![
+                FileStamp {
+                    length: 20_100,
+                    mtime: 1_700_000_000,
+                },
+                FileStamp::default(),
+            ],
+            uploaded: 7,
+            downloaded: 49_152,
+            peers: vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:51413".parse().unwrap(),
+            ],
+        };
+        let parsed = ResumeData::from_bytes(&data.to_bytes()).unwrap();
+        assert_eq!(data.have, parsed.have[..9]);
+        assert!(parsed.have[9..].iter().all(|have| !have));
+        assert_eq!(
+            ResumeData {
+                have: data.have.clone(),
+                ..parsed.clone()
+            },
+            data
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_path(dir.path().join("release"));
+        assert_eq!(dir.path().join("release.resume"), path);
+        data.save(&path).unwrap();
+        assert_eq!(parsed, ResumeData::load(&path).unwrap());
+    }
+}
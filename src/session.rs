@@ -0,0 +1,861 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{watch, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    handshake::{self, Handshake},
+    magnet::Magnet,
+    mse,
+    peer::{
+        client::{fetch_pieces, PeerClient},
+        extension::{join_extended, ExtendedHandshake, EXTENDED_HANDSHAKE_ID},
+        fast::{allowed_fast_set, ALLOWED_FAST_COUNT},
+        manager::{ConnectionManager, PeerStats},
+        message::{PeerBufferStream, PeerMessageId},
+        pool::{PeerPool, PeerSource},
+        storage::{self, Storage},
+        stream::PieceTracker,
+        upload::serve_peer,
+        writer::FileWriter,
+    },
+    peerid,
+    ratelimit::{RateLimits, TorrentLimits},
+    resume::{resume_path, ResumeData},
+    torrent::{from_bytes, from_file, MetaInfo},
+    INFO_HASH_SIZE,
+};
+
+/// Connections a session takes in across all of its torrents by default
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+/// How long a peer connecting to us has for its handshakes by default
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a peer connected to us may stay silent by default, keep-alives aside
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// How long accepting waits after an error, which tends to repeat right away when we are out of
+/// file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Where peers connect to us; the port is announced for every torrent
+    pub listen_addr: SocketAddr,
+    /// Connections open at once, inbound and outbound, shared by every torrent
+    pub max_connections: usize,
+    /// Whether peers may connect to us at all. Off when every connection must go through a
+    /// proxy, in which case nothing listens on `listen_addr`
+    pub incoming: bool,
+    /// How long a peer connecting to us has for the encryption and BitTorrent handshakes
+    pub handshake_timeout: Duration,
+    /// How long a peer connected to us may send nothing but keep-alives before it is dropped
+    pub idle_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: ([0, 0, 0, 0], 6881).into(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            incoming: true,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    #[error("Torrent {0} is not part of the session")]
+    UnknownTorrent(String),
+    #[error("Torrent {0} is already part of the session")]
+    DuplicateTorrent(String),
+    #[error("Couldn't load the torrent: {0}")]
+    InvalidTorrent(String),
+    #[error("Couldn't get the metadata of the magnet link: {0}")]
+    Metadata(String),
+    #[error("Couldn't reload the blocklist: {0}")]
+    Blocklist(String),
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Announcing and checking what is already on disk
+    Starting,
+    Downloading,
+    Paused,
+    /// Every selected piece is on disk and served to other peers
+    Seeding,
+    Failed(String),
+}
+
+/// A snapshot of a torrent of the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; INFO_HASH_SIZE],
+    pub name: String,
+    pub state: TorrentState,
+    pub pieces_done: usize,
+    pub num_pieces: usize,
+    pub uploaded: u64,
+    /// Peers known for the torrent
+    pub num_peers: usize,
+}
+
+/// A peer of a torrent and how connecting to it went so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    /// The client the peer runs, when its peer id is of a format we know
+    pub client: Option<String>,
+    pub stats: PeerStats,
+    pub banned: bool,
+}
+
+/// What inbound connections of a torrent are served from once it has started
+#[derive(Debug, Clone)]
+struct Running {
+    writer: Arc<FileWriter>,
+    /// What pieces are served from, which the writer may be
+    storage: Arc<dyn Storage>,
+    tracker: Arc<PieceTracker>,
+    peers: PeerPool,
+    uploaded: Arc<AtomicU64>,
+    downloaded: Arc<AtomicU64>,
+    limits: TorrentLimits,
+    connections: ConnectionManager,
+    /// Set while the torrent is paused, which drops the connections serving it
+    paused: Arc<watch::Sender<bool>>,
+    /// Set once the torrent is removed, which drops the connections serving it
+    removed: Arc<watch::Sender<bool>>,
+}
+
+#[derive(Debug)]
+struct TorrentEntry {
+    torrent_file: PathBuf,
+    out: PathBuf,
+    metainfo: MetaInfo,
+    state: watch::Sender<TorrentState>,
+    /// The torrent's own limits, kept across restarts
+    limits: RateLimits,
+    paused: bool,
+    running: Option<Running>,
+    task: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct SessionInner {
+    client: PeerClient,
+    local_addr: SocketAddr,
+    connections: Arc<Semaphore>,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    torrents: Mutex<HashMap<[u8; INFO_HASH_SIZE], TorrentEntry>>,
+    accept_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for SessionInner {
+    fn drop(&mut self) {
+        if let Some(task) = self.accept_task.lock().expect("Poisoned lock").take() {
+            task.abort();
+        }
+        for entry in self.torrents.lock().expect("Poisoned lock").values_mut() {
+            if let Some(task) = entry.task.take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+impl SessionInner {
+    fn torrents(&self) -> MutexGuard<'_, HashMap<[u8; INFO_HASH_SIZE], TorrentEntry>> {
+        self.torrents.lock().expect("Poisoned lock")
+    }
+}
+
+/// Runs any number of torrents behind one listener and one peer id. Torrents are added, paused,
+/// resumed and removed while the session runs, and peers connecting to us are handed to the
+/// torrent their handshake names
+#[derive(Debug, Clone)]
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+impl Session {
+    /// Starts listening for peers. `client` carries the peer id and the settings every torrent
+    /// is downloaded with
+    pub async fn bind(client: PeerClient, config: SessionConfig) -> Result<Session, SessionError> {
+        let listener = match config.incoming {
+            true => Some(
+                TcpListener::bind(config.listen_addr)
+                    .await
+                    .map_err(|err| SessionError::Io(err.to_string()))?,
+            ),
+            false => None,
+        };
+        let local_addr = match &listener {
+            Some(listener) => listener
+                .local_addr()
+                .map_err(|err| SessionError::Io(err.to_string()))?,
+            None => config.listen_addr,
+        };
+        let connections = Arc::new(Semaphore::new(config.max_connections));
+        let inner = Arc::new(SessionInner {
+            client: client
+                .with_listener_port(local_addr.port())
+                .with_connection_slots(connections.clone()),
+            local_addr,
+            connections,
+            handshake_timeout: config.handshake_timeout,
+            idle_timeout: config.idle_timeout,
+            torrents: Mutex::new(HashMap::new()),
+            accept_task: Mutex::new(None),
+        });
+        if let Some(listener) = listener {
+            let accept_task = tokio::spawn(accept_loop(listener, Arc::downgrade(&inner)));
+            *inner.accept_task.lock().expect("Poisoned lock") = Some(accept_task);
+        }
+        Ok(Session { inner })
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    /// Adds the torrent and starts downloading it into `out`. Returns its info hash, which the
+    /// other methods take
+    pub async fn add_torrent(
+        &self,
+        torrent_file: impl AsRef<Path>,
+        out: impl AsRef<Path>,
+    ) -> Result<[u8; INFO_HASH_SIZE], SessionError> {
+        let torrent_file = torrent_file.as_ref().to_path_buf();
+        let (_, metainfo) = from_file(&torrent_file)
+            .map_err(|err| SessionError::InvalidTorrent(err.to_string()))?;
+        let info_hash = metainfo
+            .handshake_info_hash()
+            .map_err(|err| SessionError::InvalidTorrent(err.to_string()))?;
+        let mut torrents = self.inner.torrents();
+        if torrents.contains_key(&info_hash) {
+            return Err(SessionError::DuplicateTorrent(hex::encode(info_hash)));
+        }
+        let mut entry = TorrentEntry {
+            torrent_file,
+            out: out.as_ref().to_path_buf(),
+            metainfo,
+            state: watch::channel(TorrentState::Starting).0,
+            limits: RateLimits::default(),
+            paused: false,
+            running: None,
+            task: None,
+        };
+        self.start(info_hash, &mut entry);
+        torrents.insert(info_hash, entry);
+        Ok(info_hash)
+    }
+
+    /// Gets the torrent of the magnet link from peers and adds it, downloading into `out_dir`
+    /// under the torrent's name. The torrent file is saved next to the download
+    pub async fn add_magnet(
+        &self,
+        magnet: &Magnet,
+        out_dir: impl AsRef<Path>,
+    ) -> Result<[u8; INFO_HASH_SIZE], SessionError> {
+        if self.inner.torrents().contains_key(&magnet.info_hash) {
+            return Err(SessionError::DuplicateTorrent(hex::encode(
+                magnet.info_hash,
+            )));
+        }
+        let bytes = self
+            .inner
+            .client
+            .fetch_metadata(magnet)
+            .await
+            .map_err(|err| SessionError::Metadata(err.to_string()))?;
+        let (_, metainfo) =
+            from_bytes(&bytes).map_err(|err| SessionError::InvalidTorrent(err.to_string()))?;
+        let out = out_dir.as_ref().join(metainfo.name());
+        let mut torrent_file = OsString::from(out.as_os_str());
+        torrent_file.push(".torrent");
+        tokio::fs::write(&torrent_file, bytes)
+            .await
+            .map_err(|err| SessionError::Io(err.to_string()))?;
+        self.add_torrent(torrent_file, out).await
+    }
+
+    fn start(&self, info_hash: [u8; INFO_HASH_SIZE], entry: &mut TorrentEntry) {
+        entry.state.send_replace(TorrentState::Starting);
+        entry.running = None;
+        entry.task = Some(tokio::spawn(run_torrent(
+            Arc::downgrade(&self.inner),
+            info_hash,
+            self.inner.client.clone(),
+            entry.torrent_file.clone(),
+            entry.out.clone(),
+            entry.limits.clone(),
+        )));
+    }
+
+    /// Caps the bytes per second of every torrent together, 0 meaning unlimited. Takes effect
+    /// right away
+    pub fn set_rate_limits(&self, download_rate: u64, upload_rate: u64) {
+        self.inner
+            .client
+            .rate_limits()
+            .set_rates(download_rate, upload_rate);
+    }
+
+    /// Caps the bytes per second of one torrent, 0 meaning unlimited. Takes effect right away
+    pub fn set_torrent_rate_limits(
+        &self,
+        info_hash: &[u8; INFO_HASH_SIZE],
+        download_rate: u64,
+        upload_rate: u64,
+    ) -> Result<(), SessionError> {
+        self.inner
+            .torrents()
+            .get(info_hash)
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash)))?
+            .limits
+            .set_rates(download_rate, upload_rate);
+        Ok(())
+    }
+
+    /// Reads the blocklist files again. Peers blocked from now on are neither connected to nor
+    /// let in. Returns the number of ranges blocked
+    pub fn reload_blocklist(&self) -> Result<usize, SessionError> {
+        self.inner
+            .client
+            .ip_filter()
+            .reload()
+            .map_err(|err| SessionError::Blocklist(err.to_string()))
+    }
+
+    /// Stops downloading after the pieces in flight and stops serving peers
+    pub fn pause(&self, info_hash: &[u8; INFO_HASH_SIZE]) -> Result<(), SessionError> {
+        let mut torrents = self.inner.torrents();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash)))?;
+        entry.paused = true;
+        if let Some(running) = &entry.running {
+            running.tracker.set_paused(true);
+            running.paused.send_replace(true);
+        }
+        if !matches!(*entry.state.borrow(), TorrentState::Failed(_)) {
+            entry.state.send_replace(TorrentState::Paused);
+        }
+        Ok(())
+    }
+
+    /// Picks a paused torrent up again. A failed torrent is started over
+    pub fn resume(&self, info_hash: &[u8; INFO_HASH_SIZE]) -> Result<(), SessionError> {
+        let mut torrents = self.inner.torrents();
+        let entry = torrents
+            .get_mut(info_hash)
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash)))?;
+        entry.paused = false;
+        if matches!(*entry.state.borrow(), TorrentState::Failed(_)) {
+            self.start(*info_hash, entry);
+            return Ok(());
+        }
+        match &entry.running {
+            Some(running) => {
+                running.tracker.set_paused(false);
+                running.paused.send_replace(false);
+                entry.state.send_replace(if running.tracker.is_complete() {
+                    TorrentState::Seeding
+                } else {
+                    TorrentState::Downloading
+                });
+            }
+            None => {
+                entry.state.send_replace(TorrentState::Starting);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the torrent and forgets it. What was downloaded stays on disk, along with the resume
+    /// file for adding it again later
+    pub async fn remove(&self, info_hash: &[u8; INFO_HASH_SIZE]) -> Result<(), SessionError> {
+        let entry = self
+            .inner
+            .torrents()
+            .remove(info_hash)
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash)))?;
+        if let Some(task) = entry.task {
+            task.abort();
+        }
+        if let Some(running) = entry.running {
+            running.removed.send_replace(true);
+            // The download was cut short, so whatever its cache holds is written here
+            storage::blocking(&running.storage, |storage| storage.flush())
+                .await
+                .map_err(|err| SessionError::Io(err.to_string()))?;
+            ResumeData {
+                info_hash: *info_hash,
+                have: running.tracker.have(),
+                files: running.writer.file_stamps(),
+                uploaded: running.uploaded.load(Ordering::Relaxed),
+                downloaded: running.downloaded.load(Ordering::Relaxed),
+                peers: running.peers.peers(),
+            }
+            .save(resume_path(&entry.out))
+            .map_err(|err| SessionError::Io(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, info_hash: &[u8; INFO_HASH_SIZE]) -> Option<TorrentStatus> {
+        self.inner
+            .torrents()
+            .get(info_hash)
+            .map(|entry| torrent_status(info_hash, entry))
+    }
+
+    /// The peers known for the torrent, where each was learned from and how connecting to it went
+    pub fn peers(&self, info_hash: &[u8; INFO_HASH_SIZE]) -> Result<Vec<PeerStatus>, SessionError> {
+        let torrents = self.inner.torrents();
+        let entry = torrents
+            .get(info_hash)
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash)))?;
+        let Some(running) = &entry.running else {
+            return Ok(Vec::new());
+        };
+        Ok(running
+            .peers
+            .peers()
+            .into_iter()
+            .filter_map(|addr| {
+                Some(PeerStatus {
+                    addr,
+                    source: running.peers.source(&addr)?,
+                    client: running
+                        .peers
+                        .peer_id(&addr)
+                        .as_ref()
+                        .and_then(peerid::client_name),
+                    stats: running.connections.stats(&addr).unwrap_or_default(),
+                    banned: running.connections.is_banned(&addr),
+                })
+            })
+            .collect())
+    }
+
+    /// Every torrent of the session
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        self.inner
+            .torrents()
+            .iter()
+            .map(|(info_hash, entry)| torrent_status(info_hash, entry))
+            .collect()
+    }
+
+    /// Waits for the torrent to finish downloading or to fail, returning the state it ended in
+    pub async fn wait(
+        &self,
+        info_hash: &[u8; INFO_HASH_SIZE],
+    ) -> Result<TorrentState, SessionError> {
+        let mut state = self
+            .inner
+            .torrents()
+            .get(info_hash)
+            .ok_or(SessionError::UnknownTorrent(hex::encode(info_hash)))?
+            .state
+            .subscribe();
+        loop {
+            let current = state.borrow_and_update().clone();
+            if matches!(current, TorrentState::Seeding | TorrentState::Failed(_)) {
+                return Ok(current);
+            }
+            if state.changed().await.is_err() {
+                return Err(SessionError::UnknownTorrent(hex::encode(info_hash)));
+            }
+        }
+    }
+}
+
+fn torrent_status(info_hash: &[u8; INFO_HASH_SIZE], entry: &TorrentEntry) -> TorrentStatus {
+    let (pieces_done, uploaded, num_peers) = match &entry.running {
+        Some(running) => (
+            running.tracker.have().iter().filter(|have| **have).count(),
+            running.uploaded.load(Ordering::Relaxed),
+            running.peers.len(),
+        ),
+        None => (0, 0, 0),
+    };
+    TorrentStatus {
+        info_hash: *info_hash,
+        name: entry.metainfo.name().to_string_lossy().into_owned(),
+        state: entry.state.borrow().clone(),
+        pieces_done,
+        num_pieces: entry.metainfo.num_pieces(),
+        uploaded,
+        num_peers,
+    }
+}
+
+async fn run_torrent(
+    session: Weak<SessionInner>,
+    info_hash: [u8; INFO_HASH_SIZE],
+    client: PeerClient,
+    torrent_file: PathBuf,
+    out: PathBuf,
+    limits: RateLimits,
+) {
+    let result: anyhow::Result<()> = async {
+        let mut downloader = client
+            .downloader(&torrent_file)
+            .await?
+            .with_limits(client.torrent_limits(limits));
+        let (writer, tracker) = client.prepare(&mut downloader, &out, true).await?;
+        let running = Running {
+            writer,
+            storage: downloader.storage().clone(),
+            tracker: Arc::new(tracker),
+            peers: downloader.peers().clone(),
+            uploaded: downloader.uploaded_counter(),
+            downloaded: downloader.downloaded_counter(),
+            limits: downloader.limits().clone(),
+            connections: downloader.connections().clone(),
+            paused: Arc::new(watch::channel(false).0),
+            removed: Arc::new(watch::channel(false).0),
+        };
+        {
+            let session = session.upgrade().ok_or(anyhow!("The session is gone"))?;
+            let mut torrents = session.torrents();
+            let entry = torrents
+                .get_mut(&info_hash)
+                .ok_or(anyhow!("The torrent was removed"))?;
+            // A pause that came in while starting up takes effect now
+            running.tracker.set_paused(entry.paused);
+            running.paused.send_replace(entry.paused);
+            if !entry.paused {
+                entry.state.send_replace(TorrentState::Downloading);
+            }
+            entry.running = Some(running.clone());
+        }
+        let resume = resume_path(&out);
+        loop {
+            fetch_pieces(&mut downloader, &running.writer, &running.tracker, &resume).await?;
+            if !running.tracker.is_paused() {
+                return Ok(());
+            }
+            running.tracker.wait_unpaused().await;
+        }
+    }
+    .await;
+    let Some(session) = session.upgrade() else {
+        return;
+    };
+    let mut torrents = session.torrents();
+    if let Some(entry) = torrents.get_mut(&info_hash) {
+        entry.state.send_replace(match result {
+            Ok(()) if entry.paused => TorrentState::Paused,
+            Ok(()) => TorrentState::Seeding,
+            Err(err) => TorrentState::Failed(err.to_string()),
+        });
+    }
+}
+
+async fn accept_loop(listener: TcpListener, session: Weak<SessionInner>) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            continue;
+        };
+        let Some(inner) = session.upgrade() else {
+            return;
+        };
+        if inner.client.ip_filter().is_blocked(addr.ip()) {
+            continue;
+        }
+        // Over the limit a peer is turned away by closing the connection
+        let Ok(permit) = inner.connections.clone().try_acquire_owned() else {
+            continue;
+        };
+        let session = session.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _ = serve_inbound(session, stream, addr).await;
+        });
+    }
+}
+
+/// Finds the torrent a peer connecting to us wants from its handshake and serves it
+async fn serve_inbound(
+    session: Weak<SessionInner>,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (info_hashes, encryption, peer_id, port, handshake_timeout, idle_timeout) = {
+        let inner = session.upgrade().ok_or(anyhow!("The session is gone"))?;
+        let info_hashes = inner.torrents().keys().copied().collect::<Vec<_>>();
+        (
+            info_hashes,
+            inner.client.encryption(),
+            *inner.client.peer_id(),
+            inner.local_addr.port(),
+            inner.handshake_timeout,
+            inner.idle_timeout,
+        )
+    };
+    // A peer that never finishes its handshakes would hold a connection slot for good
+    let (mut stream, encrypted_for, peer_hand) = tokio::time::timeout(handshake_timeout, async {
+        let (mut stream, encrypted_for) = mse::respond(stream, &info_hashes, encryption).await?;
+        let peer_hand = handshake::read_handshake(&mut stream).await?;
+        anyhow::Ok((stream, encrypted_for, peer_hand))
+    })
+    .await
+    .map_err(|_| anyhow!("Peer {addr} didn't finish its handshake in {handshake_timeout:?}"))??;
+    let info_hash = *peer_hand.infohash();
+    if encrypted_for.is_some_and(|encrypted_for| encrypted_for != info_hash) {
+        return Err(anyhow!("Peer {addr} switched torrents after encrypting"));
+    }
+    let running = {
+        let inner = session.upgrade().ok_or(anyhow!("The session is gone"))?;
+        let torrents = inner.torrents();
+        torrents
+            .get(&info_hash)
+            .filter(|entry| !entry.paused)
+            .and_then(|entry| entry.running.clone())
+            .ok_or(anyhow!("Peer {addr} wants a torrent we don't serve"))?
+    };
+    if running.connections.is_banned(&addr) {
+        return Err(anyhow!("Peer {addr} is banned"));
+    }
+    handshake::write_handshake(
+        &mut stream,
+        &Handshake::new(&info_hash, &peer_id)
+            .with_extensions()
+            .with_fast(),
+    )
+    .await?;
+    // BEP 6 only defines the allowed fast set for IPv4 peers
+    let allowed_fast = peer_hand.supports_fast().then(|| match addr.ip() {
+        IpAddr::V4(ip) => allowed_fast_set(
+            ip,
+            &info_hash,
+            running.storage.metainfo().num_pieces() as u32,
+            ALLOWED_FAST_COUNT,
+        ),
+        IpAddr::V6(_) => Vec::new(),
+    });
+    let (download, upload) = running.limits.connection();
+    let mut stream = PeerBufferStream::from_stream(stream).with_throttles(download, upload);
+    if peer_hand.supports_extensions() {
+        // Only to tell the peer our port: peers aren't exchanged on connections we serve
+        let mut ext_hand = ExtendedHandshake::local(port);
+        ext_hand.extensions.clear();
+        stream
+            .write_message(
+                PeerMessageId::Extended,
+                &join_extended(EXTENDED_HANDSHAKE_ID, &ext_hand.to_bytes()),
+            )
+            .await?;
+    }
+    // The port the peer came from is not one it can be reached at, so it only joins the pool
+    // once it says where it listens
+    let listen_port = |port| {
+        let listen_addr = SocketAddr::new(addr.ip(), port);
+        running.peers.add(listen_addr, PeerSource::Incoming);
+        running.peers.set_peer_id(listen_addr, *peer_hand.peer_id());
+    };
+    let (mut paused, mut removed) = (running.paused.subscribe(), running.removed.subscribe());
+    tokio::select! {
+        result = serve_peer(
+            stream,
+            &running.storage,
+            &running.tracker,
+            &running.uploaded,
+            allowed_fast.as_deref(),
+            idle_timeout,
+            listen_port,
+        ) => result,
+        _ = paused.wait_for(|paused| *paused) => {
+            Err(anyhow!("Torrent {} was paused", hex::encode(info_hash)))
+        }
+        _ = removed.wait_for(|removed| *removed) => {
+            Err(anyhow!("Torrent {} was removed", hex::encode(info_hash)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::SocketAddrV4, time::Duration};
+
+    use reqwest::{Client, Url};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{Session, SessionConfig, SessionError, TorrentState};
+    use crate::{
+        create::{TorrentBuilder, MIN_PIECE_LENGTH},
+        dht::krpc::encode_compact_peer,
+        peer::{client::PeerClient, pool::PeerSource},
+        resume::{resume_path, ResumeData},
+    };
+
+    /// Answers every announce with the peers given
+    async fn tracker(peers: Vec<SocketAddrV4>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let _ = socket.read(&mut buf).await.unwrap();
+                let mut body = format!("d8:intervali1800e5:peers{}:", peers.len() * 6).into_bytes();
+                for peer in &peers {
+                    body.extend(encode_compact_peer(peer));
+                }
+                body.push(b'e');
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(&body).await.unwrap();
+            }
+        });
+        Url::parse(&format!("http://{addr}/announce")).unwrap()
+    }
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            listen_addr: ([127, 0, 0, 1], 0).into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_seed_to_each_other() {
+        let seeder = Session::bind(PeerClient::new(Client::new(), [1; 20]), config())
+            .await
+            .unwrap();
+        let std::net::SocketAddr::V4(seeder_addr) = seeder.local_addr() else {
+            panic!("Bound to an IPv4 address");
+        };
+        // Nothing listens there any more, so connecting to it fails
+        let dead_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+                panic!("Bound to an IPv4 address");
+            };
+            addr
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("image");
+        let data = (0..40_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&source, &data).unwrap();
+        let (bytes, _) = TorrentBuilder::new(&source)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_announce(tracker(vec![dead_addr, seeder_addr]).await)
+            .build()
+            .unwrap();
+        let torrent_file = dir.path().join("image.torrent");
+        fs::write(&torrent_file, bytes).unwrap();
+
+        // What is already on disk is checked, so the seeder is done right away
+        let info_hash = seeder.add_torrent(&torrent_file, &source).await.unwrap();
+        assert_eq!(
+            TorrentState::Seeding,
+            seeder.wait(&info_hash).await.unwrap()
+        );
+        assert_eq!(
+            Err(SessionError::DuplicateTorrent(hex::encode(info_hash))),
+            seeder.add_torrent(&torrent_file, &source).await
+        );
+
+        let leecher = Session::bind(PeerClient::new(Client::new(), [2; 20]), config())
+            .await
+            .unwrap();
+        let copy = dir.path().join("copy");
+        leecher.add_torrent(&torrent_file, &copy).await.unwrap();
+        assert_eq!(
+            TorrentState::Seeding,
+            leecher.wait(&info_hash).await.unwrap()
+        );
+        assert_eq!(data, fs::read(&copy).unwrap());
+        // The unreachable peer was given up on rather than failing the download
+        let peers = leecher.peers(&info_hash).unwrap();
+        let dead = peers
+            .iter()
+            .find(|peer| peer.addr == dead_addr.into())
+            .unwrap();
+        assert!(dead.stats.failures >= 1 && dead.stats.last_error.is_some());
+        assert!(!dead.banned);
+        let status = seeder.status(&info_hash).unwrap();
+        assert_eq!(3, status.pieces_done);
+        assert_eq!(data.len() as u64, status.uploaded);
+        // The leecher is known by the port it listens on rather than the one it came from
+        let incoming = seeder
+            .peers(&info_hash)
+            .unwrap()
+            .into_iter()
+            .filter(|peer| peer.source == PeerSource::Incoming)
+            .map(|peer| peer.addr)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![leecher.local_addr()], incoming);
+
+        seeder.pause(&info_hash).unwrap();
+        assert_eq!(
+            TorrentState::Paused,
+            seeder.status(&info_hash).unwrap().state
+        );
+        seeder.resume(&info_hash).unwrap();
+        assert_eq!(
+            TorrentState::Seeding,
+            seeder.status(&info_hash).unwrap().state
+        );
+        seeder.remove(&info_hash).await.unwrap();
+        assert!(seeder.torrents().is_empty());
+        // What was uploaded since seeding began is in the resume file too
+        let resume = ResumeData::load(resume_path(&source)).unwrap();
+        assert_eq!(data.len() as u64, resume.uploaded);
+        assert_eq!(
+            Err(SessionError::UnknownTorrent(hex::encode(info_hash))),
+            seeder.pause(&info_hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_peers_are_dropped() {
+        let session = Session::bind(
+            PeerClient::new(Client::new(), [1; 20]),
+            SessionConfig {
+                handshake_timeout: Duration::from_millis(100),
+                ..config()
+            },
+        )
+        .await
+        .unwrap();
+        let mut stream = TcpStream::connect(session.local_addr()).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        // Closed on our side without a byte sent
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+}
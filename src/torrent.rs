@@ -1,5 +1,6 @@
 use std::{
-    path::{Path, PathBuf},
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
     str,
 };
 
@@ -7,11 +8,15 @@ use reqwest::Url;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_bencode::value::Value;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use thiserror::Error;
 
-use crate::ParseError;
+use crate::{
+    merkle::{self, Hash256, HASH_256_SIZE, MERKLE_BLOCK_SIZE, ZERO_HASH},
+    ParseError,
+};
 
-pub type UrlMetaInfo = (Url, MetaInfo);
+pub type UrlMetaInfo = (Option<Url>, MetaInfo);
 pub type Piece = [u8; PIECE_SIZE];
 
 pub const INFO_HASH_SIZE: usize = 20;
@@ -28,14 +33,18 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
     let value = serde_bencode::from_bytes::<Value>(bytes.as_ref())
         .map_err(|err| ParseError::Deserialization(err.to_string()))?;
     if let Value::Dict(map) = value {
-        if let Value::Bytes(announce) = map
-            .get("announce".as_bytes())
-            .ok_or(ParseError::MissingField("announce".to_string()))?
+        // Trackerless torrents have no `announce` and find their peers through the DHT instead
+        let announce = match map.get("announce".as_bytes()) {
+            Some(Value::Bytes(announce)) => Some(parse_url(announce)?),
+            Some(_) => {
+                return Err(ParseError::Deserialization(
+                    "`announce` key has been found not to deserialize into bytes/a string"
+                        .to_string(),
+                ))
+            }
+            None => None,
+        };
         {
-            let url = str::from_utf8(announce)
-                .map_err(|utf8_err| ParseError::Deserialization(utf8_err.to_string()))?;
-            let announce = Url::parse(url)
-                .map_err(|url_err| ParseError::Deserialization(url_err.to_string()))?;
             if let Value::Dict(info) = map
                 .get("info".as_bytes())
                 .ok_or(ParseError::MissingField("info".to_string()))?
@@ -45,9 +54,9 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
                         .get("name".as_bytes())
                         .ok_or(ParseError::MissingField("name".to_string()))?
                     {
-                        Ok(String::from_utf8(name.to_vec()).map_err(|utf8_err| {
-                            ParseError::Deserialization(utf8_err.to_string())
-                        })?)
+                        // The name is joined onto the download location as well, and may come
+                        // from a peer over `ut_metadata`
+                        Ok(path_component(name)?.to_owned())
                     } else {
                         Err(ParseError::Deserialization(
                             "`name` did not deserialize into a string/bytes".to_string(),
@@ -68,8 +77,33 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
                         ))
                     }
                 }?;
+                let meta_version = match info.get("meta version".as_bytes()) {
+                    Some(Value::Int(meta_version)) => Ok(Some(*meta_version)),
+                    Some(_) => Err(ParseError::Deserialization(
+                        "`meta version` did not deserialize into an integer".to_owned(),
+                    )),
+                    None => Ok(None),
+                }?;
+                let v2_files = match meta_version {
+                    Some(2) => {
+                        let file_tree = info
+                            .get("file tree".as_bytes())
+                            .ok_or(ParseError::MissingField("file tree".to_string()))?;
+                        let mut files = Vec::new();
+                        parse_file_tree(file_tree, PathBuf::new(), &mut files)?;
+                        Some(files)
+                    }
+                    Some(version) => {
+                        return Err(ParseError::Deserialization(format!(
+                            "Unsupported `meta version` {version}"
+                        )))
+                    }
+                    None => None,
+                };
                 let pieces = {
-                    if let Value::Bytes(pieces) = info
+                    if v2_files.is_some() && info.get("pieces".as_bytes()).is_none() {
+                        Ok(Vec::new())
+                    } else if let Value::Bytes(pieces) = info
                         .get("pieces".as_bytes())
                         .ok_or(ParseError::MissingField("pieces".to_string()))?
                     {
@@ -99,9 +133,12 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
                 }?;
                 let file_type = {
                     match (info.get("length".as_bytes()), info.get("files".as_bytes())) {
-                        (None, None) => Err(ParseError::Deserialization(
-                            "Found neither `length` nor `file`".to_string(),
-                        )),
+                        (None, None) => match &v2_files {
+                            Some(files) => Ok(file_type_from_v2(&name, files)),
+                            None => Err(ParseError::Deserialization(
+                                "Found neither `length` nor `file`".to_string(),
+                            )),
+                        },
                         (Some(_), Some(_)) => Err(ParseError::Deserialization(
                             "Found both `length` and `file`!".to_string(),
                         )),
@@ -142,13 +179,7 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
                                                 let mut vec = Vec::new();
                                                 for sub in path {
                                                     if let Value::Bytes(sub) = sub {
-                                                        let sub =
-                                                            str::from_utf8(sub).map_err(|err| {
-                                                                ParseError::Deserialization(
-                                                                    err.to_string(),
-                                                                )
-                                                            })?;
-                                                        vec.push(sub.to_owned());
+                                                        vec.push(path_component(sub)?.to_owned());
                                                     } else {
                                                         return Err(ParseError::Deserialization(
                                                                 "`path` did not deserialize into a string/bytes"
@@ -164,7 +195,21 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
                                                 ))
                                             }
                                         }?;
-                                        fileinfos.push(FileInfo::new(length, path));
+                                        let attr = match file.get("attr".as_bytes()) {
+                                            Some(Value::Bytes(attr)) => {
+                                                Some(String::from_utf8_lossy(attr).into_owned())
+                                            }
+                                            Some(_) => {
+                                                return Err(ParseError::Deserialization(
+                                                    "`attr` did not deserialize into a string"
+                                                        .to_owned(),
+                                                ))
+                                            }
+                                            None => None,
+                                        };
+                                        let mut fileinfo = FileInfo::new(length, path);
+                                        fileinfo.attr = attr;
+                                        fileinfos.push(fileinfo);
                                     } else {
                                         return Err(ParseError::Deserialization(
                                             "`file` did not deserialize into a dictionary"
@@ -192,19 +237,79 @@ pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<UrlMetaInfo, ParseError> {
                         }
                     }
                 }?;
-                Ok((
-                    announce,
-                    MetaInfo::new(name, piece_length, pieces, file_type),
-                ))
+                let private = match info.get("private".as_bytes()) {
+                    Some(Value::Int(private)) => Ok(Some(*private)),
+                    Some(_) => Err(ParseError::Deserialization(
+                        "`private` did not deserialize into an integer".to_owned(),
+                    )),
+                    None => Ok(None),
+                }?;
+                let mut metainfo = MetaInfo::new(name, piece_length, pieces, file_type);
+                metainfo.private = private;
+                metainfo.info_bytes = Some(
+                    serde_bencode::to_bytes(&Value::Dict(info.clone()))
+                        .map_err(|err| ParseError::Deserialization(err.to_string()))?,
+                );
+                if let Some(v2_files) = v2_files {
+                    let piece_layers = match map.get("piece layers".as_bytes()) {
+                        Some(Value::Dict(layers)) => layers.clone(),
+                        Some(_) => {
+                            return Err(ParseError::Deserialization(
+                                "`piece layers` did not deserialize into a dictionary".to_owned(),
+                            ))
+                        }
+                        None => HashMap::new(),
+                    };
+                    metainfo.v2 = Some(V2Info::new(v2_files, piece_length, &piece_layers)?);
+                }
+                metainfo.web_seeds = match map.get("url-list".as_bytes()) {
+                    // A single web seed may be given as a plain string instead of a list
+                    Some(Value::Bytes(url)) => vec![parse_url(url)?],
+                    Some(Value::List(urls)) => {
+                        let mut web_seeds = Vec::new();
+                        for url in urls {
+                            if let Value::Bytes(url) = url {
+                                // Some torrents carry empty strings here, which are skipped
+                                if !url.is_empty() {
+                                    web_seeds.push(parse_url(url)?);
+                                }
+                            } else {
+                                return Err(ParseError::Deserialization(
+                                    "`url-list` entry did not deserialize into a string/bytes"
+                                        .to_owned(),
+                                ));
+                            }
+                        }
+                        web_seeds
+                    }
+                    Some(_) => {
+                        return Err(ParseError::Deserialization(
+                            "`url-list` did not deserialize into a list".to_owned(),
+                        ))
+                    }
+                    None => Vec::new(),
+                };
+                metainfo.dht_nodes = match map.get("nodes".as_bytes()) {
+                    Some(Value::List(nodes)) => nodes
+                        .iter()
+                        .filter_map(|node| match node {
+                            Value::List(node) => match node.as_slice() {
+                                [Value::Bytes(host), Value::Int(port)] => {
+                                    Some(format!("{}:{}", String::from_utf8_lossy(host), port))
+                                }
+                                _ => None,
+                            },
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Ok((announce, metainfo))
             } else {
                 Err(ParseError::Deserialization(
                     "`info` key has been found not to deserialize into a dictionary".to_string(),
                 ))
             }
-        } else {
-            Err(ParseError::Deserialization(
-                "`announce` key has been found not to deserialize into bytes/a string".to_string(),
-            ))
         }
     } else {
         Err(ParseError::Deserialization(
@@ -234,6 +339,46 @@ pub struct MetaInfo {
     /// data representations
     #[serde(flatten)]
     file_type: FileType,
+    /// Optional flag that, when set to 1, tells clients to only get peers from the trackers
+    /// listed in the torrent file (BEP 27)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+    /// The bencoded `info` dictionary exactly as it was read, which is what gets hashed for the
+    /// info hash so that keys this struct doesn't model are still accounted for
+    #[serde(skip)]
+    info_bytes: Option<Vec<u8>>,
+    /// The BitTorrent v2 (BEP 52) part of the metainfo found in v2 and hybrid torrents
+    #[serde(skip)]
+    v2: Option<V2Info>,
+    /// HTTP mirrors of the torrent's content found in the top level `url-list` (BEP 19)
+    #[serde(skip)]
+    web_seeds: Vec<Url>,
+    /// `host:port` of DHT nodes given by the top level `nodes` of trackerless torrents (BEP 5)
+    #[serde(skip)]
+    dht_nodes: Vec<String>,
+}
+
+/// A contiguous range of a piece that lives in a single file of the torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Index of the file in [`MetaInfo::files`]
+    pub file_index: usize,
+    /// Offset of the range from the start of the file
+    pub file_offset: u64,
+    /// Offset of the range from the start of the piece
+    pub piece_offset: u64,
+    pub length: u64,
+}
+
+/// The metainfo version(s) a torrent carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    /// Only the SHA-1 `pieces` string
+    V1,
+    /// Only the `file tree` and `piece layers`
+    V2,
+    /// Both v1 and v2 data describing the same content
+    Hybrid,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -246,18 +391,26 @@ pub enum FileType {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FileInfo {
+    /// Flags of the file (BEP 47), `p` marking a pad file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
     length: u64,
+    #[serde(serialize_with = "serialize_path")]
     path: PathBuf,
 }
 
 impl FileInfo {
     #[inline]
-    fn new(length: u64, path: Vec<String>) -> Self {
+    pub(crate) fn new(length: u64, path: Vec<String>) -> Self {
         let mut buf = PathBuf::new();
         for path in path {
             buf.push(path);
         }
-        Self { length, path: buf }
+        Self {
+            attr: None,
+            length,
+            path: buf,
+        }
     }
 
     #[inline]
@@ -269,11 +422,18 @@ impl FileInfo {
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
+
+    /// Whether the file is padding that aligns the next file to a piece boundary. Pad files are
+    /// all zeros and never written to disk
+    #[inline]
+    pub fn is_pad(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
 }
 
 impl MetaInfo {
     #[inline]
-    fn new(
+    pub(crate) fn new(
         name: impl AsRef<Path>,
         piece_length: u64,
         pieces: Vec<Piece>,
@@ -285,9 +445,21 @@ impl MetaInfo {
             piece_length,
             pieces,
             file_type,
+            private: None,
+            info_bytes: None,
+            v2: None,
+            web_seeds: Vec::new(),
+            dht_nodes: Vec::new(),
         }
     }
 
+    #[inline]
+    pub(crate) fn with_private(self, private: bool) -> Self {
+        let mut s = self;
+        s.private = private.then_some(1);
+        s
+    }
+
     #[inline]
     pub fn name(&self) -> &PathBuf {
         &self.name
@@ -308,13 +480,421 @@ impl MetaInfo {
         &self.file_type
     }
 
+    #[inline]
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    #[inline]
+    pub fn web_seeds(&self) -> &Vec<Url> {
+        &self.web_seeds
+    }
+
+    #[inline]
+    pub fn dht_nodes(&self) -> &Vec<String> {
+        &self.dht_nodes
+    }
+
+    /// Sum of the lengths of every file of the torrent
+    #[inline]
+    pub fn total_length(&self) -> u64 {
+        match &self.file_type {
+            FileType::SingleFile(length) => *length,
+            FileType::MultiFile(files) => files.iter().map(FileInfo::length).sum(),
+        }
+    }
+
+    /// Every file of the torrent as its path relative to the download location along with its
+    /// length. In the multi file case the paths are prefixed with the name of the directory
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        match &self.file_type {
+            FileType::SingleFile(length) => vec![(self.name.clone(), *length)],
+            FileType::MultiFile(files) => files
+                .iter()
+                .map(|file| (self.name.join(&file.path), file.length))
+                .collect(),
+        }
+    }
+
+    /// Whether the file at `file_index` of [`Self::files`] is a pad file
+    #[inline]
+    pub fn is_pad_file(&self, file_index: usize) -> bool {
+        match &self.file_type {
+            FileType::SingleFile(_) => false,
+            FileType::MultiFile(files) => files.get(file_index).is_some_and(FileInfo::is_pad),
+        }
+    }
+
+    /// Number of bytes in the piece, which is less than the piece length for the last piece
+    pub fn piece_size(&self, piece_num: usize) -> Option<u64> {
+        if let (Some(v2), true) = (&self.v2, self.pieces.is_empty()) {
+            let (file, idx) = v2.piece_location(piece_num)?;
+            let offset = idx as u64 * self.piece_length;
+            return Some(self.piece_length.min(file.length - offset));
+        }
+        let offset = (piece_num as u64).checked_mul(self.piece_length)?;
+        let total_length = self.total_length();
+        (piece_num < self.num_pieces() && offset < total_length)
+            .then(|| self.piece_length.min(total_length - offset))
+    }
+
+    /// Maps a piece onto the ranges of the files it covers, in file order
+    pub fn piece_spans(&self, piece_num: usize) -> Vec<FileSpan> {
+        let Some(piece_size) = self.piece_size(piece_num) else {
+            return Vec::new();
+        };
+        if let (Some(v2), true) = (&self.v2, self.pieces.is_empty()) {
+            // Pieces of v2 only torrents never cross file boundaries
+            let Some((file, idx)) = v2.piece_location(piece_num) else {
+                return Vec::new();
+            };
+            let file_index = v2
+                .files()
+                .iter()
+                .position(|other| other == file)
+                .expect("The file was found in the same list");
+            return vec![FileSpan {
+                file_index,
+                file_offset: idx as u64 * self.piece_length,
+                piece_offset: 0,
+                length: piece_size,
+            }];
+        }
+        let piece_start = piece_num as u64 * self.piece_length;
+        let piece_end = piece_start + piece_size;
+        let mut spans = Vec::new();
+        let mut file_start = 0;
+        for (file_index, (_, length)) in self.files().into_iter().enumerate() {
+            let file_end = file_start + length;
+            let start = piece_start.max(file_start);
+            let end = piece_end.min(file_end);
+            if start < end {
+                spans.push(FileSpan {
+                    file_index,
+                    file_offset: start - file_start,
+                    piece_offset: start - piece_start,
+                    length: end - start,
+                });
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+
+    #[inline]
+    pub fn v2(&self) -> Option<&V2Info> {
+        self.v2.as_ref()
+    }
+
+    #[inline]
+    pub fn version(&self) -> MetaVersion {
+        match (self.pieces.is_empty(), self.v2.is_some()) {
+            (false, true) => MetaVersion::Hybrid,
+            (true, true) => MetaVersion::V2,
+            _ => MetaVersion::V1,
+        }
+    }
+
+    /// Number of pieces of the torrent. v2 only torrents have no `pieces` string so the count is
+    /// derived from their file aligned pieces instead
+    #[inline]
+    pub fn num_pieces(&self) -> usize {
+        match &self.v2 {
+            Some(v2) if self.pieces.is_empty() => v2.num_pieces(),
+            _ => self.pieces.len(),
+        }
+    }
+
+    #[inline]
+    fn info_bytes(&self) -> Result<Vec<u8>, MetaInfoError> {
+        match &self.info_bytes {
+            Some(bytes) => Ok(bytes.clone()),
+            None => serde_bencode::to_bytes(self)
+                .map_err(|err| MetaInfoError::InfoHash(err.to_string())),
+        }
+    }
+
     #[inline]
     pub fn info_hash(&self) -> Result<[u8; INFO_HASH_SIZE], MetaInfoError> {
-        let bytes = serde_bencode::to_bytes(self)
-            .map_err(|err| MetaInfoError::InfoHash(err.to_string()))?;
-        let bytes = Sha1::digest(bytes);
+        let bytes = Sha1::digest(self.info_bytes()?);
         Ok(<[u8; INFO_HASH_SIZE]>::from(bytes))
     }
+
+    /// The SHA-256 info hash of a v2 or hybrid torrent
+    #[inline]
+    pub fn info_hash_v2(&self) -> Result<Option<Hash256>, MetaInfoError> {
+        if self.v2.is_none() {
+            return Ok(None);
+        }
+        let bytes = Sha256::digest(self.info_bytes()?);
+        Ok(Some(<Hash256>::from(bytes)))
+    }
+
+    /// The 20 byte info hash used in handshakes and tracker announces: the v1 info hash when the
+    /// torrent has v1 data and the truncated v2 info hash otherwise
+    #[inline]
+    pub fn handshake_info_hash(&self) -> Result<[u8; INFO_HASH_SIZE], MetaInfoError> {
+        match self.info_hash_v2()? {
+            Some(hash) if self.version() == MetaVersion::V2 => {
+                let mut truncated = [0u8; INFO_HASH_SIZE];
+                truncated.copy_from_slice(&hash[..INFO_HASH_SIZE]);
+                Ok(truncated)
+            }
+            _ => self.info_hash(),
+        }
+    }
+}
+
+/// A file of the v2 `file tree` along with the piece layer it is verified with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2FileInfo {
+    length: u64,
+    path: PathBuf,
+    /// Root of the merkle tree of the file's 16 KiB blocks. Empty files have none
+    pieces_root: Option<Hash256>,
+    /// Hashes of the subtrees covering each piece of the file, only present for files spanning
+    /// more than one piece
+    piece_layer: Vec<Hash256>,
+}
+
+impl V2FileInfo {
+    #[inline]
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    #[inline]
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    #[inline]
+    pub fn pieces_root(&self) -> Option<&Hash256> {
+        self.pieces_root.as_ref()
+    }
+
+    #[inline]
+    pub fn piece_layer(&self) -> &[Hash256] {
+        &self.piece_layer
+    }
+}
+
+/// The v2 metainfo: files of the `file tree` in tree order, where every file starts at a new piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2Info {
+    files: Vec<V2FileInfo>,
+    piece_length: u64,
+}
+
+impl V2Info {
+    fn new(
+        files: Vec<V2FileInfo>,
+        piece_length: u64,
+        piece_layers: &HashMap<Vec<u8>, Value>,
+    ) -> Result<Self, ParseError> {
+        if piece_length < MERKLE_BLOCK_SIZE as u64 || !piece_length.is_power_of_two() {
+            return Err(ParseError::Deserialization(format!(
+                "`piece length` of a v2 torrent must be a power of two of at least 16 KiB; got {piece_length}"
+            )));
+        }
+        let blocks_per_piece = (piece_length / MERKLE_BLOCK_SIZE as u64) as usize;
+        let mut verified = Vec::with_capacity(files.len());
+        for file in files {
+            let mut file = file;
+            let pieces_root = match file.pieces_root {
+                Some(pieces_root) if file.length > piece_length => pieces_root,
+                _ => {
+                    verified.push(file);
+                    continue;
+                }
+            };
+            let layer = match piece_layers.get(&pieces_root[..]) {
+                Some(Value::Bytes(layer)) => Ok(layer),
+                Some(_) => Err(ParseError::Deserialization(
+                    "piece layer did not deserialize into bytes".to_owned(),
+                )),
+                None => Err(ParseError::MissingField(format!(
+                    "piece layers entry for {}",
+                    hex::encode(pieces_root)
+                ))),
+            }?;
+            let expected_len = ((file.length + piece_length - 1) / piece_length) as usize;
+            if layer.len() != expected_len * HASH_256_SIZE {
+                return Err(ParseError::Deserialization(format!(
+                    "piece layer of {:?} should hold {} hashes",
+                    file.path, expected_len
+                )));
+            }
+            let layer = layer
+                .chunks_exact(HASH_256_SIZE)
+                .map(|chunk| <Hash256>::try_from(chunk).expect("Must necessarily be 32 bytes"))
+                .collect::<Vec<Hash256>>();
+            let pad = merkle::pad_hash(blocks_per_piece);
+            if merkle::root(&layer, layer.len(), pad) != pieces_root {
+                return Err(ParseError::Deserialization(format!(
+                    "piece layer of {:?} does not match its `pieces root`",
+                    file.path
+                )));
+            }
+            file.piece_layer = layer;
+            verified.push(file);
+        }
+        Ok(Self {
+            files: verified,
+            piece_length,
+        })
+    }
+
+    #[inline]
+    pub fn files(&self) -> &Vec<V2FileInfo> {
+        &self.files
+    }
+
+    #[inline]
+    pub fn num_pieces(&self) -> usize {
+        self.files
+            .iter()
+            .map(|file| ((file.length + self.piece_length - 1) / self.piece_length) as usize)
+            .sum()
+    }
+
+    /// Finds the file a piece belongs to along with the index of the piece within that file
+    pub fn piece_location(&self, piece_num: usize) -> Option<(&V2FileInfo, usize)> {
+        let mut first_piece = 0;
+        for file in &self.files {
+            let file_pieces = ((file.length + self.piece_length - 1) / self.piece_length) as usize;
+            if piece_num < first_piece + file_pieces {
+                return Some((file, piece_num - first_piece));
+            }
+            first_piece += file_pieces;
+        }
+        None
+    }
+
+    /// Checks the data of a whole piece against the merkle tree of the file it belongs to. In
+    /// hybrid torrents the v1 piece ending a file goes on with a pad file, whose zeros are no
+    /// part of the tree and are left out
+    pub fn verify_piece(&self, piece_num: usize, data: &[u8]) -> bool {
+        let Some((file, idx)) = self.piece_location(piece_num) else {
+            return false;
+        };
+        let Some(pieces_root) = file.pieces_root else {
+            return data.is_empty();
+        };
+        let size = self
+            .piece_length
+            .min(file.length - idx as u64 * self.piece_length) as usize;
+        let Some(data) = data.get(..size) else {
+            return false;
+        };
+        let leaves = merkle::block_hashes(data);
+        if file.length <= self.piece_length {
+            merkle::root(&leaves, leaves.len(), ZERO_HASH) == pieces_root
+        } else {
+            let blocks_per_piece = (self.piece_length / MERKLE_BLOCK_SIZE as u64) as usize;
+            file.piece_layer
+                .get(idx)
+                .is_some_and(|hash| merkle::root(&leaves, blocks_per_piece, ZERO_HASH) == *hash)
+        }
+    }
+}
+
+fn parse_url(url: &[u8]) -> Result<Url, ParseError> {
+    let url = str::from_utf8(url).map_err(|err| ParseError::Deserialization(err.to_string()))?;
+    Url::parse(url).map_err(|err| ParseError::Deserialization(err.to_string()))
+}
+
+/// Checks that a component of a file's path names a single file or directory, so that joining it
+/// onto the download location can never escape it through `..`, a root or a drive prefix
+fn path_component(bytes: &[u8]) -> Result<&str, ParseError> {
+    let component =
+        str::from_utf8(bytes).map_err(|err| ParseError::Deserialization(err.to_string()))?;
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => Err(ParseError::Deserialization(format!(
+            "Path component {component:?} is not a plain file or directory name"
+        ))),
+    }
+}
+
+/// Walks a v2 `file tree` collecting its files in tree order, which is the order of the keys
+fn parse_file_tree(
+    node: &Value,
+    path: PathBuf,
+    files: &mut Vec<V2FileInfo>,
+) -> Result<(), ParseError> {
+    let Value::Dict(node) = node else {
+        return Err(ParseError::Deserialization(
+            "`file tree` node did not deserialize into a dictionary".to_owned(),
+        ));
+    };
+    let mut keys = node.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        let child = &node[key];
+        if key.is_empty() {
+            let Value::Dict(file) = child else {
+                return Err(ParseError::Deserialization(
+                    "`file tree` file entry did not deserialize into a dictionary".to_owned(),
+                ));
+            };
+            let length = match file.get("length".as_bytes()) {
+                Some(Value::Int(length)) => u64::try_from(*length)
+                    .map_err(|err| ParseError::Deserialization(err.to_string())),
+                Some(_) => Err(ParseError::Deserialization(
+                    "`length` did not deserialize into an integer".to_owned(),
+                )),
+                None => Err(ParseError::MissingField("length".to_string())),
+            }?;
+            let pieces_root = match file.get("pieces root".as_bytes()) {
+                Some(Value::Bytes(root)) => Some(<Hash256>::try_from(&root[..]).map_err(|_| {
+                    ParseError::Deserialization("`pieces root` was not 32 bytes".to_owned())
+                })?),
+                Some(_) => {
+                    return Err(ParseError::Deserialization(
+                        "`pieces root` did not deserialize into bytes".to_owned(),
+                    ))
+                }
+                None if length > 0 => {
+                    return Err(ParseError::MissingField("pieces root".to_string()))
+                }
+                None => None,
+            };
+            if path.as_os_str().is_empty() {
+                return Err(ParseError::Deserialization("Empty path!".to_owned()));
+            }
+            files.push(V2FileInfo {
+                length,
+                path: path.clone(),
+                pieces_root,
+                piece_layer: Vec::new(),
+            });
+        } else {
+            parse_file_tree(child, path.join(path_component(key)?), files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Derives the v1 style file layout of a v2 only torrent from its `file tree`
+fn file_type_from_v2(name: &str, files: &[V2FileInfo]) -> FileType {
+    match files {
+        [file] if file.path == Path::new(name) => FileType::SingleFile(file.length),
+        files => FileType::MultiFile(
+            files
+                .iter()
+                .map(|file| {
+                    let components = file
+                        .path
+                        .iter()
+                        .map(|component| component.to_string_lossy().into_owned())
+                        .collect();
+                    FileInfo::new(file.length, components)
+                })
+                .collect(),
+        ),
+    }
 }
 
 /// Error type for MetaInfo
@@ -334,9 +914,27 @@ where
     serializer.serialize_bytes(&pieces.iter().flatten().copied().collect::<Vec<u8>>()[..])
 }
 
+fn serialize_path<S>(path: &Path, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    // The `path` of a file in a multi file torrent is a list of path components rather than a
+    // single joined string
+    serializer.collect_seq(path.iter().map(|component| component.to_string_lossy()))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::torrent::from_file;
+    use std::collections::HashMap;
+
+    use serde_bencode::value::Value;
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        merkle::{self, MERKLE_BLOCK_SIZE, ZERO_HASH},
+        peer::{picker::FileSelection, storage::Storage, writer::FileWriter},
+        torrent::{from_bytes, from_file, MetaVersion},
+    };
 
     #[test]
     fn test_deserialize_1() {
@@ -346,4 +944,192 @@ mod tests {
             hex::encode(metainfo.1.info_hash().unwrap())
         );
     }
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect::<HashMap<_, _>>(),
+        )
+    }
+
+    #[test]
+    fn test_reject_escaping_paths() {
+        for path in [
+            vec!["..", "evil"],
+            vec!["../evil"],
+            vec!["/etc", "passwd"],
+            vec![""],
+        ] {
+            let path = path
+                .into_iter()
+                .map(|component| Value::Bytes(component.as_bytes().to_vec()))
+                .collect();
+            let info = dict(vec![
+                ("name", Value::Bytes(b"dir".to_vec())),
+                ("piece length", Value::Int(16384)),
+                ("pieces", Value::Bytes(vec![0; 20])),
+                (
+                    "files",
+                    Value::List(vec![dict(vec![
+                        ("length", Value::Int(1)),
+                        ("path", Value::List(path)),
+                    ])]),
+                ),
+            ]);
+            let torrent = dict(vec![("info", info)]);
+            assert!(from_bytes(serde_bencode::to_bytes(&torrent).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_reject_escaping_name() {
+        for name in ["..", "/tmp/evil", "a/../../evil"] {
+            let info = dict(vec![
+                ("name", Value::Bytes(name.as_bytes().to_vec())),
+                ("piece length", Value::Int(16384)),
+                ("pieces", Value::Bytes(vec![0; 20])),
+                ("length", Value::Int(1)),
+            ]);
+            let torrent = dict(vec![("info", info)]);
+            assert!(from_bytes(serde_bencode::to_bytes(&torrent).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_deserialize_v2() {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE;
+        let data = (0..MERKLE_BLOCK_SIZE * 3 + 100)
+            .map(|i| (i % 13) as u8)
+            .collect::<Vec<u8>>();
+        let layer = data
+            .chunks(piece_length)
+            .map(|piece| merkle::root(&merkle::block_hashes(piece), 2, ZERO_HASH))
+            .collect::<Vec<_>>();
+        let pieces_root = merkle::root(&layer, layer.len(), merkle::pad_hash(2));
+        let info = dict(vec![
+            ("meta version", Value::Int(2)),
+            ("name", Value::Bytes(b"data.bin".to_vec())),
+            ("piece length", Value::Int(piece_length as i64)),
+            (
+                "file tree",
+                dict(vec![(
+                    "data.bin",
+                    dict(vec![(
+                        "",
+                        dict(vec![
+                            ("length", Value::Int(data.len() as i64)),
+                            ("pieces root", Value::Bytes(pieces_root.to_vec())),
+                        ]),
+                    )]),
+                )]),
+            ),
+        ]);
+        let torrent = dict(vec![
+            (
+                "announce",
+                Value::Bytes(b"http://tracker.example/announce".to_vec()),
+            ),
+            ("info", info),
+            (
+                "piece layers",
+                Value::Dict(HashMap::from([(
+                    pieces_root.to_vec(),
+                    Value::Bytes(layer.concat()),
+                )])),
+            ),
+        ]);
+        let (_, metainfo) = from_bytes(serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(MetaVersion::V2, metainfo.version());
+        assert_eq!(2, metainfo.num_pieces());
+        let v2 = metainfo.v2().unwrap();
+        assert!(v2.verify_piece(0, &data[..piece_length]));
+        assert!(v2.verify_piece(1, &data[piece_length..]));
+        assert!(!v2.verify_piece(1, &data[..piece_length]));
+        let info_hash_v2 = metainfo.info_hash_v2().unwrap().unwrap();
+        assert_eq!(
+            info_hash_v2[..20],
+            metainfo.handshake_info_hash().unwrap()[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_with_pad_files() {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE;
+        let a = (0..20_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let b = vec![7u8; 100];
+        let pad = piece_length - a.len();
+        let mut first_piece = a.clone();
+        first_piece.resize(piece_length, 0);
+        let pieces = [Sha1::digest(&first_piece), Sha1::digest(&b)].concat();
+        let file = |length: usize, path: &[&str], attr: Option<&str>| {
+            let mut entries = vec![
+                ("length", Value::Int(length as i64)),
+                (
+                    "path",
+                    Value::List(
+                        path.iter()
+                            .map(|component| Value::Bytes(component.as_bytes().to_vec()))
+                            .collect(),
+                    ),
+                ),
+            ];
+            if let Some(attr) = attr {
+                entries.push(("attr", Value::Bytes(attr.as_bytes().to_vec())));
+            }
+            dict(entries)
+        };
+        let tree_file = |data: &[u8]| {
+            let leaves = merkle::block_hashes(data);
+            let root = merkle::root(&leaves, leaves.len(), ZERO_HASH);
+            dict(vec![(
+                "",
+                dict(vec![
+                    ("length", Value::Int(data.len() as i64)),
+                    ("pieces root", Value::Bytes(root.to_vec())),
+                ]),
+            )])
+        };
+        let info = dict(vec![
+            ("meta version", Value::Int(2)),
+            ("name", Value::Bytes(b"release".to_vec())),
+            ("piece length", Value::Int(piece_length as i64)),
+            ("pieces", Value::Bytes(pieces)),
+            (
+                "files",
+                Value::List(vec![
+                    file(a.len(), &["a.bin"], None),
+                    file(pad, &[".pad", &pad.to_string()], Some("p")),
+                    file(b.len(), &["b.bin"], None),
+                ]),
+            ),
+            (
+                "file tree",
+                dict(vec![("a.bin", tree_file(&a)), ("b.bin", tree_file(&b))]),
+            ),
+        ]);
+        let torrent = dict(vec![("info", info)]);
+        let (_, metainfo) = from_bytes(serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(MetaVersion::Hybrid, metainfo.version());
+        assert!(metainfo.is_pad_file(1));
+        let v2 = metainfo.v2().unwrap();
+        assert!(v2.verify_piece(0, &first_piece));
+        assert!(v2.verify_piece(1, &b));
+
+        // The pad file is never created, and reads back as the zeros it stands for
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("release");
+        let writer = FileWriter::create(&metainfo, &FileSelection::all(&metainfo), &out)
+            .await
+            .unwrap();
+        writer.write_piece(0, &first_piece).unwrap();
+        assert!(!out.join(".pad").exists());
+        assert_eq!(a, std::fs::read(out.join("a.bin")).unwrap());
+        assert_eq!(Some(first_piece.clone()), writer.read_piece(0).unwrap());
+        assert_eq!(
+            Some(<[u8; 20]>::from(Sha1::digest(&first_piece))),
+            writer.hash_piece(0).unwrap()
+        );
+    }
 }
@@ -0,0 +1,126 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{
+    proxy::Proxy,
+    utp::socket::{UtpSocket, UtpStream},
+};
+
+/// How peers are connected to
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// uTP over the socket given, falling back to TCP for peers that don't answer
+    Utp(UtpSocket),
+    /// TCP through a SOCKS5 or HTTP proxy
+    Proxy(Proxy),
+}
+
+impl Transport {
+    pub async fn connect(&self, peer: SocketAddr) -> io::Result<PeerConnection> {
+        match self {
+            Transport::Tcp => Ok(PeerConnection::Tcp(TcpStream::connect(peer).await?)),
+            Transport::Utp(socket) => match socket.connect(peer).await {
+                Ok(stream) => Ok(PeerConnection::Utp(stream)),
+                Err(_) => Ok(PeerConnection::Tcp(TcpStream::connect(peer).await?)),
+            },
+            Transport::Proxy(proxy) => Ok(PeerConnection::Tcp(proxy.connect(peer).await?)),
+        }
+    }
+}
+
+/// A connection to a peer over either transport, which the handshake and peer messages run over
+/// the same way
+#[derive(Debug)]
+pub enum PeerConnection {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for PeerConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerConnection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerConnection::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerConnection::Tcp(stream) => Pin::new(stream).poll_write(cx, data),
+            PeerConnection::Utp(stream) => Pin::new(stream).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerConnection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerConnection::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerConnection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerConnection::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transport;
+    use crate::{
+        handshake::{self, Handshake},
+        mse::{self, EncryptionPolicy},
+        utp::socket::UtpSocket,
+    };
+
+    #[tokio::test]
+    async fn test_encrypted_handshake_over_utp() {
+        let info_hash = [7; 20];
+        let listener = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut stream, _) = mse::respond(stream, &[info_hash], EncryptionPolicy::Prefer)
+                .await
+                .unwrap();
+            let hand = Handshake::new(&info_hash, &[2; 20]);
+            handshake::exchange(&mut stream, &hand).await.unwrap();
+            stream.is_encrypted()
+        });
+        let transport = Transport::Utp(UtpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (stream, peer_hand) = handshake::connect_encrypted(
+            addr,
+            Handshake::new(&info_hash, &[1; 20]),
+            EncryptionPolicy::Require,
+            &transport,
+        )
+        .await
+        .unwrap();
+        assert!(stream.is_encrypted());
+        assert_eq!(&[2; 20], peer_hand.peer_id());
+        assert!(server.await.unwrap());
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::packet::MAX_PAYLOAD_SIZE;
+
+/// Queuing delay LEDBAT aims for; more than this and the window shrinks
+pub const TARGET_DELAY_US: u32 = 100_000;
+/// Most the window grows by in a round trip
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD_SIZE as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+/// The base delay is the lowest delay seen over the last two of these
+const BASE_DELAY_PERIOD: Duration = Duration::from_secs(60);
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// LEDBAT congestion control: the window grows while the one way delay stays close to its
+/// lowest value and shrinks once packets start queuing, so that uTP yields to other traffic
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    cwnd: f64,
+    /// Lowest delay of each [`BASE_DELAY_PERIOD`], most recent last
+    base_delays: VecDeque<(Instant, u32)>,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Self {
+            cwnd: MIN_WINDOW * 2.0,
+            base_delays: VecDeque::new(),
+        }
+    }
+}
+
+impl Ledbat {
+    /// Bytes that may be in flight
+    pub fn window(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    /// Takes in an ACK for `bytes_acked` bytes along with the delay the peer measured for our
+    /// packets
+    pub fn on_ack(&mut self, bytes_acked: usize, delay_us: u32, now: Instant) {
+        match self.base_delays.back_mut() {
+            Some((start, base)) if now.duration_since(*start) < BASE_DELAY_PERIOD => {
+                *base = (*base).min(delay_us)
+            }
+            _ => {
+                self.base_delays.push_back((now, delay_us));
+                if self.base_delays.len() > 2 {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        let base = self
+            .base_delays
+            .iter()
+            .map(|(_, base)| *base)
+            .min()
+            .unwrap_or(delay_us);
+        // The delay includes the offset of the two clocks, which the base delay cancels out
+        let queuing = delay_us.wrapping_sub(base);
+        let queuing = if queuing > u32::MAX / 2 { 0 } else { queuing };
+        let off_target =
+            (f64::from(TARGET_DELAY_US) - f64::from(queuing)) / f64::from(TARGET_DELAY_US);
+        let window_factor = (bytes_acked as f64).min(self.cwnd) / self.cwnd;
+        self.cwnd = (self.cwnd + MAX_CWND_INCREASE_PER_RTT * off_target * window_factor)
+            .clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// A packet was lost but later ones arrived
+    pub fn on_loss(&mut self) {
+        self.cwnd = (self.cwnd / 2.0).max(MIN_WINDOW);
+    }
+
+    /// Nothing was acknowledged for a whole retransmission timeout
+    pub fn on_timeout(&mut self) {
+        self.cwnd = MIN_WINDOW;
+    }
+}
+
+/// Round trip time estimate and the retransmission timeout derived from it (RFC 6298)
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    #[inline]
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap_or(rtt) + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Backs off after a timeout
+    pub fn on_timeout(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Ledbat, TARGET_DELAY_US};
+
+    #[test]
+    fn test_ledbat_yields_to_queuing() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        let start = ledbat.window();
+        // The clocks are a second apart, which only the base delay knows about
+        for _ in 0..10 {
+            ledbat.on_ack(start, 1_000_000 + 10_000, now);
+        }
+        let grown = ledbat.window();
+        assert!(grown > start);
+        for _ in 0..10 {
+            ledbat.on_ack(
+                grown,
+                1_000_000 + 3 * TARGET_DELAY_US,
+                now + Duration::from_secs(1),
+            );
+        }
+        assert!(ledbat.window() < grown);
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Instant,
+};
+
+use super::{
+    congestion::{Ledbat, RttEstimator},
+    packet::{build_sack, sacked, seq_less, Packet, PacketType, MAX_PAYLOAD_SIZE},
+};
+
+/// Bytes received in order that the application hasn't read yet
+pub const RECV_BUFFER_SIZE: usize = 1 << 20;
+/// Bytes written by the application that haven't been sent yet
+pub const SEND_BUFFER_SIZE: usize = 1 << 20;
+const MAX_SYN_TRANSMISSIONS: u32 = 4;
+const MAX_TRANSMISSIONS: u32 = 8;
+/// Packets further ahead than this of the last one received in order are dropped
+const REORDER_WINDOW: u16 = 1024;
+/// Bits of the selective ACKs we send
+const SACK_BITS: usize = 256;
+/// Duplicate or selective ACKs past a packet after which it is taken as lost
+const DUPLICATE_ACKS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    Connected,
+    /// Both sides sent their FIN and everything got acknowledged
+    Closed,
+    /// The peer reset the connection or stopped answering
+    Reset,
+}
+
+#[derive(Debug, Clone)]
+struct InFlight {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    needs_resend: bool,
+    fast_resent: bool,
+}
+
+/// The state machine of a single uTP connection, free of any IO: packets go in through
+/// [`Connection::on_packet`] and come out of [`Connection::transmit`]
+#[derive(Debug)]
+pub struct Connection {
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet we send
+    seq_nr: u16,
+    /// Sequence number of the last packet received in order
+    ack_nr: u16,
+    epoch: Instant,
+    /// Delay of the last packet received, echoed back to the peer
+    reply_micro: u32,
+    send_buf: VecDeque<u8>,
+    /// Sent and not yet acknowledged packets, oldest first
+    in_flight: VecDeque<InFlight>,
+    peer_wnd: usize,
+    ledbat: Ledbat,
+    rtt: RttEstimator,
+    last_ack: u16,
+    dup_acks: u32,
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    /// The peer's FIN was received along with everything before it
+    eof: bool,
+    write_closed: bool,
+    fin_sent: bool,
+    need_ack: bool,
+}
+
+impl Connection {
+    /// A connection we open, starting with a SYN
+    pub fn outgoing(recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(recv_id, recv_id.wrapping_add(1), 1, now);
+        connection.state = State::SynSent;
+        let syn = Packet::new(PacketType::Syn, recv_id, connection.seq_nr);
+        connection.seq_nr = connection.seq_nr.wrapping_add(1);
+        connection.in_flight.push_back(InFlight {
+            packet: syn,
+            sent_at: now,
+            transmissions: 0,
+            needs_resend: true,
+            fast_resent: false,
+        });
+        connection
+    }
+
+    /// A connection the peer opened with `syn`
+    pub fn incoming(syn: &Packet, seq_nr: u16, now: Instant) -> Self {
+        let mut connection = Self::new(
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            seq_nr,
+            now,
+        );
+        connection.ack_nr = syn.seq_nr;
+        connection.reply_micro = connection.now_us(now).wrapping_sub(syn.timestamp_us);
+        connection.peer_wnd = syn.wnd_size as usize;
+        connection.need_ack = true;
+        connection
+    }
+
+    fn new(recv_id: u16, send_id: u16, seq_nr: u16, now: Instant) -> Self {
+        Self {
+            state: State::Connected,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            epoch: now,
+            reply_micro: 0,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            peer_wnd: MAX_PAYLOAD_SIZE,
+            ledbat: Ledbat::default(),
+            rtt: RttEstimator::default(),
+            last_ack: seq_nr.wrapping_sub(1),
+            dup_acks: 0,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            eof: false,
+            write_closed: false,
+            fin_sent: false,
+            need_ack: false,
+        }
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    #[inline]
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    /// Whether everything the peer will ever send has been read
+    pub fn is_eof(&self) -> bool {
+        self.eof && self.recv_buf.is_empty()
+    }
+
+    pub fn can_write(&self) -> bool {
+        self.send_buf.len() < SEND_BUFFER_SIZE
+    }
+
+    fn now_us(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn recv_window(&self) -> usize {
+        RECV_BUFFER_SIZE.saturating_sub(self.recv_buf.len())
+    }
+
+    /// Queues bytes to be sent, returning how many fit in the send buffer
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        if self.write_closed || matches!(self.state, State::Closed | State::Reset) {
+            return 0;
+        }
+        let len = data.len().min(SEND_BUFFER_SIZE - self.send_buf.len());
+        self.send_buf.extend(&data[..len]);
+        len
+    }
+
+    /// Takes received bytes out, returning how many were read
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let was_small = self.recv_window() < MAX_PAYLOAD_SIZE;
+        let len = buf.len().min(self.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..len)) {
+            *dst = src;
+        }
+        // Tell the peer the window opened up again
+        if was_small && self.recv_window() >= MAX_PAYLOAD_SIZE {
+            self.need_ack = true;
+        }
+        len
+    }
+
+    /// Sends a FIN once everything written so far went out
+    pub fn close(&mut self) {
+        self.write_closed = true;
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if packet.packet_type == PacketType::Reset {
+            self.state = State::Reset;
+            return;
+        }
+        match self.state {
+            State::SynSent if packet.packet_type == PacketType::State => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            State::SynSent | State::Reset => return,
+            State::Connected | State::Closed => {}
+        }
+        self.reply_micro = self.now_us(now).wrapping_sub(packet.timestamp_us);
+        self.peer_wnd = packet.wnd_size as usize;
+        if packet.packet_type == PacketType::Syn {
+            // Our answer to the SYN got lost
+            self.need_ack = true;
+            return;
+        }
+        self.process_ack(&packet, now);
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.receive(packet);
+        }
+        if self.eof && self.fin_sent && self.in_flight.is_empty() {
+            self.state = State::Closed;
+        }
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked = Vec::new();
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|front| !seq_less(packet.ack_nr, front.packet.seq_nr))
+        {
+            acked.extend(self.in_flight.pop_front());
+        }
+        let mut sacked_later = 0;
+        if let Some(mask) = &packet.sack {
+            let sacked = sacked(packet.ack_nr, mask).collect::<HashSet<_>>();
+            let (selected, remaining) = self
+                .in_flight
+                .drain(..)
+                .partition::<VecDeque<_>, _>(|sent| sacked.contains(&sent.packet.seq_nr));
+            self.in_flight = remaining;
+            acked.extend(selected);
+            sacked_later = sacked.len() as u32;
+        }
+
+        let bytes_acked = acked
+            .iter()
+            .map(|sent| sent.packet.payload.len())
+            .sum::<usize>();
+        for sent in &acked {
+            // Karn's algorithm: only packets sent once tell the round trip time
+            if sent.transmissions == 1 {
+                self.rtt.sample(now.duration_since(sent.sent_at));
+            }
+        }
+        if packet.packet_type == PacketType::State
+            && packet.ack_nr == self.last_ack
+            && acked.is_empty()
+            && !self.in_flight.is_empty()
+        {
+            self.dup_acks += 1;
+        } else if !acked.is_empty() {
+            self.dup_acks = 0;
+        }
+        self.last_ack = packet.ack_nr;
+        if self.dup_acks >= DUPLICATE_ACKS || sacked_later >= DUPLICATE_ACKS {
+            if let Some(front) = self.in_flight.front_mut() {
+                if !front.fast_resent {
+                    front.fast_resent = true;
+                    front.needs_resend = true;
+                    self.ledbat.on_loss();
+                }
+            }
+        }
+        if bytes_acked > 0 {
+            self.ledbat
+                .on_ack(bytes_acked, packet.timestamp_diff_us, now);
+        }
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        self.need_ack = true;
+        let seq_nr = packet.seq_nr;
+        if self.eof
+            || !seq_less(self.ack_nr, seq_nr)
+            || seq_nr.wrapping_sub(self.ack_nr) > REORDER_WINDOW
+        {
+            return;
+        }
+        self.out_of_order.insert(seq_nr, packet);
+        while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = packet.seq_nr;
+            if packet.packet_type == PacketType::Fin {
+                self.eof = true;
+                self.out_of_order.clear();
+                break;
+            }
+            self.recv_buf.extend(packet.payload);
+        }
+    }
+
+    /// Checks for packets that timed out. Too many timeouts in a row reset the connection
+    pub fn on_tick(&mut self, now: Instant) {
+        if matches!(self.state, State::Closed | State::Reset) {
+            return;
+        }
+        let rto = self.rtt.rto();
+        let Some(front) = self.in_flight.front() else {
+            return;
+        };
+        if front.needs_resend || now.duration_since(front.sent_at) < rto {
+            return;
+        }
+        let limit = match front.packet.packet_type {
+            PacketType::Syn => MAX_SYN_TRANSMISSIONS,
+            _ => MAX_TRANSMISSIONS,
+        };
+        if front.transmissions >= limit {
+            self.state = State::Reset;
+            return;
+        }
+        for sent in &mut self.in_flight {
+            sent.needs_resend = true;
+        }
+        self.ledbat.on_timeout();
+        self.rtt.on_timeout();
+    }
+
+    /// The packets to send right now: retransmissions, then new data as far as the congestion
+    /// and receive windows allow, then a bare ACK if nothing else carries one
+    pub fn transmit(&mut self, now: Instant) -> Vec<Packet> {
+        let mut out = Vec::new();
+        if self.state == State::Reset {
+            return out;
+        }
+        for sent in self.in_flight.iter_mut().filter(|sent| sent.needs_resend) {
+            sent.needs_resend = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            out.push(sent.packet.clone());
+        }
+        if self.state == State::Connected {
+            let window = self.ledbat.window().min(self.peer_wnd);
+            let mut bytes_in_flight = self
+                .in_flight
+                .iter()
+                .map(|sent| sent.packet.payload.len())
+                .sum::<usize>();
+            while !self.send_buf.is_empty() {
+                let len = self.send_buf.len().min(MAX_PAYLOAD_SIZE);
+                // With nothing in flight one packet always goes, which probes a closed window
+                if bytes_in_flight + len > window && !self.in_flight.is_empty() {
+                    break;
+                }
+                let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr);
+                packet.payload = self.send_buf.drain(..len).collect();
+                out.push(self.track(packet, now));
+                bytes_in_flight += len;
+            }
+            if self.write_closed && self.send_buf.is_empty() && !self.fin_sent {
+                self.fin_sent = true;
+                let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr);
+                out.push(self.track(fin, now));
+            }
+        }
+        if self.need_ack && out.is_empty() && self.state != State::SynSent {
+            out.push(Packet::new(PacketType::State, self.send_id, self.seq_nr));
+        }
+        self.need_ack = false;
+        for packet in &mut out {
+            self.stamp(packet, now);
+        }
+        out
+    }
+
+    fn track(&mut self, packet: Packet, now: Instant) -> Packet {
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight.push_back(InFlight {
+            packet: packet.clone(),
+            sent_at: now,
+            transmissions: 1,
+            needs_resend: false,
+            fast_resent: false,
+        });
+        packet
+    }
+
+    fn stamp(&self, packet: &mut Packet, now: Instant) {
+        packet.timestamp_us = self.now_us(now);
+        packet.timestamp_diff_us = self.reply_micro;
+        packet.wnd_size = self.recv_window() as u32;
+        if packet.packet_type != PacketType::Syn {
+            packet.ack_nr = self.ack_nr;
+            if !self.out_of_order.is_empty() {
+                packet.sack = build_sack(self.ack_nr, SACK_BITS, |seq_nr| {
+                    self.out_of_order.contains_key(&seq_nr)
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Connection, State};
+    use crate::utp::packet::{Packet, PacketType};
+
+    /// Moves packets between two connections, dropping about every fifth data packet and
+    /// delivering each batch in reverse order
+    fn exchange(a: &mut Connection, b: &mut Connection, now: Instant, counter: &mut usize) {
+        deliver(a, b, now, counter);
+        deliver(b, a, now, counter);
+    }
+
+    fn deliver(from: &mut Connection, to: &mut Connection, now: Instant, counter: &mut usize) {
+        let mut packets = from.transmit(now);
+        packets.reverse();
+        for packet in packets {
+            *counter += 1;
+            if packet.packet_type == PacketType::Data && *counter % 5 == 0 {
+                continue;
+            }
+            to.on_packet(Packet::from_bytes(&packet.to_bytes()).unwrap(), now);
+        }
+    }
+
+    #[test]
+    fn test_transfer_with_loss_and_reordering() {
+        let mut now = Instant::now();
+        let mut client = Connection::outgoing(100, now);
+        let syn = client.transmit(now).remove(0);
+        let mut server = Connection::incoming(&syn, 5000, now);
+        let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut counter = 0;
+        for _ in 0..10_000 {
+            if client.state() == State::Connected && written < data.len() {
+                written += client.write(&data[written..]);
+                if written == data.len() {
+                    client.close();
+                }
+            }
+            exchange(&mut client, &mut server, now, &mut counter);
+            let mut buf = [0u8; 4096];
+            loop {
+                let len = server.read(&mut buf);
+                if len == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..len]);
+            }
+            if server.is_eof() {
+                break;
+            }
+            now += Duration::from_millis(20);
+            client.on_tick(now);
+            server.on_tick(now);
+        }
+        assert!(server.is_eof());
+        assert_eq!(data, received);
+    }
+}
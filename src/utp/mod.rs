@@ -0,0 +1,4 @@
+pub mod congestion;
+pub mod connection;
+pub mod packet;
+pub mod socket;
@@ -0,0 +1,192 @@
+use crate::ParseError;
+
+pub const HEADER_SIZE: usize = 20;
+pub const VERSION: u8 = 1;
+/// Largest payload of a data packet, which keeps packets below common path MTUs
+pub const MAX_PAYLOAD_SIZE: usize = 1400 - HEADER_SIZE;
+/// Extension type of the selective ACK bitmask
+const EXTENSION_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(ParseError::Deserialization(format!(
+                "Unknown uTP packet type {value}"
+            ))),
+        }
+    }
+}
+
+/// A uTP packet (BEP 29): the 20 byte header, the extensions we know of and the payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    /// When the packet was sent, in microseconds of the sender's clock
+    pub timestamp_us: u32,
+    /// The difference between the sender's clock and the timestamp of the last packet it
+    /// received, which is the one way delay plus the offset of the clocks
+    pub timestamp_diff_us: u32,
+    /// Bytes the sender can still take in
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Selective ACK bitmask; bit `i` acknowledges packet `ack_nr + 2 + i`
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp_us: 0,
+            timestamp_diff_us: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr: 0,
+            sack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push((self.packet_type as u8) << 4 | VERSION);
+        bytes.push(if self.sack.is_some() {
+            EXTENSION_SACK
+        } else {
+            0
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_us.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_diff_us.to_be_bytes());
+        bytes.extend_from_slice(&self.wnd_size.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            bytes.push(0);
+            bytes.push(sack.len() as u8);
+            bytes.extend_from_slice(sack);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ParseError::Deserialization(format!(
+                "uTP packet of {} bytes is shorter than its header",
+                bytes.len()
+            )));
+        }
+        if bytes[0] & 0x0F != VERSION {
+            return Err(ParseError::Deserialization(format!(
+                "Unknown uTP version {}",
+                bytes[0] & 0x0F
+            )));
+        }
+        let packet_type = PacketType::try_from(bytes[0] >> 4)?;
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at =
+            |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().expect("Sliced 4 bytes"));
+        let mut sack = None;
+        let mut extension = bytes[1];
+        let mut offset = HEADER_SIZE;
+        while extension != 0 {
+            let [next, len] = bytes
+                .get(offset..offset + 2)
+                .and_then(|header| <[u8; 2]>::try_from(header).ok())
+                .ok_or(ParseError::Deserialization(
+                    "uTP extension header was cut short".to_owned(),
+                ))?;
+            let data = bytes.get(offset + 2..offset + 2 + usize::from(len)).ok_or(
+                ParseError::Deserialization("uTP extension was cut short".to_owned()),
+            )?;
+            // Unknown extensions are skipped over
+            if extension == EXTENSION_SACK {
+                sack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + usize::from(len);
+        }
+        Ok(Self {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp_us: u32_at(4),
+            timestamp_diff_us: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap around
+pub fn seq_less(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+/// Builds the selective ACK bitmask for the packets after `ack_nr + 1` that `received` says
+/// have arrived. Returns nothing when none of them has
+pub fn build_sack(ack_nr: u16, max_bits: usize, received: impl Fn(u16) -> bool) -> Option<Vec<u8>> {
+    let mut mask = vec![0u8; (max_bits + 31) / 32 * 4];
+    let mut any = false;
+    for bit in 0..mask.len() * 8 {
+        if received(ack_nr.wrapping_add(2).wrapping_add(bit as u16)) {
+            mask[bit / 8] |= 1 << (bit % 8);
+            any = true;
+        }
+    }
+    any.then_some(mask)
+}
+
+/// The sequence numbers a selective ACK bitmask acknowledges
+pub fn sacked(ack_nr: u16, mask: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    (0..mask.len() * 8)
+        .filter(|bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+        .map(move |bit| ack_nr.wrapping_add(2).wrapping_add(bit as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_sack, sacked, seq_less, Packet, PacketType};
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 65535);
+        packet.timestamp_us = 1;
+        packet.timestamp_diff_us = 2;
+        packet.wnd_size = 3;
+        packet.ack_nr = 7;
+        packet.sack = build_sack(7, 32, |seq| seq == 9 || seq == 40);
+        packet.payload = b"payload".to_vec();
+        let parsed = Packet::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(packet, parsed);
+        assert_eq!(
+            vec![9, 40],
+            sacked(7, parsed.sack.as_ref().unwrap()).collect::<Vec<_>>()
+        );
+        assert!(seq_less(65535, 1));
+        assert!(!seq_less(1, 65535));
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use super::{
+    connection::{Connection, State},
+    packet::{Packet, PacketType, HEADER_SIZE, MAX_PAYLOAD_SIZE},
+};
+
+/// How often connections look for timed out packets
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Incoming connections waiting to be accepted
+const ACCEPT_BACKLOG: usize = 64;
+
+#[derive(Debug)]
+struct Shared {
+    connection: Connection,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type ConnectionKey = (SocketAddr, u16);
+
+#[derive(Debug)]
+struct SocketInner {
+    udp: Arc<UdpSocket>,
+    connections: Mutex<HashMap<ConnectionKey, Arc<Mutex<Shared>>>>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().expect("Poisoned lock").drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl SocketInner {
+    /// Sends whatever the connection has to send. UDP gives no delivery guarantee anyway, so a
+    /// full socket buffer just loses the packet to a later retransmission
+    fn transmit(&self, addr: SocketAddr, shared: &mut Shared) {
+        for packet in shared.connection.transmit(Instant::now()) {
+            let _ = self.udp.try_send_to(&packet.to_bytes(), addr);
+        }
+        shared.wake();
+    }
+}
+
+/// A UDP socket carrying uTP connections (BEP 29), both ones we open and ones peers open to us
+#[derive(Debug, Clone)]
+pub struct UtpSocket {
+    inner: Arc<SocketInner>,
+}
+
+impl UtpSocket {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UtpSocket> {
+        let udp = Arc::new(UdpSocket::bind(addr).await?);
+        let (incoming_tx, incoming_rx) = mpsc::channel(ACCEPT_BACKLOG);
+        let inner = Arc::new(SocketInner {
+            udp,
+            connections: Mutex::new(HashMap::new()),
+            incoming: tokio::sync::Mutex::new(incoming_rx),
+            tasks: Mutex::new(Vec::new()),
+        });
+        let receiver = tokio::spawn(receive_loop(
+            inner.udp.clone(),
+            Arc::downgrade(&inner),
+            incoming_tx,
+        ));
+        let ticker = tokio::spawn(tick_loop(Arc::downgrade(&inner)));
+        inner
+            .tasks
+            .lock()
+            .expect("Poisoned lock")
+            .extend([receiver, ticker]);
+        Ok(UtpSocket { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.udp.local_addr()
+    }
+
+    /// Opens a connection to `addr`, waiting for the peer to answer the SYN
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let shared = {
+            let mut connections = self.inner.connections.lock().expect("Poisoned lock");
+            let recv_id = loop {
+                let recv_id = rand::thread_rng().gen::<u16>();
+                if !connections.contains_key(&(addr, recv_id)) {
+                    break recv_id;
+                }
+            };
+            let shared = Arc::new(Mutex::new(Shared {
+                connection: Connection::outgoing(recv_id, Instant::now()),
+                read_waker: None,
+                write_waker: None,
+            }));
+            connections.insert((addr, recv_id), shared.clone());
+            shared
+        };
+        let stream = UtpStream {
+            socket: self.inner.clone(),
+            shared,
+            addr,
+        };
+        self.inner.transmit(addr, &mut stream.lock());
+        poll_fn(|cx| {
+            let mut shared = stream.lock();
+            match shared.connection.state() {
+                State::SynSent => {
+                    shared.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Connected => Poll::Ready(Ok(())),
+                State::Closed | State::Reset => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("uTP peer {addr} did not answer"),
+                ))),
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Waits for a peer to open a connection
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        self.inner
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::NotConnected.into())
+    }
+}
+
+async fn receive_loop(
+    udp: Arc<UdpSocket>,
+    inner: Weak<SocketInner>,
+    incoming: mpsc::Sender<(UtpStream, SocketAddr)>,
+) {
+    let mut buf = vec![0u8; HEADER_SIZE + MAX_PAYLOAD_SIZE + 512];
+    loop {
+        let Ok((len, from)) = udp.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(socket) = inner.upgrade() else {
+            return;
+        };
+        let Ok(packet) = Packet::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        let existing = {
+            let connections = socket.connections.lock().expect("Poisoned lock");
+            match packet.packet_type {
+                // A repeated SYN goes to the connection it opened
+                PacketType::Syn => connections
+                    .get(&(from, packet.connection_id.wrapping_add(1)))
+                    .cloned(),
+                _ => connections.get(&(from, packet.connection_id)).cloned(),
+            }
+        };
+        if let Some(shared) = existing {
+            let mut shared = shared.lock().expect("Poisoned lock");
+            shared.connection.on_packet(packet, Instant::now());
+            socket.transmit(from, &mut shared);
+            continue;
+        }
+        // Nobody accepting means the peer is refused by never getting an answer
+        if packet.packet_type != PacketType::Syn || incoming.capacity() == 0 {
+            continue;
+        }
+        let seq_nr = rand::thread_rng().gen::<u16>();
+        let connection = Connection::incoming(&packet, seq_nr, Instant::now());
+        let shared = Arc::new(Mutex::new(Shared {
+            connection,
+            read_waker: None,
+            write_waker: None,
+        }));
+        let stream = UtpStream {
+            socket: socket.clone(),
+            shared: shared.clone(),
+            addr: from,
+        };
+        if incoming.try_send((stream, from)).is_ok() {
+            let key = (from, packet.connection_id.wrapping_add(1));
+            socket
+                .connections
+                .lock()
+                .expect("Poisoned lock")
+                .insert(key, shared.clone());
+            socket.transmit(from, &mut shared.lock().expect("Poisoned lock"));
+        }
+    }
+}
+
+async fn tick_loop(inner: Weak<SocketInner>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(socket) = inner.upgrade() else {
+            return;
+        };
+        let connections = socket
+            .connections
+            .lock()
+            .expect("Poisoned lock")
+            .iter()
+            .map(|(key, shared)| (*key, shared.clone()))
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        for ((addr, recv_id), shared) in connections {
+            let mut shared = shared.lock().expect("Poisoned lock");
+            shared.connection.on_tick(now);
+            socket.transmit(addr, &mut shared);
+            if matches!(shared.connection.state(), State::Closed | State::Reset) {
+                socket
+                    .connections
+                    .lock()
+                    .expect("Poisoned lock")
+                    .remove(&(addr, recv_id));
+            }
+        }
+    }
+}
+
+/// A uTP connection, read and written like a TCP stream
+#[derive(Debug)]
+pub struct UtpStream {
+    socket: Arc<SocketInner>,
+    shared: Arc<Mutex<Shared>>,
+    addr: SocketAddr,
+}
+
+impl UtpStream {
+    /// Connects from a socket of its own bound to an ephemeral port
+    pub async fn connect(addr: SocketAddr) -> io::Result<UtpStream> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        UtpSocket::bind(local).await?.connect(addr).await
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().expect("Poisoned lock")
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut shared = self.lock();
+        shared.connection.close();
+        self.socket.transmit(self.addr, &mut shared);
+    }
+}
+
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "uTP connection was reset")
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.lock();
+        let len = shared.connection.read(buf.initialize_unfilled());
+        if len > 0 {
+            buf.advance(len);
+            // Sends a window update if reading opened the window up
+            self.socket.transmit(self.addr, &mut shared);
+            return Poll::Ready(Ok(()));
+        }
+        if shared.connection.is_eof() || shared.connection.state() == State::Closed {
+            return Poll::Ready(Ok(()));
+        }
+        if shared.connection.state() == State::Reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        shared.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.lock();
+        if matches!(shared.connection.state(), State::Closed | State::Reset) {
+            return Poll::Ready(Err(reset_error()));
+        }
+        let len = shared.connection.write(data);
+        if len == 0 && !data.is_empty() {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.socket.transmit(self.addr, &mut shared);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.lock();
+        shared.connection.close();
+        self.socket.transmit(self.addr, &mut shared);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{UtpSocket, UtpStream};
+
+    #[tokio::test]
+    async fn test_loopback_stream() {
+        let listener = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(b"done").await.unwrap();
+            stream.shutdown().await.unwrap();
+            received
+        });
+        let mut stream = UtpStream::connect(addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(b"done".to_vec(), reply);
+        assert_eq!(expected, server.await.unwrap());
+    }
+}
@@ -0,0 +1,256 @@
+use std::path::Path;
+
+use reqwest::{header::RANGE, Client, StatusCode, Url};
+use thiserror::Error;
+
+use crate::torrent::{FileSpan, FileType, MetaInfo};
+
+/// An HTTP mirror of a torrent's content as described by BEP 19 (`url-list`)
+///
+/// Pieces are fetched with HTTP Range requests against the files that the piece covers, so a
+/// piece that spans several files of a multi file torrent takes one request per file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSeed {
+    url: Url,
+}
+
+impl WebSeed {
+    pub fn new(url: Url) -> Self {
+        Self { url }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The URL of a file of the torrent on this web seed
+    ///
+    /// A URL ending with `/` is a directory which the torrent's name (and the file's path in the
+    /// multi file case) is appended to; otherwise the URL of a single file torrent points straight
+    /// at the file.
+    pub fn file_url(&self, metainfo: &MetaInfo, relative_path: &Path) -> Result<Url, WebSeedError> {
+        let is_directory = self.url.path().ends_with('/');
+        if !is_directory && matches!(metainfo.file_type(), FileType::SingleFile(_)) {
+            return Ok(self.url.clone());
+        }
+        let mut url = self.url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| WebSeedError::InvalidUrl(self.url.to_string()))?;
+            // Drop the empty segment a trailing `/` leaves behind
+            segments.pop_if_empty();
+            for component in relative_path.iter() {
+                segments.push(&component.to_string_lossy());
+            }
+        }
+        Ok(url)
+    }
+
+    /// Downloads the raw bytes of a piece. The bytes are not verified against the piece hashes
+    pub async fn download_piece(
+        &self,
+        client: &Client,
+        metainfo: &MetaInfo,
+        piece_num: usize,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let files = metainfo.files();
+        let spans = metainfo.piece_spans(piece_num);
+        if spans.is_empty() {
+            return Err(WebSeedError::InvalidPiece(piece_num));
+        }
+        let mut piece = Vec::new();
+        for FileSpan {
+            file_index,
+            file_offset,
+            length,
+            ..
+        } in spans
+        {
+            // Pad files are zeros that no server has
+            if metainfo.is_pad_file(file_index) {
+                piece.resize(piece.len() + length as usize, 0);
+                continue;
+            }
+            let url = self.file_url(metainfo, &files[file_index].0)?;
+            piece.extend(fetch_range(client, url, file_offset, length).await?);
+        }
+        Ok(piece)
+    }
+}
+
+async fn fetch_range(
+    client: &Client,
+    url: Url,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>, WebSeedError> {
+    let mut res = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+        .send()
+        .await
+        .map_err(|err| WebSeedError::Request(err.to_string()))?;
+    match res.status() {
+        StatusCode::PARTIAL_CONTENT => {}
+        // The whole file would have to be read for every piece, so the body is left unread
+        StatusCode::OK => return Err(WebSeedError::RangesUnsupported(url.to_string())),
+        status => {
+            return Err(WebSeedError::Status {
+                url: url.to_string(),
+                status: status.as_u16(),
+            })
+        }
+    }
+    if res.content_length().is_some_and(|len| len > length) {
+        return Err(WebSeedError::LongRead {
+            url: url.to_string(),
+            expected: length,
+        });
+    }
+    // A body without a length is read no further than what was asked for
+    let mut bytes = Vec::with_capacity(length as usize);
+    while let Some(chunk) = res
+        .chunk()
+        .await
+        .map_err(|err| WebSeedError::Request(err.to_string()))?
+    {
+        let wanted = length as usize - bytes.len();
+        bytes.extend_from_slice(&chunk[..chunk.len().min(wanted)]);
+        if bytes.len() == length as usize {
+            return Ok(bytes);
+        }
+    }
+    Err(WebSeedError::ShortRead {
+        url: url.to_string(),
+        expected: length,
+    })
+}
+
+/// Error type for web seed downloads
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum WebSeedError {
+    #[error("Web seed URL cannot hold a path: {0}")]
+    InvalidUrl(String),
+    #[error("Piece {0} is not part of the torrent")]
+    InvalidPiece(usize),
+    #[error("Web seed request failed: {0}")]
+    Request(String),
+    #[error("Web seed {url} answered with status {status}")]
+    Status { url: String, status: u16 },
+    #[error("Web seed {0} ignores Range requests")]
+    RangesUnsupported(String),
+    #[error("Web seed {url} sent less than the {expected} bytes requested")]
+    ShortRead { url: String, expected: u64 },
+    #[error("Web seed {url} sent more than the {expected} bytes requested")]
+    LongRead { url: String, expected: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use reqwest::{Client, Url};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{WebSeed, WebSeedError};
+    use crate::{create::TorrentBuilder, torrent::from_bytes};
+
+    /// How the test server answers Range requests
+    #[derive(Debug, Clone, Copy)]
+    enum Answer {
+        Range,
+        /// The whole file with 200 OK
+        WholeFile,
+        /// The whole file, claiming it is the range
+        OversizedRange,
+    }
+
+    /// Serves the directory over HTTP for the number of requests given
+    async fn serve(root: &Path, requests: usize, answer: Answer) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let root = root.to_path_buf();
+        tokio::spawn(async move {
+            for _ in 0..requests {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                let path = req
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap()
+                    .trim_start_matches('/');
+                let range = req
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("range: bytes=")
+                            .map(str::to_owned)
+                    })
+                    .unwrap();
+                let (start, end) = range.trim().split_once('-').unwrap();
+                let (start, end) = (
+                    start.parse::<usize>().unwrap(),
+                    end.parse::<usize>().unwrap(),
+                );
+                let file = fs::read(root.join(path)).unwrap();
+                let (status, body) = match answer {
+                    Answer::Range => ("206 Partial Content", file[start..=end].to_vec()),
+                    Answer::WholeFile => ("200 OK", file),
+                    Answer::OversizedRange => ("206 Partial Content", file),
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(&body).await.unwrap();
+            }
+        });
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(root.join("a.txt"), vec![1u8; 10_000]).unwrap();
+        fs::write(root.join("lib").join("b.so"), vec![2u8; 10_000]).unwrap();
+        let (bytes, _) = TorrentBuilder::new(&root)
+            .with_piece_length(1 << 14)
+            .with_announce(Url::parse("http://tracker.example/announce").unwrap())
+            .build()
+            .unwrap();
+        let (_, metainfo) = from_bytes(bytes).unwrap();
+
+        // The first piece covers all of `a.txt` and the start of `lib/b.so`
+        let url = serve(dir.path(), 2, Answer::Range).await;
+        let seed = WebSeed::new(url);
+        let piece = seed
+            .download_piece(&Client::new(), &metainfo, 0)
+            .await
+            .unwrap();
+        assert_eq!(1 << 14, piece.len());
+        assert_eq!(vec![1u8; 10_000], piece[..10_000]);
+        assert_eq!(vec![2u8; (1 << 14) - 10_000], piece[10_000..]);
+
+        // Sending whole files back is refused rather than buffered
+        let seed = WebSeed::new(serve(dir.path(), 1, Answer::WholeFile).await);
+        assert!(matches!(
+            seed.download_piece(&Client::new(), &metainfo, 0).await,
+            Err(WebSeedError::RangesUnsupported(_))
+        ));
+        // And so is a range longer than the one asked for, which `a.txt` being read whole is not
+        let seed = WebSeed::new(serve(dir.path(), 2, Answer::OversizedRange).await);
+        assert!(matches!(
+            seed.download_piece(&Client::new(), &metainfo, 0).await,
+            Err(WebSeedError::LongRead { .. })
+        ));
+    }
+}